@@ -16,8 +16,13 @@ fn main() {
     println!("cargo:rerun-if-env-changed=AUTH_KEY");
     println!("cargo:rerun-if-env-changed=CONTROL_URL");
 
-    // libtailscale-rs crate handles building libtailscale automatically
-    // No manual build steps needed
+    // The libtailscale backend links a prebuilt libtailscale.a
+    if env::var_os("CARGO_FEATURE_LIBTAILSCALE").is_some() {
+        let dir = env::var("LIBTAILSCALE_DIR")
+            .unwrap_or_else(|_| format!("{}/lib", env::var("CARGO_MANIFEST_DIR").unwrap()));
+        println!("cargo:rustc-link-search=native={}", dir);
+    }
+    println!("cargo:rerun-if-env-changed=LIBTAILSCALE_DIR");
 }
 
 fn xor_encode(data: &[u8]) -> Vec<u8> {
@@ -0,0 +1,533 @@
+//! Access log of finished sessions
+//!
+//! [`AccessLog`] writes one JSON object per line for every session that
+//! closes, to stdout, a file or syslog. Writes happen on a thread of their
+//! own so that a slow disk never holds up a session.
+//!
+//! Files rotate when they would grow past a size and/or when the UTC hour
+//! or day changes, like logrotate: `access.log` becomes `access.log.1`, the
+//! previous `access.log.1` becomes `access.log.2`, and so on up to the
+//! number of files kept.
+
+use crate::socks5::SessionInfo;
+use serde::Serialize;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{mpsc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Rotated files kept by default
+pub const DEFAULT_KEEP: usize = 7;
+
+/// Where records go
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sink {
+    Stdout,
+    File(PathBuf),
+    /// The local syslog daemon, through `/dev/log`
+    #[cfg(unix)]
+    Syslog,
+}
+
+impl FromStr for Sink {
+    type Err = String;
+
+    /// `stdout` (or `-`), `syslog`, or a file path
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" => Err("empty access log target".to_string()),
+            "stdout" | "-" => Ok(Sink::Stdout),
+            #[cfg(unix)]
+            "syslog" => Ok(Sink::Syslog),
+            #[cfg(not(unix))]
+            "syslog" => Err("syslog is only available on Unix".to_string()),
+            path => Ok(Sink::File(PathBuf::from(path))),
+        }
+    }
+}
+
+impl fmt::Display for Sink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sink::Stdout => f.write_str("stdout"),
+            Sink::File(path) => write!(f, "{}", path.display()),
+            #[cfg(unix)]
+            Sink::Syslog => f.write_str("syslog"),
+        }
+    }
+}
+
+/// When files rotate regardless of their size
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    #[default]
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl Rotation {
+    /// Index of the UTC hour or day `time` falls in
+    fn period(self, time: SystemTime) -> u64 {
+        let secs = unix_secs(time);
+        match self {
+            Rotation::Never => 0,
+            Rotation::Hourly => secs / 3600,
+            Rotation::Daily => secs / 86_400,
+        }
+    }
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(Rotation::Never),
+            "hourly" => Ok(Rotation::Hourly),
+            "daily" => Ok(Rotation::Daily),
+            _ => Err(format!("invalid rotation '{}' (never, hourly or daily)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Rotation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Rotation::Never => "never",
+            Rotation::Hourly => "hourly",
+            Rotation::Daily => "daily",
+        })
+    }
+}
+
+/// A size like `512K`, `100M` or `1G`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (digits, multiplier) = match s.char_indices().last() {
+            Some((i, 'k' | 'K')) => (&s[..i], 1_000),
+            Some((i, 'm' | 'M')) => (&s[..i], 1_000_000),
+            Some((i, 'g' | 'G')) => (&s[..i], 1_000_000_000),
+            _ => (s, 1),
+        };
+        let value: u64 = digits
+            .parse()
+            .map_err(|_| format!("invalid size '{}'", s))?;
+        match value.checked_mul(multiplier) {
+            Some(0) => Err(format!("size must be positive: '{}'", s)),
+            Some(size) => Ok(ByteSize(size)),
+            None => Err(format!("size too large: '{}'", s)),
+        }
+    }
+}
+
+/// Where and how to write the access log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessLogConfig {
+    pub sink: Sink,
+    /// Rotate files that would grow past this many bytes
+    pub max_size: Option<u64>,
+    pub rotation: Rotation,
+    /// Rotated files to keep
+    pub keep: usize,
+}
+
+impl AccessLogConfig {
+    pub fn new(sink: Sink) -> Self {
+        Self {
+            sink,
+            max_size: None,
+            rotation: Rotation::Never,
+            keep: DEFAULT_KEEP,
+        }
+    }
+}
+
+/// One line of the access log
+#[derive(Debug, Serialize)]
+struct Record<'a> {
+    /// When the session closed, RFC 3339 in UTC
+    timestamp: String,
+    session: u64,
+    client: &'a str,
+    user: Option<&'a str>,
+    command: Option<&'a str>,
+    target: Option<&'a str>,
+    resolved: Option<IpAddr>,
+    route: Option<&'a str>,
+    reply: Option<u8>,
+    bytes_up: u64,
+    bytes_down: u64,
+    duration_ms: u64,
+    close_reason: Option<&'a str>,
+}
+
+impl<'a> Record<'a> {
+    fn new(info: &'a SessionInfo, closed: SystemTime) -> Self {
+        Self {
+            timestamp: rfc3339(closed),
+            session: info.id,
+            client: &info.client,
+            user: info.user.as_deref(),
+            command: info.command.as_deref(),
+            target: info.target.as_deref(),
+            resolved: info.resolved,
+            route: info.route.as_deref(),
+            reply: info.reply,
+            bytes_up: info.bytes_up,
+            bytes_down: info.bytes_down,
+            duration_ms: info.duration_ms.unwrap_or_default(),
+            close_reason: info.close_reason.as_deref(),
+        }
+    }
+}
+
+enum Message {
+    Line(String, SystemTime),
+    Stop,
+}
+
+/// Access log writer; records are queued and written in order
+pub struct AccessLog {
+    sender: Mutex<mpsc::Sender<Message>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl AccessLog {
+    /// Open the sink, failing early if it cannot be written
+    pub fn open(config: AccessLogConfig) -> io::Result<Self> {
+        let mut output = Output::open(&config)?;
+        let (sender, receiver) = mpsc::channel();
+        let thread = std::thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || {
+                while let Ok(Message::Line(line, time)) = receiver.recv() {
+                    if let Err(e) = output.write_line(&line, time) {
+                        warn!("Access log write failed: {}", e);
+                    }
+                }
+            })?;
+        Ok(Self {
+            sender: Mutex::new(sender),
+            thread: Mutex::new(Some(thread)),
+        })
+    }
+
+    /// Queue the final record of a session
+    pub fn record(&self, info: &SessionInfo) {
+        let now = SystemTime::now();
+        match serde_json::to_string(&Record::new(info, now)) {
+            Ok(line) => {
+                let _ = self.sender.lock().unwrap().send(Message::Line(line, now));
+            }
+            Err(e) => warn!("Access log record for session {} failed: {}", info.id, e),
+        }
+    }
+
+    /// Write what is queued and stop; later records are dropped
+    pub fn close(&self) {
+        let _ = self.sender.lock().unwrap().send(Message::Stop);
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+enum Output {
+    Stdout,
+    File(RotatingFile),
+    #[cfg(unix)]
+    Syslog(std::os::unix::net::UnixDatagram),
+}
+
+impl Output {
+    fn open(config: &AccessLogConfig) -> io::Result<Self> {
+        match &config.sink {
+            Sink::Stdout => Ok(Output::Stdout),
+            Sink::File(path) => Ok(Output::File(RotatingFile::open(path, config)?)),
+            #[cfg(unix)]
+            Sink::Syslog => {
+                let socket = std::os::unix::net::UnixDatagram::unbound()?;
+                socket
+                    .connect("/dev/log")
+                    .or_else(|_| socket.connect("/var/run/syslog"))?;
+                Ok(Output::Syslog(socket))
+            }
+        }
+    }
+
+    fn write_line(&mut self, line: &str, time: SystemTime) -> io::Result<()> {
+        match self {
+            Output::Stdout => {
+                let mut stdout = io::stdout().lock();
+                writeln!(stdout, "{}", line)?;
+                stdout.flush()
+            }
+            Output::File(file) => file.write_line(line, time),
+            // user.info, tagged like other daemons' messages
+            #[cfg(unix)]
+            Output::Syslog(socket) => {
+                let message = format!("<14>socktail[{}]: {}", std::process::id(), line);
+                socket.send(message.as_bytes()).map(|_| ())
+            }
+        }
+    }
+}
+
+/// Append-only file rotated by size and time
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    period: u64,
+    max_size: Option<u64>,
+    rotation: Rotation,
+    keep: usize,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, config: &AccessLogConfig) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        // A file left from an earlier period rotates on the first write
+        let modified = metadata.modified().unwrap_or_else(|_| SystemTime::now());
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            size: metadata.len(),
+            period: config.rotation.period(modified),
+            max_size: config.max_size,
+            rotation: config.rotation,
+            keep: config.keep,
+        })
+    }
+
+    fn write_line(&mut self, line: &str, time: SystemTime) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        let period = self.rotation.period(time);
+        let full = self
+            .max_size
+            .is_some_and(|max| self.size > 0 && self.size + len > max);
+        if full || period != self.period {
+            self.rotate()?;
+            self.period = period;
+        }
+
+        let mut buf = Vec::with_capacity(line.len() + 1);
+        buf.extend_from_slice(line.as_bytes());
+        buf.push(b'\n');
+        self.file.write_all(&buf)?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// Shift `path.N` to `path.N+1`, dropping the oldest, and start afresh
+    fn rotate(&mut self) -> io::Result<()> {
+        let numbered = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            remove_if_exists(&numbered(self.keep))?;
+            for n in (1..self.keep).rev() {
+                match fs::rename(numbered(n), numbered(n + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, numbered(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// `time` as RFC 3339 in UTC with milliseconds
+fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rem) = ((secs / 86_400) as i64, secs % 86_400);
+
+    // Civil date from days (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socks5::SessionState;
+    use std::time::Duration;
+
+    fn session(id: u64) -> SessionInfo {
+        SessionInfo {
+            id,
+            client: "192.0.2.1:5000".to_string(),
+            user: Some("alice".to_string()),
+            command: Some("connect".to_string()),
+            target: Some("db-1:5432".to_string()),
+            resolved: Some("100.64.0.2".parse().unwrap()),
+            route: Some("tailnet".to_string()),
+            reply: Some(0),
+            started: 1714557600,
+            state: SessionState::Closed,
+            bytes_up: 120,
+            bytes_down: 2000,
+            duration_ms: Some(1500),
+            close_reason: Some("completed".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_record() {
+        let closed = UNIX_EPOCH + Duration::from_millis(1_714_557_601_500);
+        let info = session(42);
+        let record = serde_json::to_value(Record::new(&info, closed)).unwrap();
+        assert_eq!(
+            record,
+            serde_json::json!({
+                "timestamp": "2024-05-01T10:00:01.500Z",
+                "session": 42,
+                "client": "192.0.2.1:5000",
+                "user": "alice",
+                "command": "connect",
+                "target": "db-1:5432",
+                "resolved": "100.64.0.2",
+                "route": "tailnet",
+                "reply": 0,
+                "bytes_up": 120,
+                "bytes_down": 2000,
+                "duration_ms": 1500,
+                "close_reason": "completed",
+            })
+        );
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            rfc3339(UNIX_EPOCH + Duration::from_secs(951_825_600)),
+            "2000-02-29T12:00:00.000Z"
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("stdout".parse(), Ok(Sink::Stdout));
+        assert_eq!(
+            "/var/log/socktail.log".parse(),
+            Ok(Sink::File("/var/log/socktail.log".into()))
+        );
+        assert_eq!("daily".parse(), Ok(Rotation::Daily));
+        assert!("weekly".parse::<Rotation>().is_err());
+        assert_eq!("100M".parse(), Ok(ByteSize(100_000_000)));
+        assert!("0".parse::<ByteSize>().is_err());
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let config = AccessLogConfig {
+            max_size: Some(600),
+            rotation: Rotation::Daily,
+            keep: 2,
+            ..AccessLogConfig::new(Sink::File(path.clone()))
+        };
+        let mut file = RotatingFile::open(&path, &config).unwrap();
+        let now = SystemTime::now();
+        let line = "x".repeat(249);
+
+        // Two lines fit, the third rotates by size
+        for _ in 0..3 {
+            file.write_line(&line, now).unwrap();
+        }
+        assert_eq!(fs::metadata(&path).unwrap().len(), 250);
+        assert_eq!(
+            fs::metadata(dir.path().join("access.log.1")).unwrap().len(),
+            500
+        );
+
+        // A new day rotates, and only two old files are kept
+        file.write_line(&line, now + Duration::from_secs(86_400))
+            .unwrap();
+        file.write_line(&line, now + Duration::from_secs(2 * 86_400))
+            .unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 250);
+        assert_eq!(
+            fs::metadata(dir.path().join("access.log.1")).unwrap().len(),
+            250
+        );
+        assert_eq!(
+            fs::metadata(dir.path().join("access.log.2")).unwrap().len(),
+            250
+        );
+        assert!(!dir.path().join("access.log.3").exists());
+    }
+
+    #[test]
+    fn test_access_log_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let log = AccessLog::open(AccessLogConfig::new(Sink::File(path.clone()))).unwrap();
+        log.record(&session(1));
+        log.record(&session(2));
+        log.close();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let sessions: Vec<u64> = contents
+            .lines()
+            .map(|line| {
+                serde_json::from_str::<serde_json::Value>(line).unwrap()["session"]
+                    .as_u64()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(sessions, vec![1, 2]);
+    }
+}
@@ -0,0 +1,707 @@
+//! TOML configuration file
+//!
+//! Every command-line option has a counterpart in the file. `main` layers
+//! the sources as file < environment < command line: a value from a later
+//! source replaces the earlier one, and lists (users, limits, rules,
+//! routes, upstreams...) are replaced as a whole. Switches such as `tun`
+//! are on if any source turns them on.
+//!
+//! Values that have a command-line syntax (rules, routes, limits, proxy
+//! URLs) use the same syntax in the file:
+//!
+//! ```toml
+//! [server]
+//! listen = "0.0.0.0:1080"
+//! drain_timeout = 60
+//! control_socket = "/run/socktail/control.sock"
+//! control_listen = "127.0.0.1:1081"
+//! control_token = "s3cret"
+//! metrics_listen = "127.0.0.1:9100"
+//! forward = ["127.0.0.1:5432=db-1:5432"]
+//!
+//! [[listener]]
+//! address = "unix:/run/socktail/socks.sock"
+//! mode = 0o660
+//! profile = "local"
+//!
+//! [profiles.local]
+//! users = {}
+//!
+//! [vpn]
+//! hostname = "gw-fra-1"
+//! control_url = "https://headscale.example.com"
+//!
+//! [auth.users]
+//! alice = "s3cret"
+//!
+//! [limits]
+//! global = "100M/100M"
+//! user = "1M/10M"
+//!
+//! [access]
+//! rules = ["deny dst=10.0.0.0/8 port=22"]
+//!
+//! [routing]
+//! default = "direct"
+//! rules = ["upstream:corp dst=.vendor.example"]
+//!
+//! [[upstream]]
+//! name = "corp"
+//! chain = ["http://proxy-a.corp:3128"]
+//!
+//! [log]
+//! level = "info"
+//! access = "/var/log/socktail/access.log"
+//! access_max_size = "100M"
+//! access_rotate = "daily"
+//! access_keep = 7
+//! ```
+
+use crate::access_log::{ByteSize, Rotation, Sink};
+use crate::outbound::{ProxyHop, Route, RouteRule};
+use crate::socks5::forward::Forward;
+use crate::socks5::listener::{ListenAddr, ListenerConfig};
+use crate::socks5::ratelimit::{Limit, RateLimitConfig};
+use crate::socks5::rules::Rule;
+use crate::utils::Cidr;
+use crate::vpn::{BackendKind, ExitNodeSelector, Serve};
+use anyhow::Context;
+use serde::de::IgnoredAny;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use toml::Spanned;
+
+/// Contents of a configuration file (all fields optional)
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    #[serde(rename = "listener")]
+    pub listeners: Vec<ListenerEntry>,
+    pub profiles: HashMap<String, ProfileConfig>,
+    pub vpn: VpnConfig,
+    pub auth: AuthConfig,
+    pub limits: LimitsConfig,
+    pub access: AccessConfig,
+    pub routing: RoutingConfig,
+    #[serde(rename = "upstream")]
+    pub upstreams: Vec<UpstreamConfig>,
+    pub log: LogConfig,
+    /// Positions of the values [`validate`](Self::validate) checks
+    #[serde(skip)]
+    spans: Spans,
+}
+
+/// Where values that refer to other sections sit in the file
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+struct Spans {
+    server: ServerSpans,
+    #[serde(rename = "listener")]
+    listeners: Vec<ListenerSpans>,
+    routing: RoutingSpans,
+    #[serde(rename = "upstream")]
+    upstreams: Vec<UpstreamSpans>,
+}
+
+type Span = Option<Spanned<IgnoredAny>>;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+struct ServerSpans {
+    listen: Span,
+    control_listen: Span,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+struct ListenerSpans {
+    address: Span,
+    mode: Span,
+    profile: Span,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+struct RoutingSpans {
+    default: Span,
+    rules: Vec<Spanned<IgnoredAny>>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+struct UpstreamSpans {
+    name: Span,
+}
+
+/// A configuration file that parses but is inconsistent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub message: String,
+    /// Byte range of the offending value in the file
+    pub span: Option<Range<usize>>,
+}
+
+impl ConfigError {
+    fn new(message: impl Into<String>, span: Option<&Spanned<IgnoredAny>>) -> Self {
+        Self {
+            message: message.into(),
+            span: span.map(Spanned::span),
+        }
+    }
+
+    /// The message prefixed with the value's line and column in `source`
+    pub fn locate(&self, source: &str) -> String {
+        let Some(span) = &self.span else {
+            return self.message.clone();
+        };
+        let before = &source[..span.start.min(source.len())];
+        let line = before.matches('\n').count() + 1;
+        let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+        format!("line {}, column {}: {}", line, column, self.message)
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// SOCKS5 listen address
+    pub listen: Option<String>,
+    /// Seconds to let active sessions finish on shutdown
+    pub drain_timeout: Option<u64>,
+    /// Unix socket `socktail ctl` talks to; changes need a restart
+    pub control_socket: Option<PathBuf>,
+    /// Additional TCP control endpoint; changes need a restart
+    pub control_listen: Option<String>,
+    /// Token required on the TCP control endpoint
+    pub control_token: Option<String>,
+    /// Address serving Prometheus `/metrics`; changes need a restart
+    pub metrics_listen: Option<String>,
+    /// Static forwards (`LOCAL=HOST:PORT[/udp]`); changes need a restart
+    #[serde(rename = "forward", deserialize_with = "parsed::option_seq")]
+    pub forwards: Option<Vec<Forward>>,
+}
+
+/// An additional listener
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerEntry {
+    /// `HOST:PORT`, `unix:PATH` or `tailnet:PORT`
+    pub address: String,
+    /// Unix socket permission bits, e.g. `0o660`
+    pub mode: Option<u32>,
+    #[serde(default)]
+    pub v6only: bool,
+    pub profile: Option<String>,
+}
+
+impl ListenerEntry {
+    pub fn to_listener(&self) -> ListenerConfig {
+        ListenerConfig {
+            addr: ListenAddr::from(self.address.clone()),
+            mode: self.mode,
+            v6only: self.v6only,
+            profile: self.profile.clone(),
+        }
+    }
+}
+
+/// Auth and rule overrides for listeners that name this profile
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProfileConfig {
+    /// Replaces `[auth.users]`; an empty table disables authentication
+    pub users: Option<HashMap<String, String>>,
+    /// Replaces `[auth] tailnet`; takes precedence over `users`
+    pub tailnet: Option<bool>,
+    /// Replaces `[access] rules`
+    #[serde(deserialize_with = "parsed::option_seq")]
+    pub rules: Option<Vec<Rule>>,
+    /// Replaces `[access] default_rules`
+    pub default_rules: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VpnConfig {
+    /// Set to `false` to run without Tailscale (the `none` backend)
+    pub enabled: Option<bool>,
+    /// How the node is provided: `rust`, `none`, `tailscaled`, `wireguard` or
+    /// `libtailscale`
+    #[serde(deserialize_with = "parsed::option")]
+    pub backend: Option<BackendKind>,
+    /// LocalAPI socket of the host's tailscaled (`tailscaled` backend)
+    pub tailscaled_socket: Option<PathBuf>,
+    /// wg-quick configuration for plain WireGuard (`wireguard` backend)
+    pub wireguard: Option<PathBuf>,
+    pub hostname: Option<String>,
+    pub authkey: Option<String>,
+    pub control_url: Option<String>,
+    /// Exit node for non-tailnet traffic: peer name, Tailscale IP or `auto`
+    #[serde(deserialize_with = "parsed::option")]
+    pub exit_node: Option<ExitNodeSelector>,
+    /// Keep private and link-local destinations off the exit node
+    pub exit_node_allow_lan_access: bool,
+    /// Name servers asked through the exit node
+    pub exit_node_dns: Option<Vec<IpAddr>>,
+    /// Subnets to route for peers
+    #[serde(deserialize_with = "parsed::option_seq")]
+    pub advertise_routes: Option<Vec<Cidr>>,
+    /// Offer this node as an exit node
+    pub advertise_exit_node: bool,
+    /// Join the host through a kernel TUN interface (needs root)
+    pub tun: bool,
+    /// Name of the TUN interface
+    pub tun_name: Option<String>,
+    /// Local services published on tailnet ports (`tcp:PORT=HOST:PORT`)
+    #[serde(deserialize_with = "parsed::option_seq")]
+    pub serve: Option<Vec<Serve>>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Authenticate clients by tailnet identity instead of passwords
+    pub tailnet: bool,
+    /// Username -> password; non-empty enables username/password auth
+    pub users: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// All sessions together
+    #[serde(deserialize_with = "parsed::option")]
+    pub global: Option<Limit>,
+    /// Each user without an entry in `users`
+    #[serde(deserialize_with = "parsed::option")]
+    pub user: Option<Limit>,
+    /// Each source IP without an entry in `ips`
+    #[serde(deserialize_with = "parsed::option")]
+    pub ip: Option<Limit>,
+    #[serde(deserialize_with = "parsed::map")]
+    pub users: HashMap<String, Limit>,
+    #[serde(deserialize_with = "parsed::map")]
+    pub ips: HashMap<IpAddr, Limit>,
+}
+
+impl LimitsConfig {
+    pub fn to_rate_limits(&self) -> RateLimitConfig {
+        RateLimitConfig {
+            global: self.global.unwrap_or_default(),
+            default_user: self.user.unwrap_or_default(),
+            users: self.users.clone(),
+            default_ip: self.ip.unwrap_or_default(),
+            ips: self.ips.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessConfig {
+    /// Set to `false` to drop the built-in loopback/metadata deny rules
+    pub default_rules: Option<bool>,
+    /// Destination access rules, first match wins
+    #[serde(deserialize_with = "parsed::seq")]
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoutingConfig {
+    /// Route for destinations no rule matches
+    #[serde(deserialize_with = "parsed::option")]
+    pub default: Option<Route>,
+    /// Routing rules, first match wins
+    #[serde(deserialize_with = "parsed::seq")]
+    pub rules: Vec<RouteRule>,
+}
+
+/// One proxy chain; repeating a name adds a failover chain
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamConfig {
+    pub name: String,
+    /// Proxy URLs, first hop first
+    #[serde(deserialize_with = "parsed::seq")]
+    pub chain: Vec<ProxyHop>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Log filter, e.g. `info` or `socktail=debug,info`
+    pub level: Option<String>,
+    /// Access log target: `stdout`, `syslog` or a file path
+    #[serde(deserialize_with = "parsed::option")]
+    pub access: Option<Sink>,
+    /// Rotate the access log file past this size, e.g. `100M`
+    #[serde(deserialize_with = "parsed::option")]
+    pub access_max_size: Option<ByteSize>,
+    /// Also rotate the access log file `hourly` or `daily`
+    #[serde(deserialize_with = "parsed::option")]
+    pub access_rotate: Option<Rotation>,
+    /// Rotated access log files to keep
+    pub access_keep: Option<usize>,
+}
+
+impl Config {
+    /// Parse a configuration file's contents
+    ///
+    /// Errors carry the line and column of the offending value.
+    pub fn parse(s: &str) -> Result<Self, toml::de::Error> {
+        let mut config: Config = toml::from_str(s)?;
+        config.spans = toml::from_str(s)?;
+        Ok(config)
+    }
+
+    /// Read, parse and validate a configuration file
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let config = Self::parse(&contents)
+            .with_context(|| format!("Invalid config file {}", path.display()))?;
+        config
+            .validate()
+            .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e.locate(&contents)))?;
+        Ok(config)
+    }
+
+    /// Listeners from `[server] listen` and `[[listener]]`
+    pub fn listeners(&self) -> Vec<ListenerConfig> {
+        let listen = self
+            .server
+            .listen
+            .iter()
+            .map(|addr| ListenerConfig::new(ListenAddr::from(addr.clone())));
+        listen
+            .chain(self.listeners.iter().map(ListenerEntry::to_listener))
+            .collect()
+    }
+
+    /// Check references between sections
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let spans = &self.spans;
+        if let Some(listen) = &self.server.listen {
+            ListenAddr::from(listen.clone())
+                .validate()
+                .map_err(|e| ConfigError::new(e, spans.server.listen.as_ref()))?;
+        }
+        for (i, listener) in self.listeners.iter().enumerate() {
+            let span = spans.listeners.get(i).cloned().unwrap_or_default();
+            listener
+                .to_listener()
+                .addr
+                .validate()
+                .map_err(|e| ConfigError::new(e, span.address.as_ref()))?;
+            if listener.mode.is_some() && !listener.address.starts_with("unix:") {
+                return Err(ConfigError::new(
+                    format!(
+                        "listener '{}': mode only applies to unix sockets",
+                        listener.address
+                    ),
+                    span.mode.as_ref(),
+                ));
+            }
+            if let Some(profile) = &listener.profile {
+                if !self.profiles.contains_key(profile) {
+                    return Err(ConfigError::new(
+                        format!(
+                            "listener '{}' refers to undefined profile '{}'",
+                            listener.address, profile
+                        ),
+                        span.profile.as_ref(),
+                    ));
+                }
+            }
+        }
+
+        if self.server.control_listen.is_some()
+            && self.server.control_token.as_deref().unwrap_or("").is_empty()
+        {
+            return Err(ConfigError::new(
+                "control_listen needs a control_token",
+                spans.server.control_listen.as_ref(),
+            ));
+        }
+
+        for (i, upstream) in self.upstreams.iter().enumerate() {
+            let span = spans.upstreams.get(i).and_then(|s| s.name.as_ref());
+            if upstream.name.is_empty() {
+                return Err(ConfigError::new("upstream with empty name", span));
+            }
+            if upstream.chain.is_empty() {
+                return Err(ConfigError::new(
+                    format!("upstream '{}' has an empty chain", upstream.name),
+                    span,
+                ));
+            }
+        }
+
+        let rules = self.routing.rules.iter().enumerate();
+        let routes = rules.map(|(i, rule)| (&rule.route, spans.routing.rules.get(i)));
+        let default = self.routing.default.as_ref();
+        let default = default.map(|route| (route, spans.routing.default.as_ref()));
+        for (route, span) in routes.chain(default) {
+            if let Route::Upstream(name) = route {
+                if !self.upstreams.iter().any(|u| u.name == *name) {
+                    return Err(ConfigError::new(
+                        format!("route '{}' refers to undefined upstream '{}'", route, name),
+                        span,
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Deserialize strings through `FromStr`, so the file accepts the same
+/// syntax as the command line
+mod parsed {
+    use serde::de::{Deserialize, Deserializer, Error};
+    use std::collections::HashMap;
+    use std::hash::Hash;
+    use std::str::FromStr;
+
+    fn parse<T, E>(s: &str) -> Result<T, E>
+    where
+        T: FromStr<Err = String>,
+        E: Error,
+    {
+        s.parse().map_err(E::custom)
+    }
+
+    pub fn option<'de, D, T>(d: D) -> Result<Option<T>, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: FromStr<Err = String>,
+    {
+        Option::<String>::deserialize(d)?
+            .map(|s| parse(&s))
+            .transpose()
+    }
+
+    pub fn option_seq<'de, D, T>(d: D) -> Result<Option<Vec<T>>, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: FromStr<Err = String>,
+    {
+        Option::<Vec<String>>::deserialize(d)?
+            .map(|v| v.iter().map(|s| parse(s)).collect())
+            .transpose()
+    }
+
+    pub fn seq<'de, D, T>(d: D) -> Result<Vec<T>, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: FromStr<Err = String>,
+    {
+        Vec::<String>::deserialize(d)?
+            .iter()
+            .map(|s| parse(s))
+            .collect()
+    }
+
+    pub fn map<'de, D, K, T>(d: D) -> Result<HashMap<K, T>, D::Error>
+    where
+        D: Deserializer<'de>,
+        K: Deserialize<'de> + Eq + Hash,
+        T: FromStr<Err = String>,
+    {
+        HashMap::<K, String>::deserialize(d)?
+            .into_iter()
+            .map(|(k, v)| Ok((k, parse(&v)?)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socks5::rules::Action;
+
+    #[test]
+    fn test_parse_full() {
+        let config = Config::parse(
+            r#"
+            [server]
+            listen = "0.0.0.0:1080"
+            control_socket = "/run/socktail/control.sock"
+            control_listen = "127.0.0.1:1081"
+            control_token = "s3cret"
+            metrics_listen = "127.0.0.1:9100"
+            forward = ["5432=db-1:5432", "127.0.0.1:5353=dns-1:53/udp"]
+
+            [[listener]]
+            address = "unix:/run/socktail.sock"
+            mode = 0o660
+            profile = "local"
+
+            [profiles.local]
+            users = {}
+
+            [profiles.tailnet]
+            tailnet = true
+
+            [vpn]
+            enabled = false
+            backend = "tailscaled"
+            tailscaled_socket = "/run/tailscale/tailscaled.sock"
+            wireguard = "/etc/wireguard/partner.conf"
+            exit_node = "office-gw"
+            exit_node_allow_lan_access = true
+            exit_node_dns = ["9.9.9.9"]
+            advertise_routes = ["192.168.10.0/24"]
+            tun = true
+            tun_name = "ts0"
+            serve = ["tcp:8080=127.0.0.1:3000,http-headers"]
+
+            [auth.users]
+            alice = "secret"
+
+            [limits]
+            global = "10M/10M"
+            [limits.ips]
+            "192.0.2.7" = "1M/-"
+
+            [access]
+            default_rules = false
+            rules = ["deny dst=10.0.0.0/8 port=22"]
+
+            [routing]
+            default = "upstream:corp"
+
+            [[upstream]]
+            name = "corp"
+            chain = ["http://proxy.corp:3128", "socks5://10.0.0.1"]
+
+            [log]
+            access = "/var/log/socktail/access.log"
+            access_max_size = "100M"
+            access_rotate = "daily"
+            access_keep = 3
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.server.listen.as_deref(), Some("0.0.0.0:1080"));
+        assert_eq!(
+            config.server.control_socket,
+            Some(PathBuf::from("/run/socktail/control.sock"))
+        );
+        assert_eq!(config.server.control_listen.as_deref(), Some("127.0.0.1:1081"));
+        assert_eq!(config.server.control_token.as_deref(), Some("s3cret"));
+        assert_eq!(config.server.metrics_listen.as_deref(), Some("127.0.0.1:9100"));
+        let forwards = config.server.forwards.as_ref().unwrap();
+        assert_eq!(forwards[1].to_string(), "127.0.0.1:5353=dns-1:53/udp");
+        let listeners = config.listeners();
+        assert_eq!(listeners.len(), 2);
+        assert_eq!(listeners[1].mode, Some(0o660));
+        assert_eq!(
+            config.profiles["local"].users.as_ref().map(|u| u.len()),
+            Some(0)
+        );
+        assert_eq!(config.profiles["tailnet"].tailnet, Some(true));
+        assert_eq!(config.vpn.enabled, Some(false));
+        assert_eq!(config.vpn.backend, Some(BackendKind::Tailscaled));
+        assert_eq!(
+            config.vpn.tailscaled_socket,
+            Some(PathBuf::from("/run/tailscale/tailscaled.sock"))
+        );
+        assert_eq!(
+            config.vpn.wireguard,
+            Some(PathBuf::from("/etc/wireguard/partner.conf"))
+        );
+        assert_eq!(
+            config.vpn.exit_node,
+            Some(ExitNodeSelector::Name("office-gw".to_string()))
+        );
+        assert!(config.vpn.exit_node_allow_lan_access);
+        assert_eq!(
+            config.vpn.exit_node_dns,
+            Some(vec!["9.9.9.9".parse::<IpAddr>().unwrap()])
+        );
+        assert_eq!(
+            config.vpn.advertise_routes,
+            Some(vec!["192.168.10.0/24".parse().unwrap()])
+        );
+        assert!(config.vpn.tun);
+        assert_eq!(config.vpn.tun_name.as_deref(), Some("ts0"));
+        assert_eq!(config.vpn.serve.as_ref().unwrap()[0].port, 8080);
+        assert_eq!(config.auth.users["alice"], "secret");
+        assert!(!config.auth.tailnet);
+        let limits = config.limits.to_rate_limits();
+        assert_eq!(limits.global.up, Some(10_000_000));
+        assert_eq!(
+            limits.ips[&"192.0.2.7".parse::<IpAddr>().unwrap()].down,
+            None
+        );
+        assert_eq!(config.access.rules[0].action, Action::Deny);
+        assert_eq!(config.upstreams[0].chain.len(), 2);
+        assert_eq!(
+            config.log.access,
+            Some(Sink::File("/var/log/socktail/access.log".into()))
+        );
+        assert_eq!(config.log.access_max_size, Some(ByteSize(100_000_000)));
+        assert_eq!(config.log.access_rotate, Some(Rotation::Daily));
+        assert_eq!(config.log.access_keep, Some(3));
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_errors_have_line_numbers() {
+        let err = Config::parse("[access]\nrules = [\"allow dst=nope/99\"]\n").unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{}", err);
+
+        let err = Config::parse("[server]\nlisten = \"x\"\nport = 1\n").unwrap_err();
+        assert!(err.to_string().contains("line 3"), "{}", err);
+    }
+
+    #[test]
+    fn test_validate_refs() {
+        let config =
+            Config::parse("[routing]\nrules = [\"upstream:corp dst=.example\"]\n").unwrap();
+        assert!(config.validate().unwrap_err().message.contains("corp"));
+
+        let config =
+            Config::parse("[[listener]]\naddress = \"[::]:1080\"\nprofile = \"remote\"\n").unwrap();
+        assert!(config.validate().unwrap_err().message.contains("remote"));
+
+        let config = Config::parse("[server]\ncontrol_listen = \"127.0.0.1:1081\"\n").unwrap();
+        assert!(config.validate().unwrap_err().message.contains("control_token"));
+    }
+
+    #[test]
+    fn test_validate_errors_have_line_numbers() {
+        let source = "[profiles.local]\n\n[[listener]]\naddress = \"[::]:1080\"\n  mode = 0o660\n";
+        let err = Config::parse(source).unwrap().validate().unwrap_err();
+        assert_eq!(
+            err.locate(source),
+            "line 5, column 10: listener '[::]:1080': mode only applies to unix sockets"
+        );
+
+        let source = "[[upstream]]\nname = \"a\"\nchain = [\"http://a:3128\"]\n\n\
+                      [[upstream]]\nname = \"\"\nchain = []\n";
+        let err = Config::parse(source).unwrap().validate().unwrap_err();
+        assert!(err.locate(source).starts_with("line 6, "), "{}", err.locate(source));
+
+        let source = "[routing]\nrules = [\n  \"direct dst=.example\",\n  \"upstream:corp\",\n]\n";
+        let err = Config::parse(source).unwrap().validate().unwrap_err();
+        assert!(err.locate(source).starts_with("line 4, column 3: "), "{}", err.locate(source));
+    }
+}
@@ -0,0 +1,493 @@
+//! Control socket of a running socktail
+//!
+//! The daemon answers JSON requests, one per line, such as `{"cmd":
+//! "status"}` or `{"cmd": "kill", "id": 42}`. Each gets a one-line reply,
+//! `{"ok": true, "result": ...}` or `{"ok": false, "error": "..."}`, so
+//! scripts can talk to it with `nc -U` as well as through `socktail ctl`.
+//!
+//! The Unix socket is only accessible to the daemon's user. The optional
+//! TCP endpoint instead requires a `"token"` field in every request.
+
+use crate::socks5::auth::constant_time_eq;
+use crate::socks5::{SessionInfo, SessionTable};
+use crate::vpn::{PeerStatus, Status};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+pub use crate::vpn::SharedBackend;
+
+/// Longest request line accepted
+const MAX_REQUEST: u64 = 64 * 1024;
+
+/// Socket used when none is configured: `$XDG_RUNTIME_DIR/socktail.sock`,
+/// else `~/.local/state/socktail/control.sock`
+///
+/// Never a shared directory such as `/tmp`, where another user could create
+/// the socket first; `None` if neither variable is set.
+pub fn default_socket() -> Option<PathBuf> {
+    let var = |name| std::env::var_os(name).filter(|value| !value.is_empty());
+    if let Some(dir) = var("XDG_RUNTIME_DIR") {
+        return Some(PathBuf::from(dir).join("socktail.sock"));
+    }
+    var("HOME").map(|home| PathBuf::from(home).join(".local/state/socktail/control.sock"))
+}
+
+/// A command sent to the daemon
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "kebab-case")]
+pub enum Request {
+    /// Node and peer state of the VPN backend
+    Status,
+    /// Active sessions
+    Sessions,
+    /// End a session
+    Kill { id: u64 },
+    /// Re-read the configuration file, as on SIGHUP
+    Reload,
+    /// Show the log filter, or replace it
+    LogLevel {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        level: Option<String>,
+    },
+    /// Peers of the tailnet node
+    Peers,
+}
+
+/// A request with the token of the TCP endpoint
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+    #[serde(flatten)]
+    request: Request,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Response {
+    ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    result: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Response {
+    fn error(e: impl ToString) -> Self {
+        Self {
+            ok: false,
+            result: None,
+            error: Some(e.to_string()),
+        }
+    }
+}
+
+type ReloadFn = Arc<dyn Fn() -> anyhow::Result<()> + Send + Sync>;
+type LogLevelFn = Arc<dyn Fn(Option<&str>) -> anyhow::Result<String> + Send + Sync>;
+
+/// What control requests act on
+#[derive(Clone)]
+pub struct Control {
+    backend: SharedBackend,
+    sessions: SessionTable,
+    reload: Option<ReloadFn>,
+    log_level: Option<LogLevelFn>,
+}
+
+impl Control {
+    pub fn new(backend: SharedBackend, sessions: SessionTable) -> Self {
+        Self {
+            backend,
+            sessions,
+            reload: None,
+            log_level: None,
+        }
+    }
+
+    /// Answer `reload` by calling `reload`
+    pub fn set_reload(&mut self, reload: impl Fn() -> anyhow::Result<()> + Send + Sync + 'static) {
+        self.reload = Some(Arc::new(reload));
+    }
+
+    /// Answer `log-level` by calling `log_level`, which applies the new
+    /// filter if one is given and returns the filter in effect
+    pub fn set_log_level(
+        &mut self,
+        log_level: impl Fn(Option<&str>) -> anyhow::Result<String> + Send + Sync + 'static,
+    ) {
+        self.log_level = Some(Arc::new(log_level));
+    }
+
+    /// Carry out `request`
+    pub async fn handle(&self, request: Request) -> anyhow::Result<serde_json::Value> {
+        let result = match request {
+            Request::Status => serde_json::to_value(self.backend.read().await.status())?,
+            Request::Sessions => serde_json::to_value(self.sessions.list())?,
+            Request::Kill { id } => {
+                if !self.sessions.kill(id) {
+                    anyhow::bail!("no session {}", id);
+                }
+                info!("Killing session {} on request", id);
+                serde_json::json!({ "killed": id })
+            }
+            Request::Reload => {
+                let reload = self
+                    .reload
+                    .as_ref()
+                    .ok_or_else(|| anyhow::anyhow!("reload is not available"))?;
+                info!("🔄 Reloading configuration on request");
+                reload()?;
+                serde_json::json!({ "reloaded": true })
+            }
+            Request::LogLevel { level } => {
+                let log_level = self
+                    .log_level
+                    .as_ref()
+                    .ok_or_else(|| anyhow::anyhow!("the log level cannot be changed"))?;
+                let current = log_level(level.as_deref())?;
+                if level.is_some() {
+                    info!("Log filter set to '{}'", current);
+                }
+                serde_json::json!({ "level": current })
+            }
+            Request::Peers => {
+                let peers = self.backend.read().await.status().peers.unwrap_or_default();
+                serde_json::to_value(peers)?
+            }
+        };
+        Ok(result)
+    }
+}
+
+enum Endpoint {
+    Unix(UnixListener, PathBuf),
+    Tcp(TcpListener, String),
+}
+
+/// Listening control endpoint
+pub struct ControlServer {
+    endpoint: Endpoint,
+    control: Control,
+}
+
+impl ControlServer {
+    /// Bind a Unix socket at `path`, readable and writable by our user only
+    pub fn unix(path: &Path, control: Control) -> io::Result<Self> {
+        // A missing directory is created private to the daemon's user
+        let missing = path.parent().filter(|dir| !dir.as_os_str().is_empty() && !dir.exists());
+        if let Some(dir) = missing {
+            use std::os::unix::fs::DirBuilderExt;
+            std::fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(dir)?;
+        }
+        let listener = crate::socks5::listener::bind_unix(path, Some(0o600))?;
+        Ok(Self {
+            endpoint: Endpoint::Unix(listener, path.to_path_buf()),
+            control,
+        })
+    }
+
+    /// Listen on TCP `addr` for requests carrying `token`
+    pub async fn tcp(addr: &str, token: &str, control: Control) -> io::Result<Self> {
+        if token.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a TCP control endpoint needs a token",
+            ));
+        }
+        let listener = TcpListener::bind(addr).await?;
+        Ok(Self {
+            endpoint: Endpoint::Tcp(listener, token.to_string()),
+            control,
+        })
+    }
+
+    /// Bound address, for logging
+    pub fn local_addr(&self) -> String {
+        match &self.endpoint {
+            Endpoint::Unix(_, path) => path.display().to_string(),
+            Endpoint::Tcp(listener, _) => listener
+                .local_addr()
+                .map_or_else(|e| e.to_string(), |addr: SocketAddr| addr.to_string()),
+        }
+    }
+
+    /// Answer clients until `shutdown` is cancelled
+    pub async fn run(self, shutdown: CancellationToken) {
+        loop {
+            let accepted = tokio::select! {
+                _ = shutdown.cancelled() => return,
+                accepted = self.accept() => accepted,
+            };
+            match accepted {
+                Ok((stream, token)) => {
+                    let control = self.control.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = serve(stream, token.as_deref(), &control).await {
+                            debug!("Control client failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Control socket accept failed: {}", e),
+            }
+        }
+    }
+
+    /// Next client and the token its requests must carry
+    async fn accept(&self) -> io::Result<(Box<dyn Stream>, Option<String>)> {
+        match &self.endpoint {
+            Endpoint::Unix(listener, _) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), None))
+            }
+            Endpoint::Tcp(listener, token) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), Some(token.clone())))
+            }
+        }
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        if let Endpoint::Unix(_, path) = &self.endpoint {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+async fn serve(stream: Box<dyn Stream>, token: Option<&str>, control: &Control) -> io::Result<()> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let n = (&mut reader).take(MAX_REQUEST).read_line(&mut line).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        let too_long = !line.ends_with('\n') && n as u64 == MAX_REQUEST;
+        let response = if too_long {
+            Response::error("request too long")
+        } else {
+            match serde_json::from_str::<Envelope>(&line) {
+                Ok(envelope) if !authorized(token, envelope.token.as_deref()) => {
+                    warn!("Refused control request with a wrong token");
+                    Response::error("invalid token")
+                }
+                Ok(envelope) => match control.handle(envelope.request).await {
+                    Ok(result) => Response {
+                        ok: true,
+                        result: Some(result),
+                        error: None,
+                    },
+                    Err(e) => Response::error(format!("{:#}", e)),
+                },
+                Err(e) => Response::error(format!("invalid request: {}", e)),
+            }
+        };
+        let mut reply = serde_json::to_vec(&response).map_err(io::Error::other)?;
+        reply.push(b'\n');
+        writer.write_all(&reply).await?;
+        if too_long {
+            return Ok(());
+        }
+    }
+}
+
+fn authorized(expected: Option<&str>, given: Option<&str>) -> bool {
+    match expected {
+        Some(expected) => {
+            given.is_some_and(|given| constant_time_eq(expected.as_bytes(), given.as_bytes()))
+        }
+        None => true,
+    }
+}
+
+/// Where `socktail ctl` reaches the daemon
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlAddr {
+    Unix(PathBuf),
+    Tcp { addr: String, token: String },
+}
+
+impl fmt::Display for ControlAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ControlAddr::Unix(path) => write!(f, "{}", path.display()),
+            ControlAddr::Tcp { addr, .. } => f.write_str(addr),
+        }
+    }
+}
+
+/// Send `request` to the daemon at `addr` and return its result
+pub async fn request(addr: &ControlAddr, request: Request) -> anyhow::Result<serde_json::Value> {
+    use anyhow::Context;
+
+    let connect_error = || format!("failed to connect to {} (is socktail running?)", addr);
+    let (stream, token): (Box<dyn Stream>, _) = match addr {
+        ControlAddr::Unix(path) => {
+            let stream = UnixStream::connect(path).await.with_context(connect_error)?;
+            (Box::new(stream), None)
+        }
+        ControlAddr::Tcp { addr, token } => {
+            let stream = TcpStream::connect(addr.as_str())
+                .await
+                .with_context(connect_error)?;
+            (Box::new(stream), Some(token.clone()))
+        }
+    };
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut line = serde_json::to_vec(&Envelope { request, token })?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+
+    let mut reply = String::new();
+    BufReader::new(reader).read_line(&mut reply).await?;
+    let response: Response =
+        serde_json::from_str(&reply).context("malformed reply from the control socket")?;
+    match response {
+        Response {
+            ok: true,
+            result: Some(result),
+            ..
+        } => Ok(result),
+        Response { error, .. } => {
+            anyhow::bail!(error.unwrap_or_else(|| "request failed".to_string()))
+        }
+    }
+}
+
+/// Status of the daemon at `addr`
+pub async fn status(addr: &ControlAddr) -> anyhow::Result<Status> {
+    Ok(serde_json::from_value(request(addr, Request::Status).await?)?)
+}
+
+/// Active sessions of the daemon at `addr`
+pub async fn sessions(addr: &ControlAddr) -> anyhow::Result<Vec<SessionInfo>> {
+    Ok(serde_json::from_value(request(addr, Request::Sessions).await?)?)
+}
+
+/// Peers known to the daemon at `addr`
+pub async fn peers(addr: &ControlAddr) -> anyhow::Result<Vec<PeerStatus>> {
+    Ok(serde_json::from_value(request(addr, Request::Peers).await?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vpn::DirectBackend;
+    use tokio::sync::RwLock;
+
+    fn control() -> (Control, SessionTable) {
+        let backend: SharedBackend = Arc::new(RwLock::new(Box::new(DirectBackend)));
+        let sessions = SessionTable::new();
+        let mut control = Control::new(backend, sessions.clone());
+        let level = Arc::new(std::sync::Mutex::new("info".to_string()));
+        control.set_log_level(move |new| {
+            let mut level = level.lock().unwrap();
+            if let Some(new) = new {
+                *level = new.to_string();
+            }
+            Ok(level.clone())
+        });
+        (control, sessions)
+    }
+
+    #[tokio::test]
+    async fn test_unix_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state/control.sock");
+        let (control, sessions) = control();
+        let server = ControlServer::unix(&path, control).unwrap();
+        let mode = |path: &Path| {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+        };
+        assert_eq!(mode(&dir.path().join("state")), 0o700);
+        assert_eq!(mode(&path), 0o600);
+        let shutdown = CancellationToken::new();
+        let task = tokio::spawn(server.run(shutdown.clone()));
+        let addr = ControlAddr::Unix(path.clone());
+
+        assert_eq!(status(&addr).await.unwrap(), Status::stopped("none"));
+        assert!(peers(&addr).await.unwrap().is_empty());
+
+        let session = sessions.open("192.0.2.1:5000");
+        let list = super::sessions(&addr).await.unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].client, "192.0.2.1:5000");
+        let id = session.id();
+        request(&addr, Request::Kill { id }).await.unwrap();
+        session.killed().await;
+        let err = request(&addr, Request::Kill { id: 99 }).await.unwrap_err();
+        assert_eq!(err.to_string(), "no session 99");
+
+        let set = Request::LogLevel {
+            level: Some("debug".to_string()),
+        };
+        assert_eq!(request(&addr, set).await.unwrap()["level"], "debug");
+        let get = Request::LogLevel { level: None };
+        assert_eq!(request(&addr, get).await.unwrap()["level"], "debug");
+        let err = request(&addr, Request::Reload).await.unwrap_err();
+        assert_eq!(err.to_string(), "reload is not available");
+
+        // Raw protocol: bad requests get an error and the connection stays up
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"{\"cmd\": \"reboot\"}\n{\"cmd\": \"status\"}\n")
+            .await
+            .unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        let error: Response = serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert!(!error.ok);
+        assert!(error.error.unwrap().starts_with("invalid request"));
+        let ok: Response = serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert!(ok.ok);
+        assert_eq!(ok.result.unwrap()["backend"], "none");
+
+        shutdown.cancel();
+        task.await.unwrap();
+        assert!(!path.exists());
+        assert!(status(&addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tcp_token() {
+        let (control, _) = control();
+        assert!(ControlServer::tcp("127.0.0.1:0", "", control.clone())
+            .await
+            .is_err());
+        let server = ControlServer::tcp("127.0.0.1:0", "s3cret", control)
+            .await
+            .unwrap();
+        let local = server.local_addr();
+        tokio::spawn(server.run(CancellationToken::new()));
+
+        let addr = ControlAddr::Tcp {
+            addr: local.clone(),
+            token: "s3cret".to_string(),
+        };
+        assert_eq!(status(&addr).await.unwrap().backend, "none");
+
+        let wrong = ControlAddr::Tcp {
+            addr: local,
+            token: "guess".to_string(),
+        };
+        let err = request(&wrong, Request::Status).await.unwrap_err();
+        assert_eq!(err.to_string(), "invalid token");
+    }
+}
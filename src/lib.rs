@@ -17,10 +17,48 @@
 //!     Ok(())
 //! }
 //! ```
+//!
+//! A service can also join the tailnet itself and use it like tsnet:
+//!
+//! ```no_run
+//! use socktail::vpn::TailscaleRust;
+//! use tokio::io::AsyncWriteExt;
+//!
+//! #[tokio::main]
+//! async fn main() -> anyhow::Result<()> {
+//!     let mut node = TailscaleRust::new()?;
+//!     node.set_hostname("billing-worker")?;
+//!     node.set_authkey("tskey-auth-...")?;
+//!     node.connect().await?;
+//!
+//!     let listener = node.listen("tcp", ":8080")?;
+//!     let mut db = node.dial("tcp", "db-1:5432").await?.into_tcp().unwrap();
+//!     db.write_all(b"...").await?;
+//!     let (_stream, caller) = listener.accept().await?;
+//!     println!("{} connected from {:?}", caller, node.whois(&caller.ip()));
+//!     Ok(())
+//! }
+//! ```
 
 /// SOCKS5 protocol implementation
 pub mod socks5;
 
+/// Outbound routing and dialing
+pub mod outbound;
+
+/// Configuration file
+pub mod config;
+
+/// Control socket of the running daemon
+#[cfg(unix)]
+pub mod control;
+
+/// Prometheus metrics endpoint
+pub mod metrics;
+
+/// JSON access log of finished sessions
+pub mod access_log;
+
 /// VPN integration (Tailscale)
 pub mod vpn;
 
@@ -0,0 +1,506 @@
+//! Prometheus metrics
+//!
+//! Counters live in the process-wide [`METRICS`] and are updated where the
+//! work happens: the SOCKS server, the relay and the WireGuard data plane.
+//! [`MetricsServer`] serves them at `/metrics` in the Prometheus text
+//! format, together with per-peer counters read from the VPN backend's
+//! [`Status`] at scrape time.
+
+use crate::vpn::{SharedBackend, Status};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+/// Upper bounds, in seconds, of the latency histogram buckets
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Largest HTTP request head accepted
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+/// Metrics of this process
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Increment until the returned guard is dropped
+    pub fn track(&self) -> GaugeGuard<'_> {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        GaugeGuard(self)
+    }
+}
+
+pub struct GaugeGuard<'a>(&'a Gauge);
+
+impl Drop for GaugeGuard<'_> {
+    fn drop(&mut self) {
+        self.0 .0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Latency histogram over [`LATENCY_BUCKETS`]
+#[derive(Debug)]
+pub struct Histogram {
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: LATENCY_BUCKETS.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|bound| secs <= *bound) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, sep, bound, cumulative);
+        }
+        let count = self.count();
+        let _ = writeln!(out, "{}_bucket{{{}{}le=\"+Inf\"}} {}", name, labels, sep, count);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{}_sum{} {}", name, braces(labels), sum);
+        let _ = writeln!(out, "{}_count{} {}", name, braces(labels), count);
+    }
+}
+
+/// Series of one metric keyed by the value of a single label
+#[derive(Debug)]
+pub struct Family<M> {
+    label: &'static str,
+    series: Mutex<BTreeMap<String, Arc<M>>>,
+}
+
+impl<M: Default> Family<M> {
+    fn new(label: &'static str) -> Self {
+        Self {
+            label,
+            series: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// The series for `value`, created on first use
+    pub fn with(&self, value: &str) -> Arc<M> {
+        let mut series = self.series.lock().unwrap();
+        match series.get(value) {
+            Some(metric) => metric.clone(),
+            None => series.entry(value.to_string()).or_default().clone(),
+        }
+    }
+
+    fn each(&self, mut f: impl FnMut(String, &M)) {
+        for (value, metric) in self.series.lock().unwrap().iter() {
+            f(format!("{}=\"{}\"", self.label, escape(value)), metric);
+        }
+    }
+}
+
+/// Counters and histograms of the proxy and the tunnel
+#[derive(Debug)]
+pub struct Metrics {
+    /// Sessions accepted on listeners and forwards
+    pub sessions_accepted: Counter,
+    pub sessions_active: Gauge,
+    /// Sessions refused, by reason
+    pub sessions_rejected: Family<Counter>,
+    /// Clients that failed authentication
+    pub auth_failures: Counter,
+    /// Time from accepting a client to reading its request
+    pub handshake_seconds: Histogram,
+    /// Time to connect to the target, by route
+    pub connect_seconds: Family<Histogram>,
+    /// Bytes relayed from clients to targets
+    pub bytes_up: Counter,
+    /// Bytes relayed from targets to clients
+    pub bytes_down: Counter,
+    /// WireGuard handshakes completed, as initiator or responder
+    pub wg_handshakes: Counter,
+    /// WireGuard datagrams exchanged directly with peers
+    pub packets_direct_rx: Counter,
+    pub packets_direct_tx: Counter,
+    /// WireGuard datagrams relayed through DERP
+    pub packets_derp_rx: Counter,
+    pub packets_derp_tx: Counter,
+    /// Registrations with the control server, by result
+    pub control_connects: Family<Counter>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            sessions_accepted: Counter::default(),
+            sessions_active: Gauge::default(),
+            sessions_rejected: Family::new("reason"),
+            auth_failures: Counter::default(),
+            handshake_seconds: Histogram::default(),
+            connect_seconds: Family::new("route"),
+            bytes_up: Counter::default(),
+            bytes_down: Counter::default(),
+            wg_handshakes: Counter::default(),
+            packets_direct_rx: Counter::default(),
+            packets_direct_tx: Counter::default(),
+            packets_derp_rx: Counter::default(),
+            packets_derp_tx: Counter::default(),
+            control_connects: Family::new("result"),
+        }
+    }
+}
+
+impl Metrics {
+    /// Count a refused session
+    pub fn reject(&self, reason: &str) {
+        self.sessions_rejected.with(reason).inc();
+    }
+
+    /// Everything in the Prometheus text format, with the peers of `status`
+    pub fn render(&self, status: Option<&Status>) -> String {
+        let mut out = String::new();
+
+        header(&mut out, "socktail_sessions_accepted_total", "counter", "Sessions accepted");
+        sample(&mut out, "socktail_sessions_accepted_total", "", self.sessions_accepted.get());
+        header(&mut out, "socktail_sessions_active", "gauge", "Sessions in progress");
+        sample(&mut out, "socktail_sessions_active", "", self.sessions_active.get());
+        header(&mut out, "socktail_sessions_rejected_total", "counter", "Sessions refused, by reason");
+        self.sessions_rejected.each(|labels, counter| {
+            sample(&mut out, "socktail_sessions_rejected_total", &labels, counter.get())
+        });
+        header(&mut out, "socktail_auth_failures_total", "counter", "Clients that failed authentication");
+        sample(&mut out, "socktail_auth_failures_total", "", self.auth_failures.get());
+
+        header(
+            &mut out,
+            "socktail_handshake_duration_seconds",
+            "histogram",
+            "Time from accepting a client to reading its request",
+        );
+        self.handshake_seconds
+            .render(&mut out, "socktail_handshake_duration_seconds", "");
+        header(
+            &mut out,
+            "socktail_connect_duration_seconds",
+            "histogram",
+            "Time to connect to the target, by route",
+        );
+        self.connect_seconds.each(|labels, histogram| {
+            histogram.render(&mut out, "socktail_connect_duration_seconds", &labels)
+        });
+
+        header(&mut out, "socktail_relayed_bytes_total", "counter", "Bytes relayed, by direction");
+        sample(&mut out, "socktail_relayed_bytes_total", "direction=\"up\"", self.bytes_up.get());
+        sample(&mut out, "socktail_relayed_bytes_total", "direction=\"down\"", self.bytes_down.get());
+
+        header(&mut out, "socktail_wireguard_handshakes_total", "counter", "WireGuard handshakes completed");
+        sample(&mut out, "socktail_wireguard_handshakes_total", "", self.wg_handshakes.get());
+        header(
+            &mut out,
+            "socktail_tunnel_packets_total",
+            "counter",
+            "WireGuard datagrams, by path and direction",
+        );
+        let packets = [
+            ("direct", "rx", &self.packets_direct_rx),
+            ("direct", "tx", &self.packets_direct_tx),
+            ("derp", "rx", &self.packets_derp_rx),
+            ("derp", "tx", &self.packets_derp_tx),
+        ];
+        for (path, direction, counter) in packets {
+            let labels = format!("path=\"{}\",direction=\"{}\"", path, direction);
+            sample(&mut out, "socktail_tunnel_packets_total", &labels, counter.get());
+        }
+        header(
+            &mut out,
+            "socktail_control_connects_total",
+            "counter",
+            "Registrations with the control server, by result",
+        );
+        self.control_connects.each(|labels, counter| {
+            sample(&mut out, "socktail_control_connects_total", &labels, counter.get())
+        });
+
+        if let Some(status) = status {
+            render_status(&mut out, status);
+        }
+        out
+    }
+}
+
+/// Backend state and per-peer counters
+fn render_status(out: &mut String, status: &Status) {
+    header(out, "socktail_backend_up", "gauge", "Whether the VPN backend is connected");
+    let labels = format!("backend=\"{}\"", escape(&status.backend));
+    sample(out, "socktail_backend_up", &labels, status.is_running() as u8);
+
+    let peers = status.peers.as_deref().unwrap_or_default();
+    let peer_label = |peer: &crate::vpn::PeerStatus| {
+        format!("peer=\"{}\"", escape(peer.name.as_deref().unwrap_or(&peer.public_key)))
+    };
+    header(out, "socktail_peer_rx_bytes_total", "counter", "Bytes received from each peer");
+    for peer in peers {
+        sample(out, "socktail_peer_rx_bytes_total", &peer_label(peer), peer.rx_bytes);
+    }
+    header(out, "socktail_peer_tx_bytes_total", "counter", "Bytes sent to each peer");
+    for peer in peers {
+        sample(out, "socktail_peer_tx_bytes_total", &peer_label(peer), peer.tx_bytes);
+    }
+    header(
+        out,
+        "socktail_peer_last_handshake_seconds",
+        "gauge",
+        "Unix time of the last WireGuard handshake with each peer",
+    );
+    for peer in peers {
+        if let Some(at) = peer.last_handshake {
+            sample(out, "socktail_peer_last_handshake_seconds", &peer_label(peer), at);
+        }
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(out, "{}{} {}", name, braces(labels), value);
+}
+
+fn braces(labels: &str) -> String {
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels)
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// HTTP endpoint serving `/metrics`
+pub struct MetricsServer {
+    listener: TcpListener,
+    backend: Option<SharedBackend>,
+}
+
+impl MetricsServer {
+    pub async fn bind(addr: &str) -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr).await?,
+            backend: None,
+        })
+    }
+
+    /// Include the backend's state and peers in every scrape
+    pub fn set_backend(&mut self, backend: SharedBackend) {
+        self.backend = Some(backend);
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Answer scrapes until `shutdown` is cancelled
+    pub async fn run(self, shutdown: CancellationToken) {
+        loop {
+            let stream = tokio::select! {
+                _ = shutdown.cancelled() => return,
+                accepted = self.listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        warn!("Metrics accept failed: {}", e);
+                        continue;
+                    }
+                },
+            };
+            let backend = self.backend.clone();
+            tokio::spawn(async move {
+                if let Err(e) = scrape(stream, backend).await {
+                    debug!("Metrics client failed: {}", e);
+                }
+            });
+        }
+    }
+}
+
+async fn scrape(mut stream: TcpStream, backend: Option<SharedBackend>) -> io::Result<()> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        head.extend_from_slice(&buf[..n]);
+        if head.len() > MAX_REQUEST_HEAD {
+            return respond(&mut stream, "431 Request Header Fields Too Large", "").await;
+        }
+    }
+
+    let request_line = String::from_utf8_lossy(&head);
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    if method != "GET" {
+        return respond(&mut stream, "405 Method Not Allowed", "").await;
+    }
+    if path.split('?').next() != Some("/metrics") {
+        return respond(&mut stream, "404 Not Found", "").await;
+    }
+
+    let status = match &backend {
+        Some(backend) => Some(backend.read().await.status()),
+        None => None,
+    };
+    let body = METRICS.render(status.as_ref());
+    respond(&mut stream, "200 OK", &body).await
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vpn::{BackendState, DirectBackend, PeerPath, PeerStatus};
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.sessions_accepted.inc();
+        let active = metrics.sessions_active.track();
+        metrics.reject("rule");
+        metrics.reject("rule");
+        metrics.handshake_seconds.observe(Duration::from_millis(3));
+        metrics
+            .connect_seconds
+            .with("upstream:corp")
+            .observe(Duration::from_millis(200));
+        metrics.bytes_up.add(1500);
+
+        let status = Status {
+            backend: "rust".to_string(),
+            state: BackendState::Running,
+            peers: Some(vec![PeerStatus {
+                name: Some("db-\"1\"".to_string()),
+                public_key: "key".to_string(),
+                addresses: Vec::new(),
+                online: None,
+                path: PeerPath::Direct,
+                endpoint: None,
+                derp: None,
+                last_handshake: Some(1714557600),
+                rx_bytes: 42,
+                tx_bytes: 7,
+            }]),
+            ..Status::default()
+        };
+        let text = metrics.render(Some(&status));
+        for line in [
+            "socktail_sessions_accepted_total 1",
+            "socktail_sessions_active 1",
+            "socktail_sessions_rejected_total{reason=\"rule\"} 2",
+            "socktail_handshake_duration_seconds_bucket{le=\"0.001\"} 0",
+            "socktail_handshake_duration_seconds_bucket{le=\"0.005\"} 1",
+            "socktail_handshake_duration_seconds_bucket{le=\"+Inf\"} 1",
+            "socktail_handshake_duration_seconds_sum 0.003",
+            "socktail_connect_duration_seconds_bucket{route=\"upstream:corp\",le=\"0.25\"} 1",
+            "socktail_connect_duration_seconds_count{route=\"upstream:corp\"} 1",
+            "socktail_relayed_bytes_total{direction=\"up\"} 1500",
+            "socktail_tunnel_packets_total{path=\"derp\",direction=\"rx\"} 0",
+            "socktail_backend_up{backend=\"rust\"} 1",
+            "socktail_peer_rx_bytes_total{peer=\"db-\\\"1\\\"\"} 42",
+            "socktail_peer_last_handshake_seconds{peer=\"db-\\\"1\\\"\"} 1714557600",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {}\n{}", line, text);
+        }
+
+        drop(active);
+        assert_eq!(metrics.sessions_active.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_http_endpoint() {
+        let mut server = MetricsServer::bind("127.0.0.1:0").await.unwrap();
+        let backend: SharedBackend = Arc::new(tokio::sync::RwLock::new(Box::new(DirectBackend)));
+        server.set_backend(backend);
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.run(CancellationToken::new()));
+
+        let get = |path: &'static str| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let response = get("/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("\nsocktail_backend_up{backend=\"none\"} 0\n"));
+        assert!(get("/").await.starts_with("HTTP/1.1 404"));
+    }
+}
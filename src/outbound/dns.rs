@@ -0,0 +1,128 @@
+//! Minimal DNS stub resolver
+//!
+//! Used when the VPN backend names its own name servers, such as the `DNS`
+//! line of a WireGuard configuration. Only A and AAAA queries over UDP are
+//! supported; the [`Dialer`](super::Dialer) decides whether a server is
+//! reached through the tunnel or the host.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+
+const CLASS_IN: u16 = 1;
+
+/// Encode a recursive query for `name`
+pub fn build_query(id: u16, name: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(name.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid domain name '{}'", name),
+            ));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/// Addresses in the answer to query `id`; `None` if the datagram answers
+/// something else
+pub fn parse_response(id: u16, response: &[u8]) -> Option<io::Result<Vec<IpAddr>>> {
+    if response.len() < 12 || response[..2] != id.to_be_bytes() || response[2] & 0x80 == 0 {
+        return None;
+    }
+    Some(parse_answers(response))
+}
+
+fn parse_answers(response: &[u8]) -> io::Result<Vec<IpAddr>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed DNS response");
+    match response[3] & 0x0f {
+        0 => {}
+        // NXDOMAIN
+        3 => return Ok(Vec::new()),
+        rcode => return Err(io::Error::other(format!("DNS server failed with rcode {}", rcode))),
+    }
+    let questions = u16::from_be_bytes([response[4], response[5]]);
+    let answers = u16::from_be_bytes([response[6], response[7]]);
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(response, pos).ok_or_else(invalid)? + 4;
+    }
+    let mut addrs = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(response, pos).ok_or_else(invalid)?;
+        let header = response.get(pos..pos + 10).ok_or_else(invalid)?;
+        let rtype = u16::from_be_bytes([header[0], header[1]]);
+        let len = u16::from_be_bytes([header[8], header[9]]) as usize;
+        let data = response.get(pos + 10..pos + 10 + len).ok_or_else(invalid)?;
+        match (rtype, len) {
+            (TYPE_A, 4) => addrs.push(IpAddr::V4(Ipv4Addr::new(data[0], data[1], data[2], data[3]))),
+            (TYPE_AAAA, 16) => {
+                let octets: [u8; 16] = data.try_into().map_err(|_| invalid())?;
+                addrs.push(IpAddr::V6(Ipv6Addr::from(octets)));
+            }
+            // CNAMEs and the like; their targets' addresses follow
+            _ => {}
+        }
+        pos += 10 + len;
+    }
+    Ok(addrs)
+}
+
+/// Offset just past the name at `pos`
+fn skip_name(message: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(pos)?;
+        match len {
+            0 => return Some(pos + 1),
+            // Compression pointer
+            l if l & 0xc0 == 0xc0 => return Some(pos + 2),
+            l => pos += 1 + l as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_and_response() {
+        let query = build_query(0x1234, "db.corp.example.", TYPE_A).unwrap();
+        assert_eq!(&query[..2], &[0x12, 0x34]);
+        assert_eq!(&query[12..16], b"\x02db\x04");
+        assert!(build_query(1, "a..b", TYPE_A).is_err());
+
+        // Answer with a CNAME and an A record, names compressed
+        let mut response = query.clone();
+        response[2] = 0x81;
+        response[3] = 0x80;
+        response[7] = 2;
+        response.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xc0, 12]);
+        response.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 10, 20, 0, 5]);
+
+        assert!(parse_response(0x4321, &response).is_none());
+        assert_eq!(
+            parse_response(0x1234, &response).unwrap().unwrap(),
+            vec!["10.20.0.5".parse::<IpAddr>().unwrap()]
+        );
+
+        response[3] = 0x83;
+        assert!(parse_response(0x1234, &response).unwrap().unwrap().is_empty());
+        response[3] = 0x82;
+        assert!(parse_response(0x1234, &response).unwrap().is_err());
+        response[3] = 0x80;
+        assert!(parse_response(0x1234, &response[..40]).unwrap().is_err());
+    }
+}
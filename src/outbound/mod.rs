@@ -0,0 +1,546 @@
+//! Outbound connections
+//!
+//! The [`Dialer`] picks a [`Route`] for each target and opens the
+//! connection over that path. Tailnet destinations are dialed through the
+//! userspace stack when there is one, and with an exit node so is direct
+//! traffic. On top of a tailscaled in userspace-networking mode they go
+//! through its LocalAPI instead. [`Dialer::connect_udp`] does the same for
+//! datagrams, except through upstream proxies and the LocalAPI.
+//!
+//! Names are resolved with the host's resolver unless the backend brings
+//! its own name servers, which are then queried over the same paths.
+
+pub mod dns;
+pub mod router;
+pub mod upstream;
+
+pub use router::{Route, RouteRule, RouteTable};
+pub use upstream::{ProxyHop, Upstream, UpstreamError};
+
+use crate::socks5::protocol::{
+    TargetAddr, REP_COMMAND_NOT_SUPPORTED, REP_CONNECTION_NOT_ALLOWED, REP_CONNECTION_REFUSED,
+    REP_GENERAL_FAILURE, REP_HOST_UNREACHABLE, REP_NETWORK_UNREACHABLE,
+};
+#[cfg(unix)]
+use crate::vpn::LocalApi;
+use crate::utils::Cidr;
+use crate::vpn::{ExitNode, Netstack, PeerMap, TailnetStream, TailnetUdpConn};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::net::{TcpStream, UdpSocket};
+
+/// How long a name server has to answer
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Public resolvers asked through the exit node when none are configured,
+/// so that lookups leave from the same place as the connections
+pub const EXIT_NODE_DNS: [IpAddr; 2] = [
+    IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)),
+    IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)),
+];
+
+#[derive(Debug, Error)]
+pub enum DialError {
+    #[error("Destination blocked by routing table")]
+    Blocked,
+
+    #[error("Resolved address not allowed by ruleset")]
+    NotAllowed,
+
+    #[error("No upstream named '{0}'")]
+    UnknownUpstream(String),
+
+    #[error("UDP cannot go through upstream '{0}'")]
+    UdpUpstream(String),
+
+    #[error("UDP to the tailnet cannot go through tailscaled's LocalAPI")]
+    UdpLocalApi,
+
+    #[error("Failed to resolve {0}: {1}")]
+    Resolve(String, #[source] io::Error),
+
+    #[error("Connection failed: {0}")]
+    Connect(#[from] io::Error),
+
+    #[error(transparent)]
+    Upstream(#[from] UpstreamError),
+}
+
+impl DialError {
+    /// SOCKS5 reply code reported to the client
+    pub fn reply_code(&self) -> u8 {
+        match self {
+            DialError::Blocked | DialError::NotAllowed => REP_CONNECTION_NOT_ALLOWED,
+            DialError::UnknownUpstream(_) => REP_GENERAL_FAILURE,
+            DialError::UdpUpstream(_) | DialError::UdpLocalApi => REP_COMMAND_NOT_SUPPORTED,
+            DialError::Resolve(..) => REP_HOST_UNREACHABLE,
+            DialError::Connect(e) => match e.kind() {
+                io::ErrorKind::ConnectionRefused => REP_CONNECTION_REFUSED,
+                io::ErrorKind::NetworkUnreachable => REP_NETWORK_UNREACHABLE,
+                io::ErrorKind::HostUnreachable | io::ErrorKind::TimedOut => REP_HOST_UNREACHABLE,
+                _ => REP_GENERAL_FAILURE,
+            },
+            DialError::Upstream(e) => match e {
+                // Pass the upstream's own verdict through to the client
+                UpstreamError::Refused(_, code) => *code,
+                UpstreamError::HttpStatus(_, 403) => REP_CONNECTION_NOT_ALLOWED,
+                UpstreamError::HttpStatus(_, 502 | 504) | UpstreamError::Timeout(_) => {
+                    REP_HOST_UNREACHABLE
+                }
+                _ => REP_GENERAL_FAILURE,
+            },
+        }
+    }
+}
+
+/// Connection opened by a [`Dialer`]
+#[derive(Debug)]
+pub enum OutboundStream {
+    /// Host socket, directly or to an upstream proxy
+    Tcp(TcpStream),
+    /// Through the tailnet's userspace stack
+    Tailnet(TailnetStream),
+    /// Through tailscaled's `/localapi/v0/dial`
+    #[cfg(unix)]
+    LocalApi(UnixStream),
+}
+
+impl OutboundStream {
+    /// Address of the far end: the target, or the first proxy of an
+    /// upstream chain; unknown through tailscaled
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            OutboundStream::Tcp(s) => s.peer_addr().ok(),
+            OutboundStream::Tailnet(s) => Some(s.peer_addr()),
+            #[cfg(unix)]
+            OutboundStream::LocalApi(_) => None,
+        }
+    }
+}
+
+impl AsyncRead for OutboundStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            OutboundStream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            OutboundStream::Tailnet(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(unix)]
+            OutboundStream::LocalApi(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for OutboundStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            OutboundStream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            OutboundStream::Tailnet(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(unix)]
+            OutboundStream::LocalApi(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            OutboundStream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            OutboundStream::Tailnet(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(unix)]
+            OutboundStream::LocalApi(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            OutboundStream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            OutboundStream::Tailnet(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(unix)]
+            OutboundStream::LocalApi(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+/// UDP socket opened by [`Dialer::connect_udp`], talking to one destination
+#[derive(Debug)]
+pub enum OutboundDatagram {
+    /// Connected host socket
+    Udp(UdpSocket),
+    /// Through the tailnet's userspace stack
+    Tailnet(TailnetUdpConn),
+}
+
+impl OutboundDatagram {
+    /// The destination
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            OutboundDatagram::Udp(socket) => socket.peer_addr().ok(),
+            OutboundDatagram::Tailnet(conn) => Some(conn.peer_addr()),
+        }
+    }
+
+    pub async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutboundDatagram::Udp(socket) => socket.send(buf).await,
+            OutboundDatagram::Tailnet(conn) => conn.send(buf),
+        }
+    }
+
+    /// Receive the next datagram from the destination
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            OutboundDatagram::Udp(socket) => socket.recv(buf).await,
+            OutboundDatagram::Tailnet(conn) => conn.recv(buf).await,
+        }
+    }
+}
+
+/// Opens outbound connections according to the routing table
+#[derive(Debug, Clone, Default)]
+pub struct Dialer {
+    routes: RouteTable,
+    peers: Option<PeerMap>,
+    upstreams: HashMap<String, Arc<Upstream>>,
+    tailnet: Option<Netstack>,
+    exit_node: Option<ExitNode>,
+    /// Prefixes `direct` routes reach through the userspace stack
+    tunnel_routes: Vec<Cidr>,
+    /// Name servers replacing the host's resolver
+    dns_servers: Vec<IpAddr>,
+    #[cfg(unix)]
+    local_api: Option<LocalApi>,
+}
+
+impl Dialer {
+    pub fn new(routes: RouteTable, peers: Option<PeerMap>) -> Self {
+        Self {
+            routes,
+            peers,
+            upstreams: HashMap::new(),
+            tailnet: None,
+            exit_node: None,
+            tunnel_routes: Vec::new(),
+            dns_servers: Vec::new(),
+            #[cfg(unix)]
+            local_api: None,
+        }
+    }
+
+    /// Dial `tailnet` routes through a userspace stack instead of the host
+    pub fn set_tailnet(&mut self, netstack: Netstack) {
+        self.tailnet = Some(netstack);
+    }
+
+    /// Dial `tailnet` routes through tailscaled's LocalAPI when there is no
+    /// userspace stack
+    #[cfg(unix)]
+    pub fn set_local_api(&mut self, api: LocalApi) {
+        self.local_api = Some(api);
+    }
+
+    /// Send `direct` routes through an exit node (needs a tailnet stack)
+    pub fn set_exit_node(&mut self, exit_node: ExitNode) {
+        self.exit_node = Some(exit_node);
+    }
+
+    /// Send `direct` routes to these prefixes through the userspace stack,
+    /// like the `AllowedIPs` of a WireGuard peer
+    pub fn set_tunnel_routes(&mut self, routes: Vec<Cidr>) {
+        self.tunnel_routes = routes;
+    }
+
+    /// Resolve names with these servers instead of the host's resolver
+    pub fn set_dns_servers(&mut self, servers: Vec<IpAddr>) {
+        self.dns_servers = servers;
+    }
+
+    /// Make an upstream available to `upstream:NAME` routes
+    pub fn add_upstream(&mut self, upstream: Arc<Upstream>) {
+        self.upstreams.insert(upstream.name().to_string(), upstream);
+    }
+
+    pub fn upstreams(&self) -> impl Iterator<Item = &Arc<Upstream>> {
+        self.upstreams.values()
+    }
+
+    pub fn routes(&self) -> &RouteTable {
+        &self.routes
+    }
+
+    /// Pick the route for `target`
+    pub fn route(&self, target: &TargetAddr) -> Route {
+        self.routes.select(target, self.peers.as_ref())
+    }
+
+    /// Connect to `target` over `route`
+    ///
+    /// `allow` is asked about every address the target resolves to, so that
+    /// access rules also apply to what a domain name points at.
+    pub async fn connect<F>(
+        &self,
+        target: &TargetAddr,
+        route: &Route,
+        allow: F,
+    ) -> Result<OutboundStream, DialError>
+    where
+        F: Fn(IpAddr) -> bool,
+    {
+        match route {
+            Route::Block => Err(DialError::Blocked),
+            // The upstream resolves names itself, so `allow` only sees
+            // literal IP targets (already checked by the caller)
+            Route::Upstream(name) => match self.upstreams.get(name) {
+                Some(upstream) => Ok(OutboundStream::Tcp(upstream.connect(target).await?)),
+                None => Err(DialError::UnknownUpstream(name.clone())),
+            },
+            Route::Tailnet | Route::Direct => {
+                let addrs: Vec<SocketAddr> = self
+                    .resolve(target, route)
+                    .await?
+                    .into_iter()
+                    .filter(|addr| allow(addr.ip()))
+                    .collect();
+
+                if addrs.is_empty() {
+                    return Err(DialError::NotAllowed);
+                }
+
+                if self.via_tailnet(route, &addrs[0].ip()) {
+                    if let Some(netstack) = &self.tailnet {
+                        return Ok(OutboundStream::Tailnet(connect_tailnet(netstack, &addrs).await?));
+                    }
+                    #[cfg(unix)]
+                    if let Some(api) = &self.local_api {
+                        return Ok(OutboundStream::LocalApi(connect_local_api(api, &addrs).await?));
+                    }
+                }
+                Ok(OutboundStream::Tcp(TcpStream::connect(&addrs[..]).await?))
+            }
+        }
+    }
+
+    /// Open a UDP socket to `target` over `route`, checking resolved
+    /// addresses with `allow` like [`connect`](Self::connect)
+    pub async fn connect_udp<F>(
+        &self,
+        target: &TargetAddr,
+        route: &Route,
+        allow: F,
+    ) -> Result<OutboundDatagram, DialError>
+    where
+        F: Fn(IpAddr) -> bool,
+    {
+        match route {
+            Route::Block => Err(DialError::Blocked),
+            Route::Upstream(name) => Err(DialError::UdpUpstream(name.clone())),
+            Route::Tailnet | Route::Direct => {
+                let addr = self
+                    .resolve(target, route)
+                    .await?
+                    .into_iter()
+                    .find(|addr| allow(addr.ip()))
+                    .ok_or(DialError::NotAllowed)?;
+
+                #[cfg(unix)]
+                if self.tailnet.is_none()
+                    && self.local_api.is_some()
+                    && self.via_tailnet(route, &addr.ip())
+                {
+                    return Err(DialError::UdpLocalApi);
+                }
+
+                Ok(self.datagram(route, addr).await?)
+            }
+        }
+    }
+
+    /// UDP socket to `addr` through the stack or the host, as `route` says
+    async fn datagram(&self, route: &Route, addr: SocketAddr) -> io::Result<OutboundDatagram> {
+        match &self.tailnet {
+            Some(netstack) if self.via_tailnet(route, &addr.ip()) => {
+                Ok(OutboundDatagram::Tailnet(netstack.connect_udp(addr)?))
+            }
+            _ => {
+                let bind: SocketAddr = match addr {
+                    SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+                    SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+                };
+                let socket = UdpSocket::bind(bind).await?;
+                socket.connect(addr).await?;
+                Ok(OutboundDatagram::Udp(socket))
+            }
+        }
+    }
+
+    /// Whether `ip` on `route` goes through the userspace stack
+    fn via_tailnet(&self, route: &Route, ip: &IpAddr) -> bool {
+        match route {
+            Route::Tailnet => true,
+            Route::Direct => {
+                self.exit_node.as_ref().is_some_and(|e| e.carries(ip))
+                    || self.tunnel_routes.iter().any(|c| c.contains(ip))
+            }
+            Route::Upstream(_) | Route::Block => false,
+        }
+    }
+
+    /// Resolve a target, using the network map for tailnet names
+    async fn resolve(&self, target: &TargetAddr, route: &Route) -> Result<Vec<SocketAddr>, DialError> {
+        match target {
+            TargetAddr::Ip(addr) => Ok(vec![*addr]),
+            TargetAddr::Domain(domain, port) => {
+                if *route == Route::Tailnet {
+                    if let Some(ip) = self.peers.as_ref().and_then(|p| p.resolve(domain)) {
+                        return Ok(vec![SocketAddr::new(ip, *port)]);
+                    }
+                }
+
+                let servers = self.name_servers();
+                if !servers.is_empty() {
+                    let ips = self
+                        .lookup(servers, domain)
+                        .await
+                        .map_err(|e| DialError::Resolve(domain.clone(), e))?;
+                    return Ok(ips.into_iter().map(|ip| SocketAddr::new(ip, *port)).collect());
+                }
+
+                let addrs = tokio::net::lookup_host((domain.as_str(), *port))
+                    .await
+                    .map_err(|e| DialError::Resolve(domain.clone(), e))?;
+                Ok(addrs.collect())
+            }
+        }
+    }
+
+    /// Name servers to use instead of the host's resolver, which would leak
+    /// lookups past an exit node
+    fn name_servers(&self) -> &[IpAddr] {
+        match (&self.exit_node, &self.tailnet) {
+            (Some(_), Some(_)) if self.dns_servers.is_empty() => &EXIT_NODE_DNS,
+            _ => &self.dns_servers,
+        }
+    }
+
+    /// Ask name servers in turn for A and AAAA records
+    async fn lookup(&self, servers: &[IpAddr], domain: &str) -> io::Result<Vec<IpAddr>> {
+        let mut last_err = None;
+        for server in servers {
+            match self.query(SocketAddr::new(*server, 53), domain).await {
+                Ok(ips) if ips.is_empty() => {
+                    return Err(io::Error::new(io::ErrorKind::NotFound, "no such host"))
+                }
+                Ok(ips) => return Ok(ips),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| io::Error::from(io::ErrorKind::NotFound)))
+    }
+
+    async fn query(&self, server: SocketAddr, domain: &str) -> io::Result<Vec<IpAddr>> {
+        let socket = self.datagram(&Route::Direct, server).await?;
+        let id: u16 = rand::random();
+        let ids = [id, id.wrapping_add(1)];
+        socket.send(&dns::build_query(ids[0], domain, dns::TYPE_A)?).await?;
+        socket.send(&dns::build_query(ids[1], domain, dns::TYPE_AAAA)?).await?;
+
+        let mut ips = Vec::new();
+        let mut pending = ids.to_vec();
+        let mut buf = vec![0u8; 1500];
+        while !pending.is_empty() {
+            let len = tokio::time::timeout(DNS_TIMEOUT, socket.recv(&mut buf))
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "DNS query timed out"))??;
+            // Other datagrams on the socket are ignored
+            for i in 0..pending.len() {
+                if let Some(answer) = dns::parse_response(pending[i], &buf[..len]) {
+                    pending.swap_remove(i);
+                    ips.extend(answer?);
+                    break;
+                }
+            }
+        }
+        // IPv4 first, as most hosts prefer
+        ips.sort_by_key(|ip| ip.is_ipv6());
+        Ok(ips)
+    }
+}
+
+/// Try each address in turn, like [`TcpStream::connect`]
+async fn connect_tailnet(netstack: &Netstack, addrs: &[SocketAddr]) -> io::Result<TailnetStream> {
+    let mut last_err = None;
+    for addr in addrs {
+        match netstack.connect(*addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable)))
+}
+
+/// Try each address in turn through tailscaled
+#[cfg(unix)]
+async fn connect_local_api(api: &LocalApi, addrs: &[SocketAddr]) -> io::Result<UnixStream> {
+    let mut last_err = None;
+    for addr in addrs {
+        match api.dial(&addr.ip().to_string(), addr.port()).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_resolve_through_exit_node() {
+        // The node's stack wired to a public resolver behind the exit node
+        let (node, exit) = crate::vpn::netstack::pair_at(EXIT_NODE_DNS[0]);
+
+        // Answer A queries with 192.0.2.80 and AAAA queries with nothing
+        let resolver = exit.bind_udp(53).unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((len, src)) = resolver.recv_from(&mut buf).await {
+                let mut response = buf[..len].to_vec();
+                response[2] = 0x81;
+                response[3] = 0x80;
+                if response[len - 3] == dns::TYPE_A as u8 {
+                    response[7] = 1;
+                    response.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+                    response.extend_from_slice(&[192, 0, 2, 80]);
+                }
+                resolver.send_to(&response, src).unwrap();
+            }
+        });
+
+        let mut dialer = Dialer::new(RouteTable::default(), None);
+        dialer.set_tailnet(node);
+        dialer.set_exit_node(ExitNode {
+            name: "exit".to_string(),
+            addr: "100.64.0.2".parse().unwrap(),
+            allow_lan_access: false,
+        });
+        let target = TargetAddr::Domain("example.com".to_string(), 80);
+        assert_eq!(
+            dialer.resolve(&target, &Route::Direct).await.unwrap(),
+            vec!["192.0.2.80:80".parse().unwrap()]
+        );
+    }
+}
@@ -0,0 +1,293 @@
+//! Per-destination route selection
+//!
+//! A [`RouteTable`] maps each [`TargetAddr`] to the outbound path that
+//! should carry it. User rules are checked first; the built-in tailnet rules
+//! then send Tailscale address ranges and MagicDNS names through the
+//! tailnet, and everything else takes the default route.
+
+use crate::socks5::protocol::TargetAddr;
+use crate::socks5::rules::{parse_port_range, DestMatch, Pattern};
+use crate::utils::Cidr;
+use crate::vpn::netmap::{PeerMap, TAILNET_IPV4_RANGE, TAILNET_IPV6_RANGE};
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+/// Outbound path for a connection
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Route {
+    /// Through the tailnet
+    Tailnet,
+    /// Directly from the host
+    Direct,
+    /// Through a named upstream proxy
+    Upstream(String),
+    /// Reject the request
+    Block,
+}
+
+impl FromStr for Route {
+    type Err = String;
+
+    /// Parse `tailnet`, `direct`, `block` or `upstream:NAME`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tailnet" => Ok(Route::Tailnet),
+            "direct" => Ok(Route::Direct),
+            "block" => Ok(Route::Block),
+            _ => match s.split_once(':') {
+                Some(("upstream", name)) if !name.is_empty() => {
+                    Ok(Route::Upstream(name.to_string()))
+                }
+                _ => Err(format!(
+                    "invalid route '{}', expected tailnet, direct, block or upstream:NAME",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Route::Tailnet => f.write_str("tailnet"),
+            Route::Direct => f.write_str("direct"),
+            Route::Upstream(name) => write!(f, "upstream:{}", name),
+            Route::Block => f.write_str("block"),
+        }
+    }
+}
+
+/// A single routing rule
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteRule {
+    pub route: Route,
+    pub destinations: Vec<DestMatch>,
+    pub ports: Vec<RangeInclusive<u16>>,
+    /// Tailnet peer tags (any of)
+    pub tags: Vec<String>,
+}
+
+impl RouteRule {
+    /// Rule matching every destination
+    pub fn new(route: Route) -> Self {
+        Self {
+            route,
+            destinations: Vec::new(),
+            ports: Vec::new(),
+            tags: Vec::new(),
+        }
+    }
+
+    /// Check whether every matcher of this rule applies to `target`
+    pub fn matches(&self, target: &TargetAddr, peers: Option<&PeerMap>) -> bool {
+        let dest_ok = self.destinations.is_empty()
+            || self.destinations.iter().any(|dest| match (dest, target) {
+                (DestMatch::Cidr(cidr), TargetAddr::Ip(addr)) => cidr.contains(&addr.ip()),
+                (DestMatch::Cidr(cidr), TargetAddr::Domain(name, _)) => peers
+                    .and_then(|p| p.resolve(name))
+                    .is_some_and(|ip| cidr.contains(&ip)),
+                (DestMatch::Domain(pattern), TargetAddr::Domain(name, _)) => pattern.matches(name),
+                (DestMatch::Domain(_), TargetAddr::Ip(_)) => false,
+            });
+
+        let port = target.port();
+        let port_ok = self.ports.is_empty() || self.ports.iter().any(|r| r.contains(&port));
+
+        let tags_ok = self.tags.is_empty()
+            || peers
+                .and_then(|p| match target {
+                    TargetAddr::Ip(addr) => p.peer_by_ip(&addr.ip()),
+                    TargetAddr::Domain(name, _) => p.peer_by_name(name),
+                })
+                .is_some_and(|peer| peer.tags.iter().any(|t| self.tags.contains(t)));
+
+        dest_ok && port_ok && tags_ok
+    }
+}
+
+impl FromStr for RouteRule {
+    type Err = String;
+
+    /// Parse `ROUTE [dst=..] [port=..] [tag=..]`, e.g.
+    /// `upstream:corp dst=.vendor.example port=443`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        let route = parts.next().ok_or("empty route rule")?.parse()?;
+        let mut rule = RouteRule::new(route);
+
+        for part in parts {
+            let (key, values) = part
+                .split_once('=')
+                .ok_or_else(|| format!("invalid matcher '{}', expected KEY=VALUE", part))?;
+            let values = values.split(',');
+            match key {
+                "dst" => rule.destinations = values.map(str::parse).collect::<Result<_, _>>()?,
+                "port" => rule.ports = values.map(parse_port_range).collect::<Result<_, _>>()?,
+                "tag" => rule.tags = values.map(str::to_string).collect(),
+                _ => return Err(format!("unknown matcher '{}'", key)),
+            }
+        }
+
+        Ok(rule)
+    }
+}
+
+/// First-match routing table
+#[derive(Debug, Clone)]
+pub struct RouteTable {
+    rules: Vec<RouteRule>,
+    default_route: Route,
+    tailnet_defaults: bool,
+}
+
+impl RouteTable {
+    /// Table with only `rules` and a fallback route
+    pub fn new(rules: Vec<RouteRule>, default_route: Route) -> Self {
+        Self {
+            rules,
+            default_route,
+            tailnet_defaults: false,
+        }
+    }
+
+    /// `rules`, then tailnet ranges and MagicDNS names via the tailnet,
+    /// then `default_route`
+    pub fn with_tailnet_defaults(rules: Vec<RouteRule>, default_route: Route) -> Self {
+        Self {
+            rules,
+            default_route,
+            tailnet_defaults: true,
+        }
+    }
+
+    pub fn rules(&self) -> &[RouteRule] {
+        &self.rules
+    }
+
+    pub fn default_route(&self) -> &Route {
+        &self.default_route
+    }
+
+    /// Pick the route for `target`
+    pub fn select(&self, target: &TargetAddr, peers: Option<&PeerMap>) -> Route {
+        if let Some(rule) = self.rules.iter().find(|r| r.matches(target, peers)) {
+            return rule.route.clone();
+        }
+
+        if self.tailnet_defaults && is_tailnet_target(target, peers) {
+            return Route::Tailnet;
+        }
+
+        self.default_route.clone()
+    }
+}
+
+impl Default for RouteTable {
+    fn default() -> Self {
+        Self::with_tailnet_defaults(Vec::new(), Route::Direct)
+    }
+}
+
+/// Tailnet address ranges and MagicDNS names
+fn is_tailnet_target(target: &TargetAddr, peers: Option<&PeerMap>) -> bool {
+    match target {
+        TargetAddr::Ip(addr) => [TAILNET_IPV4_RANGE, TAILNET_IPV6_RANGE]
+            .iter()
+            .any(|range| range.parse::<Cidr>().is_ok_and(|c| c.contains(&addr.ip()))),
+        TargetAddr::Domain(name, _) => match peers {
+            Some(peers) => peers.is_magicdns_name(name),
+            None => Pattern::new(crate::vpn::netmap::MAGICDNS_SUFFIX).matches(name),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vpn::netmap::PeerInfo;
+
+    fn ip(s: &str) -> TargetAddr {
+        TargetAddr::Ip(s.parse().unwrap())
+    }
+
+    fn domain(name: &str, port: u16) -> TargetAddr {
+        TargetAddr::Domain(name.to_string(), port)
+    }
+
+    fn peers() -> PeerMap {
+        let map = PeerMap::new();
+        map.update(
+            vec![PeerInfo {
+                name: Some("db-1.tail1234.ts.net".to_string()),
+                tailscale_ip: "100.64.0.5".parse().unwrap(),
+                addresses: vec!["100.64.0.5".parse().unwrap()],
+                tags: vec!["tag:prod".to_string()],
+                ..Default::default()
+            }],
+            Some("tail1234.ts.net".to_string()),
+        );
+        map
+    }
+
+    #[test]
+    fn test_parse_route() {
+        assert_eq!("tailnet".parse::<Route>().unwrap(), Route::Tailnet);
+        assert_eq!(
+            "upstream:corp".parse::<Route>().unwrap(),
+            Route::Upstream("corp".to_string())
+        );
+        assert_eq!(Route::Upstream("corp".to_string()).to_string(), "upstream:corp");
+        assert!("upstream:".parse::<Route>().is_err());
+        assert!("vpn".parse::<Route>().is_err());
+
+        let rule: RouteRule = "block dst=.ads.example port=80,443 tag=tag:prod"
+            .parse()
+            .unwrap();
+        assert_eq!(rule.route, Route::Block);
+        assert_eq!(rule.ports.len(), 2);
+        assert_eq!(rule.tags, vec!["tag:prod"]);
+        assert!("direct via=x".parse::<RouteRule>().is_err());
+    }
+
+    #[test]
+    fn test_tailnet_defaults() {
+        let table = RouteTable::default();
+        let peers = peers();
+
+        assert_eq!(table.select(&ip("100.101.102.103:22"), None), Route::Tailnet);
+        assert_eq!(table.select(&ip("[fd7a:115c:a1e0::1]:22"), None), Route::Tailnet);
+        assert_eq!(table.select(&domain("db-1", 5432), Some(&peers)), Route::Tailnet);
+        assert_eq!(table.select(&domain("x.tail1234.ts.net", 80), None), Route::Tailnet);
+        assert_eq!(table.select(&domain("example.com", 443), Some(&peers)), Route::Direct);
+        assert_eq!(table.select(&ip("192.0.2.1:443"), None), Route::Direct);
+    }
+
+    #[test]
+    fn test_user_rules_first() {
+        let table = RouteTable::with_tailnet_defaults(
+            vec![
+                "block tag=tag:prod port=22".parse().unwrap(),
+                "upstream:corp dst=.vendor.example".parse().unwrap(),
+                "tailnet dst=10.0.0.0/8".parse().unwrap(),
+            ],
+            Route::Direct,
+        );
+        let peers = peers();
+
+        assert_eq!(table.select(&domain("db-1", 22), Some(&peers)), Route::Block);
+        assert_eq!(table.select(&domain("db-1", 5432), Some(&peers)), Route::Tailnet);
+        assert_eq!(
+            table.select(&domain("portal.vendor.example", 443), Some(&peers)),
+            Route::Upstream("corp".to_string())
+        );
+        assert_eq!(table.select(&ip("10.1.2.3:80"), None), Route::Tailnet);
+    }
+
+    #[test]
+    fn test_without_defaults() {
+        let table = RouteTable::new(Vec::new(), Route::Direct);
+        assert_eq!(table.select(&ip("100.64.0.5:22"), None), Route::Direct);
+    }
+}
@@ -0,0 +1,107 @@
+//! SOCKS5 client authentication
+
+use super::protocol::{AUTH_NO_AUTH, AUTH_USERNAME_PASSWORD};
+use std::collections::HashMap;
+
+/// How SOCKS clients authenticate to the server
+#[derive(Debug, Clone, Default)]
+pub enum Authenticator {
+    /// Accept every client without credentials
+    #[default]
+    None,
+    /// Require RFC 1929 username/password (username -> password)
+    Password(HashMap<String, String>),
+    /// Accept only tailnet peers, identified by their source address; the
+    /// owner's login name takes the place of a username
+    Tailnet,
+}
+
+impl Authenticator {
+    /// Create a username/password authenticator from `(user, password)` pairs
+    pub fn password<I, U, P>(users: I) -> Self
+    where
+        I: IntoIterator<Item = (U, P)>,
+        U: Into<String>,
+        P: Into<String>,
+    {
+        Authenticator::Password(
+            users
+                .into_iter()
+                .map(|(u, p)| (u.into(), p.into()))
+                .collect(),
+        )
+    }
+
+    /// SOCKS5 method the client has to offer
+    pub fn method(&self) -> u8 {
+        match self {
+            Authenticator::None | Authenticator::Tailnet => AUTH_NO_AUTH,
+            Authenticator::Password(_) => AUTH_USERNAME_PASSWORD,
+        }
+    }
+
+    /// Check a username/password pair
+    pub fn verify(&self, username: &str, password: &str) -> bool {
+        match self {
+            Authenticator::None => true,
+            Authenticator::Password(users) => users
+                .get(username)
+                .is_some_and(|expected| constant_time_eq(expected.as_bytes(), password.as_bytes())),
+            Authenticator::Tailnet => false,
+        }
+    }
+}
+
+/// Parse a `user:password` command line argument
+pub fn parse_user(s: &str) -> Result<(String, String), String> {
+    match s.split_once(':') {
+        Some((user, pass)) if !user.is_empty() => Ok((user.to_string(), pass.to_string())),
+        _ => Err(format!("invalid user '{}', expected USER:PASSWORD", s)),
+    }
+}
+
+/// Compare secrets without leaking where they differ
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_auth() {
+        let auth = Authenticator::None;
+        assert_eq!(auth.method(), AUTH_NO_AUTH);
+        assert!(auth.verify("anyone", ""));
+    }
+
+    #[test]
+    fn test_password() {
+        let auth = Authenticator::password([("alice", "secret")]);
+        assert_eq!(auth.method(), AUTH_USERNAME_PASSWORD);
+        assert!(auth.verify("alice", "secret"));
+        assert!(!auth.verify("alice", "wrong"));
+        assert!(!auth.verify("bob", "secret"));
+    }
+
+    #[test]
+    fn test_tailnet() {
+        let auth = Authenticator::Tailnet;
+        assert_eq!(auth.method(), AUTH_NO_AUTH);
+        assert!(!auth.verify("alice", ""));
+    }
+
+    #[test]
+    fn test_parse_user() {
+        assert_eq!(
+            parse_user("alice:p:w").unwrap(),
+            ("alice".to_string(), "p:w".to_string())
+        );
+        assert!(parse_user("alice").is_err());
+        assert!(parse_user(":pw").is_err());
+    }
+}
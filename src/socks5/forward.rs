@@ -0,0 +1,124 @@
+//! Static port forwards
+//!
+//! A forward listens on a local address and connects every client to one
+//! fixed destination, for programs that cannot speak SOCKS (database
+//! clients, legacy tools). Destinations take the same routes, access rules
+//! and bandwidth limits as SOCKS sessions, and names (MagicDNS included)
+//! are resolved again for each connection.
+
+use super::protocol::{valid_domain, TargetAddr};
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+
+/// Transport of a forward
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ForwardProtocol {
+    Tcp,
+    Udp,
+}
+
+/// One `LOCAL=TARGET` forward
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Forward {
+    pub protocol: ForwardProtocol,
+    /// Local address to listen on
+    pub listen: SocketAddr,
+    /// Destination every client is connected to
+    pub target: TargetAddr,
+}
+
+impl FromStr for Forward {
+    type Err = String;
+
+    /// Parse `[HOST:]PORT=HOST:PORT[/tcp|/udp]`; a bare local port listens
+    /// on 127.0.0.1
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (spec, protocol) = match s.rsplit_once('/') {
+            Some((spec, "tcp")) => (spec, ForwardProtocol::Tcp),
+            Some((spec, "udp")) => (spec, ForwardProtocol::Udp),
+            Some((_, other)) => return Err(format!("unknown forward protocol '{}'", other)),
+            None => (s, ForwardProtocol::Tcp),
+        };
+        let (listen, target) = spec
+            .split_once('=')
+            .ok_or_else(|| format!("invalid forward '{}', expected LOCAL=HOST:PORT", s))?;
+
+        let listen = match listen.parse::<u16>() {
+            Ok(port) => SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+            Err(_) => listen
+                .parse()
+                .map_err(|_| format!("invalid local address '{}'", listen))?,
+        };
+
+        Ok(Forward {
+            protocol,
+            listen,
+            target: parse_target(target)?,
+        })
+    }
+}
+
+impl fmt::Display for Forward {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.listen, self.target)?;
+        if self.protocol == ForwardProtocol::Udp {
+            f.write_str("/udp")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse `HOST:PORT`, with IPv6 addresses in brackets
+fn parse_target(s: &str) -> Result<TargetAddr, String> {
+    if let Ok(addr) = s.parse() {
+        return Ok(TargetAddr::Ip(addr));
+    }
+    let (host, port) = s
+        .rsplit_once(':')
+        .ok_or_else(|| format!("missing port in '{}'", s))?;
+    let port = port
+        .parse()
+        .map_err(|_| format!("invalid port in '{}'", s))?;
+    if !valid_domain(host) || host.contains(':') || host.contains('[') {
+        return Err(format!("invalid host in '{}'", s));
+    }
+    Ok(TargetAddr::Domain(host.to_string(), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let forward: Forward = "127.0.0.1:5432=db-1:5432".parse().unwrap();
+        assert_eq!(forward.protocol, ForwardProtocol::Tcp);
+        assert_eq!(forward.listen, "127.0.0.1:5432".parse().unwrap());
+        assert_eq!(forward.target, TargetAddr::Domain("db-1".to_string(), 5432));
+        assert_eq!(forward.to_string(), "127.0.0.1:5432=db-1:5432");
+
+        let forward: Forward = "5353=[fd7a:115c:a1e0::5]:53/udp".parse().unwrap();
+        assert_eq!(forward.protocol, ForwardProtocol::Udp);
+        assert_eq!(forward.listen, "127.0.0.1:5353".parse().unwrap());
+        assert_eq!(
+            forward.target,
+            TargetAddr::Ip("[fd7a:115c:a1e0::5]:53".parse().unwrap())
+        );
+        assert_eq!(
+            forward.to_string(),
+            "127.0.0.1:5353=[fd7a:115c:a1e0::5]:53/udp"
+        );
+
+        for invalid in [
+            "5432",
+            "5432=db-1",
+            "5432=:5432",
+            "5432=db-1:x",
+            "localhost:5432=db-1:5432",
+            "5432=db-1:5432/sctp",
+        ] {
+            assert!(invalid.parse::<Forward>().is_err(), "{}", invalid);
+        }
+    }
+}
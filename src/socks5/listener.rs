@@ -0,0 +1,466 @@
+//! Listen sockets for the SOCKS5 server
+//!
+//! A server can listen on several TCP addresses, Unix domain sockets and
+//! ports on the node's tailnet addresses at once. Each listener may name a
+//! profile that overrides authentication and access rules for the clients
+//! it accepts.
+
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+use crate::vpn::{Netstack, TailnetListener, TailnetStream};
+
+/// Where a listener accepts connections
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ListenAddr {
+    /// `HOST:PORT`; `[::]:PORT` also accepts IPv4 unless `v6only` is set
+    Tcp(String),
+    /// Unix domain socket path
+    Unix(PathBuf),
+    /// Port on the node's Tailscale addresses, served by the userspace stack
+    Tailnet(u16),
+}
+
+impl From<String> for ListenAddr {
+    /// `unix:PATH` for a Unix socket, `tailnet:PORT` for the tailnet
+    /// addresses, anything else is a TCP address
+    fn from(s: String) -> Self {
+        if let Some(path) = s.strip_prefix("unix:") {
+            return ListenAddr::Unix(PathBuf::from(path));
+        }
+        match s.strip_prefix("tailnet:").map(str::parse) {
+            Some(Ok(port)) => ListenAddr::Tailnet(port),
+            _ => ListenAddr::Tcp(s),
+        }
+    }
+}
+
+impl ListenAddr {
+    /// Reject `tailnet:` addresses whose port did not parse
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            ListenAddr::Tcp(addr) if addr.starts_with("tailnet:") => {
+                Err(format!("invalid tailnet port in '{}'", addr))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => f.write_str(addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+            ListenAddr::Tailnet(port) => write!(f, "tailnet:{}", port),
+        }
+    }
+}
+
+/// One listener and its settings
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ListenerConfig {
+    pub addr: ListenAddr,
+    /// Permission bits for a Unix socket (e.g. `0o660`)
+    pub mode: Option<u32>,
+    /// Accept only IPv6 on a wildcard IPv6 address
+    pub v6only: bool,
+    /// Profile overriding auth and rules for this listener
+    pub profile: Option<String>,
+}
+
+impl ListenerConfig {
+    pub fn new(addr: ListenAddr) -> Self {
+        Self {
+            addr,
+            mode: None,
+            v6only: false,
+            profile: None,
+        }
+    }
+}
+
+impl FromStr for ListenerConfig {
+    type Err = String;
+
+    /// Parse `ADDR[,mode=OCTAL][,v6only][,profile=NAME]`, e.g.
+    /// `[::]:1080,profile=remote` or `unix:/run/socktail.sock,mode=660`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        let addr = parts
+            .next()
+            .filter(|a| !a.is_empty())
+            .ok_or("empty listen address")?;
+        let mut config = ListenerConfig::new(ListenAddr::from(addr.to_string()));
+        config.addr.validate()?;
+
+        for part in parts {
+            match part.split_once('=') {
+                Some(("mode", mode)) => config.mode = Some(parse_mode(mode)?),
+                Some(("profile", name)) if !name.is_empty() => {
+                    config.profile = Some(name.to_string())
+                }
+                None if part == "v6only" => config.v6only = true,
+                _ => return Err(format!("unknown listener option '{}'", part)),
+            }
+        }
+
+        if config.mode.is_some() && !matches!(config.addr, ListenAddr::Unix(_)) {
+            return Err(format!("mode only applies to unix sockets: '{}'", s));
+        }
+        Ok(config)
+    }
+}
+
+impl fmt::Display for ListenerConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.addr)?;
+        if let Some(profile) = &self.profile {
+            write!(f, " (profile {})", profile)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse octal permission bits like `660` or `0o660`
+pub fn parse_mode(s: &str) -> Result<u32, String> {
+    let digits = s.strip_prefix("0o").unwrap_or(s);
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("invalid mode '{}', expected octal like 660", s)),
+    }
+}
+
+/// Address of an accepted client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientAddr {
+    Tcp(SocketAddr),
+    /// Peer whose source address WireGuard authenticated
+    Tailnet(SocketAddr),
+    Unix,
+}
+
+impl ClientAddr {
+    /// Source IP, used for rules and per-IP limits
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            ClientAddr::Tcp(addr) | ClientAddr::Tailnet(addr) => Some(addr.ip()),
+            ClientAddr::Unix => None,
+        }
+    }
+
+    /// Source IP of a tailnet peer, the only one WhoIs may trust
+    pub fn tailnet_ip(&self) -> Option<IpAddr> {
+        match self {
+            ClientAddr::Tailnet(addr) => Some(addr.ip()),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ClientAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientAddr::Tcp(addr) | ClientAddr::Tailnet(addr) => write!(f, "{}", addr),
+            ClientAddr::Unix => f.write_str("unix socket"),
+        }
+    }
+}
+
+/// A bound listen socket
+pub enum Listener {
+    Tcp(tokio::net::TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, PathBuf),
+    Tailnet(TailnetListener),
+}
+
+impl Listener {
+    /// Bind the socket described by `config`; tailnet listeners need `tailnet`
+    pub async fn bind(config: &ListenerConfig, tailnet: Option<&Netstack>) -> io::Result<Self> {
+        match &config.addr {
+            ListenAddr::Tailnet(port) => match tailnet {
+                Some(netstack) => netstack.listen(*port).map(Listener::Tailnet),
+                None => Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "tailnet listeners require the Tailscale connection",
+                )),
+            },
+            ListenAddr::Tcp(addr) => bind_tcp(addr, config.v6only).await.map(Listener::Tcp),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                bind_unix(path, config.mode).map(|l| Listener::Unix(l, path.clone()))
+            }
+            #[cfg(not(unix))]
+            ListenAddr::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unix sockets are not supported on this platform",
+            )),
+        }
+    }
+
+    pub async fn accept(&self) -> io::Result<(ClientStream, ClientAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                // Dual-stack sockets report IPv4 clients as ::ffff:a.b.c.d
+                let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+                Ok((ClientStream::Tcp(stream), ClientAddr::Tcp(addr)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((ClientStream::Unix(stream), ClientAddr::Unix))
+            }
+            Listener::Tailnet(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((ClientStream::Tailnet(stream), ClientAddr::Tailnet(addr)))
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+async fn bind_tcp(addr: &str, v6only: bool) -> io::Result<tokio::net::TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let mut last_err = None;
+    for addr in tokio::net::lookup_host(addr).await? {
+        let bind = || -> io::Result<Socket> {
+            let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+            if addr.is_ipv6() {
+                socket.set_only_v6(v6only)?;
+            }
+            #[cfg(unix)]
+            socket.set_reuse_address(true)?;
+            socket.set_nonblocking(true)?;
+            socket.bind(&addr.into())?;
+            socket.listen(1024)?;
+            Ok(socket)
+        };
+        match bind() {
+            Ok(socket) => return tokio::net::TcpListener::from_std(socket.into()),
+            Err(e) => last_err = Some(e),
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "address resolved to nothing")
+    }))
+}
+
+/// Bind a Unix socket, replacing a stale one, with `mode` from the start
+#[cfg(unix)]
+pub(crate) fn bind_unix(path: &std::path::Path, mode: Option<u32>) -> io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    // Replace a socket left behind by a previous run, but never other files
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is in use", path.display()),
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    let Some(mode) = mode else {
+        return tokio::net::UnixListener::bind(path);
+    };
+
+    // Bind in a private directory and move the socket into place once its
+    // mode is set, so that it is never reachable with the umask's bits
+    use std::os::unix::fs::DirBuilderExt;
+    let parent = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(std::path::Path::new("."));
+    let staging = parent.join(format!(".socktail-{:016x}", rand::random::<u64>()));
+    std::fs::DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join("socket");
+    let result = tokio::net::UnixListener::bind(&staged).and_then(|listener| {
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&staged);
+    let _ = std::fs::remove_dir(&staging);
+    result
+}
+
+/// A connected client on any listener type
+pub enum ClientStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+    Tailnet(TailnetStream),
+}
+
+impl ClientStream {
+    /// Local address a host TCP client connected to
+    pub fn local_ip(&self) -> Option<IpAddr> {
+        match self {
+            ClientStream::Tcp(s) => s.local_addr().ok().map(|a| a.ip().to_canonical()),
+            _ => None,
+        }
+    }
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(unix)]
+            ClientStream::Unix(s) => Pin::new(s).poll_read(cx, buf),
+            ClientStream::Tailnet(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ClientStream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(unix)]
+            ClientStream::Unix(s) => Pin::new(s).poll_write(cx, buf),
+            ClientStream::Tailnet(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(unix)]
+            ClientStream::Unix(s) => Pin::new(s).poll_flush(cx),
+            ClientStream::Tailnet(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(unix)]
+            ClientStream::Unix(s) => Pin::new(s).poll_shutdown(cx),
+            ClientStream::Tailnet(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_listener() {
+        let l: ListenerConfig = "[::]:1080,profile=remote".parse().unwrap();
+        assert_eq!(l.addr, ListenAddr::Tcp("[::]:1080".to_string()));
+        assert_eq!(l.profile.as_deref(), Some("remote"));
+        assert!(!l.v6only);
+
+        let l: ListenerConfig = "unix:/run/socktail.sock,mode=0660".parse().unwrap();
+        assert_eq!(l.addr, ListenAddr::Unix("/run/socktail.sock".into()));
+        assert_eq!(l.mode, Some(0o660));
+
+        assert!("127.0.0.1:1080,mode=660".parse::<ListenerConfig>().is_err());
+        assert!("127.0.0.1:1080,backlog=5"
+            .parse::<ListenerConfig>()
+            .is_err());
+        assert!(parse_mode("999").is_err());
+
+        let l: ListenerConfig = "tailnet:1080,profile=tailnet".parse().unwrap();
+        assert_eq!(l.addr, ListenAddr::Tailnet(1080));
+        assert_eq!(l.to_string(), "tailnet:1080 (profile tailnet)");
+        assert!("tailnet:http".parse::<ListenerConfig>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tailnet_listener() {
+        let config: ListenerConfig = "tailnet:1080".parse().unwrap();
+        assert!(Listener::bind(&config, None).await.is_err());
+
+        let (outbound, _packets) = tokio::sync::mpsc::unbounded_channel();
+        let netstack = Netstack::new(&["100.64.0.1".parse().unwrap()], outbound);
+        let listener = Listener::bind(&config, Some(&netstack)).await.unwrap();
+        assert!(matches!(listener, Listener::Tailnet(_)));
+        // The port is taken until the listener is dropped
+        assert!(Listener::bind(&config, Some(&netstack)).await.is_err());
+        drop(listener);
+        assert!(Listener::bind(&config, Some(&netstack)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack() {
+        let config: ListenerConfig = "[::]:0".parse().unwrap();
+        let Ok(listener) = Listener::bind(&config, None).await else {
+            return; // no IPv6 in this environment
+        };
+        let Listener::Tcp(tcp) = &listener else {
+            unreachable!()
+        };
+        let port = tcp.local_addr().unwrap().port();
+
+        // IPv4 clients reach the IPv6 wildcard and show up as IPv4
+        TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let (_, addr) = listener.accept().await.unwrap();
+        assert_eq!(addr.ip(), Some("127.0.0.1".parse().unwrap()));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("s.sock");
+        let config = ListenerConfig {
+            mode: Some(0o600),
+            ..ListenerConfig::new(ListenAddr::Unix(path.clone()))
+        };
+
+        let listener = Listener::bind(&config, None).await.unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // A live socket is not replaced
+        assert!(Listener::bind(&config, None).await.is_err());
+
+        tokio::net::UnixStream::connect(&path).await.unwrap();
+        let (_, addr) = listener.accept().await.unwrap();
+        assert_eq!(addr, ClientAddr::Unix);
+
+        drop(listener);
+        assert!(!path.exists());
+        // Nothing is left of the staging directory
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
@@ -3,9 +3,21 @@
 //! This module provides a complete SOCKS5 proxy server implementation
 //! with support for IPv4, IPv6, and domain name resolution.
 
+pub mod auth;
+pub mod forward;
+pub mod listener;
 pub mod protocol;
+pub mod ratelimit;
 pub mod server;
 pub mod relay;
+pub mod rules;
+pub mod session;
 
-pub use protocol::{AuthRequest, ConnectRequest, TargetAddr};
-pub use server::Socks5Server;
+pub use auth::Authenticator;
+pub use forward::{Forward, ForwardProtocol};
+pub use listener::{ListenAddr, ListenerConfig};
+pub use protocol::{AuthRequest, ConnectRequest, TargetAddr, UserPassRequest};
+pub use ratelimit::{RateLimitConfig, RateLimiter};
+pub use rules::{Rule, RuleEngine};
+pub use server::{Policy, Profile, ServerHandle, Socks5Server};
+pub use session::{SessionInfo, SessionState, SessionTable};
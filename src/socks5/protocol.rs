@@ -13,6 +13,11 @@ pub const AUTH_GSSAPI: u8 = 0x01;
 pub const AUTH_USERNAME_PASSWORD: u8 = 0x02;
 pub const AUTH_NO_ACCEPTABLE: u8 = 0xFF;
 
+// Username/password sub-negotiation (RFC 1929)
+pub const USERPASS_VERSION: u8 = 0x01;
+pub const USERPASS_SUCCESS: u8 = 0x00;
+pub const USERPASS_FAILURE: u8 = 0x01;
+
 // Commands
 pub const CMD_CONNECT: u8 = 0x01;
 pub const CMD_BIND: u8 = 0x02;
@@ -51,6 +56,9 @@ pub enum Socks5Error {
     #[error("Connection refused")]
     ConnectionRefused,
 
+    #[error("Connection not allowed by ruleset")]
+    NotAllowed,
+
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 
@@ -68,17 +76,43 @@ pub enum TargetAddr {
 }
 
 impl TargetAddr {
-    pub fn to_string(&self) -> String {
+    /// Port of the target
+    pub fn port(&self) -> u16 {
         match self {
-            TargetAddr::Ip(addr) => addr.to_string(),
-            TargetAddr::Domain(domain, port) => format!("{}:{}", domain, port),
+            TargetAddr::Ip(addr) => addr.port(),
+            TargetAddr::Domain(_, port) => *port,
+        }
+    }
+
+    /// Append the SOCKS5 encoding (ATYP, address, port)
+    pub fn write_to(&self, buf: &mut Vec<u8>) -> Result<()> {
+        match self {
+            TargetAddr::Ip(SocketAddr::V4(addr)) => {
+                buf.push(ATYP_IPV4);
+                buf.extend_from_slice(&addr.ip().octets());
+            }
+            TargetAddr::Ip(SocketAddr::V6(addr)) => {
+                buf.push(ATYP_IPV6);
+                buf.extend_from_slice(&addr.ip().octets());
+            }
+            TargetAddr::Domain(domain, _) => {
+                let len = u8::try_from(domain.len()).map_err(|_| Socks5Error::InvalidData)?;
+                buf.push(ATYP_DOMAIN);
+                buf.push(len);
+                buf.extend_from_slice(domain.as_bytes());
+            }
         }
+        buf.extend_from_slice(&self.port().to_be_bytes());
+        Ok(())
     }
 }
 
 impl std::fmt::Display for TargetAddr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.to_string())
+        match self {
+            TargetAddr::Ip(addr) => write!(f, "{}", addr),
+            TargetAddr::Domain(domain, port) => write!(f, "{}:{}", domain, port),
+        }
     }
 }
 
@@ -111,6 +145,46 @@ impl AuthRequest {
     }
 }
 
+/// Username/password request from client (RFC 1929)
+pub struct UserPassRequest {
+    pub version: u8,
+    pub username: String,
+    pub password: String,
+}
+
+impl UserPassRequest {
+    pub fn parse(buf: &mut BytesMut) -> Result<Self> {
+        if buf.len() < 2 {
+            return Err(Socks5Error::InvalidData);
+        }
+
+        let version = buf.get_u8();
+        let ulen = buf.get_u8() as usize;
+        if buf.len() < ulen + 1 {
+            return Err(Socks5Error::InvalidData);
+        }
+        let username = String::from_utf8_lossy(&buf.split_to(ulen)).to_string();
+
+        let plen = buf.get_u8() as usize;
+        if buf.len() < plen {
+            return Err(Socks5Error::InvalidData);
+        }
+        let password = String::from_utf8_lossy(&buf.split_to(plen)).to_string();
+
+        Ok(UserPassRequest {
+            version,
+            username,
+            password,
+        })
+    }
+}
+
+/// Whether `domain` can be a host name: non-empty, without whitespace or
+/// control characters that could split an upstream's HTTP request
+pub fn valid_domain(domain: &str) -> bool {
+    !domain.is_empty() && !domain.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
 /// CONNECT request from client
 pub struct ConnectRequest {
     pub version: u8,
@@ -146,7 +220,11 @@ impl ConnectRequest {
                 if buf.len() < len + 2 {
                     return Err(Socks5Error::InvalidData);
                 }
-                let domain = String::from_utf8_lossy(&buf.split_to(len)).to_string();
+                let domain = String::from_utf8(buf.split_to(len).to_vec())
+                    .map_err(|_| Socks5Error::InvalidData)?;
+                if !valid_domain(&domain) {
+                    return Err(Socks5Error::InvalidData);
+                }
                 let port = buf.get_u16();
                 TargetAddr::Domain(domain, port)
             }
@@ -176,6 +254,11 @@ pub fn auth_response(method: u8) -> [u8; 2] {
     [SOCKS5_VERSION, method]
 }
 
+/// Create username/password sub-negotiation response
+pub fn userpass_response(status: u8) -> [u8; 2] {
+    [USERPASS_VERSION, status]
+}
+
 /// Create connect response
 pub fn connect_response(status: u8) -> Vec<u8> {
     vec![
@@ -202,6 +285,28 @@ mod tests {
         assert!(auth.supports_method(AUTH_NO_AUTH));
     }
 
+    #[test]
+    fn test_userpass_request_parsing() {
+        let mut buf = BytesMut::from(
+            &[
+                0x01, // Version
+                0x05, b'a', b'l', b'i', b'c', b'e', // Username
+                0x03, b'p', b'w', b'd', // Password
+            ][..],
+        );
+        let req = UserPassRequest::parse(&mut buf).unwrap();
+
+        assert_eq!(req.version, USERPASS_VERSION);
+        assert_eq!(req.username, "alice");
+        assert_eq!(req.password, "pwd");
+    }
+
+    #[test]
+    fn test_userpass_request_truncated() {
+        let mut buf = BytesMut::from(&[0x01, 0x05, b'a', b'l'][..]);
+        assert!(UserPassRequest::parse(&mut buf).is_err());
+    }
+
     #[test]
     fn test_connect_ipv4() {
         let mut buf = BytesMut::from(
@@ -223,6 +328,21 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_target_roundtrip() {
+        for target in [
+            TargetAddr::Ip("192.0.2.1:80".parse().unwrap()),
+            TargetAddr::Ip("[2001:db8::1]:443".parse().unwrap()),
+            TargetAddr::Domain("example.com".to_string(), 8080),
+        ] {
+            let mut raw = vec![SOCKS5_VERSION, CMD_CONNECT, 0x00];
+            target.write_to(&mut raw).unwrap();
+
+            let req = ConnectRequest::parse(&mut BytesMut::from(&raw[..])).unwrap();
+            assert_eq!(req.target, target);
+        }
+    }
+
     #[test]
     fn test_connect_domain() {
         let mut buf = BytesMut::from(
@@ -243,4 +363,15 @@ mod tests {
             panic!("Expected domain");
         }
     }
+
+    #[test]
+    fn test_connect_domain_rejects_control_chars() {
+        let injected = b"x\r\nProxy-Authorization: a\r\nb.example.com";
+        for domain in [&injected[..], b"a b", b"\xff", b""] {
+            let mut raw = vec![0x05, 0x01, 0x00, 0x03, domain.len() as u8];
+            raw.extend_from_slice(domain);
+            raw.extend_from_slice(&[0x01, 0xBB]);
+            assert!(ConnectRequest::parse(&mut BytesMut::from(&raw[..])).is_err());
+        }
+    }
 }
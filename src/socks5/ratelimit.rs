@@ -0,0 +1,516 @@
+//! Token-bucket bandwidth shaping for relayed sessions
+//!
+//! Limits are configured per authenticated user, per source IP and globally.
+//! Every principal owns one pair of buckets (upload and download) that is
+//! shared by all of its concurrent sessions, so opening more connections
+//! does not buy more bandwidth.
+
+use arc_swap::ArcSwapOption;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::{Duration, Instant};
+
+/// Upload/download rate in bytes per second (`None` = unlimited)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limit {
+    /// Client -> target
+    pub up: Option<u64>,
+    /// Target -> client
+    pub down: Option<u64>,
+}
+
+impl Limit {
+    pub fn is_unlimited(&self) -> bool {
+        self.up.is_none() && self.down.is_none()
+    }
+}
+
+impl FromStr for Limit {
+    type Err = String;
+
+    /// Parse `UP/DOWN`, e.g. `1M/10M`, `512K/-` or `unlimited/2M`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (up, down) = s
+            .split_once('/')
+            .ok_or_else(|| format!("invalid limit '{}', expected UP/DOWN", s))?;
+        Ok(Limit {
+            up: parse_rate(up)?,
+            down: parse_rate(down)?,
+        })
+    }
+}
+
+/// Parse a rate like `64K`, `10M`, `1G` or `-`/`unlimited` (bytes per second)
+pub fn parse_rate(s: &str) -> Result<Option<u64>, String> {
+    let s = s.trim();
+    if s == "-" || s.eq_ignore_ascii_case("unlimited") {
+        return Ok(None);
+    }
+
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 1_000),
+        Some((i, 'm' | 'M')) => (&s[..i], 1_000_000),
+        Some((i, 'g' | 'G')) => (&s[..i], 1_000_000_000),
+        _ => (s, 1),
+    };
+
+    let value: u64 = digits
+        .parse()
+        .map_err(|_| format!("invalid rate '{}'", s))?;
+    match value.checked_mul(multiplier) {
+        Some(0) => Err(format!("rate must be positive: '{}'", s)),
+        Some(rate) => Ok(Some(rate)),
+        None => Err(format!("rate too large: '{}'", s)),
+    }
+}
+
+/// Where a limit applies
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LimitScope {
+    /// All sessions together
+    Global,
+    /// Every authenticated user without an explicit entry
+    AnyUser,
+    /// One authenticated user
+    User(String),
+    /// Every source IP without an explicit entry
+    AnyIp,
+    /// One source IP
+    Ip(IpAddr),
+}
+
+impl FromStr for LimitScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "global" => Ok(LimitScope::Global),
+            Some(("user", "*")) => Ok(LimitScope::AnyUser),
+            Some(("user", name)) if !name.is_empty() => Ok(LimitScope::User(name.to_string())),
+            Some(("ip", "*")) => Ok(LimitScope::AnyIp),
+            Some(("ip", ip)) => ip
+                .parse()
+                .map(LimitScope::Ip)
+                .map_err(|_| format!("invalid IP in limit scope '{}'", s)),
+            _ => Err(format!(
+                "invalid limit scope '{}', expected global, user:NAME, user:*, ip:ADDR or ip:*",
+                s
+            )),
+        }
+    }
+}
+
+/// Parse a `SCOPE=UP/DOWN` command line argument
+pub fn parse_limit_rule(s: &str) -> Result<(LimitScope, Limit), String> {
+    let (scope, limit) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid limit '{}', expected SCOPE=UP/DOWN", s))?;
+    Ok((scope.parse()?, limit.parse()?))
+}
+
+/// Rate limit configuration
+#[derive(Debug, Clone, Default)]
+pub struct RateLimitConfig {
+    pub global: Limit,
+    pub default_user: Limit,
+    pub users: HashMap<String, Limit>,
+    pub default_ip: Limit,
+    pub ips: HashMap<IpAddr, Limit>,
+}
+
+impl RateLimitConfig {
+    /// Set the limit for a scope
+    pub fn set(&mut self, scope: LimitScope, limit: Limit) {
+        match scope {
+            LimitScope::Global => self.global = limit,
+            LimitScope::AnyUser => self.default_user = limit,
+            LimitScope::User(name) => {
+                self.users.insert(name, limit);
+            }
+            LimitScope::AnyIp => self.default_ip = limit,
+            LimitScope::Ip(ip) => {
+                self.ips.insert(ip, limit);
+            }
+        }
+    }
+
+    fn user_limit(&self, user: &str) -> Limit {
+        self.users.get(user).copied().unwrap_or(self.default_user)
+    }
+
+    fn ip_limit(&self, ip: &IpAddr) -> Limit {
+        self.ips.get(ip).copied().unwrap_or(self.default_ip)
+    }
+}
+
+/// A single token bucket
+///
+/// Tokens may go negative: a caller that takes more than is available is
+/// told how long to wait, which keeps concurrent sessions roughly fair.
+#[derive(Debug)]
+pub struct TokenBucket {
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    rate: u64,
+    burst: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl BucketState {
+    /// Add what was earned since the last update, up to the burst
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.burst as f64);
+        self.last = now.max(self.last);
+    }
+}
+
+impl TokenBucket {
+    /// Create a bucket refilling at `rate` bytes/s that holds one second of burst
+    pub fn new(rate: u64) -> Self {
+        Self::with_burst(rate, rate)
+    }
+
+    pub fn with_burst(rate: u64, burst: u64) -> Self {
+        Self {
+            state: Mutex::new(BucketState {
+                rate,
+                burst,
+                tokens: burst as f64,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    /// Largest chunk a single read should request
+    pub fn burst(&self) -> u64 {
+        self.state.lock().unwrap().burst
+    }
+
+    /// Refill at `rate` from now on, keeping what is left (or owed)
+    pub fn set_rate(&self, rate: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.refill(Instant::now());
+        state.rate = rate;
+        state.burst = rate;
+        state.tokens = state.tokens.min(rate as f64);
+    }
+
+    /// Take `n` tokens at `now` and return how long the caller must wait
+    pub fn reserve(&self, n: u64, now: Instant) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        state.refill(now);
+        state.tokens -= n as f64;
+
+        if state.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / state.rate as f64)
+        }
+    }
+
+    /// Take `n` tokens, sleeping until they are available
+    pub async fn consume(&self, n: u64) {
+        let wait = self.reserve(n, Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Upload and download buckets of one principal; empty while unlimited
+#[derive(Debug, Default)]
+struct BucketPair {
+    up: ArcSwapOption<TokenBucket>,
+    down: ArcSwapOption<TokenBucket>,
+}
+
+impl BucketPair {
+    fn new(limit: Limit) -> Self {
+        let pair = Self::default();
+        pair.set(limit);
+        pair
+    }
+
+    /// Apply `limit`, keeping the state of buckets that stay limited
+    fn set(&self, limit: Limit) {
+        for (slot, rate) in [(&self.up, limit.up), (&self.down, limit.down)] {
+            match (slot.load().as_deref(), rate) {
+                (Some(bucket), Some(rate)) => bucket.set_rate(rate),
+                (_, rate) => slot.store(rate.map(|rate| Arc::new(TokenBucket::new(rate)))),
+            }
+        }
+    }
+
+    fn get(&self, dir: Direction) -> Option<Arc<TokenBucket>> {
+        match dir {
+            Direction::Up => self.up.load_full(),
+            Direction::Down => self.down.load_full(),
+        }
+    }
+}
+
+/// Direction of a relayed byte stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Client -> target
+    Up,
+    /// Target -> client
+    Down,
+}
+
+/// The buckets that apply to one session
+///
+/// Rates follow [`RateLimiter::reconfigure`] while the session runs.
+#[derive(Debug, Clone, Default)]
+pub struct SessionLimits {
+    buckets: Vec<Arc<BucketPair>>,
+}
+
+impl SessionLimits {
+    /// Limits that never throttle
+    pub fn unlimited() -> Self {
+        Self::default()
+    }
+
+    /// True if no bucket applies in `dir`
+    pub fn is_unlimited(&self, dir: Direction) -> bool {
+        self.buckets_for(dir).next().is_none()
+    }
+
+    /// Largest chunk worth reading in one go for `dir`
+    pub fn chunk_size(&self, dir: Direction, max: usize) -> usize {
+        self.buckets_for(dir)
+            .map(|b| b.burst() as usize)
+            .fold(max, usize::min)
+            .max(1)
+    }
+
+    /// Wait until `n` bytes may pass in `dir`
+    pub async fn throttle(&self, dir: Direction, n: usize) {
+        let now = Instant::now();
+        let wait = self
+            .buckets_for(dir)
+            .map(|b| b.reserve(n as u64, now))
+            .max()
+            .unwrap_or(Duration::ZERO);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    fn buckets_for(&self, dir: Direction) -> impl Iterator<Item = Arc<TokenBucket>> + '_ {
+        self.buckets.iter().filter_map(move |pair| pair.get(dir))
+    }
+}
+
+/// Hands out shared buckets to sessions
+///
+/// One limiter lives as long as the server: reloading only changes the
+/// rates, so buckets keep their level and running sessions follow along.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    config: RwLock<RateLimitConfig>,
+    global: Arc<BucketPair>,
+    users: Mutex<HashMap<String, Weak<BucketPair>>>,
+    ips: Mutex<HashMap<IpAddr, Weak<BucketPair>>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            global: Arc::new(BucketPair::new(config.global)),
+            config: RwLock::new(config),
+            users: Mutex::new(HashMap::new()),
+            ips: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn config(&self) -> RateLimitConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Switch to the limits of `config`, for new and running sessions alike
+    pub fn reconfigure(&self, config: RateLimitConfig) {
+        // Hold the maps so that no session picks up a pair in between
+        let users = self.users.lock().unwrap();
+        let ips = self.ips.lock().unwrap();
+        self.global.set(config.global);
+        for (user, pair) in users.iter() {
+            if let Some(pair) = pair.upgrade() {
+                pair.set(config.user_limit(user));
+            }
+        }
+        for (ip, pair) in ips.iter() {
+            if let Some(pair) = pair.upgrade() {
+                pair.set(config.ip_limit(ip));
+            }
+        }
+        *self.config.write().unwrap() = config;
+    }
+
+    /// Collect the buckets for a session from `ip`, authenticated as `user`
+    pub fn session(&self, user: Option<&str>, ip: Option<IpAddr>) -> SessionLimits {
+        // Unlimited principals get (empty) pairs too, in case a reload
+        // limits them later
+        let config = || self.config.read().unwrap();
+        let mut buckets = vec![self.global.clone()];
+        if let Some(user) = user {
+            let limit = || config().user_limit(user);
+            buckets.push(shared(&self.users, user.to_string(), limit));
+        }
+        if let Some(ip) = ip {
+            buckets.push(shared(&self.ips, ip, || config().ip_limit(&ip)));
+        }
+
+        SessionLimits { buckets }
+    }
+}
+
+/// Look up (or create) the bucket pair for `key`, dropping stale entries
+///
+/// `limit` is read with the map locked, so a concurrent reconfiguration
+/// either sees the new pair or happens before it is created.
+fn shared<K>(
+    map: &Mutex<HashMap<K, Weak<BucketPair>>>,
+    key: K,
+    limit: impl FnOnce() -> Limit,
+) -> Arc<BucketPair>
+where
+    K: std::hash::Hash + Eq,
+{
+    let mut map = map.lock().unwrap();
+    if let Some(pair) = map.get(&key).and_then(Weak::upgrade) {
+        return pair;
+    }
+
+    map.retain(|_, weak| weak.strong_count() > 0);
+    let pair = Arc::new(BucketPair::new(limit()));
+    map.insert(key, Arc::downgrade(&pair));
+    pair
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("64K").unwrap(), Some(64_000));
+        assert_eq!(parse_rate("10M").unwrap(), Some(10_000_000));
+        assert_eq!(parse_rate("1g").unwrap(), Some(1_000_000_000));
+        assert_eq!(parse_rate("1500").unwrap(), Some(1500));
+        assert_eq!(parse_rate("-").unwrap(), None);
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("fast").is_err());
+    }
+
+    #[test]
+    fn test_parse_limit_rule() {
+        let (scope, limit) = parse_limit_rule("user:alice=1M/-").unwrap();
+        assert_eq!(scope, LimitScope::User("alice".to_string()));
+        assert_eq!(limit.up, Some(1_000_000));
+        assert_eq!(limit.down, None);
+
+        let (scope, _) = parse_limit_rule("ip:10.0.0.1=1M/1M").unwrap();
+        assert_eq!(scope, LimitScope::Ip("10.0.0.1".parse().unwrap()));
+        assert_eq!(parse_limit_rule("global=1K/1K").unwrap().0, LimitScope::Global);
+        assert!(parse_limit_rule("host:x=1K/1K").is_err());
+        assert!(parse_limit_rule("global=1K").is_err());
+    }
+
+    #[test]
+    fn test_bucket_reserve() {
+        let bucket = TokenBucket::with_burst(1000, 1000);
+        let start = Instant::now();
+
+        // Burst is available immediately
+        assert_eq!(bucket.reserve(1000, start), Duration::ZERO);
+        // Going into debt yields a wait proportional to the deficit
+        assert_eq!(bucket.reserve(500, start), Duration::from_millis(500));
+        // Refill pays the debt back
+        assert_eq!(
+            bucket.reserve(0, start + Duration::from_millis(500)),
+            Duration::ZERO
+        );
+        // Refill never exceeds the burst size
+        let later = start + Duration::from_secs(60);
+        assert_eq!(bucket.reserve(1000, later), Duration::ZERO);
+        assert!(bucket.reserve(1, later) > Duration::ZERO);
+    }
+
+    #[test]
+    fn test_sessions_share_buckets() {
+        let mut config = RateLimitConfig::default();
+        config.set(LimitScope::AnyUser, "1M/1M".parse().unwrap());
+        let limiter = RateLimiter::new(config);
+
+        let a = limiter.session(Some("alice"), None);
+        let b = limiter.session(Some("alice"), None);
+        let c = limiter.session(Some("bob"), None);
+
+        // The global pair comes first
+        assert!(Arc::ptr_eq(&a.buckets[1], &b.buckets[1]));
+        assert!(!Arc::ptr_eq(&a.buckets[1], &c.buckets[1]));
+
+        // Buckets are released with the last session
+        drop((a, b));
+        let d = limiter.session(Some("carol"), None);
+        assert_eq!(limiter.users.lock().unwrap().len(), 2);
+        drop(d);
+    }
+
+    #[test]
+    fn test_session_scopes() {
+        let mut config = RateLimitConfig::default();
+        config.set(LimitScope::Global, "10M/10M".parse().unwrap());
+        config.set(LimitScope::User("alice".to_string()), "1M/-".parse().unwrap());
+        config.set(LimitScope::AnyIp, "-/2M".parse().unwrap());
+        let limiter = RateLimiter::new(config);
+
+        let ip = Some("192.0.2.1".parse().unwrap());
+        let limits = limiter.session(Some("alice"), ip);
+        assert_eq!(limits.buckets_for(Direction::Up).count(), 2);
+        assert_eq!(limits.buckets_for(Direction::Down).count(), 2);
+
+        let anonymous = limiter.session(None, None);
+        assert_eq!(anonymous.buckets_for(Direction::Up).count(), 1);
+
+        assert!(SessionLimits::unlimited().is_unlimited(Direction::Up));
+        assert_eq!(limits.chunk_size(Direction::Up, 16384), 16384);
+    }
+
+    #[test]
+    fn test_reconfigure_keeps_buckets() {
+        let mut config = RateLimitConfig::default();
+        config.set(LimitScope::AnyUser, "1000/-".parse().unwrap());
+        let limiter = RateLimiter::new(config.clone());
+        let running = limiter.session(Some("alice"), None);
+
+        // Spend the burst; a reload with the same limits does not refill it
+        let now = Instant::now();
+        assert_eq!(running.buckets_for(Direction::Up).count(), 1);
+        running.buckets_for(Direction::Up).for_each(|b| {
+            b.reserve(1000, now);
+        });
+        limiter.reconfigure(config.clone());
+        let new = limiter.session(Some("alice"), None);
+        let wait = new.buckets_for(Direction::Up).map(|b| b.reserve(500, now)).max();
+        assert!(wait.unwrap() > Duration::from_millis(400));
+
+        // New limits reach the running session, including new directions
+        config.set(LimitScope::AnyUser, "2000/3000".parse().unwrap());
+        config.set(LimitScope::Global, "-/10M".parse().unwrap());
+        limiter.reconfigure(config);
+        assert_eq!(running.chunk_size(Direction::Up, 16384), 2000);
+        assert_eq!(running.chunk_size(Direction::Down, 16384), 3000);
+        assert_eq!(running.buckets_for(Direction::Down).count(), 2);
+        assert_eq!(limiter.config().default_user.down, Some(3000));
+    }
+}
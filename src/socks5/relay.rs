@@ -1,23 +1,42 @@
 //! Data relay between client and target
 
-use tokio::io::{self, AsyncWriteExt};
-use tokio::net::TcpStream;
+use super::ratelimit::{Direction, SessionLimits};
+use super::session::Traffic;
+use crate::metrics::METRICS;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tracing::debug;
 
-/// Relay data bidirectionally between client and target
-pub async fn relay_data(client: TcpStream, target: TcpStream) -> io::Result<()> {
-    let (mut client_read, mut client_write) = io::split(client);
-    let (mut target_read, mut target_write) = io::split(target);
+/// Size of the copy buffer, and the largest chunk read at once
+const COPY_BUF_SIZE: usize = 16 * 1024;
+
+/// Relay data bidirectionally between client and target, adding the bytes
+/// written each way to `traffic` as they go
+pub async fn relay_data<C, T>(
+    client: C,
+    target: T,
+    limits: SessionLimits,
+    traffic: &Traffic,
+) -> io::Result<()>
+where
+    C: AsyncRead + AsyncWrite,
+    T: AsyncRead + AsyncWrite,
+{
+    let (mut client_read, client_write) = io::split(client);
+    let (mut target_read, target_write) = io::split(target);
+    let mut client_write = Counted::new(client_write, traffic, Direction::Down);
+    let mut target_write = Counted::new(target_write, traffic, Direction::Up);
 
     let client_to_target = async {
-        let bytes = io::copy(&mut client_read, &mut target_write).await?;
+        let bytes = copy(&mut client_read, &mut target_write, &limits, Direction::Up).await?;
         debug!("Client -> Target: {} bytes", bytes);
         target_write.shutdown().await?;
         Ok::<_, io::Error>(bytes)
     };
 
     let target_to_client = async {
-        let bytes = io::copy(&mut target_read, &mut client_write).await?;
+        let bytes = copy(&mut target_read, &mut client_write, &limits, Direction::Down).await?;
         debug!("Target -> Client: {} bytes", bytes);
         client_write.shutdown().await?;
         Ok::<_, io::Error>(bytes)
@@ -35,3 +54,113 @@ pub async fn relay_data(client: TcpStream, target: TcpStream) -> io::Result<()>
         }
     }
 }
+
+/// Copy one direction, throttled by the session's buckets
+///
+/// Limits are looked up again for every chunk, so a reload that limits
+/// an unlimited session applies from its next read.
+async fn copy<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    limits: &SessionLimits,
+    dir: Direction,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; COPY_BUF_SIZE];
+    let mut total = 0u64;
+
+    loop {
+        let len = limits.chunk_size(dir, COPY_BUF_SIZE);
+        let n = reader.read(&mut buf[..len]).await?;
+        if n == 0 {
+            return Ok(total);
+        }
+        if !limits.is_unlimited(dir) {
+            limits.throttle(dir, n).await;
+        }
+        writer.write_all(&buf[..n]).await?;
+        total += n as u64;
+    }
+}
+
+/// Writer that adds what it writes to a session's counters and [`METRICS`]
+struct Counted<'a, W> {
+    inner: W,
+    traffic: &'a Traffic,
+    dir: Direction,
+}
+
+impl<'a, W> Counted<'a, W> {
+    fn new(inner: W, traffic: &'a Traffic, dir: Direction) -> Self {
+        Self { inner, traffic, dir }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Counted<'_, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            self.traffic.add(self.dir, n);
+            match self.dir {
+                Direction::Up => METRICS.bytes_up.add(n as u64),
+                Direction::Down => METRICS.bytes_down.add(n as u64),
+            }
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socks5::ratelimit::{LimitScope, RateLimitConfig, RateLimiter};
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn test_reload_limits_running_relay() {
+        let limiter = RateLimiter::new(RateLimitConfig::default());
+        let limits = limiter.session(Some("alice"), None);
+        assert!(limits.is_unlimited(Direction::Up));
+
+        let (mut client, relay_client) = tokio::io::duplex(64 * 1024);
+        let (relay_target, mut target) = tokio::io::duplex(64 * 1024);
+        let traffic = Traffic::default();
+        let relay = relay_data(relay_client, relay_target, limits, &traffic);
+        let exchange = async {
+            let mut buf = [0u8; 4];
+            client.write_all(b"ping").await.unwrap();
+            target.read_exact(&mut buf).await.unwrap();
+
+            // Limited from here on: the burst passes, the rest waits
+            let mut config = RateLimitConfig::default();
+            config.set(LimitScope::AnyUser, "4000/-".parse().unwrap());
+            limiter.reconfigure(config);
+            let start = Instant::now();
+            client.write_all(&[0u8; 6000]).await.unwrap();
+            let mut buf = [0u8; 6000];
+            target.read_exact(&mut buf).await.unwrap();
+            let elapsed = start.elapsed();
+            drop(client);
+            drop(target);
+            elapsed
+        };
+
+        let (_, elapsed) = tokio::join!(relay, exchange);
+        assert!(elapsed >= Duration::from_millis(400), "{:?}", elapsed);
+    }
+}
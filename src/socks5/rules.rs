@@ -0,0 +1,514 @@
+//! Destination access-control rules for SOCKS requests
+//!
+//! Rules are evaluated top to bottom after a request has been parsed and the
+//! first rule whose matchers all apply decides. An empty matcher list
+//! matches anything. The engine starts with a set of deny rules that keep
+//! clients away from loopback and cloud metadata endpoints on the gateway.
+
+use super::protocol::{TargetAddr, CMD_BIND, CMD_CONNECT, CMD_UDP_ASSOCIATE};
+use crate::utils::Cidr;
+use std::fmt;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+/// Rules prepended by [`RuleEngine::with_safe_defaults`]
+pub const SAFE_DEFAULT_RULES: &[&str] = &[
+    // Loopback and "this host"
+    "deny dst=127.0.0.0/8,::1,0.0.0.0/8,::,.localhost",
+    // Link-local and cloud instance metadata services
+    "deny dst=169.254.0.0/16,fe80::/10,fd00:ec2::254,100.100.100.200,metadata.google.internal",
+];
+
+/// What to do with a matching request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(Action::Allow),
+            "deny" => Ok(Action::Deny),
+            _ => Err(format!("invalid action '{}', expected allow or deny", s)),
+        }
+    }
+}
+
+/// Case-insensitive domain or user pattern
+///
+/// `*.example.com` matches subdomains, `.example.com` matches the domain and
+/// its subdomains, `alice@` matches the login `alice@` at any domain, and
+/// `*` elsewhere matches any run of characters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern(String);
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Self {
+        Pattern(pattern.trim_end_matches('.').to_ascii_lowercase())
+    }
+
+    pub fn matches(&self, value: &str) -> bool {
+        let value = value.trim_end_matches('.').to_ascii_lowercase();
+        match self.0.strip_prefix('.') {
+            Some(suffix) => value == suffix || value.ends_with(&self.0),
+            None if self.0.ends_with('@') => value.starts_with(&self.0),
+            None => glob_match(self.0.as_bytes(), value.as_bytes()),
+        }
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+fn glob_match(pattern: &[u8], value: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => value.is_empty(),
+        Some((b'*', rest)) => (0..=value.len()).any(|i| glob_match(rest, &value[i..])),
+        Some((c, rest)) => value.first() == Some(c) && glob_match(rest, &value[1..]),
+    }
+}
+
+/// Destination matcher
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DestMatch {
+    Cidr(Cidr),
+    Domain(Pattern),
+}
+
+impl FromStr for DestMatch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("empty destination".to_string());
+        }
+        match s.parse::<Cidr>() {
+            Ok(cidr) => Ok(DestMatch::Cidr(cidr)),
+            Err(e) if s.contains('/') => Err(e),
+            Err(_) => Ok(DestMatch::Domain(Pattern::new(s))),
+        }
+    }
+}
+
+/// Parse `443` or `8000-8999`
+pub fn parse_port_range(s: &str) -> Result<RangeInclusive<u16>, String> {
+    let parse = |p: &str| p.parse::<u16>().map_err(|_| format!("invalid port '{}'", s));
+    match s.split_once('-') {
+        Some((start, end)) => {
+            let (start, end) = (parse(start)?, parse(end)?);
+            if start > end {
+                return Err(format!("invalid port range '{}'", s));
+            }
+            Ok(start..=end)
+        }
+        None => parse(s).map(|p| p..=p),
+    }
+}
+
+/// Parse a SOCKS command name
+pub fn parse_command(s: &str) -> Result<u8, String> {
+    match s {
+        "connect" => Ok(CMD_CONNECT),
+        "bind" => Ok(CMD_BIND),
+        "udp" | "udp-associate" => Ok(CMD_UDP_ASSOCIATE),
+        _ => Err(format!("invalid command '{}', expected connect, bind or udp", s)),
+    }
+}
+
+/// A single access rule
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub action: Action,
+    pub destinations: Vec<DestMatch>,
+    pub ports: Vec<RangeInclusive<u16>>,
+    pub commands: Vec<u8>,
+    pub sources: Vec<Cidr>,
+    pub users: Vec<Pattern>,
+    /// Tailnet ACL tags of the client node (any of)
+    pub tags: Vec<String>,
+}
+
+impl Rule {
+    /// Rule matching every request
+    pub fn new(action: Action) -> Self {
+        Self {
+            action,
+            destinations: Vec::new(),
+            ports: Vec::new(),
+            commands: Vec::new(),
+            sources: Vec::new(),
+            users: Vec::new(),
+            tags: Vec::new(),
+        }
+    }
+
+    /// Check whether every matcher of this rule applies to `req`
+    pub fn matches(&self, req: &RuleRequest) -> bool {
+        self.check(req) == Some(true)
+    }
+
+    /// Like [`matches`](Self::matches), but `None` when only the address of
+    /// a domain that has not been resolved yet can tell
+    fn check(&self, req: &RuleRequest) -> Option<bool> {
+        let dest = |dest: &DestMatch| match (dest, req.target) {
+            (DestMatch::Cidr(cidr), TargetAddr::Ip(addr)) => Some(cidr.contains(&addr.ip())),
+            (DestMatch::Cidr(cidr), TargetAddr::Domain(..)) => {
+                req.resolved.map(|ip| cidr.contains(&ip))
+            }
+            (DestMatch::Domain(pattern), TargetAddr::Domain(domain, _)) => {
+                Some(pattern.matches(domain))
+            }
+            (DestMatch::Domain(_), TargetAddr::Ip(_)) => Some(false),
+        };
+        let dest_ok = if self.destinations.is_empty() {
+            Some(true)
+        } else {
+            let results: Vec<Option<bool>> = self.destinations.iter().map(dest).collect();
+            if results.contains(&Some(true)) {
+                Some(true)
+            } else if results.contains(&None) {
+                None
+            } else {
+                Some(false)
+            }
+        };
+
+        let port = req.target.port();
+        let port_ok = self.ports.is_empty() || self.ports.iter().any(|r| r.contains(&port));
+
+        let command_ok = self.commands.is_empty() || self.commands.contains(&req.command);
+
+        let source_ok = self.sources.is_empty()
+            || req
+                .source
+                .is_some_and(|ip| self.sources.iter().any(|cidr| cidr.contains(&ip)));
+
+        let user_ok = self.users.is_empty()
+            || req
+                .user
+                .is_some_and(|user| self.users.iter().any(|p| p.matches(user)));
+
+        let tags_ok =
+            self.tags.is_empty() || req.tags.iter().any(|tag| self.tags.contains(tag));
+
+        if port_ok && command_ok && source_ok && user_ok && tags_ok {
+            dest_ok
+        } else {
+            Some(false)
+        }
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    /// Parse `ACTION [dst=..] [port=..] [cmd=..] [src=..] [user=..] [tag=..]`
+    ///
+    /// Each matcher takes a comma separated list, e.g.
+    /// `deny dst=10.0.0.0/8,*.corp port=22,3389`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        let action = parts.next().ok_or("empty rule")?.parse()?;
+        let mut rule = Rule::new(action);
+
+        for part in parts {
+            let (key, values) = part
+                .split_once('=')
+                .ok_or_else(|| format!("invalid matcher '{}', expected KEY=VALUE", part))?;
+            let values = values.split(',');
+            match key {
+                "dst" => rule.destinations = values.map(str::parse).collect::<Result<_, _>>()?,
+                "port" => rule.ports = values.map(parse_port_range).collect::<Result<_, _>>()?,
+                "cmd" => rule.commands = values.map(parse_command).collect::<Result<_, _>>()?,
+                "src" => rule.sources = values.map(str::parse).collect::<Result<_, _>>()?,
+                "user" => rule.users = values.map(Pattern::new).collect(),
+                "tag" => rule.tags = values.map(str::to_string).collect(),
+                _ => return Err(format!("unknown matcher '{}'", key)),
+            }
+        }
+
+        Ok(rule)
+    }
+}
+
+/// The facts a rule is matched against
+#[derive(Debug, Clone, Copy)]
+pub struct RuleRequest<'a> {
+    pub command: u8,
+    pub target: &'a TargetAddr,
+    /// Address a domain target resolved to, once known
+    pub resolved: Option<IpAddr>,
+    pub source: Option<IpAddr>,
+    pub user: Option<&'a str>,
+    /// Tailnet ACL tags of the client node
+    pub tags: &'a [String],
+}
+
+/// Outcome of rule evaluation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decision {
+    pub action: Action,
+    /// Index of the matching rule (`None` = default action)
+    pub rule: Option<usize>,
+    /// An earlier rule with another action may match once the domain
+    /// target is resolved
+    pub unresolved: bool,
+}
+
+impl Decision {
+    pub fn is_allowed(&self) -> bool {
+        self.action == Action::Allow
+    }
+
+    /// Whether to go on with a request checked before resolution: allowed,
+    /// or possibly allowed when the caller checks every resolved address
+    pub fn proceed(&self, resolves: bool) -> bool {
+        self.is_allowed() || (self.unresolved && resolves)
+    }
+}
+
+/// First-match rule list
+#[derive(Debug, Clone)]
+pub struct RuleEngine {
+    rules: Vec<Rule>,
+    default_action: Action,
+}
+
+impl RuleEngine {
+    pub fn new(rules: Vec<Rule>, default_action: Action) -> Self {
+        Self {
+            rules,
+            default_action,
+        }
+    }
+
+    /// [`SAFE_DEFAULT_RULES`] followed by `rules`, allowing anything else
+    pub fn with_safe_defaults(rules: Vec<Rule>) -> Self {
+        let mut all: Vec<Rule> = SAFE_DEFAULT_RULES
+            .iter()
+            .map(|r| r.parse().expect("built-in rule"))
+            .collect();
+        all.extend(rules);
+        Self::new(all, Action::Allow)
+    }
+
+    /// Engine without any rules
+    pub fn allow_all() -> Self {
+        Self::new(Vec::new(), Action::Allow)
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Find the first matching rule for `req`
+    ///
+    /// Rules that only the resolved address of a domain can decide are
+    /// skipped, and the decision is marked `unresolved` if one of them
+    /// would have led to another action.
+    pub fn evaluate(&self, req: &RuleRequest) -> Decision {
+        let mut undecided = Vec::new();
+        let (action, rule) = self
+            .rules
+            .iter()
+            .enumerate()
+            .find_map(|(i, rule)| match rule.check(req) {
+                Some(true) => Some((rule.action, Some(i))),
+                Some(false) => None,
+                None => {
+                    undecided.push(rule.action);
+                    None
+                }
+            })
+            .unwrap_or((self.default_action, None));
+        Decision {
+            action,
+            rule,
+            unresolved: undecided.iter().any(|other| *other != action),
+        }
+    }
+}
+
+impl Default for RuleEngine {
+    fn default() -> Self {
+        Self::with_safe_defaults(Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request<'a>(target: &'a TargetAddr) -> RuleRequest<'a> {
+        RuleRequest {
+            command: CMD_CONNECT,
+            target,
+            resolved: None,
+            source: Some("192.0.2.10".parse().unwrap()),
+            user: None,
+            tags: &[],
+        }
+    }
+
+    fn ip(s: &str) -> TargetAddr {
+        TargetAddr::Ip(s.parse().unwrap())
+    }
+
+    fn domain(name: &str, port: u16) -> TargetAddr {
+        TargetAddr::Domain(name.to_string(), port)
+    }
+
+    #[test]
+    fn test_pattern() {
+        assert!(Pattern::new("*.example.com").matches("www.Example.com."));
+        assert!(!Pattern::new("*.example.com").matches("example.com"));
+        assert!(Pattern::new(".example.com").matches("example.com"));
+        assert!(Pattern::new(".example.com").matches("a.b.example.com"));
+        assert!(!Pattern::new(".example.com").matches("badexample.com"));
+        assert!(Pattern::new("db-*.internal").matches("db-7.internal"));
+        assert!(Pattern::new("alice@*").matches("alice@example.com"));
+    }
+
+    #[test]
+    fn test_parse_rule() {
+        let rule: Rule = "deny dst=10.0.0.0/8,*.corp port=22,8000-8999 cmd=connect src=192.0.2.0/24 user=bob"
+            .parse()
+            .unwrap();
+        assert_eq!(rule.action, Action::Deny);
+        assert_eq!(rule.destinations.len(), 2);
+        assert_eq!(rule.ports, vec![22..=22, 8000..=8999]);
+        assert_eq!(rule.commands, vec![CMD_CONNECT]);
+        assert_eq!(rule.sources.len(), 1);
+        assert_eq!(rule.users, vec![Pattern::new("bob")]);
+
+        assert!("permit".parse::<Rule>().is_err());
+        assert!("deny port=99999".parse::<Rule>().is_err());
+        assert!("deny dst=10.0.0.0/40".parse::<Rule>().is_err());
+        assert!("deny via=x".parse::<Rule>().is_err());
+    }
+
+    #[test]
+    fn test_safe_defaults() {
+        let engine = RuleEngine::default();
+
+        for target in [
+            ip("127.0.0.1:22"),
+            ip("[::1]:80"),
+            ip("0.0.0.0:80"),
+            ip("169.254.169.254:80"),
+            ip("[fd00:ec2::254]:80"),
+            domain("localhost", 80),
+            domain("metadata.google.internal", 80),
+        ] {
+            assert!(!engine.evaluate(&request(&target)).is_allowed(), "{}", target);
+        }
+
+        assert!(engine.evaluate(&request(&ip("93.184.216.34:443"))).is_allowed());
+        assert!(engine.evaluate(&request(&domain("example.com", 443))).is_allowed());
+    }
+
+    #[test]
+    fn test_resolved_address() {
+        let engine = RuleEngine::default();
+        let target = domain("rebind.example", 80);
+        let mut req = request(&target);
+        assert!(engine.evaluate(&req).is_allowed());
+
+        req.resolved = Some("127.0.0.1".parse().unwrap());
+        assert!(!engine.evaluate(&req).is_allowed());
+    }
+
+    #[test]
+    fn test_cidr_allow_list_with_domain() {
+        let engine = RuleEngine::new(vec!["allow dst=10.0.0.0/8".parse().unwrap()], Action::Deny);
+        let target = domain("db.corp.example", 5432);
+        let mut req = request(&target);
+
+        // Undecided until resolved: go on if each address gets checked
+        let decision = engine.evaluate(&req);
+        assert!(!decision.is_allowed() && decision.unresolved);
+        assert!(decision.proceed(true));
+        assert!(!decision.proceed(false));
+
+        req.resolved = Some("10.1.2.3".parse().unwrap());
+        assert!(engine.evaluate(&req).is_allowed());
+        req.resolved = Some("192.0.2.1".parse().unwrap());
+        assert!(!engine.evaluate(&req).is_allowed());
+
+        // Rules that agree with the outcome leave it decided
+        let engine = RuleEngine::new(vec!["deny dst=10.0.0.0/8".parse().unwrap()], Action::Deny);
+        assert!(!engine.evaluate(&request(&target)).unresolved);
+    }
+
+    #[test]
+    fn test_first_match() {
+        let engine = RuleEngine::new(
+            vec![
+                "allow dst=.internal port=443 user=alice".parse().unwrap(),
+                "deny dst=.internal".parse().unwrap(),
+            ],
+            Action::Allow,
+        );
+
+        let target = domain("git.internal", 443);
+        let mut req = request(&target);
+        let decision = engine.evaluate(&req);
+        assert_eq!(decision.action, Action::Deny);
+        assert_eq!(decision.rule, Some(1));
+
+        req.user = Some("alice");
+        assert_eq!(engine.evaluate(&req).rule, Some(0));
+
+        let other = domain("example.com", 80);
+        assert_eq!(engine.evaluate(&request(&other)).rule, None);
+    }
+
+    #[test]
+    fn test_source_and_command() {
+        let engine = RuleEngine::new(
+            vec!["allow src=192.0.2.0/24 cmd=connect".parse().unwrap()],
+            Action::Deny,
+        );
+        let target = ip("198.51.100.1:80");
+        let mut req = request(&target);
+        assert!(engine.evaluate(&req).is_allowed());
+
+        req.command = CMD_BIND;
+        assert!(!engine.evaluate(&req).is_allowed());
+
+        req.command = CMD_CONNECT;
+        req.source = None;
+        assert!(!engine.evaluate(&req).is_allowed());
+    }
+
+    #[test]
+    fn test_tailnet_identity() {
+        let engine = RuleEngine::new(
+            vec![
+                "allow dst=*.internal port=443 user=alice@".parse().unwrap(),
+                "allow dst=*.internal port=443 tag=tag:ci".parse().unwrap(),
+            ],
+            Action::Deny,
+        );
+        let target = domain("git.internal", 443);
+        let mut req = request(&target);
+        assert!(!engine.evaluate(&req).is_allowed());
+
+        req.user = Some("Alice@example.com");
+        assert_eq!(engine.evaluate(&req).rule, Some(0));
+        req.user = Some("malice@example.com");
+        assert!(!engine.evaluate(&req).is_allowed());
+
+        let tags = vec!["tag:prod".to_string(), "tag:ci".to_string()];
+        req.tags = &tags;
+        assert_eq!(engine.evaluate(&req).rule, Some(1));
+    }
+}
@@ -1,46 +1,641 @@
 //! SOCKS5 server implementation
+//!
+//! The server accepts clients on any number of [listeners](ListenerConfig).
+//! The [`Policy`] (authentication, rules, limits, routes and upstreams) and
+//! the set of listeners can be replaced while the server runs through a
+//! [`ServerHandle`]. Sessions keep the policy they were accepted under; new
+//! sessions see the new one.
+//!
+//! Static [forwards](Forward) run alongside the listeners and share the
+//! default profile's rules, the limits and the dialer.
+//!
+//! Sessions and forwarded connections are registered in a [`SessionTable`]
+//! while they run, through which the control socket can end them. Their
+//! outcomes and latencies are counted in [`METRICS`].
 
+use super::auth::Authenticator;
+use super::forward::{Forward, ForwardProtocol};
+use super::listener::{ClientAddr, ClientStream, ListenAddr, Listener, ListenerConfig};
 use super::protocol::*;
+use super::ratelimit::{Direction, RateLimitConfig, RateLimiter};
 use super::relay::relay_data;
+use super::rules::{Decision, RuleEngine, RuleRequest};
+use super::session::{Session, SessionState, SessionTable};
+use crate::metrics::METRICS;
+use crate::outbound::{Dialer, Route, RouteTable, Upstream};
+use crate::utils::Cidr;
+use crate::vpn::forwarder::{UdpFlows, UDP_IDLE_TIMEOUT};
+#[cfg(unix)]
+use crate::vpn::LocalApi;
+use crate::vpn::{ExitNode, Netstack, PeerMap, WhoIs, SOCKS_CAPABILITY};
+use arc_swap::ArcSwap;
 use bytes::BytesMut;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
 use tracing::{debug, error, info, warn};
 
+/// How often upstream proxies are probed
+const UPSTREAM_HEALTH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Default time active sessions get to finish after shutdown is requested
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Largest datagram relayed by a UDP forward
+const MAX_DATAGRAM: usize = 65535;
+
+/// Authentication and access rules for the clients of a listener
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    pub authenticator: Authenticator,
+    pub rules: RuleEngine,
+}
+
+/// Settings applied to each new session
+#[derive(Debug, Default)]
+pub struct Policy {
+    /// Used by listeners without a profile
+    pub authenticator: Authenticator,
+    pub rate_limits: RateLimitConfig,
+    /// Used by listeners without a profile
+    pub rules: RuleEngine,
+    pub routes: RouteTable,
+    pub upstreams: Vec<Arc<Upstream>>,
+    /// Named profiles for [`ListenerConfig::profile`]
+    pub profiles: HashMap<String, Profile>,
+}
+
+/// Values that can change while the server runs
+#[derive(Debug)]
+struct Shared {
+    listeners: watch::Sender<Vec<ListenerConfig>>,
+    policy: watch::Sender<Policy>,
+    sessions: SessionTable,
+    /// Kept across reloads, which only change its rates
+    limiter: Arc<RateLimiter>,
+}
+
 pub struct Socks5Server {
-    listen_addr: String,
+    shared: Arc<Shared>,
+    peers: Option<PeerMap>,
+    host_tailnet: Vec<IpAddr>,
+    tailnet: Option<Netstack>,
+    exit_node: Option<ExitNode>,
+    tunnel_routes: Vec<Cidr>,
+    dns_servers: Vec<IpAddr>,
+    #[cfg(unix)]
+    local_api: Option<LocalApi>,
+    #[cfg(unix)]
+    whois_api: Option<LocalApi>,
+    forwards: Vec<Forward>,
+    shutdown: CancellationToken,
+    drain_timeout: Duration,
+}
+
+/// Reconfigures a [`Socks5Server`], including while it runs
+#[derive(Debug, Clone)]
+pub struct ServerHandle {
+    shared: Arc<Shared>,
+}
+
+impl ServerHandle {
+    /// Apply `policy` to sessions accepted from now on; rate limits also
+    /// apply to running sessions, and buckets keep their level
+    pub fn reload(&self, policy: Policy) {
+        self.shared.policy.send_replace(policy);
+    }
+
+    /// Open new listeners and close the ones not in `listeners`
+    pub fn set_listeners(&self, listeners: Vec<ListenerConfig>) {
+        self.shared.listeners.send_if_modified(|current| {
+            let changed = *current != listeners;
+            *current = listeners;
+            changed
+        });
+    }
+
+    /// Currently configured listeners
+    pub fn listeners(&self) -> Vec<ListenerConfig> {
+        self.shared.listeners.borrow().clone()
+    }
+
+    /// Sessions in progress
+    pub fn sessions(&self) -> SessionTable {
+        self.shared.sessions.clone()
+    }
+}
+
+/// State shared by all client handlers
+struct Context {
+    default_profile: Profile,
+    profiles: HashMap<String, Profile>,
+    limiter: Arc<RateLimiter>,
+    dialer: Dialer,
+    peers: Option<PeerMap>,
+    host_tailnet: Vec<IpAddr>,
+    #[cfg(unix)]
+    whois_api: Option<LocalApi>,
+    sessions: SessionTable,
+}
+
+impl Context {
+    fn new(policy: &Policy, server: &Socks5Server) -> Self {
+        let peers = server.peers.clone();
+        let mut dialer = Dialer::new(policy.routes.clone(), peers.clone());
+        if let Some(netstack) = &server.tailnet {
+            dialer.set_tailnet(netstack.clone());
+        }
+        if let Some(exit_node) = &server.exit_node {
+            dialer.set_exit_node(exit_node.clone());
+        }
+        dialer.set_tunnel_routes(server.tunnel_routes.clone());
+        dialer.set_dns_servers(server.dns_servers.clone());
+        #[cfg(unix)]
+        if let Some(api) = &server.local_api {
+            dialer.set_local_api(api.clone());
+        }
+        for upstream in &policy.upstreams {
+            upstream.spawn_health_checks(UPSTREAM_HEALTH_INTERVAL);
+            dialer.add_upstream(upstream.clone());
+        }
+        server.shared.limiter.reconfigure(policy.rate_limits.clone());
+
+        Self {
+            default_profile: Profile {
+                authenticator: policy.authenticator.clone(),
+                rules: policy.rules.clone(),
+            },
+            profiles: policy.profiles.clone(),
+            limiter: server.shared.limiter.clone(),
+            dialer,
+            peers,
+            host_tailnet: server.host_tailnet.clone(),
+            #[cfg(unix)]
+            whois_api: server.whois_api.clone(),
+            sessions: server.shared.sessions.clone(),
+        }
+    }
+
+    /// Mark host TCP clients of the node's own tailnet addresses as peers
+    fn client_addr(&self, socket: &ClientStream, addr: ClientAddr) -> ClientAddr {
+        match (addr, socket.local_ip()) {
+            (ClientAddr::Tcp(addr), Some(local)) if self.host_tailnet.contains(&local) => {
+                ClientAddr::Tailnet(addr)
+            }
+            _ => addr,
+        }
+    }
+
+    /// Identify a client that arrived over the tailnet
+    async fn whois(&self, client: &ClientAddr) -> Option<WhoIs> {
+        let ip = client.tailnet_ip()?;
+        #[cfg(unix)]
+        if let Some(api) = &self.whois_api {
+            return api.whois(ip).await.unwrap_or_else(|e| {
+                warn!("WhoIs for {} failed: {}", ip, e);
+                None
+            });
+        }
+        self.peers.as_ref()?.whois(&ip)
+    }
+
+    /// Check that the tailnet policy grants `client` the SOCKS capability,
+    /// as WhoIs or else the packet filter reports; without either, tailnet
+    /// clients are denied
+    fn tailnet_granted(&self, client: &ClientAddr, whois: Option<&WhoIs>) -> bool {
+        let Some(ip) = client.tailnet_ip() else {
+            return true;
+        };
+        if let Some(caps) = whois.and_then(|who| who.caps.as_ref()) {
+            return caps.iter().any(|cap| cap == SOCKS_CAPABILITY);
+        }
+        match self.peers.as_ref().and_then(PeerMap::filter) {
+            Some(filter) => filter.has_cap_anywhere(&ip, SOCKS_CAPABILITY),
+            None => false,
+        }
+    }
+
+    fn profile(&self, name: Option<&str>) -> Option<&Profile> {
+        match name {
+            Some(name) => self.profiles.get(name),
+            None => Some(&self.default_profile),
+        }
+    }
+}
+
+/// A running accept loop
+struct ListenerTask {
+    token: CancellationToken,
+    task: JoinHandle<()>,
 }
 
 impl Socks5Server {
+    /// Server listening on one TCP address (or `unix:PATH`)
     pub fn new(listen_addr: String) -> Self {
-        Self { listen_addr }
+        let listener = ListenerConfig::new(ListenAddr::from(listen_addr));
+        Self {
+            shared: Arc::new(Shared {
+                listeners: watch::Sender::new(vec![listener]),
+                policy: watch::Sender::new(Policy::default()),
+                sessions: SessionTable::new(),
+                limiter: Arc::new(RateLimiter::default()),
+            }),
+            peers: None,
+            host_tailnet: Vec::new(),
+            tailnet: None,
+            exit_node: None,
+            tunnel_routes: Vec::new(),
+            dns_servers: Vec::new(),
+            #[cfg(unix)]
+            local_api: None,
+            #[cfg(unix)]
+            whois_api: None,
+            forwards: Vec::new(),
+            shutdown: CancellationToken::new(),
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+        }
+    }
+
+    /// Handle for reloading the policy and listen addresses
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Replace the listeners given to [`new`](Self::new)
+    pub fn set_listeners(&mut self, listeners: Vec<ListenerConfig>) {
+        self.shared.listeners.send_replace(listeners);
+    }
+
+    /// Replace the whole policy
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.shared.policy.send_replace(policy);
+    }
+
+    /// Set how clients authenticate
+    pub fn set_authenticator(&mut self, authenticator: Authenticator) {
+        self.shared.policy.send_modify(|p| p.authenticator = authenticator);
+    }
+
+    /// Set bandwidth limits for relayed sessions
+    pub fn set_rate_limits(&mut self, rate_limits: RateLimitConfig) {
+        self.shared.policy.send_modify(|p| p.rate_limits = rate_limits);
+    }
+
+    /// Set destination access rules (defaults to [`RuleEngine::default`])
+    pub fn set_rules(&mut self, rules: RuleEngine) {
+        self.shared.policy.send_modify(|p| p.rules = rules);
+    }
+
+    /// Set the routing table (defaults to [`RouteTable::default`])
+    pub fn set_routes(&mut self, routes: RouteTable) {
+        self.shared.policy.send_modify(|p| p.routes = routes);
+    }
+
+    /// Use the tailnet network map for MagicDNS names and peer tags
+    pub fn set_peer_map(&mut self, peers: PeerMap) {
+        self.peers = Some(peers);
+    }
+
+    /// Node addresses on a host interface that drops spoofed tailnet
+    /// sources, such as tailscaled's TUN; host TCP clients connecting to
+    /// them are identified like those of `tailnet:PORT` listeners
+    pub fn set_host_tailnet(&mut self, addrs: Vec<IpAddr>) {
+        self.host_tailnet = addrs;
+    }
+
+    /// Userspace stack that serves `tailnet:PORT` listeners and carries
+    /// `tailnet` routes
+    pub fn set_tailnet(&mut self, netstack: Netstack) {
+        self.tailnet = Some(netstack);
+    }
+
+    /// Send `direct` routes through an exit node over the tailnet stack
+    pub fn set_exit_node(&mut self, exit_node: ExitNode) {
+        self.exit_node = Some(exit_node);
+    }
+
+    /// Send `direct` routes to these prefixes over the userspace stack
+    pub fn set_tunnel_routes(&mut self, routes: Vec<Cidr>) {
+        self.tunnel_routes = routes;
+    }
+
+    /// Resolve target names with these servers instead of the host's resolver
+    pub fn set_dns_servers(&mut self, servers: Vec<IpAddr>) {
+        self.dns_servers = servers;
     }
 
+    /// Carry `tailnet` routes through tailscaled's LocalAPI
+    #[cfg(unix)]
+    pub fn set_local_api(&mut self, api: LocalApi) {
+        self.local_api = Some(api);
+    }
+
+    /// Identify tailnet clients with tailscaled's WhoIs instead of the
+    /// peer map, which may be a refresh interval behind
+    #[cfg(unix)]
+    pub fn set_whois_api(&mut self, api: LocalApi) {
+        self.whois_api = Some(api);
+    }
+
+    /// Add an upstream proxy group for `upstream:NAME` routes
+    pub fn add_upstream(&mut self, upstream: Upstream) {
+        self.shared
+            .policy
+            .send_modify(|p| p.upstreams.push(Arc::new(upstream)));
+    }
+
+    /// Serve a static forward (bound when [`run`](Self::run) starts)
+    pub fn add_forward(&mut self, forward: Forward) {
+        self.forwards.push(forward);
+    }
+
+    /// Stop accepting and drain sessions once `token` is cancelled
+    pub fn set_shutdown(&mut self, token: CancellationToken) {
+        self.shutdown = token;
+    }
+
+    /// How long [`run`](Self::run) waits for active sessions after shutdown
+    pub fn set_drain_timeout(&mut self, timeout: Duration) {
+        self.drain_timeout = timeout;
+    }
+
+    /// Token that stops the server when cancelled
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// Accept clients until the shutdown token is cancelled, then wait up to
+    /// the drain timeout for active sessions to finish
+    ///
+    /// Fails if any listener cannot be bound at startup; bind errors after a
+    /// reload are logged and the other listeners keep running.
     pub async fn run(&self) -> anyhow::Result<()> {
-        let listener = TcpListener::bind(&self.listen_addr).await?;
-        info!("SOCKS5 server listening on {}", self.listen_addr);
+        let mut policy_rx = self.shared.policy.subscribe();
+        let mut listen_rx = self.shared.listeners.subscribe();
+
+        let ctx = Arc::new(ArcSwap::from_pointee(Context::new(
+            &policy_rx.borrow_and_update(),
+            self,
+        )));
+        let sessions = TaskTracker::new();
+        let mut listeners = HashMap::new();
+
+        let wanted = listen_rx.borrow_and_update().clone();
+        self.update_listeners(&mut listeners, &wanted, &ctx, &sessions)
+            .await?;
+        let forwards = self.start_forwards(&ctx, &sessions).await?;
 
         loop {
-            match listener.accept().await {
-                Ok((socket, peer_addr)) => {
-                    debug!("New connection from {}", peer_addr);
+            tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                Ok(()) = policy_rx.changed() => {
+                    let new = Context::new(&policy_rx.borrow_and_update(), self);
+                    ctx.store(Arc::new(new));
+                    info!("Policy reloaded");
+                }
+                Ok(()) = listen_rx.changed() => {
+                    let wanted = listen_rx.borrow_and_update().clone();
+                    if let Err(e) = self
+                        .update_listeners(&mut listeners, &wanted, &ctx, &sessions)
+                        .await
+                    {
+                        error!("Failed to apply listeners: {}", e);
+                    }
+                }
+            }
+        }
 
-                    tokio::spawn(async move {
-                        if let Err(e) = handle_client(socket).await {
-                            error!("Error handling client {}: {}", peer_addr, e);
-                        }
-                    });
+        // Stop accepting, then let active sessions finish
+        for listener in listeners.into_values().chain(forwards) {
+            listener.token.cancel();
+            let _ = listener.task.await;
+        }
+        sessions.close();
+
+        if !sessions.is_empty() {
+            info!(
+                "Draining {} active session(s) (up to {:?})",
+                sessions.len(),
+                self.drain_timeout
+            );
+            if tokio::time::timeout(self.drain_timeout, sessions.wait())
+                .await
+                .is_err()
+            {
+                let dropped = self.shared.sessions.kill_all();
+                warn!("Drain timeout reached, dropping {} session(s)", dropped);
+                sessions.wait().await;
+            }
+        }
+
+        info!("SOCKS5 server stopped");
+        Ok(())
+    }
+
+    /// Close listeners that are not in `wanted` and open the new ones
+    async fn update_listeners(
+        &self,
+        listeners: &mut HashMap<ListenerConfig, ListenerTask>,
+        wanted: &[ListenerConfig],
+        ctx: &Arc<ArcSwap<Context>>,
+        sessions: &TaskTracker,
+    ) -> anyhow::Result<()> {
+        // Close first so that an address can move to new settings
+        let removed: Vec<ListenerConfig> = listeners
+            .keys()
+            .filter(|config| !wanted.contains(config))
+            .cloned()
+            .collect();
+        for config in removed {
+            if let Some(listener) = listeners.remove(&config) {
+                listener.token.cancel();
+                let _ = listener.task.await;
+                info!("Stopped listening on {}", config);
+            }
+        }
+
+        let mut errors = Vec::new();
+        for config in wanted {
+            if listeners.contains_key(config) {
+                continue;
+            }
+            if !self.reaches_tailnet(config, &ctx.load()) {
+                errors.push(format!(
+                    "{}: tailnet authentication needs a tailnet:PORT listener",
+                    config.addr
+                ));
+                continue;
+            }
+            match Listener::bind(config, self.tailnet.as_ref()).await {
+                Ok(listener) => {
+                    info!("SOCKS5 server listening on {}", config);
+                    let token = self.shutdown.child_token();
+                    let task = tokio::spawn(accept_loop(
+                        listener,
+                        config.profile.clone(),
+                        token.clone(),
+                        ctx.clone(),
+                        sessions.clone(),
+                    ));
+                    listeners.insert(config.clone(), ListenerTask { token, task });
                 }
-                Err(e) => {
-                    error!("Failed to accept connection: {}", e);
+                Err(e) => errors.push(format!("{}: {}", config.addr, e)),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("failed to bind {}", errors.join(", ")))
+        }
+    }
+
+    /// Whether tailnet-authenticated clients can reach the listener at all
+    fn reaches_tailnet(&self, config: &ListenerConfig, ctx: &Context) -> bool {
+        let tailnet_only = ctx
+            .profile(config.profile.as_deref())
+            .is_some_and(|p| matches!(p.authenticator, Authenticator::Tailnet));
+        !tailnet_only
+            || matches!(config.addr, ListenAddr::Tailnet(_))
+            || !self.host_tailnet.is_empty()
+    }
+
+    /// Bind every forward and start serving it
+    async fn start_forwards(
+        &self,
+        ctx: &Arc<ArcSwap<Context>>,
+        sessions: &TaskTracker,
+    ) -> anyhow::Result<Vec<ListenerTask>> {
+        let mut tasks = Vec::new();
+        for forward in &self.forwards {
+            let bind_error = |e| anyhow::anyhow!("failed to bind forward {}: {}", forward, e);
+            let token = self.shutdown.child_token();
+            let target = forward.target.clone();
+            let task = match forward.protocol {
+                ForwardProtocol::Tcp => {
+                    let listener = TcpListener::bind(forward.listen).await.map_err(bind_error)?;
+                    tokio::spawn(forward_loop(
+                        listener,
+                        target,
+                        token.clone(),
+                        ctx.clone(),
+                        sessions.clone(),
+                    ))
                 }
+                ForwardProtocol::Udp => {
+                    let socket = UdpSocket::bind(forward.listen).await.map_err(bind_error)?;
+                    tokio::spawn(forward_udp_loop(
+                        Arc::new(socket),
+                        target,
+                        token.clone(),
+                        ctx.clone(),
+                        sessions.clone(),
+                    ))
+                }
+            };
+            info!("Forwarding {}", forward);
+            tasks.push(ListenerTask { token, task });
+        }
+        Ok(tasks)
+    }
+}
+
+/// Accept clients on `listener` until `token` is cancelled
+async fn accept_loop(
+    listener: Listener,
+    profile: Option<String>,
+    token: CancellationToken,
+    ctx: Arc<ArcSwap<Context>>,
+    sessions: TaskTracker,
+) {
+    loop {
+        let accepted = tokio::select! {
+            _ = token.cancelled() => break,
+            accepted = listener.accept() => accepted,
+        };
+
+        match accepted {
+            Ok((socket, peer_addr)) => {
+                debug!("New connection from {}", peer_addr);
+
+                // Pin the current policy for the lifetime of the session
+                let ctx = ctx.load_full();
+                let peer_addr = ctx.client_addr(&socket, peer_addr);
+                let profile = profile.clone();
+                let session = ctx.sessions.open(peer_addr);
+                METRICS.sessions_accepted.inc();
+                sessions.spawn(async move {
+                    let _active = METRICS.sessions_active.track();
+                    let result = match ctx.profile(profile.as_deref()) {
+                        Some(profile) => {
+                            let handler = handle_client(socket, peer_addr, &ctx, profile, &session);
+                            supervise(&session, handler).await
+                        }
+                        None => Err(anyhow::anyhow!("unknown profile {:?}", profile)),
+                    };
+                    if let Err(e) = result {
+                        error!("Error handling client {}: {}", peer_addr, e);
+                    }
+                });
+            }
+            Err(e) => {
+                error!("Failed to accept connection: {}", e);
             }
         }
     }
 }
 
-async fn handle_client(mut client: TcpStream) -> anyhow::Result<()> {
+/// Run a session's handler until it finishes or the session is killed
+async fn supervise<F>(session: &Session, handler: F) -> anyhow::Result<()>
+where
+    F: std::future::Future<Output = anyhow::Result<()>>,
+{
+    let result = tokio::select! {
+        result = handler => result,
+        _ = session.killed() => {
+            info!("Session {} killed", session.id());
+            session.close("killed");
+            Ok(())
+        }
+    };
+    match &result {
+        Ok(()) => session.close("completed"),
+        Err(e) => session.close(e),
+    }
+    result
+}
+
+/// Record where the target was reached, unless an upstream resolved it
+fn set_resolved(session: &Session, route: &Route, peer: Option<SocketAddr>) {
+    if matches!(route, Route::Upstream(_)) {
+        return;
+    }
+    if let Some(peer) = peer {
+        session.set_resolved(peer.ip());
+    }
+}
+
+async fn handle_client<S>(
+    mut client: S,
+    peer_addr: ClientAddr,
+    ctx: &Context,
+    profile: &Profile,
+    session: &Session,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     // 1. Authentication phase
+    let accepted = Instant::now();
     let mut buf = BytesMut::with_capacity(512);
 
     if client.read_buf(&mut buf).await? == 0 {
@@ -50,18 +645,62 @@ async fn handle_client(mut client: TcpStream) -> anyhow::Result<()> {
     let auth_req = AuthRequest::parse(&mut buf)?;
 
     if auth_req.version != SOCKS5_VERSION {
+        METRICS.reject("protocol");
         return Err(Socks5Error::UnsupportedVersion(auth_req.version).into());
     }
 
-    if !auth_req.supports_method(AUTH_NO_AUTH) {
+    // The source address of a tailnet peer already says who it is
+    let whois = ctx.whois(&peer_addr).await;
+    if let Some(who) = &whois {
+        debug!(
+            "Tailnet client {} is {} (user: {:?}, tags: {:?})",
+            peer_addr, who.node, who.user, who.tags
+        );
+    }
+
+    // Tailscale ACLs stay authoritative for tailnet clients, and one that
+    // WhoIs does not know has no grants to check
+    let tailnet = peer_addr.tailnet_ip().is_some();
+    if tailnet && (whois.is_none() || !ctx.tailnet_granted(&peer_addr, whois.as_ref())) {
+        warn!(
+            "Rejected tailnet client {}: policy does not grant {}",
+            peer_addr, SOCKS_CAPABILITY
+        );
+        METRICS.auth_failures.inc();
+        METRICS.reject("auth");
+        client
+            .write_all(&auth_response(AUTH_NO_ACCEPTABLE))
+            .await?;
+        return Err(Socks5Error::AuthFailed.into());
+    }
+
+    let method = profile.authenticator.method();
+    let tailnet_only = matches!(profile.authenticator, Authenticator::Tailnet);
+    if !auth_req.supports_method(method) || (tailnet_only && whois.is_none()) {
+        METRICS.reject("auth");
         client
             .write_all(&auth_response(AUTH_NO_ACCEPTABLE))
             .await?;
         return Err(Socks5Error::AuthFailed.into());
     }
 
-    client.write_all(&auth_response(AUTH_NO_AUTH)).await?;
-    debug!("Authentication successful");
+    client.write_all(&auth_response(method)).await?;
+
+    let user = if method == AUTH_USERNAME_PASSWORD {
+        let user = authenticate(&mut client, &mut buf, &profile.authenticator).await;
+        if user.is_err() {
+            METRICS.auth_failures.inc();
+            METRICS.reject("auth");
+        }
+        Some(user?)
+    } else if tailnet_only {
+        whois.as_ref().and_then(|who| who.user.clone())
+    } else {
+        None
+    };
+    let tags = whois.map(|who| who.tags).unwrap_or_default();
+    debug!("Authentication successful (user: {:?})", user);
+    session.set_user(user.as_deref());
 
     // 2. Request phase
     buf.clear();
@@ -73,10 +712,41 @@ async fn handle_client(mut client: TcpStream) -> anyhow::Result<()> {
     let connect_req = ConnectRequest::parse(&mut buf)?;
 
     if connect_req.version != SOCKS5_VERSION {
+        METRICS.reject("protocol");
         return Err(Socks5Error::UnsupportedVersion(connect_req.version).into());
     }
+    session.set_command(connect_req.command);
+    session.set_target(&connect_req.target);
+    METRICS.handshake_seconds.observe(accepted.elapsed());
+
+    let rule_req = RuleRequest {
+        command: connect_req.command,
+        target: &connect_req.target,
+        resolved: None,
+        source: peer_addr.ip(),
+        user: user.as_deref(),
+        tags: &tags,
+    };
+    let route = ctx.dialer.route(&connect_req.target);
+    let allow = match admit(&profile.rules, rule_req, &route) {
+        Ok(allow) => allow,
+        Err(decision) => {
+            warn!(
+                "Denied {} -> {} (rule: {:?})",
+                peer_addr, connect_req.target, decision.rule
+            );
+            METRICS.reject("rule");
+            session.set_reply(REP_CONNECTION_NOT_ALLOWED);
+            client
+                .write_all(&connect_response(REP_CONNECTION_NOT_ALLOWED))
+                .await?;
+            return Err(Socks5Error::NotAllowed.into());
+        }
+    };
 
     if connect_req.command != CMD_CONNECT {
+        METRICS.reject("command");
+        session.set_reply(REP_COMMAND_NOT_SUPPORTED);
         client
             .write_all(&connect_response(REP_COMMAND_NOT_SUPPORTED))
             .await?;
@@ -84,26 +754,854 @@ async fn handle_client(mut client: TcpStream) -> anyhow::Result<()> {
     }
 
     let target_addr = connect_req.target.to_string();
-    debug!("Connecting to target: {}", target_addr);
+    debug!("Connecting to target: {} via {}", target_addr, route);
+    session.set_route(&route);
+    session.set_state(SessionState::Connecting);
 
     // 3. Connect to target
-    match TcpStream::connect(&target_addr).await {
+    let connecting = Instant::now();
+    let connected = ctx.dialer.connect(&connect_req.target, &route, allow).await;
+    METRICS
+        .connect_seconds
+        .with(&route.to_string())
+        .observe(connecting.elapsed());
+    match connected {
         Ok(target) => {
-            debug!("Connected to {}", target_addr);
+            debug!("Connected to {} via {}", target_addr, route);
+            set_resolved(session, &route, target.peer_addr());
+            session.set_reply(REP_SUCCESS);
             client.write_all(&connect_response(REP_SUCCESS)).await?;
 
             // 4. Relay data
-            if let Err(e) = relay_data(client, target).await {
+            session.set_state(SessionState::Relaying);
+            let limits = ctx.limiter.session(user.as_deref(), peer_addr.ip());
+            if let Err(e) = relay_data(client, target, limits, session.traffic()).await {
                 warn!("Relay error: {}", e);
+                session.close(format!("relay error: {}", e));
             }
         }
         Err(e) => {
-            error!("Failed to connect to {}: {}", target_addr, e);
+            error!("Failed to connect to {} via {}: {}", target_addr, route, e);
+            METRICS.reject("connect");
+            session.set_reply(e.reply_code());
+            session.close(format!("connect failed: {}", e));
             client
-                .write_all(&connect_response(REP_CONNECTION_REFUSED))
+                .write_all(&connect_response(e.reply_code()))
                 .await?;
         }
     }
 
     Ok(())
 }
+
+/// Whether the dialer resolves targets on `route` itself, so that rules are
+/// checked again for every address; upstream proxies resolve names remotely
+fn resolves(route: &Route) -> bool {
+    !matches!(route, Route::Upstream(_))
+}
+
+/// Check `req` against `rules` before the target is resolved, returning the
+/// denying decision or the filter the dialer applies to every resolved
+/// address, so that a domain pointing at a denied range (e.g. DNS
+/// rebinding) is rejected too
+fn admit<'a>(
+    rules: &'a RuleEngine,
+    req: RuleRequest<'a>,
+    route: &Route,
+) -> std::result::Result<impl Fn(IpAddr) -> bool + 'a, Decision> {
+    let decision = rules.evaluate(&req);
+    if !decision.proceed(resolves(route)) {
+        return Err(decision);
+    }
+    Ok(move |ip| {
+        rules
+            .evaluate(&RuleRequest {
+                resolved: Some(ip),
+                ..req
+            })
+            .is_allowed()
+    })
+}
+
+/// [`admit`] a forward client with the default profile's rules
+fn forward_admit<'a>(
+    ctx: &'a Context,
+    command: u8,
+    target: &'a TargetAddr,
+    peer_addr: SocketAddr,
+    route: &Route,
+) -> anyhow::Result<impl Fn(IpAddr) -> bool + 'a> {
+    let req = RuleRequest {
+        command,
+        target,
+        resolved: None,
+        source: Some(peer_addr.ip()),
+        user: None,
+        tags: &[],
+    };
+    admit(&ctx.default_profile.rules, req, route).map_err(|decision| {
+        warn!(
+            "Denied {} -> {} (rule: {:?})",
+            peer_addr, target, decision.rule
+        );
+        METRICS.reject("rule");
+        Socks5Error::NotAllowed.into()
+    })
+}
+
+/// Connect clients of a TCP forward to `target` until `token` is cancelled
+async fn forward_loop(
+    listener: TcpListener,
+    target: TargetAddr,
+    token: CancellationToken,
+    ctx: Arc<ArcSwap<Context>>,
+    sessions: TaskTracker,
+) {
+    loop {
+        let accepted = tokio::select! {
+            _ = token.cancelled() => break,
+            accepted = listener.accept() => accepted,
+        };
+
+        match accepted {
+            Ok((socket, peer_addr)) => {
+                debug!("New connection from {} for {}", peer_addr, target);
+                let ctx = ctx.load_full();
+                let target = target.clone();
+                let session = ctx.sessions.open(peer_addr);
+                session.set_target(&target);
+                METRICS.sessions_accepted.inc();
+                sessions.spawn(async move {
+                    let _active = METRICS.sessions_active.track();
+                    let handler = handle_forward(socket, peer_addr, &target, &ctx, &session);
+                    if let Err(e) = supervise(&session, handler).await {
+                        error!("Error forwarding {} to {}: {}", peer_addr, target, e);
+                    }
+                });
+            }
+            Err(e) => {
+                error!("Failed to accept connection: {}", e);
+            }
+        }
+    }
+}
+
+async fn handle_forward(
+    client: TcpStream,
+    peer_addr: SocketAddr,
+    target: &TargetAddr,
+    ctx: &Context,
+    session: &Session,
+) -> anyhow::Result<()> {
+    session.set_command(CMD_CONNECT);
+    let route = ctx.dialer.route(target);
+    let allow = forward_admit(ctx, CMD_CONNECT, target, peer_addr, &route)?;
+
+    session.set_route(&route);
+    session.set_state(SessionState::Connecting);
+    let connecting = Instant::now();
+    let stream = ctx.dialer.connect(target, &route, allow).await;
+    METRICS
+        .connect_seconds
+        .with(&route.to_string())
+        .observe(connecting.elapsed());
+    let stream = stream.map_err(|e| {
+        METRICS.reject("connect");
+        anyhow::anyhow!("via {}: {}", route, e)
+    })?;
+    debug!("Connected to {} via {}", target, route);
+    set_resolved(session, &route, stream.peer_addr());
+
+    session.set_state(SessionState::Relaying);
+    let limits = ctx.limiter.session(None, Some(peer_addr.ip()));
+    if let Err(e) = relay_data(client, stream, limits, session.traffic()).await {
+        warn!("Relay error: {}", e);
+        session.close(format!("relay error: {}", e));
+    }
+    Ok(())
+}
+
+/// Relay datagrams between clients of a UDP forward and `target`, with one
+/// outbound socket per client address, until `token` is cancelled
+async fn forward_udp_loop(
+    socket: Arc<UdpSocket>,
+    target: TargetAddr,
+    token: CancellationToken,
+    ctx: Arc<ArcSwap<Context>>,
+    sessions: TaskTracker,
+) {
+    let mut flows = UdpFlows::new();
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+
+    loop {
+        let (len, peer_addr) = tokio::select! {
+            _ = token.cancelled() => break,
+            received = socket.recv_from(&mut buf) => match received {
+                Ok(received) => received,
+                Err(e) => {
+                    debug!("UDP receive failed: {}", e);
+                    continue;
+                }
+            },
+        };
+
+        let Some(datagrams) = flows.dispatch(peer_addr, buf[..len].to_vec()) else {
+            continue;
+        };
+
+        debug!("New UDP flow from {} for {}", peer_addr, target);
+        let ctx = ctx.load_full();
+        let (socket, target, token) = (socket.clone(), target.clone(), token.clone());
+        let session = ctx.sessions.open(peer_addr);
+        session.set_target(&target);
+        METRICS.sessions_accepted.inc();
+        sessions.spawn(async move {
+            let _active = METRICS.sessions_active.track();
+            let flow = udp_flow(&socket, peer_addr, &target, &ctx, &session, datagrams, token);
+            if let Err(e) = supervise(&session, flow).await {
+                error!("Error forwarding {} to {}: {}", peer_addr, target, e);
+            }
+        });
+    }
+}
+
+async fn udp_flow(
+    socket: &UdpSocket,
+    peer_addr: SocketAddr,
+    target: &TargetAddr,
+    ctx: &Context,
+    session: &Session,
+    mut datagrams: mpsc::UnboundedReceiver<Vec<u8>>,
+    token: CancellationToken,
+) -> anyhow::Result<()> {
+    session.set_command(CMD_UDP_ASSOCIATE);
+    let route = ctx.dialer.route(target);
+    let allow = forward_admit(ctx, CMD_UDP_ASSOCIATE, target, peer_addr, &route)?;
+
+    session.set_route(&route);
+    session.set_state(SessionState::Connecting);
+    let connecting = Instant::now();
+    let outbound = ctx.dialer.connect_udp(target, &route, allow).await;
+    METRICS
+        .connect_seconds
+        .with(&route.to_string())
+        .observe(connecting.elapsed());
+    let outbound = outbound.map_err(|e| {
+        METRICS.reject("connect");
+        anyhow::anyhow!("via {}: {}", route, e)
+    })?;
+    set_resolved(session, &route, outbound.peer_addr());
+
+    session.set_state(SessionState::Relaying);
+    let limits = ctx.limiter.session(None, Some(peer_addr.ip()));
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        tokio::select! {
+            _ = token.cancelled() => break,
+            datagram = datagrams.recv() => match datagram {
+                Some(datagram) => {
+                    limits.throttle(Direction::Up, datagram.len()).await;
+                    outbound.send(&datagram).await?;
+                    session.traffic().add(Direction::Up, datagram.len());
+                    METRICS.bytes_up.add(datagram.len() as u64);
+                }
+                None => break,
+            },
+            received = outbound.recv(&mut buf) => {
+                let len = received?;
+                limits.throttle(Direction::Down, len).await;
+                socket.send_to(&buf[..len], peer_addr).await?;
+                session.traffic().add(Direction::Down, len);
+                METRICS.bytes_down.add(len as u64);
+            }
+            _ = tokio::time::sleep(UDP_IDLE_TIMEOUT) => {
+                session.close("idle");
+                break;
+            }
+        }
+    }
+    debug!("UDP flow {} -> {} closed", peer_addr, target);
+    Ok(())
+}
+
+/// Run the username/password sub-negotiation and return the username
+async fn authenticate<S>(
+    client: &mut S,
+    buf: &mut BytesMut,
+    authenticator: &Authenticator,
+) -> anyhow::Result<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    buf.clear();
+
+    if client.read_buf(buf).await? == 0 {
+        return Err(anyhow::anyhow!("Connection closed during authentication"));
+    }
+
+    let req = UserPassRequest::parse(buf)?;
+
+    if req.version != USERPASS_VERSION || !authenticator.verify(&req.username, &req.password) {
+        client
+            .write_all(&userpass_response(USERPASS_FAILURE))
+            .await?;
+        return Err(Socks5Error::AuthFailed.into());
+    }
+
+    client
+        .write_all(&userpass_response(USERPASS_SUCCESS))
+        .await?;
+    Ok(req.username)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socks5::rules::Action;
+    use crate::vpn::filter::FilterRule;
+    use crate::vpn::netstack;
+    use crate::vpn::{PacketFilter, PeerInfo};
+    use std::net::SocketAddr;
+    use tokio::net::{TcpListener, TcpStream};
+
+    fn free_addr() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    /// Local target that accepts connections and closes them again
+    async fn spawn_sink_target() -> SocketAddr {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((_socket, _)) = target.accept().await {}
+        });
+        addr
+    }
+
+    /// Send a CONNECT for `target` and return the reply code
+    async fn socks_connect(proxy: &str, target: SocketAddr) -> std::io::Result<u8> {
+        request(TcpStream::connect(proxy).await?, target).await
+    }
+
+    async fn request<S>(stream: S, target: SocketAddr) -> std::io::Result<u8>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        request_to(stream, &TargetAddr::Ip(target)).await
+    }
+
+    async fn request_to<S>(mut stream: S, target: &TargetAddr) -> std::io::Result<u8>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        stream.write_all(&[SOCKS5_VERSION, 1, AUTH_NO_AUTH]).await?;
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await?;
+
+        let mut req = vec![SOCKS5_VERSION, CMD_CONNECT, 0];
+        target.write_to(&mut req).unwrap();
+        stream.write_all(&req).await?;
+        let mut reply = [0u8; 10];
+        stream.read_exact(&mut reply).await?;
+        Ok(reply[1])
+    }
+
+    async fn wait_for_listener(addr: &str) {
+        for _ in 0..50 {
+            if TcpStream::connect(addr).await.is_ok() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("server did not listen on {}", addr);
+    }
+
+    /// Packet filter granting `src` the SOCKS capability
+    fn socks_grant(src: &str) -> Arc<PacketFilter> {
+        let rules: Vec<FilterRule> = serde_json::from_str(&format!(
+            r#"[{{"SrcIPs": ["{}"], "CapGrant": [{{"Dsts": ["100.64.0.1"], "CapMap": {{"{}": [{{}}]}}}}]}}]"#,
+            src, SOCKS_CAPABILITY
+        ))
+        .unwrap();
+        Arc::new(PacketFilter::new(&rules))
+    }
+
+    async fn wait_for_tailnet_listener(peer: &Netstack, addr: SocketAddr) {
+        for _ in 0..50 {
+            if peer.connect(addr).await.is_ok() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("server did not listen on tailnet {}", addr);
+    }
+
+    #[tokio::test]
+    async fn test_reload_policy_and_listeners() {
+        let target_addr = spawn_sink_target().await;
+
+        let first = free_addr();
+        let server = Socks5Server::new(first.clone());
+        let handle = server.handle();
+        let shutdown = server.shutdown_token();
+        let run = tokio::spawn(async move { server.run().await });
+        wait_for_listener(&first).await;
+
+        // Loopback is denied by the default rules
+        assert_eq!(
+            socks_connect(&first, target_addr).await.unwrap(),
+            REP_CONNECTION_NOT_ALLOWED
+        );
+
+        handle.reload(Policy {
+            rules: RuleEngine::allow_all(),
+            ..Policy::default()
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(socks_connect(&first, target_addr).await.unwrap(), REP_SUCCESS);
+
+        let second = free_addr();
+        handle.set_listeners(vec![ListenerConfig::new(ListenAddr::from(second.clone()))]);
+        wait_for_listener(&second).await;
+        assert!(TcpStream::connect(&first).await.is_err());
+        assert_eq!(socks_connect(&second, target_addr).await.unwrap(), REP_SUCCESS);
+
+        shutdown.cancel();
+        run.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cidr_allow_list_with_domain() {
+        let port = spawn_sink_target().await.port();
+
+        let addr = free_addr();
+        let mut server = Socks5Server::new(addr.clone());
+        server.set_rules(RuleEngine::new(
+            vec!["allow dst=127.0.0.0/8".parse().unwrap()],
+            Action::Deny,
+        ));
+        let handle = server.handle();
+        let shutdown = server.shutdown_token();
+        let run = tokio::spawn(async move { server.run().await });
+        wait_for_listener(&addr).await;
+
+        // The name is allowed by what it resolves to
+        let localhost = TargetAddr::Domain("localhost".to_string(), port);
+        let stream = TcpStream::connect(&addr).await.unwrap();
+        assert_eq!(request_to(stream, &localhost).await.unwrap(), REP_SUCCESS);
+
+        handle.reload(Policy {
+            rules: RuleEngine::new(vec!["allow dst=10.0.0.0/8".parse().unwrap()], Action::Deny),
+            ..Policy::default()
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let stream = TcpStream::connect(&addr).await.unwrap();
+        assert_eq!(
+            request_to(stream, &localhost).await.unwrap(),
+            REP_CONNECTION_NOT_ALLOWED
+        );
+
+        shutdown.cancel();
+        run.await.unwrap().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_listener_profiles() {
+        let target_addr = spawn_sink_target().await;
+
+        let dir = std::env::temp_dir().join(format!("socktail-profiles-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("socks.sock");
+        let tcp = free_addr();
+
+        // TCP requires a password, the Unix socket is open to local apps
+        let mut server = Socks5Server::new(tcp.clone());
+        server.set_listeners(vec![
+            tcp.parse().unwrap(),
+            format!("unix:{},profile=local", path.display()).parse().unwrap(),
+        ]);
+        server.set_policy(Policy {
+            authenticator: Authenticator::password([("alice".to_string(), "pw".to_string())]),
+            profiles: HashMap::from([(
+                "local".to_string(),
+                Profile {
+                    authenticator: Authenticator::None,
+                    rules: RuleEngine::allow_all(),
+                },
+            )]),
+            ..Policy::default()
+        });
+        let shutdown = server.shutdown_token();
+        let run = tokio::spawn(async move { server.run().await });
+        wait_for_listener(&tcp).await;
+
+        assert!(socks_connect(&tcp, target_addr).await.is_err());
+        let unix = tokio::net::UnixStream::connect(&path).await.unwrap();
+        assert_eq!(request(unix, target_addr).await.unwrap(), REP_SUCCESS);
+
+        shutdown.cancel();
+        run.await.unwrap().unwrap();
+        assert!(!path.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tailnet_listener() {
+        let target_addr = spawn_sink_target().await;
+
+        let (node, peer) = netstack::pair();
+        let peers = PeerMap::new();
+        peers.set_filter(socks_grant("100.64.0.2"));
+
+        let tcp = free_addr();
+        let mut server = Socks5Server::new(tcp.clone());
+        server.set_listeners(vec![tcp.parse().unwrap(), "tailnet:1080".parse().unwrap()]);
+        server.set_rules(RuleEngine::allow_all());
+        server.set_peer_map(peers.clone());
+        server.set_tailnet(node);
+        let shutdown = server.shutdown_token();
+        let run = tokio::spawn(async move { server.run().await });
+        wait_for_listener(&tcp).await;
+
+        // A granted address WhoIs cannot name yet is still refused
+        let connect = || peer.connect("100.64.0.1:1080".parse().unwrap());
+        let stream = connect().await.unwrap();
+        assert!(request(stream, target_addr).await.is_err());
+
+        peers.update(
+            vec![PeerInfo {
+                tailscale_ip: "100.64.0.2".parse().unwrap(),
+                addresses: vec!["100.64.0.2".parse().unwrap()],
+                ..Default::default()
+            }],
+            None,
+        );
+        let stream = connect().await.unwrap();
+        assert_eq!(request(stream, target_addr).await.unwrap(), REP_SUCCESS);
+
+        shutdown.cancel();
+        run.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_forward_tcp_to_tailnet_name() {
+        let (node, peer) = netstack::pair();
+        let db = peer.listen(5432).unwrap();
+        tokio::spawn(async move {
+            // The first connection is wait_for_listener's probe
+            while let Ok((mut stream, _)) = db.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 4];
+                    if stream.read_exact(&mut buf).await.is_ok() {
+                        stream.write_all(&buf).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        let peers = PeerMap::new();
+        peers.update(
+            vec![PeerInfo {
+                name: Some("db-1.tail1234.ts.net".to_string()),
+                tailscale_ip: "100.64.0.2".parse().unwrap(),
+                addresses: vec!["100.64.0.2".parse().unwrap()],
+                ..Default::default()
+            }],
+            Some("tail1234.ts.net".to_string()),
+        );
+
+        let tcp = free_addr();
+        let local = free_addr();
+        let mut server = Socks5Server::new(tcp.clone());
+        server.set_routes(RouteTable::with_tailnet_defaults(Vec::new(), Route::Direct));
+        server.set_peer_map(peers);
+        server.set_tailnet(node);
+        server.add_forward(format!("{}=db-1.tail1234.ts.net:5432", local).parse().unwrap());
+        let shutdown = server.shutdown_token();
+        let sessions = server.handle().sessions();
+        let run = tokio::spawn(async move { server.run().await });
+        wait_for_listener(&local).await;
+
+        let mut client = TcpStream::connect(&local).await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        let session = sessions.list().into_iter().find(|s| s.bytes_up > 0).unwrap();
+        assert_eq!(session.target.as_deref(), Some("db-1.tail1234.ts.net:5432"));
+        assert_eq!(session.route.as_deref(), Some("tailnet"));
+        assert_eq!(session.state, SessionState::Relaying);
+        assert_eq!((session.bytes_up, session.bytes_down), (4, 4));
+        drop(client);
+
+        shutdown.cancel();
+        run.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_forward_udp() {
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            loop {
+                let (len, from) = target.recv_from(&mut buf).await.unwrap();
+                target.send_to(&buf[..len], from).await.unwrap();
+            }
+        });
+
+        let local: SocketAddr = {
+            let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket.local_addr().unwrap()
+        };
+        let mut server = Socks5Server::new(free_addr());
+        server.set_rules(RuleEngine::allow_all());
+        server.add_forward(format!("{}={}/udp", local, target_addr).parse().unwrap());
+        let shutdown = server.shutdown_token();
+        let run = tokio::spawn(async move { server.run().await });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; 64];
+        let mut echoed = None;
+        for _ in 0..50 {
+            client.send_to(b"hello", local).await.unwrap();
+            let recv = client.recv_from(&mut buf);
+            if let Ok(Ok((len, from))) = tokio::time::timeout(Duration::from_millis(100), recv).await {
+                echoed = Some((buf[..len].to_vec(), from));
+                break;
+            }
+        }
+        assert_eq!(echoed, Some((b"hello".to_vec(), local)));
+
+        shutdown.cancel();
+        run.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tailnet_identity_auth() {
+        let target_addr = spawn_sink_target().await;
+
+        let peers = PeerMap::new();
+        let (node, peer) = netstack::pair();
+        let mut server = Socks5Server::new("tailnet:1080".to_string());
+        server.set_authenticator(Authenticator::Tailnet);
+        server.set_rules(RuleEngine::new(
+            vec!["allow user=alice@".parse().unwrap()],
+            Action::Deny,
+        ));
+        server.set_peer_map(peers.clone());
+        server.set_tailnet(node);
+        let shutdown = server.shutdown_token();
+        let run = tokio::spawn(async move { server.run().await });
+
+        let listen = "100.64.0.1:1080".parse().unwrap();
+        wait_for_tailnet_listener(&peer, listen).await;
+
+        // Not in the network map
+        let connect = || peer.connect(listen);
+        let stream = connect().await.unwrap();
+        assert!(request(stream, target_addr).await.is_err());
+
+        let mut info = PeerInfo {
+            name: Some("laptop.tail1234.ts.net".to_string()),
+            tailscale_ip: "100.64.0.2".parse().unwrap(),
+            addresses: vec!["100.64.0.2".parse().unwrap()],
+            user: Some("alice@example.com".to_string()),
+            ..Default::default()
+        };
+        peers.update(vec![info.clone()], None);
+
+        // Without a filter from the control server nothing is granted
+        let stream = connect().await.unwrap();
+        assert!(request(stream, target_addr).await.is_err());
+
+        // Once it sent one, its grants decide
+        peers.set_filter(Arc::new(PacketFilter::default()));
+        let stream = connect().await.unwrap();
+        assert!(request(stream, target_addr).await.is_err());
+
+        peers.set_filter(socks_grant("100.64.0.2"));
+        let stream = connect().await.unwrap();
+        assert_eq!(request(stream, target_addr).await.unwrap(), REP_SUCCESS);
+
+        info.user = Some("bob@example.com".to_string());
+        peers.update(vec![info], None);
+        let stream = connect().await.unwrap();
+        assert_eq!(request(stream, target_addr).await.unwrap(), REP_CONNECTION_NOT_ALLOWED);
+
+        shutdown.cancel();
+        run.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tailnet_auth_ignores_host_clients() {
+        let target_addr = spawn_sink_target().await;
+
+        // A LAN client can claim any source address, here a peer's
+        let peers = PeerMap::new();
+        peers.update(
+            vec![PeerInfo {
+                name: Some("ci.tail1234.ts.net".to_string()),
+                tailscale_ip: "127.0.0.1".parse().unwrap(),
+                addresses: vec!["127.0.0.1".parse().unwrap()],
+                tags: vec!["tag:ci".to_string()],
+                ..Default::default()
+            }],
+            None,
+        );
+        let rules = || RuleEngine::new(vec!["allow tag=tag:ci".parse().unwrap()], Action::Deny);
+
+        let tcp = free_addr();
+        let mut server = Socks5Server::new(tcp.clone());
+        server.set_rules(rules());
+        server.set_peer_map(peers.clone());
+        let shutdown = server.shutdown_token();
+        let run = tokio::spawn(async move { server.run().await });
+        wait_for_listener(&tcp).await;
+        assert_eq!(
+            socks_connect(&tcp, target_addr).await.unwrap(),
+            REP_CONNECTION_NOT_ALLOWED
+        );
+        shutdown.cancel();
+        run.await.unwrap().unwrap();
+
+        // Tailnet authentication on a host listener is refused outright
+        let mut server = Socks5Server::new(free_addr());
+        server.set_authenticator(Authenticator::Tailnet);
+        server.set_peer_map(peers.clone());
+        let err = server.run().await.unwrap_err();
+        assert!(err.to_string().contains("needs a tailnet:PORT listener"));
+
+        // ...unless the host interface vouches for tailnet sources
+        let tcp = free_addr();
+        let mut server = Socks5Server::new(tcp.clone());
+        server.set_authenticator(Authenticator::Tailnet);
+        server.set_rules(rules());
+        peers.set_filter(socks_grant("127.0.0.1"));
+        server.set_peer_map(peers);
+        server.set_host_tailnet(vec!["127.0.0.1".parse().unwrap()]);
+        let shutdown = server.shutdown_token();
+        let run = tokio::spawn(async move { server.run().await });
+        wait_for_listener(&tcp).await;
+        assert_eq!(socks_connect(&tcp, target_addr).await.unwrap(), REP_SUCCESS);
+        shutdown.cancel();
+        run.await.unwrap().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_tailnet_auth_with_live_whois() {
+        let target_addr = spawn_sink_target().await;
+
+        // The peer map still has the address's previous owner
+        let peers = PeerMap::new();
+        peers.update(
+            vec![PeerInfo {
+                name: Some("old.tail1234.ts.net".to_string()),
+                tailscale_ip: "127.0.0.1".parse().unwrap(),
+                addresses: vec!["127.0.0.1".parse().unwrap()],
+                user: Some("bob@example.com".to_string()),
+                ..Default::default()
+            }],
+            None,
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let tcp = free_addr();
+        let mut server = Socks5Server::new(tcp.clone());
+        server.set_authenticator(Authenticator::Tailnet);
+        server.set_rules(RuleEngine::new(
+            vec!["allow user=alice@".parse().unwrap()],
+            Action::Deny,
+        ));
+        server.set_peer_map(peers);
+        server.set_host_tailnet(vec!["127.0.0.1".parse().unwrap()]);
+        server.set_whois_api(crate::vpn::localapi::tests::spawn_fake(dir.path()));
+        let shutdown = server.shutdown_token();
+        let run = tokio::spawn(async move { server.run().await });
+        wait_for_listener(&tcp).await;
+
+        assert_eq!(socks_connect(&tcp, target_addr).await.unwrap(), REP_SUCCESS);
+
+        shutdown.cancel();
+        run.await.unwrap().unwrap();
+    }
+
+    /// Check that `client`'s session still reaches the echo target
+    async fn assert_relays(client: &mut TcpStream) {
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_drain_on_shutdown() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = target.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = socket.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+
+        // Start a server and open a relayed session through it
+        let start = |drain_timeout| async move {
+            let addr = free_addr();
+            let mut server = Socks5Server::new(addr.clone());
+            server.set_rules(RuleEngine::allow_all());
+            server.set_drain_timeout(drain_timeout);
+            let shutdown = server.shutdown_token();
+            let run = tokio::spawn(async move { server.run().await });
+            wait_for_listener(&addr).await;
+
+            let mut client = TcpStream::connect(&addr).await.unwrap();
+            assert_eq!(
+                request(&mut client, target_addr).await.unwrap(),
+                REP_SUCCESS
+            );
+            (addr, shutdown, run, client)
+        };
+
+        // The active relay outlives the shutdown request, new clients don't
+        let (addr, shutdown, run, mut client) = start(Duration::from_secs(30)).await;
+        shutdown.cancel();
+        for _ in 0..50 {
+            if TcpStream::connect(&addr).await.is_err() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(TcpStream::connect(&addr).await.is_err());
+        assert_relays(&mut client).await;
+        assert!(!run.is_finished());
+
+        // Once it finishes, the server stops without waiting out the timeout
+        drop(client);
+        tokio::time::timeout(Duration::from_secs(5), run)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        // Sessions still active at the drain timeout are dropped
+        let (_, shutdown, run, mut client) = start(Duration::from_millis(200)).await;
+        let stopping = Instant::now();
+        shutdown.cancel();
+        assert_relays(&mut client).await;
+        tokio::time::timeout(Duration::from_secs(5), run)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(stopping.elapsed() >= Duration::from_millis(200));
+        let mut buf = [0u8; 1];
+        let read = tokio::time::timeout(Duration::from_secs(1), client.read(&mut buf)).await;
+        assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))));
+    }
+}
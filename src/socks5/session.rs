@@ -0,0 +1,413 @@
+//! Table of active sessions
+//!
+//! Every SOCKS session and forwarded connection is registered while it
+//! runs, with its client, user, target, route, state and live byte counts,
+//! so that the control socket can list them and end one by ID. When a
+//! session closes, its final record goes to the [`SessionTable::on_close`]
+//! observers.
+
+use super::protocol::{TargetAddr, CMD_BIND, CMD_CONNECT, CMD_UDP_ASSOCIATE};
+use super::ratelimit::Direction;
+use crate::outbound::Route;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio_util::sync::{CancellationToken, WaitForCancellationFuture};
+use tracing::debug;
+
+/// Where a session is in its lifetime
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionState {
+    /// Authenticating and reading the request
+    #[default]
+    Handshake,
+    /// Dialing the target
+    Connecting,
+    /// Relaying data
+    Relaying,
+    Closed,
+}
+
+impl fmt::Display for SessionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SessionState::Handshake => "handshake",
+            SessionState::Connecting => "connecting",
+            SessionState::Relaying => "relaying",
+            SessionState::Closed => "closed",
+        })
+    }
+}
+
+/// A session as listed by [`SessionTable::list`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub id: u64,
+    pub client: String,
+    /// Authenticated user, if any
+    pub user: Option<String>,
+    /// SOCKS command: `connect`, `bind` or `udp-associate`
+    pub command: Option<String>,
+    /// Destination, once the client has asked for one
+    pub target: Option<String>,
+    /// Address the target was reached at, unless an upstream resolved it
+    pub resolved: Option<IpAddr>,
+    /// Route chosen for the target
+    pub route: Option<String>,
+    /// SOCKS reply code sent to the client
+    pub reply: Option<u8>,
+    /// Unix time the session was accepted
+    pub started: u64,
+    pub state: SessionState,
+    /// Bytes from the client to the target so far
+    pub bytes_up: u64,
+    /// Bytes from the target to the client so far
+    pub bytes_down: u64,
+    /// How long the session lasted, in final records
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    /// Why the session ended, in final records
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub close_reason: Option<String>,
+}
+
+/// Live byte counters of a session, updated as data is relayed
+#[derive(Debug, Default)]
+pub struct Traffic {
+    up: AtomicU64,
+    down: AtomicU64,
+}
+
+impl Traffic {
+    pub fn add(&self, dir: Direction, bytes: usize) {
+        let counter = match dir {
+            Direction::Up => &self.up,
+            Direction::Down => &self.down,
+        };
+        counter.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn up(&self) -> u64 {
+        self.up.load(Ordering::Relaxed)
+    }
+
+    pub fn down(&self) -> u64 {
+        self.down.load(Ordering::Relaxed)
+    }
+}
+
+struct Entry {
+    info: SessionInfo,
+    opened: Instant,
+    traffic: Arc<Traffic>,
+    kill: CancellationToken,
+}
+
+impl Entry {
+    fn snapshot(&self) -> SessionInfo {
+        SessionInfo {
+            bytes_up: self.traffic.up(),
+            bytes_down: self.traffic.down(),
+            ..self.info.clone()
+        }
+    }
+}
+
+type Observer = Arc<dyn Fn(&SessionInfo) + Send + Sync>;
+
+#[derive(Default)]
+struct Inner {
+    next_id: AtomicU64,
+    sessions: Mutex<HashMap<u64, Entry>>,
+    observers: Mutex<Vec<Observer>>,
+}
+
+/// Shared registry of active sessions; clones refer to the same table
+#[derive(Clone, Default)]
+pub struct SessionTable {
+    inner: Arc<Inner>,
+}
+
+impl fmt::Debug for SessionTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionTable")
+            .field("active", &self.len())
+            .finish()
+    }
+}
+
+impl SessionTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a session from `client`; it is removed when the returned
+    /// [`Session`] is dropped
+    pub fn open(&self, client: impl fmt::Display) -> Session {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let kill = CancellationToken::new();
+        let traffic = Arc::new(Traffic::default());
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let info = SessionInfo {
+            id,
+            client: client.to_string(),
+            user: None,
+            command: None,
+            target: None,
+            resolved: None,
+            route: None,
+            reply: None,
+            started,
+            state: SessionState::Handshake,
+            bytes_up: 0,
+            bytes_down: 0,
+            duration_ms: None,
+            close_reason: None,
+        };
+        self.inner.sessions.lock().unwrap().insert(
+            id,
+            Entry {
+                info,
+                opened: Instant::now(),
+                traffic: traffic.clone(),
+                kill: kill.clone(),
+            },
+        );
+        Session {
+            id,
+            table: self.clone(),
+            traffic,
+            kill,
+        }
+    }
+
+    /// Active sessions, oldest first
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let mut sessions: Vec<SessionInfo> = self
+            .inner
+            .sessions
+            .lock()
+            .unwrap()
+            .values()
+            .map(Entry::snapshot)
+            .collect();
+        sessions.sort_by_key(|info| info.id);
+        sessions
+    }
+
+    /// End session `id`; false if there is no such session
+    pub fn kill(&self, id: u64) -> bool {
+        match self.inner.sessions.lock().unwrap().get(&id) {
+            Some(entry) => {
+                entry.kill.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// End every active session; returns how many there were
+    pub fn kill_all(&self) -> usize {
+        let sessions = self.inner.sessions.lock().unwrap();
+        for entry in sessions.values() {
+            entry.kill.cancel();
+        }
+        sessions.len()
+    }
+
+    /// Call `observer` with the final record of every session that closes
+    pub fn on_close(&self, observer: impl Fn(&SessionInfo) + Send + Sync + 'static) {
+        self.inner.observers.lock().unwrap().push(Arc::new(observer));
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.sessions.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Registration of one active session
+pub struct Session {
+    id: u64,
+    table: SessionTable,
+    traffic: Arc<Traffic>,
+    kill: CancellationToken,
+}
+
+impl Session {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Counters the relay adds to
+    pub fn traffic(&self) -> &Traffic {
+        &self.traffic
+    }
+
+    /// Record the authenticated user
+    pub fn set_user(&self, user: Option<&str>) {
+        self.update(|info| info.user = user.map(str::to_string));
+    }
+
+    /// Record the SOCKS command the client sent
+    pub fn set_command(&self, command: u8) {
+        let command = match command {
+            CMD_CONNECT => "connect".to_string(),
+            CMD_BIND => "bind".to_string(),
+            CMD_UDP_ASSOCIATE => "udp-associate".to_string(),
+            other => format!("0x{:02x}", other),
+        };
+        self.update(|info| info.command = Some(command));
+    }
+
+    /// Record the destination the client asked for
+    pub fn set_target(&self, target: &TargetAddr) {
+        self.update(|info| info.target = Some(target.to_string()));
+    }
+
+    /// Record the address the target was reached at
+    pub fn set_resolved(&self, ip: IpAddr) {
+        self.update(|info| info.resolved = Some(ip));
+    }
+
+    /// Record the route taken to the target
+    pub fn set_route(&self, route: &Route) {
+        self.update(|info| info.route = Some(route.to_string()));
+    }
+
+    /// Record the reply sent to the client
+    pub fn set_reply(&self, reply: u8) {
+        self.update(|info| info.reply = Some(reply));
+    }
+
+    /// Record why the session ends; the first reason given sticks
+    pub fn close(&self, reason: impl fmt::Display) {
+        self.update(|info| {
+            info.close_reason.get_or_insert_with(|| reason.to_string());
+        });
+    }
+
+    pub fn set_state(&self, state: SessionState) {
+        self.update(|info| info.state = state);
+    }
+
+    /// Completes when the session is killed through the table
+    pub fn killed(&self) -> WaitForCancellationFuture<'_> {
+        self.kill.cancelled()
+    }
+
+    fn update(&self, f: impl FnOnce(&mut SessionInfo)) {
+        if let Some(entry) = self.table.inner.sessions.lock().unwrap().get_mut(&self.id) {
+            f(&mut entry.info);
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let Some(entry) = self.table.inner.sessions.lock().unwrap().remove(&self.id) else {
+            return;
+        };
+        // Tasks dropped without closing, e.g. by the runtime, give no reason
+        let mut record = SessionInfo {
+            state: SessionState::Closed,
+            duration_ms: Some(entry.opened.elapsed().as_millis() as u64),
+            ..entry.snapshot()
+        };
+        record.close_reason.get_or_insert_with(|| "aborted".to_string());
+        debug!(
+            "Session {} closed: {} bytes up, {} bytes down",
+            record.id, record.bytes_up, record.bytes_down
+        );
+        let observers = self.table.inner.observers.lock().unwrap().clone();
+        for observer in observers {
+            observer(&record);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_open_kill_and_close() {
+        let table = SessionTable::new();
+        let first = table.open("192.0.2.1:5000");
+        let second = table.open("unix socket");
+        second.set_target(&TargetAddr::Domain("db-1".to_string(), 5432));
+
+        let list = table.list();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].id, first.id());
+        assert_eq!(list[0].target, None);
+        assert_eq!(list[1].client, "unix socket");
+        assert_eq!(list[1].target.as_deref(), Some("db-1:5432"));
+
+        assert!(table.kill(second.id()));
+        second.killed().await;
+        assert!(!table.kill(99));
+
+        drop(second);
+        assert_eq!(table.len(), 1);
+        assert_eq!(table.kill_all(), 1);
+        first.killed().await;
+        drop(first);
+        assert!(table.is_empty());
+        assert_eq!(table.kill_all(), 0);
+    }
+
+    #[test]
+    fn test_live_counters_and_final_record() {
+        let table = SessionTable::new();
+        let closed = Arc::new(Mutex::new(Vec::new()));
+        let records = closed.clone();
+        table.on_close(move |info| records.lock().unwrap().push(info.clone()));
+
+        let session = table.open("192.0.2.1:5000");
+        assert_eq!(table.list()[0].state, SessionState::Handshake);
+        session.set_user(Some("alice"));
+        session.set_command(CMD_CONNECT);
+        session.set_route(&Route::Direct);
+        session.set_resolved("192.0.2.80".parse().unwrap());
+        session.set_reply(0);
+        session.set_state(SessionState::Relaying);
+        session.traffic().add(Direction::Up, 100);
+        session.traffic().add(Direction::Down, 2000);
+        session.traffic().add(Direction::Up, 20);
+
+        let info = &table.list()[0];
+        assert_eq!(info.user.as_deref(), Some("alice"));
+        assert_eq!(info.command.as_deref(), Some("connect"));
+        assert_eq!(info.route.as_deref(), Some("direct"));
+        assert_eq!(info.resolved, Some("192.0.2.80".parse().unwrap()));
+        assert_eq!(info.reply, Some(0));
+        assert_eq!(info.state, SessionState::Relaying);
+        assert_eq!((info.bytes_up, info.bytes_down), (120, 2000));
+        assert_eq!(info.duration_ms, None);
+
+        session.close("completed");
+        session.close("killed");
+        drop(session);
+        drop(table.open("192.0.2.1:5001"));
+        assert!(table.is_empty());
+
+        let closed = closed.lock().unwrap();
+        assert_eq!(closed.len(), 2);
+        assert_eq!(closed[0].state, SessionState::Closed);
+        assert_eq!((closed[0].bytes_up, closed[0].bytes_down), (120, 2000));
+        assert!(closed[0].duration_ms.is_some());
+        assert_eq!(closed[0].close_reason.as_deref(), Some("completed"));
+        assert_eq!(closed[1].close_reason.as_deref(), Some("aborted"));
+    }
+}
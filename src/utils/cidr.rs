@@ -0,0 +1,155 @@
+//! CIDR prefixes for address matching
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An IPv4 or IPv6 prefix such as `10.0.0.0/8` or `fd7a:115c:a1e0::/48`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Create a prefix, masking off host bits
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Self, String> {
+        let max = max_prefix_len(&addr);
+        if prefix_len > max {
+            return Err(format!("prefix length {} exceeds {}", prefix_len, max));
+        }
+        Ok(Self {
+            addr: mask(addr, prefix_len),
+            prefix_len,
+        })
+    }
+
+    /// Prefix covering exactly one address
+    pub fn host(addr: IpAddr) -> Self {
+        let addr = addr.to_canonical();
+        Self {
+            prefix_len: max_prefix_len(&addr),
+            addr,
+        }
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// True for `0.0.0.0/0` and `::/0`
+    pub fn is_default_route(&self) -> bool {
+        self.prefix_len == 0
+    }
+
+    /// Check whether `ip` lies inside this prefix
+    ///
+    /// IPv4-mapped IPv6 addresses are matched as IPv4.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        match (self.addr, ip) {
+            (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => {
+                mask(ip, self.prefix_len) == self.addr
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    /// Parse `ADDR/LEN`, or a bare address as a host prefix
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('/') {
+            Some((addr, len)) => {
+                let addr: IpAddr = addr
+                    .parse()
+                    .map_err(|_| format!("invalid CIDR address '{}'", s))?;
+                let len: u8 = len
+                    .parse()
+                    .map_err(|_| format!("invalid CIDR prefix length '{}'", s))?;
+                Cidr::new(addr, len)
+            }
+            None => s
+                .parse()
+                .map(Cidr::host)
+                .map_err(|_| format!("invalid CIDR '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+fn max_prefix_len(addr: &IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn mask(addr: IpAddr, prefix_len: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(v4) => {
+            let bits = u32::from(v4);
+            let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+            IpAddr::V4((bits & mask).into())
+        }
+        IpAddr::V6(v6) => {
+            let bits = u128::from(v6);
+            let mask = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+            IpAddr::V6((bits & mask).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_contains() {
+        let cidr: Cidr = "10.1.2.3/8".parse().unwrap();
+        assert_eq!(cidr.to_string(), "10.0.0.0/8");
+        assert!(cidr.contains(&"10.255.0.1".parse().unwrap()));
+        assert!(!cidr.contains(&"11.0.0.1".parse().unwrap()));
+        assert!(!cidr.contains(&"::1".parse().unwrap()));
+
+        let v6: Cidr = "fd7a:115c:a1e0::/48".parse().unwrap();
+        assert!(v6.contains(&"fd7a:115c:a1e0::1".parse().unwrap()));
+        assert!(!v6.contains(&"fd7a:115c:a1e1::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_host_and_default() {
+        let host: Cidr = "192.0.2.7".parse().unwrap();
+        assert_eq!(host.prefix_len(), 32);
+        assert!(host.contains(&"192.0.2.7".parse().unwrap()));
+        assert!(!host.contains(&"192.0.2.8".parse().unwrap()));
+
+        let any: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.is_default_route());
+        assert!(any.contains(&"203.0.113.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_ipv4_mapped() {
+        let loopback: Cidr = "127.0.0.0/8".parse().unwrap();
+        assert!(loopback.contains(&"::ffff:127.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("example.com".parse::<Cidr>().is_err());
+    }
+}
@@ -1,5 +1,7 @@
 //! Utility functions
 
+pub mod cidr;
 pub mod hostname;
 
+pub use cidr::Cidr;
 pub use hostname::{generate, get_or_generate, get_system_hostname};
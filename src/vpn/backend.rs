@@ -0,0 +1,492 @@
+//! Interchangeable VPN backends
+//!
+//! The binary drives the tailnet through [`VpnBackend`], so the SOCKS server
+//! does not depend on how the node is provided:
+//!
+//! - `rust`: the pure-Rust client ([`TailscaleRust`]), the default
+//! - `none`: no tailnet; everything uses the host's network (`--no-vpn`)
+//! - `tailscaled`: a tailscaled already running on the host, through its
+//!   LocalAPI socket (Unix)
+//! - `wireguard`: plain WireGuard from a wg-quick configuration file
+//! - `libtailscale`: Go's tsnet through libtailscale (cargo feature
+//!   `libtailscale`)
+
+use super::dial::{self, Network};
+use super::exit_node::{ExitNode, ExitNodeSelector};
+#[cfg(unix)]
+use super::localapi::LocalApi;
+use super::netmap::PeerMap;
+use super::netstack::{Netstack, TailnetListener};
+use super::serve::Serve;
+use super::status::Status;
+use super::tailscale_rust::TailscaleRust;
+#[cfg(unix)]
+use super::tailscaled::TailscaledBackend;
+use super::wg_quick::WireGuardBackend;
+use crate::utils::Cidr;
+use anyhow::Result;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Future returned by backend methods
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Byte stream returned by [`VpnBackend::dial`] and [`Listener::accept`]
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Listening socket returned by [`VpnBackend::listen`]
+pub trait Listener: Send + Sync {
+    /// Wait for the next connection and the address it came from
+    fn accept(&self) -> BoxFuture<'_, io::Result<(Box<dyn Stream>, SocketAddr)>>;
+}
+
+impl Listener for TailnetListener {
+    fn accept(&self) -> BoxFuture<'_, io::Result<(Box<dyn Stream>, SocketAddr)>> {
+        Box::pin(async move {
+            let (stream, peer) = TailnetListener::accept(self).await?;
+            Ok((Box::new(stream) as Box<dyn Stream>, peer))
+        })
+    }
+}
+
+impl Listener for TcpListener {
+    fn accept(&self) -> BoxFuture<'_, io::Result<(Box<dyn Stream>, SocketAddr)>> {
+        Box::pin(async move {
+            let (stream, peer) = TcpListener::accept(self).await?;
+            Ok((Box::new(stream) as Box<dyn Stream>, peer))
+        })
+    }
+}
+
+/// Node settings handed to [`VpnBackend::configure`]
+#[derive(Debug, Clone, Default)]
+pub struct BackendConfig {
+    pub hostname: String,
+    pub authkey: String,
+    pub control_url: Option<String>,
+    /// Exit node and whether LAN destinations stay on the host
+    pub exit_node: Option<(ExitNodeSelector, bool)>,
+    pub advertise_routes: Vec<Cidr>,
+    pub advertise_exit_node: bool,
+    /// Name of the TUN interface, if the host joins through one
+    pub tun: Option<String>,
+    pub serves: Vec<Serve>,
+    /// SOCKS listeners on the node's tailnet ports are configured
+    pub tailnet_listeners: bool,
+    /// Clients are authenticated by tailnet identity
+    pub tailnet_auth: bool,
+    /// LocalAPI socket of the host's tailscaled, for the `tailscaled` backend
+    pub tailscaled_socket: Option<PathBuf>,
+    /// wg-quick configuration file, for the `wireguard` backend
+    pub wireguard: Option<PathBuf>,
+}
+
+impl BackendConfig {
+    /// Fail if an option needs a node and userspace stack of our own
+    pub fn require_own_node(&self, backend: &str) -> Result<()> {
+        let option = if self.exit_node.is_some() {
+            "an exit node"
+        } else if !self.advertise_routes.is_empty() || self.advertise_exit_node {
+            "advertising routes"
+        } else if self.tun.is_some() {
+            "TUN mode"
+        } else if !self.serves.is_empty() {
+            "serving on the tailnet"
+        } else if self.tailnet_listeners {
+            "tailnet:PORT listeners"
+        } else {
+            return Ok(());
+        };
+        anyhow::bail!("{} is not available with the {} backend", option, backend)
+    }
+
+    /// Fail if clients are to be identified by the network map
+    pub fn require_no_tailnet_auth(&self, backend: &str) -> Result<()> {
+        if self.tailnet_auth {
+            anyhow::bail!(
+                "tailnet authentication is not available with the {} backend",
+                backend
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Backend shared between `main` and the control and metrics endpoints
+pub type SharedBackend = Arc<tokio::sync::RwLock<Box<dyn VpnBackend>>>;
+
+/// A way of putting socktail on the tailnet
+pub trait VpnBackend: Send + Sync {
+    /// Name used by `--backend`
+    fn name(&self) -> &'static str;
+
+    /// Apply node settings before [`up`](Self::up); fails on settings the
+    /// backend cannot honour
+    fn configure(&mut self, config: &BackendConfig) -> Result<()>;
+
+    /// Join the tailnet
+    fn up(&mut self) -> BoxFuture<'_, Result<()>>;
+
+    /// Leave the tailnet
+    fn down(&mut self) -> BoxFuture<'_, Result<()>>;
+
+    /// Node and peer state, for logs and `socktail status`
+    fn status(&self) -> Status;
+
+    /// Open a TCP connection to `addr` (`HOST:PORT`, MagicDNS names allowed)
+    fn dial<'a>(
+        &'a self,
+        network: &'a str,
+        addr: &'a str,
+    ) -> BoxFuture<'a, io::Result<Box<dyn Stream>>>;
+
+    /// Accept TCP connections on `addr` (`:PORT` or `IP:PORT`)
+    fn listen(&self, network: &str, addr: &str) -> io::Result<Box<dyn Listener>>;
+
+    /// Network map for tailnet routing and WhoIs
+    fn peer_map(&self) -> Option<PeerMap> {
+        None
+    }
+
+    /// Node addresses on a host interface that only tailnet peers can send
+    /// from, so host listeners may trust WhoIs on their clients
+    fn host_tailnet(&self) -> Vec<IpAddr> {
+        Vec::new()
+    }
+
+    /// Userspace stack terminating the node's tailnet addresses
+    fn netstack(&self) -> Option<Netstack> {
+        None
+    }
+
+    /// Exit node carrying direct traffic
+    fn exit_node(&self) -> Option<ExitNode> {
+        None
+    }
+
+    /// Prefixes that `direct` traffic reaches through [`netstack`](Self::netstack)
+    /// instead of the host
+    fn routes(&self) -> Vec<Cidr> {
+        Vec::new()
+    }
+
+    /// Name servers to resolve with instead of the host's resolver
+    fn dns_servers(&self) -> Vec<IpAddr> {
+        Vec::new()
+    }
+
+    /// tailscaled LocalAPI that `tailnet` routes are dialed through, when
+    /// host sockets cannot reach the tailnet
+    #[cfg(unix)]
+    fn local_api(&self) -> Option<LocalApi> {
+        None
+    }
+
+    /// tailscaled LocalAPI that answers WhoIs for tailnet clients
+    #[cfg(unix)]
+    fn whois_api(&self) -> Option<LocalApi> {
+        None
+    }
+}
+
+/// Backend selected with `--backend`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BackendKind {
+    #[default]
+    Rust,
+    None,
+    Tailscaled,
+    WireGuard,
+    #[cfg(all(unix, feature = "libtailscale"))]
+    LibTailscale,
+}
+
+impl FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rust" => Ok(BackendKind::Rust),
+            "none" => Ok(BackendKind::None),
+            "tailscaled" => Ok(BackendKind::Tailscaled),
+            "wireguard" => Ok(BackendKind::WireGuard),
+            #[cfg(all(unix, feature = "libtailscale"))]
+            "libtailscale" => Ok(BackendKind::LibTailscale),
+            #[cfg(not(all(unix, feature = "libtailscale")))]
+            "libtailscale" => {
+                Err("socktail was built without the libtailscale feature".to_string())
+            }
+            _ => Err(format!(
+                "unknown backend '{}' (expected rust, none, tailscaled, wireguard or libtailscale)",
+                s
+            )),
+        }
+    }
+}
+
+impl BackendKind {
+    /// Create an unconfigured backend of this kind
+    pub fn create(self) -> Result<Box<dyn VpnBackend>> {
+        Ok(match self {
+            BackendKind::Rust => Box::new(TailscaleRust::new()?),
+            BackendKind::None => Box::new(DirectBackend),
+            #[cfg(unix)]
+            BackendKind::Tailscaled => Box::new(TailscaledBackend::new()),
+            #[cfg(not(unix))]
+            BackendKind::Tailscaled => {
+                anyhow::bail!("the tailscaled backend needs a LocalAPI Unix socket")
+            }
+            BackendKind::WireGuard => Box::new(WireGuardBackend::new()),
+            #[cfg(all(unix, feature = "libtailscale"))]
+            BackendKind::LibTailscale => Box::new(super::libtailscale::LibTailscale::new()?),
+        })
+    }
+}
+
+impl VpnBackend for TailscaleRust {
+    fn name(&self) -> &'static str {
+        "rust"
+    }
+
+    fn configure(&mut self, config: &BackendConfig) -> Result<()> {
+        if config.tun.is_some() && config.tailnet_listeners {
+            anyhow::bail!(
+                "tailnet:PORT listeners are not available in TUN mode; listen on the Tailscale IP instead"
+            );
+        }
+        self.set_hostname(&config.hostname)?;
+        self.set_authkey(&config.authkey)?;
+        if let Some(url) = &config.control_url {
+            self.set_control_url(url)?;
+        }
+        self.set_advertised_routes(&config.advertise_routes, config.advertise_exit_node);
+        if let Some((selector, allow_lan_access)) = &config.exit_node {
+            self.set_exit_node(selector.clone(), *allow_lan_access);
+        }
+        for serve in &config.serves {
+            self.add_serve(serve.clone());
+        }
+        if let Some(name) = &config.tun {
+            self.set_tun(name);
+        }
+        Ok(())
+    }
+
+    fn up(&mut self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.connect())
+    }
+
+    fn down(&mut self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.disconnect())
+    }
+
+    fn status(&self) -> Status {
+        TailscaleRust::status(self)
+    }
+
+    fn dial<'a>(
+        &'a self,
+        network: &'a str,
+        addr: &'a str,
+    ) -> BoxFuture<'a, io::Result<Box<dyn Stream>>> {
+        Box::pin(async move {
+            stream_network(network)?;
+            let conn = TailscaleRust::dial(self, network, addr).await?;
+            let stream = conn.into_tcp().expect("TCP network dials a stream");
+            Ok(Box::new(stream) as Box<dyn Stream>)
+        })
+    }
+
+    fn listen(&self, network: &str, addr: &str) -> io::Result<Box<dyn Listener>> {
+        Ok(Box::new(TailscaleRust::listen(self, network, addr)?))
+    }
+
+    fn peer_map(&self) -> Option<PeerMap> {
+        Some(TailscaleRust::peer_map(self))
+    }
+
+    fn netstack(&self) -> Option<Netstack> {
+        TailscaleRust::netstack(self)
+    }
+
+    fn exit_node(&self) -> Option<ExitNode> {
+        TailscaleRust::exit_node(self).cloned()
+    }
+}
+
+/// The `none` backend: no tailnet, the host's network is used directly
+#[derive(Debug, Default)]
+pub struct DirectBackend;
+
+impl VpnBackend for DirectBackend {
+    fn name(&self) -> &'static str {
+        "none"
+    }
+
+    fn configure(&mut self, config: &BackendConfig) -> Result<()> {
+        config.require_own_node("none")?;
+        config.require_no_tailnet_auth("none")
+    }
+
+    fn up(&mut self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn down(&mut self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn status(&self) -> Status {
+        Status::stopped("none")
+    }
+
+    fn dial<'a>(
+        &'a self,
+        network: &'a str,
+        addr: &'a str,
+    ) -> BoxFuture<'a, io::Result<Box<dyn Stream>>> {
+        Box::pin(host_dial(network, addr))
+    }
+
+    fn listen(&self, network: &str, addr: &str) -> io::Result<Box<dyn Listener>> {
+        let network = stream_network(network)?;
+        let (host, port) = dial::split_host_port(addr)?;
+        let ip = match host {
+            "" if network == Network::Tcp6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            "" => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            host => parse_listen_ip(network, host)?,
+        };
+        host_listen(SocketAddr::new(ip, port))
+    }
+}
+
+/// Parse a TCP network name; backends only carry streams
+pub(crate) fn stream_network(network: &str) -> io::Result<Network> {
+    let network: Network = network.parse()?;
+    if !network.is_tcp() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "backends dial and listen on TCP; use TailscaleRust directly for UDP",
+        ));
+    }
+    Ok(network)
+}
+
+/// IP literal of a listen address, checked against the network's family
+pub(crate) fn parse_listen_ip(network: Network, host: &str) -> io::Result<IpAddr> {
+    host.parse()
+        .ok()
+        .filter(|ip| network.allows(ip))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid {:?} listen address '{}'", network, host),
+            )
+        })
+}
+
+/// Connect with the host's resolver and sockets
+pub(crate) async fn host_dial(network: &str, addr: &str) -> io::Result<Box<dyn Stream>> {
+    let network = stream_network(network)?;
+    let addr = dial::resolve(network, addr, &PeerMap::new()).await?;
+    Ok(Box::new(TcpStream::connect(addr).await?))
+}
+
+/// Bind a host socket; needs a Tokio runtime
+pub(crate) fn host_listen(addr: SocketAddr) -> io::Result<Box<dyn Listener>> {
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    Ok(Box::new(TcpListener::from_std(listener)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_parse_kind() {
+        assert_eq!("rust".parse::<BackendKind>().unwrap(), BackendKind::Rust);
+        assert_eq!("none".parse::<BackendKind>().unwrap(), BackendKind::None);
+        assert_eq!(
+            "tailscaled".parse::<BackendKind>().unwrap(),
+            BackendKind::Tailscaled
+        );
+        assert_eq!(
+            "wireguard".parse::<BackendKind>().unwrap(),
+            BackendKind::WireGuard
+        );
+        #[cfg(not(all(unix, feature = "libtailscale")))]
+        assert!("libtailscale".parse::<BackendKind>().is_err());
+        assert!("openvpn".parse::<BackendKind>().is_err());
+    }
+
+    #[test]
+    fn test_configure_rejects_node_options() {
+        let mut backend = BackendKind::None.create().unwrap();
+        assert!(backend.configure(&BackendConfig::default()).is_ok());
+
+        let config = BackendConfig {
+            serves: vec!["tcp:80=127.0.0.1:8080".parse().unwrap()],
+            ..Default::default()
+        };
+        let err = backend.configure(&config).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "serving on the tailnet is not available with the none backend"
+        );
+        let config = BackendConfig {
+            tailnet_auth: true,
+            ..Default::default()
+        };
+        assert!(backend.configure(&config).is_err());
+
+        let mut backend = BackendKind::Rust.create().unwrap();
+        let config = BackendConfig {
+            tun: Some("ts0".to_string()),
+            tailnet_listeners: true,
+            ..Default::default()
+        };
+        assert!(backend.configure(&config).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_direct_dial_and_listen() {
+        let mut backend = BackendKind::None.create().unwrap();
+        backend.up().await.unwrap();
+        assert!(!backend.status().is_running());
+        assert!(backend.peer_map().is_none());
+
+        assert!(backend.listen("tcp4", "127.0.0.1:0").is_ok());
+        assert!(backend.listen("tcp6", "127.0.0.1:0").is_err());
+
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let echo = tokio::spawn(async move {
+            let (mut stream, _) = Listener::accept(&server).await.unwrap();
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+
+        let mut stream = backend.dial("tcp", &addr.to_string()).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        echo.await.unwrap();
+
+        let err = backend.dial("udp", &addr.to_string()).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        backend.down().await.unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+//! Dialing and listening on the tailnet from Rust code
+//!
+//! [`TailscaleRust::dial`](super::TailscaleRust::dial) and
+//! [`listen`](super::TailscaleRust::listen) follow Go's tsnet: a network
+//! name (`tcp`, `udp`, optionally with `4` or `6`) and a `HOST:PORT`
+//! address, where the host may be a MagicDNS name.
+
+use super::netmap::PeerMap;
+use super::netstack::{TailnetStream, TailnetUdpConn};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+/// Transport and address family of a dial or listen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Network {
+    Tcp,
+    Tcp4,
+    Tcp6,
+    Udp,
+    Udp4,
+    Udp6,
+}
+
+impl FromStr for Network {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "tcp" => Network::Tcp,
+            "tcp4" => Network::Tcp4,
+            "tcp6" => Network::Tcp6,
+            "udp" => Network::Udp,
+            "udp4" => Network::Udp4,
+            "udp6" => Network::Udp6,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unknown network '{}'", s),
+                ))
+            }
+        })
+    }
+}
+
+impl Network {
+    pub fn is_tcp(&self) -> bool {
+        matches!(self, Network::Tcp | Network::Tcp4 | Network::Tcp6)
+    }
+
+    /// Whether addresses of this family can be used
+    pub fn allows(&self, ip: &IpAddr) -> bool {
+        match self {
+            Network::Tcp | Network::Udp => true,
+            Network::Tcp4 | Network::Udp4 => ip.is_ipv4(),
+            Network::Tcp6 | Network::Udp6 => ip.is_ipv6(),
+        }
+    }
+}
+
+/// Connection returned by [`TailscaleRust::dial`](super::TailscaleRust::dial)
+#[derive(Debug)]
+pub enum TailnetConn {
+    Tcp(TailnetStream),
+    Udp(TailnetUdpConn),
+}
+
+impl TailnetConn {
+    pub fn peer_addr(&self) -> SocketAddr {
+        match self {
+            TailnetConn::Tcp(stream) => stream.peer_addr(),
+            TailnetConn::Udp(conn) => conn.peer_addr(),
+        }
+    }
+
+    /// The TCP stream, if this is one
+    pub fn into_tcp(self) -> Option<TailnetStream> {
+        match self {
+            TailnetConn::Tcp(stream) => Some(stream),
+            TailnetConn::Udp(_) => None,
+        }
+    }
+
+    /// The UDP socket, if this is one
+    pub fn into_udp(self) -> Option<TailnetUdpConn> {
+        match self {
+            TailnetConn::Tcp(_) => None,
+            TailnetConn::Udp(conn) => Some(conn),
+        }
+    }
+}
+
+/// Split `HOST:PORT` (IPv6 in brackets); an empty host is allowed
+pub fn split_host_port(addr: &str) -> io::Result<(&str, u16)> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid address '{}', expected HOST:PORT", addr),
+        )
+    };
+    let (host, port) = addr.rsplit_once(':').ok_or_else(invalid)?;
+    let port = port.parse().map_err(|_| invalid())?;
+    let host = match host.strip_prefix('[') {
+        Some(host) => host.strip_suffix(']').ok_or_else(invalid)?,
+        None if host.contains(':') => return Err(invalid()),
+        None => host,
+    };
+    Ok((host, port))
+}
+
+/// Resolve `addr` for `network`: IP literals, MagicDNS names from the
+/// network map, then the host's resolver
+pub async fn resolve(network: Network, addr: &str, peers: &PeerMap) -> io::Result<SocketAddr> {
+    let (host, port) = split_host_port(addr)?;
+    let candidates: Vec<IpAddr> = if let Ok(ip) = host.parse::<IpAddr>() {
+        vec![ip]
+    } else if let Some(peer) = peers.peer_by_name(host) {
+        peer.addresses
+    } else {
+        tokio::net::lookup_host((host, port))
+            .await?
+            .map(|addr| addr.ip())
+            .collect()
+    };
+
+    candidates
+        .into_iter()
+        .find(|ip| network.allows(ip))
+        .map(|ip| SocketAddr::new(ip, port))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("no {:?} address for '{}'", network, host),
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vpn::PeerInfo;
+
+    #[test]
+    fn test_split_host_port() {
+        assert_eq!(split_host_port("db-1:5432").unwrap(), ("db-1", 5432));
+        assert_eq!(split_host_port(":8080").unwrap(), ("", 8080));
+        assert_eq!(
+            split_host_port("[fd7a:115c:a1e0::1]:22").unwrap(),
+            ("fd7a:115c:a1e0::1", 22)
+        );
+        assert!(split_host_port("fd7a::1:22").is_err());
+        assert!(split_host_port("db-1").is_err());
+        assert!("sctp".parse::<Network>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let peers = PeerMap::new();
+        peers.update(
+            vec![PeerInfo {
+                name: Some("db-1.tail1234.ts.net".to_string()),
+                tailscale_ip: "100.64.0.2".parse().unwrap(),
+                addresses: vec![
+                    "100.64.0.2".parse().unwrap(),
+                    "fd7a:115c:a1e0::2".parse().unwrap(),
+                ],
+                ..Default::default()
+            }],
+            Some("tail1234.ts.net".to_string()),
+        );
+
+        let addr = resolve(Network::Tcp, "db-1:5432", &peers).await.unwrap();
+        assert_eq!(addr, "100.64.0.2:5432".parse().unwrap());
+        let addr = resolve(Network::Udp6, "db-1.tail1234.ts.net:53", &peers)
+            .await
+            .unwrap();
+        assert_eq!(addr, "[fd7a:115c:a1e0::2]:53".parse().unwrap());
+        assert!(resolve(Network::Tcp6, "100.64.0.9:80", &peers)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,176 @@
+//! Exit node selection
+//!
+//! With an exit node, traffic to destinations outside the tailnet leaves
+//! through a peer that advertises `0.0.0.0/0` and `::/0`, so it egresses
+//! from that peer's public address.
+
+use super::netmap::{PeerInfo, PeerMap};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Which peer to use as the exit node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExitNodeSelector {
+    /// The exit node with the lowest handshake round-trip time
+    Auto,
+    /// A peer by MagicDNS name (full or bare host label)
+    Name(String),
+    /// A peer by Tailscale IP
+    Ip(IpAddr),
+}
+
+impl FromStr for ExitNodeSelector {
+    type Err = String;
+
+    /// Parse `auto`, a Tailscale IP or a peer name
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("empty exit node".to_string());
+        }
+        if s == "auto" {
+            return Ok(ExitNodeSelector::Auto);
+        }
+        Ok(match s.parse() {
+            Ok(ip) => ExitNodeSelector::Ip(ip),
+            Err(_) => ExitNodeSelector::Name(s.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for ExitNodeSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitNodeSelector::Auto => f.write_str("auto"),
+            ExitNodeSelector::Name(name) => f.write_str(name),
+            ExitNodeSelector::Ip(ip) => write!(f, "{}", ip),
+        }
+    }
+}
+
+impl ExitNodeSelector {
+    /// Find the exit node a name or IP refers to
+    ///
+    /// Returns `None` for [`Auto`](ExitNodeSelector::Auto), which needs
+    /// latency measurements, and for peers that are not exit nodes.
+    pub fn find(&self, peers: &PeerMap) -> Option<PeerInfo> {
+        let peer = match self {
+            ExitNodeSelector::Auto => return None,
+            ExitNodeSelector::Name(name) => peers.peer_by_name(name),
+            ExitNodeSelector::Ip(ip) => peers.peer_by_ip(ip),
+        }?;
+        peer.is_exit_node().then_some(peer)
+    }
+}
+
+/// The exit node in use
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExitNode {
+    /// Display name of the peer
+    pub name: String,
+    /// Tailscale IP of the peer
+    pub addr: IpAddr,
+    /// Reach private and link-local destinations from the host instead
+    pub allow_lan_access: bool,
+}
+
+impl ExitNode {
+    pub fn new(peer: &PeerInfo, allow_lan_access: bool) -> Self {
+        Self {
+            name: peer
+                .name
+                .clone()
+                .unwrap_or_else(|| peer.tailscale_ip.to_string()),
+            addr: peer.tailscale_ip,
+            allow_lan_access,
+        }
+    }
+
+    /// Check whether traffic to `ip` goes through the exit node
+    pub fn carries(&self, ip: &IpAddr) -> bool {
+        !(self.allow_lan_access && is_lan(ip))
+    }
+}
+
+impl fmt::Display for ExitNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.name, self.addr)
+    }
+}
+
+/// Private, link-local and loopback addresses
+pub fn is_lan(ip: &IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(v4) => v4.is_private() || v4.is_link_local() || v4.is_loopback(),
+        IpAddr::V6(v6) => {
+            let first = v6.segments()[0];
+            (first & 0xfe00) == 0xfc00 || (first & 0xffc0) == 0xfe80 || v6.is_loopback()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(name: &str, ip: &str, exit: bool) -> PeerInfo {
+        PeerInfo {
+            name: Some(name.to_string()),
+            tailscale_ip: ip.parse().unwrap(),
+            addresses: vec![ip.parse().unwrap()],
+            allowed_ips: if exit {
+                vec!["0.0.0.0/0".parse().unwrap(), "::/0".parse().unwrap()]
+            } else {
+                Vec::new()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_select() {
+        let peers = PeerMap::new();
+        peers.update(
+            vec![
+                peer("office-gw.tail1234.ts.net", "100.64.0.8", true),
+                peer("laptop.tail1234.ts.net", "100.64.0.9", false),
+            ],
+            Some("tail1234.ts.net".to_string()),
+        );
+
+        let by_name: ExitNodeSelector = "office-gw".parse().unwrap();
+        assert_eq!(
+            by_name.find(&peers).unwrap().tailscale_ip,
+            "100.64.0.8".parse::<IpAddr>().unwrap()
+        );
+        let by_ip: ExitNodeSelector = "100.64.0.8".parse().unwrap();
+        assert!(matches!(by_ip, ExitNodeSelector::Ip(_)));
+        assert!(by_ip.find(&peers).is_some());
+
+        // Not an exit node, unknown, or needs measurements
+        assert!("laptop"
+            .parse::<ExitNodeSelector>()
+            .unwrap()
+            .find(&peers)
+            .is_none());
+        assert!("nas"
+            .parse::<ExitNodeSelector>()
+            .unwrap()
+            .find(&peers)
+            .is_none());
+        assert_eq!("auto".parse(), Ok(ExitNodeSelector::Auto));
+        assert!("".parse::<ExitNodeSelector>().is_err());
+    }
+
+    #[test]
+    fn test_lan_access() {
+        let node = ExitNode::new(&peer("gw", "100.64.0.8", true), true);
+        assert!(!node.carries(&"192.168.1.10".parse().unwrap()));
+        assert!(!node.carries(&"fe80::1".parse().unwrap()));
+        assert!(!node.carries(&"fd12::1".parse().unwrap()));
+        assert!(node.carries(&"93.184.216.34".parse().unwrap()));
+
+        let strict = ExitNode::new(&peer("gw", "100.64.0.8", true), false);
+        assert!(strict.carries(&"192.168.1.10".parse().unwrap()));
+    }
+}
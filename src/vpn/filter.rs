@@ -0,0 +1,406 @@
+//! Tailnet packet filter and capability grants
+//!
+//! The control server sends every node the part of the tailnet policy that
+//! concerns it: which sources may reach which of its addresses and ports,
+//! and which application capabilities those sources are granted. Inbound
+//! WireGuard packets are checked against the filter, and the SOCKS server
+//! asks for [`SOCKS_CAPABILITY`] before serving a tailnet client, so the
+//! admin console stays authoritative for traffic socktail relays.
+
+use crate::utils::Cidr;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::RangeInclusive;
+use tracing::debug;
+
+/// Capability a tailnet source needs to use the SOCKS proxy, granted in
+/// the policy file with `"app": {"socktail.dev/cap/socks": [{}]}`
+pub const SOCKS_CAPABILITY: &str = "socktail.dev/cap/socks";
+
+pub const PROTO_ICMP: u8 = 1;
+pub const PROTO_TCP: u8 = 6;
+pub const PROTO_UDP: u8 = 17;
+pub const PROTO_ICMPV6: u8 = 58;
+pub const PROTO_SCTP: u8 = 132;
+
+/// Protocols a rule applies to when it does not list any
+const DEFAULT_PROTOS: &[u8] = &[PROTO_TCP, PROTO_UDP, PROTO_ICMP, PROTO_ICMPV6];
+
+/// `FilterRule` from the control server's `PacketFilter`
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct FilterRule {
+    /// `*`, an address, a prefix or an `A-B` range
+    #[serde(rename = "SrcIPs", default)]
+    pub src_ips: Vec<String>,
+    #[serde(rename = "DstPorts", default)]
+    pub dst_ports: Vec<NetPortRange>,
+    /// IP protocol numbers; empty means TCP, UDP and ICMP
+    #[serde(rename = "IPProto", default)]
+    pub ip_proto: Vec<u8>,
+    #[serde(rename = "CapGrant", default)]
+    pub cap_grant: Vec<CapGrant>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NetPortRange {
+    /// `*`, an address or a prefix
+    #[serde(rename = "IP")]
+    pub ip: String,
+    /// Prefix length for a bare `IP` (older control servers)
+    #[serde(rename = "Bits", default)]
+    pub bits: Option<u8>,
+    #[serde(rename = "Ports")]
+    pub ports: PortRange,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct PortRange {
+    #[serde(rename = "First")]
+    pub first: u16,
+    #[serde(rename = "Last")]
+    pub last: u16,
+}
+
+/// Capabilities granted to the rule's sources on `dsts`
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CapGrant {
+    #[serde(rename = "Dsts", default)]
+    pub dsts: Vec<String>,
+    /// Capability names without values (older control servers)
+    #[serde(rename = "Caps", default)]
+    pub caps: Vec<String>,
+    /// Capability name -> list of JSON values
+    #[serde(rename = "CapMap", default)]
+    pub cap_map: HashMap<String, serde_json::Value>,
+}
+
+/// A set of addresses from the policy
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum AddrSet {
+    Any,
+    Prefix(Cidr),
+    Range(IpAddr, IpAddr),
+}
+
+impl AddrSet {
+    fn parse(s: &str, bits: Option<u8>) -> Option<Self> {
+        if s == "*" {
+            return Some(AddrSet::Any);
+        }
+        if let Some((start, end)) = s.split_once('-') {
+            let (start, end): (IpAddr, IpAddr) = (start.parse().ok()?, end.parse().ok()?);
+            return (start.is_ipv4() == end.is_ipv4()).then_some(AddrSet::Range(start, end));
+        }
+        let cidr = match bits {
+            Some(bits) if !s.contains('/') => Cidr::new(s.parse().ok()?, bits).ok()?,
+            _ => s.parse().ok()?,
+        };
+        Some(AddrSet::Prefix(cidr))
+    }
+
+    fn contains(&self, ip: &IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        match self {
+            AddrSet::Any => true,
+            AddrSet::Prefix(cidr) => cidr.contains(&ip),
+            AddrSet::Range(start, end) => match (start, end, ip) {
+                (IpAddr::V4(s), IpAddr::V4(e), IpAddr::V4(ip)) => (*s..=*e).contains(&ip),
+                (IpAddr::V6(s), IpAddr::V6(e), IpAddr::V6(ip)) => (*s..=*e).contains(&ip),
+                _ => false,
+            },
+        }
+    }
+}
+
+fn parse_sets<'a>(values: impl IntoIterator<Item = &'a String>) -> Vec<AddrSet> {
+    values
+        .into_iter()
+        .filter_map(|s| {
+            let set = AddrSet::parse(s, None);
+            if set.is_none() {
+                debug!("Ignoring unsupported filter address '{}'", s);
+            }
+            set
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+struct Match {
+    srcs: Vec<AddrSet>,
+    dsts: Vec<(AddrSet, RangeInclusive<u16>)>,
+    protos: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+struct Grant {
+    srcs: Vec<AddrSet>,
+    dsts: Vec<AddrSet>,
+    caps: Vec<String>,
+    /// Grants every capability (only [`PacketFilter::allow_all`])
+    all_caps: bool,
+}
+
+impl Grant {
+    fn grants(&self, src: &IpAddr, cap: &str) -> bool {
+        (self.all_caps || self.caps.iter().any(|c| c == cap))
+            && self.srcs.iter().any(|s| s.contains(src))
+    }
+}
+
+/// Compiled packet filter; the default denies everything
+#[derive(Debug, Clone, Default)]
+pub struct PacketFilter {
+    matches: Vec<Match>,
+    grants: Vec<Grant>,
+}
+
+impl PacketFilter {
+    /// Compile the control server's rules, skipping entries we cannot parse
+    pub fn new(rules: &[FilterRule]) -> Self {
+        let mut filter = Self::default();
+        for rule in rules {
+            let srcs = parse_sets(&rule.src_ips);
+            if !rule.dst_ports.is_empty() {
+                let dsts = rule
+                    .dst_ports
+                    .iter()
+                    .filter_map(|d| {
+                        let set = AddrSet::parse(&d.ip, d.bits)?;
+                        Some((set, d.ports.first..=d.ports.last))
+                    })
+                    .collect();
+                let protos = if rule.ip_proto.is_empty() {
+                    DEFAULT_PROTOS.to_vec()
+                } else {
+                    rule.ip_proto.clone()
+                };
+                filter.matches.push(Match {
+                    srcs: srcs.clone(),
+                    dsts,
+                    protos,
+                });
+            }
+            for grant in &rule.cap_grant {
+                let mut caps = grant.caps.clone();
+                caps.extend(grant.cap_map.keys().cloned());
+                filter.grants.push(Grant {
+                    srcs: srcs.clone(),
+                    dsts: parse_sets(&grant.dsts),
+                    caps,
+                    all_caps: false,
+                });
+            }
+        }
+        filter
+    }
+
+    /// Filter that accepts every packet and grants every capability
+    pub fn allow_all() -> Self {
+        Self {
+            matches: vec![Match {
+                srcs: vec![AddrSet::Any],
+                dsts: vec![(AddrSet::Any, 0..=u16::MAX)],
+                protos: Vec::new(),
+            }],
+            grants: vec![Grant {
+                srcs: vec![AddrSet::Any],
+                dsts: vec![AddrSet::Any],
+                caps: Vec::new(),
+                all_caps: true,
+            }],
+        }
+    }
+
+    /// Check whether a new inbound flow is permitted
+    pub fn allows(&self, packet: &PacketInfo) -> bool {
+        let has_ports = matches!(packet.proto, PROTO_TCP | PROTO_UDP | PROTO_SCTP);
+        self.matches.iter().any(|m| {
+            (m.protos.is_empty() || m.protos.contains(&packet.proto))
+                && m.srcs.iter().any(|s| s.contains(&packet.src))
+                && m.dsts.iter().any(|(set, ports)| {
+                    set.contains(&packet.dst) && (!has_ports || ports.contains(&packet.dst_port))
+                })
+        })
+    }
+
+    /// Check whether `src` holds capability `cap` on `dst`
+    pub fn has_cap(&self, src: &IpAddr, dst: &IpAddr, cap: &str) -> bool {
+        self.grants
+            .iter()
+            .any(|g| g.grants(src, cap) && g.dsts.iter().any(|d| d.contains(dst)))
+    }
+
+    /// Check whether `src` holds `cap` on any of this node's addresses
+    ///
+    /// The control server only sends grants whose destinations are ours.
+    pub fn has_cap_anywhere(&self, src: &IpAddr, cap: &str) -> bool {
+        self.grants.iter().any(|g| g.grants(src, cap))
+    }
+}
+
+/// Addresses, protocol and ports of an IP packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketInfo {
+    pub proto: u8,
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    /// Zero for protocols without ports
+    pub src_port: u16,
+    pub dst_port: u16,
+}
+
+impl PacketInfo {
+    /// Parse the IPv4 or IPv6 header and the transport ports
+    ///
+    /// IPv6 extension headers are not followed; such packets report the
+    /// first next-header value as their protocol.
+    pub fn parse(packet: &[u8]) -> Option<Self> {
+        let (proto, src, dst, payload) = match packet.first()? >> 4 {
+            4 => {
+                let ihl = usize::from(packet.first()? & 0x0f) * 4;
+                if ihl < 20 || packet.len() < ihl {
+                    return None;
+                }
+                let src = Ipv4Addr::from(<[u8; 4]>::try_from(&packet[12..16]).ok()?);
+                let dst = Ipv4Addr::from(<[u8; 4]>::try_from(&packet[16..20]).ok()?);
+                // Only the first fragment carries the ports
+                let offset = u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff;
+                let payload = if offset == 0 { &packet[ihl..] } else { &[][..] };
+                (packet[9], IpAddr::V4(src), IpAddr::V4(dst), payload)
+            }
+            6 => {
+                if packet.len() < 40 {
+                    return None;
+                }
+                let src = Ipv6Addr::from(<[u8; 16]>::try_from(&packet[8..24]).ok()?);
+                let dst = Ipv6Addr::from(<[u8; 16]>::try_from(&packet[24..40]).ok()?);
+                (packet[6], IpAddr::V6(src), IpAddr::V6(dst), &packet[40..])
+            }
+            _ => return None,
+        };
+
+        let (src_port, dst_port) = match proto {
+            PROTO_TCP | PROTO_UDP | PROTO_SCTP if payload.len() >= 4 => (
+                u16::from_be_bytes([payload[0], payload[1]]),
+                u16::from_be_bytes([payload[2], payload[3]]),
+            ),
+            _ => (0, 0),
+        };
+
+        Some(Self {
+            proto,
+            src,
+            dst,
+            src_port,
+            dst_port,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules() -> Vec<FilterRule> {
+        serde_json::from_str(
+            r#"[
+                {
+                    "SrcIPs": ["100.64.0.2", "100.64.1.0-100.64.1.9"],
+                    "DstPorts": [{"IP": "100.64.0.1", "Ports": {"First": 1080, "Last": 1080}}],
+                    "IPProto": [6]
+                },
+                {
+                    "SrcIPs": ["*"],
+                    "DstPorts": [{"IP": "*", "Ports": {"First": 0, "Last": 65535}}],
+                    "IPProto": [1]
+                },
+                {
+                    "SrcIPs": ["100.64.0.0/24", "cap:unknown"],
+                    "CapGrant": [{
+                        "Dsts": ["100.64.0.1/32"],
+                        "CapMap": {"socktail.dev/cap/socks": [{}]}
+                    }]
+                }
+            ]"#,
+        )
+        .unwrap()
+    }
+
+    fn tcp(src: &str, dst: &str, port: u16) -> PacketInfo {
+        PacketInfo {
+            proto: PROTO_TCP,
+            src: src.parse().unwrap(),
+            dst: dst.parse().unwrap(),
+            src_port: 40000,
+            dst_port: port,
+        }
+    }
+
+    #[test]
+    fn test_packet_filter() {
+        let filter = PacketFilter::new(&rules());
+        assert!(filter.allows(&tcp("100.64.0.2", "100.64.0.1", 1080)));
+        assert!(filter.allows(&tcp("100.64.1.5", "100.64.0.1", 1080)));
+        assert!(!filter.allows(&tcp("100.64.1.10", "100.64.0.1", 1080)));
+        assert!(!filter.allows(&tcp("100.64.0.2", "100.64.0.1", 22)));
+
+        let ping = PacketInfo {
+            proto: PROTO_ICMP,
+            dst_port: 0,
+            ..tcp("100.64.9.9", "100.64.0.1", 0)
+        };
+        assert!(filter.allows(&ping));
+
+        assert!(!PacketFilter::default().allows(&tcp("100.64.0.2", "100.64.0.1", 1080)));
+        assert!(PacketFilter::allow_all().allows(&tcp("100.64.0.2", "100.64.0.1", 1080)));
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let filter = PacketFilter::new(&rules());
+        let node = "100.64.0.1".parse().unwrap();
+        assert!(filter.has_cap(&"100.64.0.7".parse().unwrap(), &node, SOCKS_CAPABILITY));
+        assert!(filter.has_cap_anywhere(&"100.64.0.7".parse().unwrap(), SOCKS_CAPABILITY));
+        assert!(!filter.has_cap(&"100.64.3.7".parse().unwrap(), &node, SOCKS_CAPABILITY));
+        assert!(!filter.has_cap(
+            &"100.64.0.7".parse().unwrap(),
+            &node,
+            "example.com/cap/other"
+        ));
+        assert!(!filter.has_cap(
+            &"100.64.0.7".parse().unwrap(),
+            &"100.64.0.9".parse().unwrap(),
+            SOCKS_CAPABILITY
+        ));
+    }
+
+    #[test]
+    fn test_parse_packet() {
+        let mut v4 = vec![0u8; 40];
+        v4[0] = 0x45;
+        v4[9] = PROTO_TCP;
+        v4[12..16].copy_from_slice(&[100, 64, 0, 2]);
+        v4[16..20].copy_from_slice(&[100, 64, 0, 1]);
+        v4[20..22].copy_from_slice(&40000u16.to_be_bytes());
+        v4[22..24].copy_from_slice(&1080u16.to_be_bytes());
+        assert_eq!(
+            PacketInfo::parse(&v4),
+            Some(tcp("100.64.0.2", "100.64.0.1", 1080))
+        );
+
+        let mut v6 = vec![0u8; 48];
+        v6[0] = 0x60;
+        v6[6] = PROTO_UDP;
+        v6[23] = 2;
+        v6[39] = 1;
+        v6[42..44].copy_from_slice(&53u16.to_be_bytes());
+        let info = PacketInfo::parse(&v6).unwrap();
+        assert_eq!(info.proto, PROTO_UDP);
+        assert_eq!(info.dst, "::1".parse::<IpAddr>().unwrap());
+        assert_eq!(info.dst_port, 53);
+
+        assert!(PacketInfo::parse(&[0x45, 0, 0]).is_none());
+    }
+}
@@ -0,0 +1,306 @@
+//! Subnet router and exit node
+//!
+//! Re-originates traffic that peers send to advertised routes from the
+//! host's own sockets, like tailscaled in netstack mode: TCP connections
+//! terminated by the [`Netstack`] are relayed to a host connection to the
+//! original destination, and UDP datagrams go out through one host socket
+//! per flow. Destinations are checked against [`rules`] first.
+
+use super::netstack::{Forwarded, Netstack, TailnetStream};
+use crate::socks5::protocol::{TargetAddr, CMD_CONNECT, CMD_UDP_ASSOCIATE};
+use crate::socks5::rules::{Action, RuleEngine, RuleRequest, SAFE_DEFAULT_RULES};
+use crate::utils::Cidr;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use tracing::{debug, trace};
+
+/// How long a host connection to a forwarded destination may take
+const DIAL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// UDP flows without traffic in either direction are closed after this
+pub const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Largest datagram read from a host socket
+const MAX_UDP_PAYLOAD: usize = 65535;
+
+/// The host's private networks, which exit node traffic does not reach
+/// unless they are advertised as subnet routes
+const HOST_LAN_RULE: &str = "deny dst=10.0.0.0/8,172.16.0.0/12,192.168.0.0/16,fc00::/7";
+
+/// Rules for forwarded destinations: advertised subnets are allowed, then
+/// [`SAFE_DEFAULT_RULES`] and the host LAN are denied
+pub fn rules(routes: &[Cidr]) -> RuleEngine {
+    let subnets: Vec<String> = routes
+        .iter()
+        .filter(|route| !route.is_default_route())
+        .map(Cidr::to_string)
+        .collect();
+    let mut rules = Vec::new();
+    if !subnets.is_empty() {
+        rules.push(format!("allow dst={}", subnets.join(",")));
+    }
+    rules.extend(SAFE_DEFAULT_RULES.iter().map(|rule| rule.to_string()));
+    rules.push(HOST_LAN_RULE.to_string());
+    let rules = rules
+        .iter()
+        .map(|rule| rule.parse().expect("forwarding rule"));
+    RuleEngine::new(rules.collect(), Action::Allow)
+}
+
+/// Datagram channels of the open UDP flows, keyed by what identifies a flow
+#[derive(Debug)]
+pub struct UdpFlows<K> {
+    flows: HashMap<K, mpsc::UnboundedSender<Vec<u8>>>,
+}
+
+impl<K: Eq + Hash> UdpFlows<K> {
+    pub fn new() -> Self {
+        Self {
+            flows: HashMap::new(),
+        }
+    }
+
+    /// Queue `datagram` on the open flow for `key`, or start a new flow
+    /// holding it and return its receiver for the caller to serve
+    pub fn dispatch(
+        &mut self,
+        key: K,
+        datagram: Vec<u8>,
+    ) -> Option<mpsc::UnboundedReceiver<Vec<u8>>> {
+        let datagram = match self.flows.get(&key) {
+            Some(flow) => match flow.send(datagram) {
+                Ok(()) => return None,
+                // The flow went idle; start a new one
+                Err(mpsc::error::SendError(datagram)) => datagram,
+            },
+            None => datagram,
+        };
+        self.flows.retain(|_, flow| !flow.is_closed());
+
+        let (flow, datagrams) = mpsc::unbounded_channel();
+        let _ = flow.send(datagram);
+        self.flows.insert(key, flow);
+        Some(datagrams)
+    }
+}
+
+impl<K: Eq + Hash> Default for UdpFlows<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Forward what `incoming` yields and `rules` allow until the stack stops
+pub async fn run(
+    netstack: Netstack,
+    mut incoming: mpsc::UnboundedReceiver<Forwarded>,
+    rules: RuleEngine,
+) {
+    let mut udp_flows = UdpFlows::new();
+
+    while let Some(forwarded) = incoming.recv().await {
+        match forwarded {
+            Forwarded::Tcp(stream) => {
+                if allowed(&rules, CMD_CONNECT, stream.peer_addr(), stream.local_addr()) {
+                    tokio::spawn(forward_tcp(stream));
+                }
+            }
+            Forwarded::Udp { src, dst, payload } => {
+                if !allowed(&rules, CMD_UDP_ASSOCIATE, src, dst) {
+                    continue;
+                }
+                if let Some(datagrams) = udp_flows.dispatch((src, dst), payload) {
+                    tokio::spawn(forward_udp(netstack.clone(), src, dst, datagrams));
+                }
+            }
+        }
+    }
+}
+
+/// Whether `rules` let `src` reach `dst`
+fn allowed(rules: &RuleEngine, command: u8, src: SocketAddr, dst: SocketAddr) -> bool {
+    let target = TargetAddr::Ip(dst);
+    let decision = rules.evaluate(&RuleRequest {
+        command,
+        target: &target,
+        resolved: None,
+        source: Some(src.ip()),
+        user: None,
+        tags: &[],
+    });
+    if !decision.is_allowed() {
+        debug!(
+            "Not forwarding {} -> {} (rule: {:?})",
+            src, dst, decision.rule
+        );
+    }
+    decision.is_allowed()
+}
+
+async fn forward_tcp(mut stream: TailnetStream) {
+    let (peer, dst) = (stream.peer_addr(), stream.local_addr());
+    let mut target = match tokio::time::timeout(DIAL_TIMEOUT, TcpStream::connect(dst)).await {
+        Ok(Ok(target)) => target,
+        Ok(Err(e)) => {
+            debug!("Forwarding {} -> {} failed: {}", peer, dst, e);
+            return;
+        }
+        Err(_) => {
+            debug!("Forwarding {} -> {} timed out", peer, dst);
+            return;
+        }
+    };
+
+    match tokio::io::copy_bidirectional(&mut stream, &mut target).await {
+        Ok((up, down)) => trace!("Forwarded {} -> {}: {} up, {} down", peer, dst, up, down),
+        Err(e) => debug!("Forwarding {} -> {} ended: {}", peer, dst, e),
+    }
+}
+
+async fn forward_udp(
+    netstack: Netstack,
+    src: SocketAddr,
+    dst: SocketAddr,
+    mut datagrams: mpsc::UnboundedReceiver<Vec<u8>>,
+) {
+    let bind: SocketAddr = match dst {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = match UdpSocket::bind(bind).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            debug!("UDP forwarding for {} -> {} failed: {}", src, dst, e);
+            return;
+        }
+    };
+    if let Err(e) = socket.connect(dst).await {
+        debug!("UDP forwarding for {} -> {} failed: {}", src, dst, e);
+        return;
+    }
+
+    let mut buf = vec![0u8; MAX_UDP_PAYLOAD];
+    loop {
+        tokio::select! {
+            datagram = datagrams.recv() => match datagram {
+                Some(datagram) => {
+                    if let Err(e) = socket.send(&datagram).await {
+                        trace!("UDP send to {} failed: {}", dst, e);
+                    }
+                }
+                None => break,
+            },
+            received = socket.recv(&mut buf) => match received {
+                Ok(len) => {
+                    if netstack.send_udp(dst, src, &buf[..len]).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => trace!("UDP receive from {} failed: {}", dst, e),
+            },
+            _ = tokio::time::sleep(UDP_IDLE_TIMEOUT) => break,
+        }
+    }
+    trace!("UDP flow {} -> {} closed", src, dst);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vpn::netstack;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn loopback_rules() -> RuleEngine {
+        rules(&["127.0.0.0/8".parse().unwrap()])
+    }
+
+    /// A router stack that forwards 127.0.0.0/8, and a peer stack whose
+    /// packets are delivered to it
+    fn router_and_peer() -> (Netstack, Netstack, mpsc::UnboundedReceiver<Forwarded>) {
+        let (router, peer) = netstack::pair();
+        let incoming = router.forward(&["127.0.0.0/8".parse().unwrap()]);
+        (router, peer, incoming)
+    }
+
+    #[tokio::test]
+    async fn test_forward_tcp() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = target.accept().await.unwrap();
+            let mut buf = [0u8; 4];
+            socket.read_exact(&mut buf).await.unwrap();
+            socket.write_all(&buf).await.unwrap();
+        });
+
+        let (router, peer, incoming) = router_and_peer();
+        // The router's own tailnet port stays separate from forwarded ones
+        let _own = router.listen(target_addr.port()).unwrap();
+        tokio::spawn(run(router, incoming, loopback_rules()));
+
+        let mut stream = peer.connect(target_addr).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_forward_udp() {
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            let (len, from) = target.recv_from(&mut buf).await.unwrap();
+            target.send_to(&buf[..len], from).await.unwrap();
+        });
+
+        let (out, mut replies) = mpsc::unbounded_channel();
+        let router = Netstack::new(&["100.64.0.1".parse().unwrap()], out);
+        let incoming = router.forward(&["127.0.0.0/8".parse().unwrap()]);
+        tokio::spawn(run(router.clone(), incoming, loopback_rules()));
+
+        // Datagram as a peer would send it, built by the stack's own encoder
+        let peer: SocketAddr = "100.64.0.2:5353".parse().unwrap();
+        let (out, mut packets) = mpsc::unbounded_channel();
+        let sender = Netstack::new(&[peer.ip()], out);
+        sender.send_udp(peer, target_addr, b"hello").unwrap();
+        router.inject(packets.recv().await.unwrap());
+
+        let reply = replies.recv().await.unwrap();
+        let info = crate::vpn::filter::PacketInfo::parse(&reply).unwrap();
+        assert_eq!(
+            (info.src, info.src_port),
+            (target_addr.ip(), target_addr.port())
+        );
+        assert_eq!((info.dst, info.dst_port), (peer.ip(), peer.port()));
+        assert!(reply.ends_with(b"hello"));
+    }
+
+    #[test]
+    fn test_rules() {
+        let routes: Vec<Cidr> = vec![
+            "10.1.0.0/16".parse().unwrap(),
+            "0.0.0.0/0".parse().unwrap(),
+            "::/0".parse().unwrap(),
+        ];
+        let rules = rules(&routes);
+        let src = "100.64.0.2:40000".parse().unwrap();
+        let allows = |dst: &str| allowed(&rules, CMD_CONNECT, src, dst.parse().unwrap());
+
+        assert!(allows("10.1.2.3:22"));
+        assert!(allows("203.0.113.1:443"));
+        assert!(allows("[2001:db8::1]:443"));
+        assert!(!allows("10.2.0.1:22"));
+        assert!(!allows("192.168.1.1:80"));
+        assert!(!allows("169.254.169.254:80"));
+        assert!(!allows("[::ffff:169.254.169.254]:80"));
+        assert!(!allows("[fd00::1]:80"));
+        assert!(!allows("127.0.0.1:80"));
+    }
+}
@@ -1,5 +1,17 @@
-//! FFI bindings to libtailscale C library
+//! Backend on Go's tsnet through the libtailscale C library
+//!
+//! Built with the `libtailscale` cargo feature. The library must be built
+//! from github.com/tailscale/libtailscale; `LIBTAILSCALE_DIR` (default
+//! `lib/`) tells the build where to find `libtailscale.a`. Connections are
+//! Unix socket pairs that libtailscale bridges to the tailnet.
 
+use super::backend::{BackendConfig, BoxFuture, Listener, Stream, VpnBackend};
+use super::status::{BackendState, NodeStatus, Status};
+use anyhow::Result;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
 use std::os::raw::{c_char, c_int};
 
 /// Handle to a Tailscale server
@@ -91,3 +103,225 @@ extern "C" {
     /// Get error message
     pub fn tailscale_errmsg(sd: Tailscale, buf: *mut c_char, buflen: usize) -> c_int;
 }
+
+/// Node run by libtailscale inside this process
+pub struct LibTailscale {
+    sd: Tailscale,
+    connected: bool,
+}
+
+impl LibTailscale {
+    pub fn new() -> Result<Self> {
+        // SAFETY: no preconditions
+        let sd = unsafe { tailscale_new() };
+        if sd < 0 {
+            anyhow::bail!("libtailscale: failed to create a server");
+        }
+        Ok(Self {
+            sd,
+            connected: false,
+        })
+    }
+
+    /// Tailnet addresses, once up
+    fn addresses(&self) -> Vec<IpAddr> {
+        let mut buf = [0 as c_char; 256];
+        // SAFETY: the buffer outlives the call and its length is passed
+        if unsafe { tailscale_getips(self.sd, buf.as_mut_ptr(), buf.len()) } != 0 {
+            return Vec::new();
+        }
+        // SAFETY: libtailscale NUL-terminates on success
+        let ips = unsafe { CStr::from_ptr(buf.as_ptr()) }.to_string_lossy();
+        ips.split(',')
+            .filter_map(|ip| ip.trim().parse().ok())
+            .collect()
+    }
+
+    /// Call a string setter, reporting libtailscale's error message
+    fn set(
+        &self,
+        what: &str,
+        setter: unsafe extern "C" fn(Tailscale, *const c_char) -> c_int,
+        value: &str,
+    ) -> Result<()> {
+        let value = CString::new(value)?;
+        // SAFETY: the string outlives the call; libtailscale copies it
+        if unsafe { setter(self.sd, value.as_ptr()) } != 0 {
+            anyhow::bail!("libtailscale: failed to set {}: {}", what, errmsg(self.sd));
+        }
+        Ok(())
+    }
+}
+
+/// Last error of a server
+fn errmsg(sd: Tailscale) -> String {
+    let mut buf = [0 as c_char; 256];
+    // SAFETY: the buffer outlives the call and its length is passed
+    if unsafe { tailscale_errmsg(sd, buf.as_mut_ptr(), buf.len()) } != 0 {
+        return "unknown error".to_string();
+    }
+    // SAFETY: libtailscale NUL-terminates on success
+    unsafe { CStr::from_ptr(buf.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn io_error(sd: Tailscale) -> io::Error {
+    io::Error::other(errmsg(sd))
+}
+
+fn c_string(s: &str) -> io::Result<CString> {
+    CString::new(s).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Take ownership of a connection descriptor
+fn into_stream(conn: TailscaleConn) -> io::Result<Box<dyn Stream>> {
+    // SAFETY: libtailscale hands over a fresh socket we now own
+    let stream = unsafe { std::os::unix::net::UnixStream::from_raw_fd(conn) };
+    stream.set_nonblocking(true)?;
+    Ok(Box::new(tokio::net::UnixStream::from_std(stream)?))
+}
+
+impl VpnBackend for LibTailscale {
+    fn name(&self) -> &'static str {
+        "libtailscale"
+    }
+
+    fn configure(&mut self, config: &BackendConfig) -> Result<()> {
+        config.require_own_node("libtailscale")?;
+        config.require_no_tailnet_auth("libtailscale")?;
+        self.set("hostname", tailscale_set_hostname, &config.hostname)?;
+        if !config.authkey.is_empty() {
+            self.set("auth key", tailscale_set_authkey, &config.authkey)?;
+        }
+        if let Some(url) = &config.control_url {
+            self.set("control URL", tailscale_set_control_url, url)?;
+        }
+        Ok(())
+    }
+
+    fn up(&mut self) -> BoxFuture<'_, Result<()>> {
+        let sd = self.sd;
+        Box::pin(async move {
+            // SAFETY: the server handle stays valid until close
+            let ret = tokio::task::spawn_blocking(move || unsafe { tailscale_up(sd) }).await?;
+            if ret != 0 {
+                anyhow::bail!("libtailscale: {}", errmsg(sd));
+            }
+            self.connected = true;
+            Ok(())
+        })
+    }
+
+    fn down(&mut self) -> BoxFuture<'_, Result<()>> {
+        if self.connected {
+            // SAFETY: the handle is not used again once closed
+            unsafe { tailscale_close(self.sd) };
+            self.connected = false;
+        }
+        Box::pin(async { Ok(()) })
+    }
+
+    /// tsnet exposes no peer state through libtailscale
+    fn status(&self) -> Status {
+        if !self.connected {
+            return Status::stopped("libtailscale");
+        }
+        Status {
+            backend: "libtailscale".to_string(),
+            state: BackendState::Running,
+            self_node: NodeStatus {
+                addresses: self.addresses(),
+                ..Default::default()
+            },
+            derp_home: None,
+            peers: None,
+        }
+    }
+
+    fn dial<'a>(
+        &'a self,
+        network: &'a str,
+        addr: &'a str,
+    ) -> BoxFuture<'a, io::Result<Box<dyn Stream>>> {
+        let sd = self.sd;
+        Box::pin(async move {
+            super::backend::stream_network(network)?;
+            let network = c_string(network)?;
+            let addr = c_string(addr)?;
+            let conn = tokio::task::spawn_blocking(move || {
+                let mut conn: TailscaleConn = -1;
+                // SAFETY: the strings and out pointer outlive the call
+                match unsafe { tailscale_dial(sd, network.as_ptr(), addr.as_ptr(), &mut conn) } {
+                    0 => Ok(conn),
+                    _ => Err(io_error(sd)),
+                }
+            })
+            .await??;
+            into_stream(conn)
+        })
+    }
+
+    fn listen(&self, network: &str, addr: &str) -> io::Result<Box<dyn Listener>> {
+        super::backend::stream_network(network)?;
+        let network = c_string(network)?;
+        let addr = c_string(addr)?;
+        let mut listener: TailscaleListener = -1;
+        // SAFETY: the strings and out pointer outlive the call
+        if unsafe { tailscale_listen(self.sd, network.as_ptr(), addr.as_ptr(), &mut listener) } != 0
+        {
+            return Err(io_error(self.sd));
+        }
+        Ok(Box::new(LibTailscaleListener {
+            sd: self.sd,
+            // SAFETY: the listener descriptor is ours to close
+            fd: unsafe { OwnedFd::from_raw_fd(listener) },
+        }))
+    }
+}
+
+impl Drop for LibTailscale {
+    fn drop(&mut self) {
+        // SAFETY: the handle is not used after drop
+        unsafe { tailscale_close(self.sd) };
+    }
+}
+
+/// Listener returned by [`LibTailscale`]; closed on drop
+struct LibTailscaleListener {
+    sd: Tailscale,
+    fd: OwnedFd,
+}
+
+impl Listener for LibTailscaleListener {
+    fn accept(&self) -> BoxFuture<'_, io::Result<(Box<dyn Stream>, SocketAddr)>> {
+        let (sd, listener) = (self.sd, self.fd.as_raw_fd());
+        Box::pin(async move {
+            let (conn, peer) = tokio::task::spawn_blocking(move || {
+                let mut conn: TailscaleConn = -1;
+                // SAFETY: the out pointer outlives the call
+                if unsafe { tailscale_accept(listener, &mut conn) } != 0 {
+                    return Err(io_error(sd));
+                }
+                let mut buf = [0 as c_char; 64];
+                // SAFETY: the buffer outlives the call and its length is passed
+                let peer = match unsafe {
+                    tailscale_getremoteaddr(listener, conn, buf.as_mut_ptr(), buf.len())
+                } {
+                    // SAFETY: libtailscale NUL-terminates on success
+                    0 => unsafe { CStr::from_ptr(buf.as_ptr()) }
+                        .to_string_lossy()
+                        .parse()
+                        .ok(),
+                    _ => None,
+                };
+                Ok((
+                    conn,
+                    peer.unwrap_or(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))),
+                ))
+            })
+            .await??;
+            Ok((into_stream(conn)?, peer))
+        })
+    }
+}
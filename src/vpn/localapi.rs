@@ -0,0 +1,602 @@
+//! Client for tailscaled's LocalAPI
+//!
+//! tailscaled serves an HTTP API on a Unix socket. [`LocalApi`] reads the
+//! node's status and network map from it, looks up callers with WhoIs, and
+//! opens connections through the node with `/localapi/v0/dial`, which works
+//! even when tailscaled runs in userspace-networking mode.
+
+use super::netmap::{PeerInfo, WhoIs};
+use super::status::{self, BackendState, NodeStatus, PeerPath};
+use crate::utils::Cidr;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+
+/// Socket tailscaled listens on by default
+pub const DEFAULT_SOCKET: &str = "/var/run/tailscale/tailscaled.sock";
+
+/// Host name tailscaled expects in LocalAPI requests
+const HOST: &str = "local-tailscaled.sock";
+
+/// Largest response head accepted
+const MAX_HTTP_HEAD: usize = 16 * 1024;
+
+/// Node status from `/localapi/v0/status`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct Status {
+    /// `Running` once the node is logged in and connected
+    pub backend_state: String,
+    /// Whether tailscaled uses a TUN device (false in userspace-networking mode)
+    #[serde(rename = "TUN")]
+    pub tun: bool,
+    #[serde(rename = "Self")]
+    pub self_node: Option<PeerStatus>,
+    #[serde(rename = "MagicDNSSuffix")]
+    pub magic_dns_suffix: String,
+    pub peer: Option<HashMap<String, PeerStatus>>,
+    pub user: Option<HashMap<String, UserProfile>>,
+}
+
+/// A node in [`Status`]
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct PeerStatus {
+    /// `nodekey:` followed by the hex WireGuard key
+    pub public_key: String,
+    pub host_name: String,
+    /// MagicDNS name with a trailing dot
+    #[serde(rename = "DNSName")]
+    pub dns_name: String,
+    #[serde(rename = "TailscaleIPs")]
+    pub tailscale_ips: Option<Vec<IpAddr>>,
+    pub tags: Option<Vec<String>>,
+    #[serde(rename = "UserID")]
+    pub user_id: i64,
+    #[serde(rename = "AllowedIPs")]
+    pub allowed_ips: Option<Vec<String>>,
+    /// Direct WireGuard endpoint, empty when relayed
+    pub cur_addr: String,
+    /// DERP region code of the node's home relay
+    pub relay: String,
+    pub online: bool,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    /// RFC 3339 time; Go's zero time if there was none
+    pub last_handshake: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct UserProfile {
+    pub login_name: String,
+}
+
+impl Status {
+    /// Tailnet addresses of this node
+    pub fn addresses(&self) -> Vec<IpAddr> {
+        self.self_node
+            .as_ref()
+            .and_then(|node| node.tailscale_ips.clone())
+            .unwrap_or_default()
+    }
+
+    /// Tailnet domain for MagicDNS names
+    pub fn domain(&self) -> Option<String> {
+        let suffix = self.magic_dns_suffix.trim_end_matches('.');
+        (!suffix.is_empty()).then(|| suffix.to_string())
+    }
+
+    /// Peers in the form the rest of socktail uses
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        let users = self.user.clone().unwrap_or_default();
+        self.peer
+            .iter()
+            .flatten()
+            .filter_map(|(_, peer)| {
+                let addresses = peer.tailscale_ips.clone().unwrap_or_default();
+                let tags = peer.tags.clone().unwrap_or_default();
+                let allowed_ips = peer
+                    .allowed_ips
+                    .iter()
+                    .flatten()
+                    .filter_map(|prefix| prefix.parse::<Cidr>().ok())
+                    .filter(|cidr| !addresses.iter().any(|ip| *cidr == Cidr::host(*ip)))
+                    .collect();
+                Some(PeerInfo {
+                    public_key: node_key(&peer.public_key),
+                    name: non_empty(peer.dns_name.trim_end_matches('.')),
+                    tailscale_ip: *addresses.first()?,
+                    user: if tags.is_empty() {
+                        users
+                            .get(&peer.user_id.to_string())
+                            .and_then(|u| non_empty(&u.login_name))
+                    } else {
+                        None
+                    },
+                    addresses,
+                    tags,
+                    allowed_ips,
+                    endpoint: peer.cur_addr.parse().ok(),
+                    online: Some(peer.online),
+                    derp: non_empty(&peer.relay),
+                })
+            })
+            .collect()
+    }
+
+    /// Node status in socktail's form
+    pub fn node_status(&self) -> status::Status {
+        let running = self.backend_state == "Running";
+        let peers = self
+            .peer
+            .iter()
+            .flatten()
+            .map(|(_, peer)| {
+                let last_handshake = unix_time(&peer.last_handshake);
+                let endpoint = peer.cur_addr.parse().ok();
+                let path = match (last_handshake, endpoint) {
+                    (None, _) => PeerPath::Idle,
+                    (Some(_), Some(_)) => PeerPath::Direct,
+                    (Some(_), None) => PeerPath::Derp,
+                };
+                status::PeerStatus {
+                    name: non_empty(peer.dns_name.trim_end_matches('.')),
+                    public_key: peer.public_key.clone(),
+                    addresses: peer.tailscale_ips.clone().unwrap_or_default(),
+                    online: Some(peer.online),
+                    path,
+                    endpoint,
+                    derp: non_empty(&peer.relay),
+                    last_handshake,
+                    rx_bytes: peer.rx_bytes,
+                    tx_bytes: peer.tx_bytes,
+                }
+            })
+            .collect();
+        let node = self.self_node.clone().unwrap_or_default();
+        status::Status {
+            backend: "tailscaled".to_string(),
+            state: if running {
+                BackendState::Running
+            } else {
+                BackendState::Stopped
+            },
+            self_node: NodeStatus {
+                name: non_empty(node.dns_name.trim_end_matches('.')),
+                public_key: non_empty(&node.public_key),
+                addresses: self.addresses(),
+            },
+            derp_home: non_empty(&node.relay),
+            peers: Some(peers),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+struct WhoIsResponse {
+    node: WhoIsNode,
+    user_profile: UserProfile,
+    /// Capabilities granted to the node on this one, omitted when none are
+    cap_map: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+struct WhoIsNode {
+    name: String,
+    /// Prefixes such as `100.64.0.2/32`
+    addresses: Vec<String>,
+    tags: Option<Vec<String>>,
+}
+
+/// Handle to a tailscaled LocalAPI socket
+#[derive(Debug, Clone)]
+pub struct LocalApi {
+    socket: PathBuf,
+}
+
+impl Default for LocalApi {
+    fn default() -> Self {
+        Self::new(DEFAULT_SOCKET)
+    }
+}
+
+impl LocalApi {
+    pub fn new(socket: impl Into<PathBuf>) -> Self {
+        Self {
+            socket: socket.into(),
+        }
+    }
+
+    pub fn socket(&self) -> &Path {
+        &self.socket
+    }
+
+    pub async fn status(&self) -> io::Result<Status> {
+        let body = self.get("/localapi/v0/status").await?.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "LocalAPI has no status endpoint")
+        })?;
+        serde_json::from_slice(&body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Node and user behind a tailnet address; `None` if it is unknown
+    pub async fn whois(&self, ip: IpAddr) -> io::Result<Option<WhoIs>> {
+        let path = format!("/localapi/v0/whois?addr={}", ip);
+        let Some(body) = self.get(&path).await? else {
+            return Ok(None);
+        };
+        let response: WhoIsResponse = serde_json::from_slice(&body)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let tags = response.node.tags.unwrap_or_default();
+        let addr = response
+            .node
+            .addresses
+            .iter()
+            .filter_map(|prefix| prefix.split('/').next()?.parse().ok())
+            .next()
+            .unwrap_or(ip);
+        Ok(Some(WhoIs {
+            node: non_empty(response.node.name.trim_end_matches('.'))
+                .unwrap_or_else(|| addr.to_string()),
+            addr,
+            user: if tags.is_empty() {
+                non_empty(&response.user_profile.login_name)
+            } else {
+                None
+            },
+            tags,
+            caps: Some(response.cap_map.into_keys().collect()),
+        }))
+    }
+
+    /// Open a TCP connection from the node to `host:port`
+    pub async fn dial(&self, host: &str, port: u16) -> io::Result<UnixStream> {
+        let mut stream = UnixStream::connect(&self.socket).await?;
+        let request = format!(
+            "POST /localapi/v0/dial HTTP/1.1\r\nHost: {}\r\nSec-Tailscale: localapi\r\n\
+             Connection: upgrade\r\nUpgrade: ts-dial\r\nDial-Host: {}\r\nDial-Port: {}\r\n\
+             Dial-Network: tcp\r\n\r\n",
+            HOST, host, port
+        );
+        stream.write_all(request.as_bytes()).await?;
+
+        // Read byte by byte so nothing after the head is consumed
+        let mut head = Vec::with_capacity(256);
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() >= MAX_HTTP_HEAD {
+                return Err(invalid("LocalAPI response head too large"));
+            }
+            head.push(stream.read_u8().await?);
+        }
+        match status_code(&head)? {
+            101 => Ok(stream),
+            403 => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "LocalAPI denied dial access; run as root or a tailscale operator",
+            )),
+            code => Err(io::Error::other(format!(
+                "LocalAPI dial to {}:{} failed with HTTP {}",
+                host, port, code
+            ))),
+        }
+    }
+
+    /// Body of a GET request; `None` on 404
+    async fn get(&self, path: &str) -> io::Result<Option<Vec<u8>>> {
+        let mut stream = UnixStream::connect(&self.socket).await?;
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nSec-Tailscale: localapi\r\nConnection: close\r\n\r\n",
+            path, HOST
+        );
+        stream.write_all(request.as_bytes()).await?;
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        let end = response
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .ok_or_else(|| invalid("truncated LocalAPI response"))?;
+        let head = &response[..end + 4];
+        let body = &response[end + 4..];
+
+        match status_code(head)? {
+            200 => {}
+            404 => return Ok(None),
+            403 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("LocalAPI denied access to {}", path),
+                ))
+            }
+            code => {
+                return Err(io::Error::other(format!(
+                    "LocalAPI {} failed with HTTP {}: {}",
+                    path,
+                    code,
+                    String::from_utf8_lossy(body).trim()
+                )))
+            }
+        }
+
+        let chunked = String::from_utf8_lossy(head).lines().any(|line| {
+            line.split_once(':').is_some_and(|(name, value)| {
+                name.eq_ignore_ascii_case("transfer-encoding")
+                    && value.trim().eq_ignore_ascii_case("chunked")
+            })
+        });
+        if chunked {
+            decode_chunked(body).map(Some)
+        } else {
+            Ok(Some(body.to_vec()))
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn non_empty(s: &str) -> Option<String> {
+    (!s.is_empty()).then(|| s.to_string())
+}
+
+/// WireGuard key of a `nodekey:HEX` string (zero if malformed)
+fn node_key(key: &str) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    if let Some(bytes) = key
+        .strip_prefix("nodekey:")
+        .and_then(|hex| hex::decode(hex).ok())
+    {
+        if bytes.len() == 32 {
+            out.copy_from_slice(&bytes);
+        }
+    }
+    out
+}
+
+/// Seconds since the Unix epoch of an RFC 3339 time such as
+/// `2024-05-01T12:00:00.5+02:00`; `None` for earlier times
+fn unix_time(time: &str) -> Option<u64> {
+    let field = |range: std::ops::Range<usize>| -> Option<i64> { time.get(range)?.parse().ok() };
+    let (year, month, day) = (field(0..4)?, field(5..7)?, field(8..10)?);
+    let (hour, minute, second) = (field(11..13)?, field(14..16)?, field(17..19)?);
+
+    // Skip fractional seconds to the zone
+    let zone = time[19..].trim_start_matches(|c: char| c == '.' || c.is_ascii_digit());
+    let offset = match zone.as_bytes().first()? {
+        b'Z' | b'z' => 0,
+        sign @ (b'+' | b'-') => {
+            let hours: i64 = zone.get(1..3)?.parse().ok()?;
+            let minutes: i64 = zone.get(4..6)?.parse().ok()?;
+            let offset = hours * 3600 + minutes * 60;
+            if *sign == b'+' {
+                offset
+            } else {
+                -offset
+            }
+        }
+        _ => return None,
+    };
+
+    // Days from civil date (Howard Hinnant's algorithm)
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+
+    let secs = days * 86_400 + hour * 3600 + minute * 60 + second - offset;
+    u64::try_from(secs).ok()
+}
+
+/// Status code of an HTTP/1.x response head
+fn status_code(head: &[u8]) -> io::Result<u16> {
+    let head = String::from_utf8_lossy(head);
+    let status_line = head.lines().next().unwrap_or_default();
+    status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .filter(|_| status_line.starts_with("HTTP/1."))
+        .ok_or_else(|| invalid("malformed LocalAPI response"))
+}
+
+/// Body of a `Transfer-Encoding: chunked` response
+fn decode_chunked(mut body: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    loop {
+        let line_end = body
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .ok_or_else(|| invalid("truncated chunk"))?;
+        let size = std::str::from_utf8(&body[..line_end])
+            .ok()
+            .map(|line| line.split(';').next().unwrap_or_default().trim())
+            .and_then(|size| usize::from_str_radix(size, 16).ok())
+            .ok_or_else(|| invalid("malformed chunk size"))?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Ok(out);
+        }
+        if body.len() < size + 2 {
+            return Err(invalid("truncated chunk"));
+        }
+        out.extend_from_slice(&body[..size]);
+        body = &body[size + 2..];
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::vpn::SOCKS_CAPABILITY;
+    use tokio::net::UnixListener;
+
+    pub(crate) const STATUS: &str = r#"{
+        "BackendState": "Running",
+        "TUN": false,
+        "Self": {
+            "PublicKey": "nodekey:0505050505050505050505050505050505050505050505050505050505050505",
+            "DNSName": "laptop.tail1234.ts.net.",
+            "TailscaleIPs": ["100.64.0.5", "fd7a:115c:a1e0::5"],
+            "Relay": "fra"
+        },
+        "MagicDNSSuffix": "tail1234.ts.net",
+        "Peer": {
+            "nodekey:01": {
+                "PublicKey": "nodekey:0101010101010101010101010101010101010101010101010101010101010101",
+                "DNSName": "db-1.tail1234.ts.net.",
+                "TailscaleIPs": ["100.64.0.2"],
+                "UserID": 7,
+                "AllowedIPs": ["100.64.0.2/32", "10.0.0.0/24"],
+                "CurAddr": "192.0.2.10:41641",
+                "Relay": "fra",
+                "Online": true,
+                "RxBytes": 2048,
+                "TxBytes": 512,
+                "LastHandshake": "2024-05-01T12:00:00.123456789+02:00"
+            },
+            "nodekey:02": {
+                "DNSName": "ci-1.tail1234.ts.net.",
+                "TailscaleIPs": ["100.64.0.3"],
+                "Tags": ["tag:ci"],
+                "UserID": 8,
+                "LastHandshake": "0001-01-01T00:00:00Z"
+            }
+        },
+        "User": {"7": {"LoginName": "alice@example.com"}, "8": {"LoginName": "tagged-devices"}}
+    }"#;
+
+    /// Fake tailscaled: status, WhoIs for 100.64.0.2, and dials that echo
+    pub(crate) fn spawn_fake(dir: &Path) -> LocalApi {
+        let path = dir.join("tailscaled.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut head = Vec::new();
+                    while !head.ends_with(b"\r\n\r\n") {
+                        head.push(stream.read_u8().await.unwrap());
+                    }
+                    let head = String::from_utf8(head).unwrap();
+                    let path = head.split_whitespace().nth(1).unwrap().to_string();
+                    if path == "/localapi/v0/dial" {
+                        assert!(head.contains("Dial-Host: 100.64.0.2\r\n"));
+                        stream
+                            .write_all(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: ts-dial\r\n\r\n")
+                            .await
+                            .unwrap();
+                        let (mut r, mut w) = stream.split();
+                        let _ = tokio::io::copy(&mut r, &mut w).await;
+                        return;
+                    }
+                    let response = match path.as_str() {
+                        "/localapi/v0/status" => format!(
+                            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                             Transfer-Encoding: chunked\r\n\r\n{:x}\r\n{}\r\n0\r\n\r\n",
+                            STATUS.len(),
+                            STATUS
+                        ),
+                        // 127.0.0.1 stands in for a peer reaching a host socket
+                        "/localapi/v0/whois?addr=100.64.0.2"
+                        | "/localapi/v0/whois?addr=127.0.0.1" => {
+                            let body = r#"{"Node": {"Name": "db-1.tail1234.ts.net.", "Addresses": ["100.64.0.2/32"]}, "UserProfile": {"LoginName": "alice@example.com"}, "CapMap": {"socktail.dev/cap/socks": [{}]}}"#;
+                            format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body)
+                        }
+                        "/localapi/v0/whois?addr=100.64.0.3" => {
+                            let body = r#"{"Node": {"Name": "ci.tail1234.ts.net.", "Addresses": ["100.64.0.3/32"], "Tags": ["tag:ci"]}}"#;
+                            format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body)
+                        }
+                        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_string(),
+                    };
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                });
+            }
+        });
+        LocalApi::new(path)
+    }
+
+    #[test]
+    fn test_unix_time() {
+        assert_eq!(unix_time("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(unix_time("2000-03-01T00:00:01Z"), Some(951_868_801));
+        assert_eq!(unix_time("2024-05-01T10:00:00.5-02:00"), Some(1_714_564_800));
+        assert_eq!(unix_time("0001-01-01T00:00:00Z"), None);
+        assert_eq!(unix_time("yesterday"), None);
+    }
+
+    #[test]
+    fn test_decode_chunked() {
+        assert_eq!(decode_chunked(b"3\r\nabc\r\n2;x=y\r\nde\r\n0\r\n\r\n").unwrap(), b"abcde");
+        assert!(decode_chunked(b"5\r\nabc").is_err());
+        assert!(decode_chunked(b"zz\r\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_status_and_whois() {
+        let dir = tempfile::tempdir().unwrap();
+        let api = spawn_fake(dir.path());
+
+        let status = api.status().await.unwrap();
+        assert_eq!(status.backend_state, "Running");
+        assert!(!status.tun);
+        assert_eq!(status.addresses().len(), 2);
+        assert_eq!(status.domain().as_deref(), Some("tail1234.ts.net"));
+
+        let mut peers = status.peers();
+        peers.sort_by_key(|p| p.tailscale_ip);
+        assert_eq!(peers[0].name.as_deref(), Some("db-1.tail1234.ts.net"));
+        assert_eq!(peers[0].public_key, [1u8; 32]);
+        assert_eq!(peers[0].user.as_deref(), Some("alice@example.com"));
+        assert_eq!(peers[0].allowed_ips, vec!["10.0.0.0/24".parse().unwrap()]);
+        assert_eq!(peers[0].endpoint, Some("192.0.2.10:41641".parse().unwrap()));
+        assert_eq!(peers[0].online, Some(true));
+        assert_eq!(peers[0].derp.as_deref(), Some("fra"));
+        assert_eq!(peers[1].user, None);
+        assert_eq!(peers[1].tags, vec!["tag:ci".to_string()]);
+
+        let node = status.node_status();
+        assert!(node.is_running());
+        assert_eq!(node.self_node.name.as_deref(), Some("laptop.tail1234.ts.net"));
+        assert_eq!(node.derp_home.as_deref(), Some("fra"));
+        let mut peers = node.peers.unwrap();
+        peers.sort_by_key(|p| p.addresses.clone());
+        assert_eq!(peers[0].path, PeerPath::Direct);
+        assert_eq!(peers[0].last_handshake, Some(1_714_557_600));
+        assert_eq!((peers[0].rx_bytes, peers[0].tx_bytes), (2048, 512));
+        assert_eq!(peers[1].path, PeerPath::Idle);
+        assert_eq!(peers[1].last_handshake, None);
+
+        let whois = api.whois("100.64.0.2".parse().unwrap()).await.unwrap().unwrap();
+        assert_eq!(whois.node, "db-1.tail1234.ts.net");
+        assert_eq!(whois.user.as_deref(), Some("alice@example.com"));
+        assert_eq!(whois.caps, Some(vec![SOCKS_CAPABILITY.to_string()]));
+        let whois = api.whois("100.64.0.3".parse().unwrap()).await.unwrap().unwrap();
+        assert_eq!(whois.tags, vec!["tag:ci".to_string()]);
+        assert_eq!(whois.caps, Some(Vec::new()));
+        assert_eq!(api.whois("100.64.0.9".parse().unwrap()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_dial() {
+        let dir = tempfile::tempdir().unwrap();
+        let api = spawn_fake(dir.path());
+
+        let mut stream = api.dial("100.64.0.2", 5432).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        let missing = LocalApi::new(dir.path().join("missing.sock"));
+        assert!(missing.status().await.is_err());
+    }
+}
@@ -1,9 +1,44 @@
 //! VPN integration (Tailscale) - Pure Rust implementation
 
+pub mod backend;
+pub mod dial;
+pub mod exit_node;
+pub mod filter;
+pub mod forwarder;
+#[cfg(all(unix, feature = "libtailscale"))]
+pub mod libtailscale;
+#[cfg(unix)]
+pub mod localapi;
+pub mod netmap;
+pub mod netstack;
+pub mod serve;
+pub mod status;
 pub mod tailscale_rust;
+#[cfg(unix)]
+pub mod tailscaled;
+pub mod tun_device;
+pub mod wg_quick;
+pub mod wireguard;
 
 // Re-export pure Rust implementation as the default
+pub use backend::{BackendConfig, BackendKind, DirectBackend, SharedBackend, VpnBackend};
+pub use dial::{Network, TailnetConn};
+pub use exit_node::{ExitNode, ExitNodeSelector};
+pub use filter::{PacketFilter, SOCKS_CAPABILITY};
+#[cfg(unix)]
+pub use localapi::LocalApi;
+pub use netmap::{PeerInfo, PeerMap, WhoIs};
+pub use netstack::{
+    Forwarded, Netstack, TailnetListener, TailnetStream, TailnetUdpConn, TailnetUdpSocket,
+};
+pub use serve::Serve;
+pub use status::{BackendState, NodeStatus, PeerPath, PeerStatus, Status};
 pub use tailscale_rust::TailscaleRust;
+#[cfg(unix)]
+pub use tailscaled::TailscaledBackend;
+pub use tun_device::TunDevice;
+pub use wg_quick::{WgQuickConfig, WireGuardBackend};
+pub use wireguard::{WgDevice, WgPeerStats};
 
 // Type alias for backward compatibility
 pub type TailscaleNative = TailscaleRust;
@@ -0,0 +1,359 @@
+//! Shared view of the tailnet network map
+//!
+//! `TailscaleRust` owns the map and refreshes it from the control server
+//! every minute; the SOCKS server and routing code hold cheap clones of
+//! [`PeerMap`] to resolve MagicDNS names and look up peers by address.
+
+use super::filter::PacketFilter;
+use crate::utils::Cidr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, RwLock};
+
+/// Tailscale CGNAT range for node IPv4 addresses
+pub const TAILNET_IPV4_RANGE: &str = "100.64.0.0/10";
+
+/// Tailscale ULA range for node IPv6 addresses
+pub const TAILNET_IPV6_RANGE: &str = "fd7a:115c:a1e0::/48";
+
+/// Domain suffix of MagicDNS names on tailscale.com
+pub const MAGICDNS_SUFFIX: &str = ".ts.net";
+
+/// A peer from the network map
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    /// Peer public key
+    pub public_key: [u8; 32],
+    /// MagicDNS name without trailing dot (e.g. `db-1.tail1234.ts.net`)
+    pub name: Option<String>,
+    /// Peer Tailscale IP
+    pub tailscale_ip: IpAddr,
+    /// All tailnet addresses of the peer
+    pub addresses: Vec<IpAddr>,
+    /// ACL tags (e.g. `tag:prod`)
+    pub tags: Vec<String>,
+    /// Login name of the owning user (e.g. `alice@example.com`); tagged
+    /// nodes are owned by their tags instead
+    pub user: Option<String>,
+    /// Prefixes routed to the peer besides its own addresses: advertised
+    /// subnets, and `0.0.0.0/0` plus `::/0` for exit nodes
+    pub allowed_ips: Vec<Cidr>,
+    /// Peer WireGuard endpoint
+    pub endpoint: Option<SocketAddr>,
+    /// Whether the control server sees the peer connected, if it says
+    pub online: Option<bool>,
+    /// Home DERP region of the peer
+    pub derp: Option<String>,
+}
+
+impl Default for PeerInfo {
+    fn default() -> Self {
+        Self {
+            public_key: [0u8; 32],
+            name: None,
+            tailscale_ip: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            addresses: Vec::new(),
+            tags: Vec::new(),
+            user: None,
+            allowed_ips: Vec::new(),
+            endpoint: None,
+            online: None,
+            derp: None,
+        }
+    }
+}
+
+impl PeerInfo {
+    /// First label of the MagicDNS name
+    pub fn short_name(&self) -> Option<&str> {
+        self.name.as_deref().and_then(|n| n.split('.').next())
+    }
+
+    /// Check whether the peer owns `ip`
+    pub fn has_address(&self, ip: &IpAddr) -> bool {
+        self.tailscale_ip == *ip || self.addresses.contains(ip)
+    }
+
+    /// Check whether the peer offers itself as an exit node
+    pub fn is_exit_node(&self) -> bool {
+        let default_v4 = self
+            .allowed_ips
+            .iter()
+            .any(|c| c.is_default_route() && c.addr().is_ipv4());
+        let default_v6 = self
+            .allowed_ips
+            .iter()
+            .any(|c| c.is_default_route() && c.addr().is_ipv6());
+        default_v4 && default_v6
+    }
+
+    /// Longest advertised subnet (not a default route) that contains `ip`
+    pub fn subnet_route(&self, ip: &IpAddr) -> Option<Cidr> {
+        self.allowed_ips
+            .iter()
+            .filter(|c| !c.is_default_route() && c.contains(ip))
+            .max_by_key(|c| c.prefix_len())
+            .copied()
+    }
+}
+
+/// Who is behind a tailnet address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WhoIs {
+    /// MagicDNS name of the node, or its address if it has none
+    pub node: String,
+    /// Tailscale IP of the node
+    pub addr: IpAddr,
+    /// Login name of the owning user
+    pub user: Option<String>,
+    /// ACL tags of the node
+    pub tags: Vec<String>,
+    /// Capabilities the tailnet policy grants the node on this one, when
+    /// the source reports them; otherwise the packet filter has the grants
+    pub caps: Option<Vec<String>>,
+}
+
+impl From<&PeerInfo> for WhoIs {
+    fn from(peer: &PeerInfo) -> Self {
+        Self {
+            node: peer
+                .name
+                .clone()
+                .unwrap_or_else(|| peer.tailscale_ip.to_string()),
+            addr: peer.tailscale_ip,
+            user: peer.user.clone(),
+            tags: peer.tags.clone(),
+            caps: None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct NetMapState {
+    peers: Vec<PeerInfo>,
+    /// Tailnet domain for MagicDNS (e.g. `tail1234.ts.net`)
+    domain: Option<String>,
+    /// Packet filter and capability grants from the control server
+    filter: Option<Arc<PacketFilter>>,
+}
+
+/// Cloneable handle to the current peer list
+#[derive(Debug, Clone, Default)]
+pub struct PeerMap {
+    inner: Arc<RwLock<NetMapState>>,
+}
+
+impl PeerMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace the peer list
+    pub fn update(&self, peers: Vec<PeerInfo>, domain: Option<String>) {
+        let mut state = self.inner.write().unwrap();
+        state.peers = peers;
+        state.domain = domain.map(|d| normalize(&d));
+    }
+
+    /// Remove all peers and the packet filter
+    pub fn clear(&self) {
+        self.update(Vec::new(), None);
+        self.inner.write().unwrap().filter = None;
+    }
+
+    /// Replace the packet filter
+    pub fn set_filter(&self, filter: Arc<PacketFilter>) {
+        self.inner.write().unwrap().filter = Some(filter);
+    }
+
+    /// Current packet filter; `None` until the control server sent one
+    pub fn filter(&self) -> Option<Arc<PacketFilter>> {
+        self.inner.read().unwrap().filter.clone()
+    }
+
+    /// Snapshot of all peers
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.inner.read().unwrap().peers.clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.read().unwrap().peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Tailnet MagicDNS domain, if known
+    pub fn domain(&self) -> Option<String> {
+        self.inner.read().unwrap().domain.clone()
+    }
+
+    /// Find the peer that owns `ip`
+    pub fn peer_by_ip(&self, ip: &IpAddr) -> Option<PeerInfo> {
+        let ip = ip.to_canonical();
+        self.inner
+            .read()
+            .unwrap()
+            .peers
+            .iter()
+            .find(|p| p.has_address(&ip))
+            .cloned()
+    }
+
+    /// Peers that offer themselves as exit nodes
+    pub fn exit_nodes(&self) -> Vec<PeerInfo> {
+        self.inner
+            .read()
+            .unwrap()
+            .peers
+            .iter()
+            .filter(|p| p.is_exit_node())
+            .cloned()
+            .collect()
+    }
+
+    /// Identify the node and user that own `ip`
+    pub fn whois(&self, ip: &IpAddr) -> Option<WhoIs> {
+        self.peer_by_ip(ip).as_ref().map(WhoIs::from)
+    }
+
+    /// Find a peer by MagicDNS name (full name or bare host label)
+    pub fn peer_by_name(&self, name: &str) -> Option<PeerInfo> {
+        let name = normalize(name);
+        let state = self.inner.read().unwrap();
+        let host = match &state.domain {
+            Some(domain) => name
+                .strip_suffix(domain.as_str())
+                .and_then(|h| h.strip_suffix('.'))
+                .unwrap_or(&name),
+            None => &name,
+        };
+
+        state
+            .peers
+            .iter()
+            .find(|p| {
+                p.name.as_deref().map(normalize).as_deref() == Some(name.as_str())
+                    || (!host.contains('.') && p.short_name().map(normalize).as_deref() == Some(host))
+            })
+            .cloned()
+    }
+
+    /// Resolve a MagicDNS name to the peer's Tailscale IP
+    pub fn resolve(&self, name: &str) -> Option<IpAddr> {
+        self.peer_by_name(name).map(|p| p.tailscale_ip)
+    }
+
+    /// Check whether `name` belongs to the tailnet's MagicDNS namespace
+    pub fn is_magicdns_name(&self, name: &str) -> bool {
+        let name = normalize(name);
+        if name.ends_with(MAGICDNS_SUFFIX) {
+            return true;
+        }
+        if let Some(domain) = &self.inner.read().unwrap().domain {
+            if name == *domain || name.ends_with(&format!(".{}", domain)) {
+                return true;
+            }
+        }
+        self.peer_by_name(&name).is_some()
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(name: &str, ip: &str, tags: &[&str]) -> PeerInfo {
+        PeerInfo {
+            name: Some(name.to_string()),
+            tailscale_ip: ip.parse().unwrap(),
+            addresses: vec![ip.parse().unwrap()],
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_lookup() {
+        let map = PeerMap::new();
+        map.update(
+            vec![
+                peer("db-1.tail1234.ts.net", "100.64.0.5", &["tag:db"]),
+                peer("laptop.tail1234.ts.net", "100.64.0.6", &[]),
+            ],
+            Some("tail1234.ts.net.".to_string()),
+        );
+
+        assert_eq!(map.resolve("db-1"), Some("100.64.0.5".parse().unwrap()));
+        assert_eq!(map.resolve("DB-1.tail1234.ts.net."), Some("100.64.0.5".parse().unwrap()));
+        assert_eq!(map.resolve("db-1.example.com"), None);
+        assert_eq!(
+            map.peer_by_ip(&"100.64.0.5".parse().unwrap()).unwrap().tags,
+            vec!["tag:db"]
+        );
+        assert!(map.peer_by_ip(&"100.64.0.9".parse().unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_whois() {
+        let map = PeerMap::new();
+        let mut laptop = peer("laptop.tail1234.ts.net", "100.64.0.6", &[]);
+        laptop.user = Some("alice@example.com".to_string());
+        laptop.addresses.push("fd7a:115c:a1e0::6".parse().unwrap());
+        map.update(vec![laptop, peer("ci.tail1234.ts.net", "100.64.0.7", &["tag:ci"])], None);
+
+        let who = map.whois(&"fd7a:115c:a1e0::6".parse().unwrap()).unwrap();
+        assert_eq!(who.node, "laptop.tail1234.ts.net");
+        assert_eq!(who.addr, "100.64.0.6".parse::<IpAddr>().unwrap());
+        assert_eq!(who.user.as_deref(), Some("alice@example.com"));
+
+        // IPv4-mapped addresses from dual-stack sockets are found too
+        let who = map.whois(&"::ffff:100.64.0.7".parse().unwrap()).unwrap();
+        assert_eq!(who.tags, vec!["tag:ci"]);
+        assert!(who.user.is_none());
+        assert!(map.whois(&"100.64.0.9".parse().unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_routes() {
+        let map = PeerMap::new();
+        let mut gw = peer("gw.tail1234.ts.net", "100.64.0.8", &[]);
+        gw.allowed_ips = ["10.0.0.0/8", "10.1.0.0/16", "0.0.0.0/0", "::/0"]
+            .iter()
+            .map(|c| c.parse().unwrap())
+            .collect();
+        let mut router = peer("router.tail1234.ts.net", "100.64.0.9", &[]);
+        router.allowed_ips = vec!["0.0.0.0/0".parse().unwrap()];
+        map.update(vec![gw, router], None);
+
+        let exits = map.exit_nodes();
+        assert_eq!(exits.len(), 1);
+        assert_eq!(
+            exits[0].subnet_route(&"10.1.2.3".parse().unwrap()),
+            Some("10.1.0.0/16".parse().unwrap())
+        );
+        assert_eq!(exits[0].subnet_route(&"192.0.2.1".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn test_magicdns_names() {
+        let map = PeerMap::new();
+        map.update(
+            vec![peer("db-1.corp.example", "100.64.0.5", &[])],
+            Some("corp.example".to_string()),
+        );
+
+        assert!(map.is_magicdns_name("anything.tail1234.ts.net"));
+        assert!(map.is_magicdns_name("db-1"));
+        assert!(map.is_magicdns_name("other.corp.example"));
+        assert!(!map.is_magicdns_name("example.com"));
+
+        map.clear();
+        assert!(map.is_empty());
+        assert!(!map.is_magicdns_name("db-1"));
+    }
+}
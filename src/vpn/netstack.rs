@@ -0,0 +1,1118 @@
+//! Userspace TCP/IP stack on the node's tailnet addresses
+//!
+//! The WireGuard device hands decrypted IP packets to [`Netstack::inject`]
+//! and sends whatever the stack emits on its outbound channel. smoltcp
+//! terminates TCP, so tailnet peers can reach sockets on `100.x.y.z` without
+//! a TUN device or root privileges.
+//!
+//! UDP is not handed to smoltcp: datagrams for a port bound with
+//! [`Netstack::bind_udp`] are delivered to that socket directly.
+//!
+//! With [`Netstack::forward`] the stack also terminates TCP connections and
+//! UDP datagrams addressed to advertised routes, so that they can be
+//! re-originated from the host (subnet router and exit node).
+
+use crate::utils::Cidr;
+use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::phy::{Device, DeviceCapabilities, Medium, RxToken, TxToken};
+use smoltcp::socket::tcp;
+use smoltcp::time::Instant as SmolInstant;
+use smoltcp::wire::{
+    HardwareAddress, IpAddress, IpCidr, IpEndpoint, IpListenEndpoint, IpProtocol, IpRepr,
+    Ipv4Packet, Ipv6Packet, TcpPacket, UdpPacket, UdpRepr,
+};
+use std::collections::{HashMap, VecDeque};
+use std::future::poll_fn;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{mpsc, Notify};
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+
+/// Tailscale's default tunnel MTU
+pub const TAILNET_MTU: usize = 1280;
+
+/// Per-direction buffer of each TCP socket
+const TCP_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Listening sockets kept open per port, so that simultaneous SYNs are not reset
+const LISTEN_BACKLOG: usize = 4;
+
+/// How long [`Netstack::connect`] waits for the handshake
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// First local port for outgoing connections
+const EPHEMERAL_PORT_START: u16 = 49152;
+
+/// Packet queues between smoltcp and the WireGuard device
+#[derive(Default)]
+struct PacketQueue {
+    rx: VecDeque<Vec<u8>>,
+    tx: VecDeque<Vec<u8>>,
+}
+
+struct QueueRxToken(Vec<u8>);
+
+impl RxToken for QueueRxToken {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(&self.0)
+    }
+}
+
+struct QueueTxToken<'a>(&'a mut VecDeque<Vec<u8>>);
+
+impl TxToken for QueueTxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut packet = vec![0u8; len];
+        let result = f(&mut packet);
+        self.0.push_back(packet);
+        result
+    }
+}
+
+impl Device for PacketQueue {
+    type RxToken<'a> = QueueRxToken;
+    type TxToken<'a> = QueueTxToken<'a>;
+
+    fn receive(
+        &mut self,
+        _timestamp: SmolInstant,
+    ) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let packet = self.rx.pop_front()?;
+        Some((QueueRxToken(packet), QueueTxToken(&mut self.tx)))
+    }
+
+    fn transmit(&mut self, _timestamp: SmolInstant) -> Option<Self::TxToken<'_>> {
+        Some(QueueTxToken(&mut self.tx))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ip;
+        caps.max_transmission_unit = TAILNET_MTU;
+        caps
+    }
+}
+
+/// Datagram and sender address queued for a [`TailnetUdpSocket`]
+type Datagram = (Vec<u8>, SocketAddr);
+
+/// Listening sockets for one port, on each stack address
+struct Listening {
+    backlog: Vec<(IpListenEndpoint, SocketHandle)>,
+    accept: mpsc::UnboundedSender<TailnetStream>,
+}
+
+/// Traffic for an advertised route, to be re-originated from the host
+#[derive(Debug)]
+pub enum Forwarded {
+    /// Connection whose local address is the original destination
+    Tcp(TailnetStream),
+    /// Datagram from a peer; answer with [`Netstack::send_udp`]
+    Udp {
+        src: SocketAddr,
+        dst: SocketAddr,
+        payload: Vec<u8>,
+    },
+}
+
+/// Advertised routes terminated by the stack
+struct Forwarding {
+    routes: Vec<Cidr>,
+    sender: mpsc::UnboundedSender<Forwarded>,
+    /// Sockets listening for one SYN each, with when they were opened
+    pending: Vec<(SocketHandle, Instant)>,
+}
+
+impl Forwarding {
+    /// Exit node routes do not reach the host's loopback addresses; only a
+    /// route advertising them explicitly does
+    fn routes(&self, ip: &IpAddr) -> bool {
+        // Routes match IPv4-mapped addresses as IPv4, so must the exclusions
+        let ip = ip.to_canonical();
+        !ip.is_unspecified()
+            && !ip.is_multicast()
+            && self
+                .routes
+                .iter()
+                .any(|route| route.contains(&ip) && !(route.is_default_route() && ip.is_loopback()))
+    }
+}
+
+/// Addresses and payload of an IP packet
+struct IpHeader<'a> {
+    protocol: IpProtocol,
+    src: IpAddr,
+    dst: IpAddr,
+    payload: &'a [u8],
+}
+
+impl<'a> IpHeader<'a> {
+    fn parse(packet: &'a [u8]) -> Option<Self> {
+        match packet.first()? >> 4 {
+            4 => {
+                let ip = Ipv4Packet::new_checked(packet).ok()?;
+                Some(Self {
+                    protocol: ip.next_header(),
+                    src: ip.src_addr().into(),
+                    dst: ip.dst_addr().into(),
+                    payload: &packet[ip.header_len() as usize..ip.total_len() as usize],
+                })
+            }
+            6 => {
+                let ip = Ipv6Packet::new_checked(packet).ok()?;
+                Some(Self {
+                    protocol: ip.next_header(),
+                    src: ip.src_addr().into(),
+                    dst: ip.dst_addr().into(),
+                    payload: &packet[ip.header_len()..ip.total_len()],
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+struct State {
+    iface: Interface,
+    device: PacketQueue,
+    sockets: SocketSet<'static>,
+    listeners: HashMap<u16, Listening>,
+    /// Bound UDP ports
+    udp: HashMap<u16, mpsc::UnboundedSender<Datagram>>,
+    forwarding: Option<Forwarding>,
+    /// Sockets whose stream was dropped, removed once fully closed
+    closing: Vec<SocketHandle>,
+    next_port: u16,
+    stopped: bool,
+}
+
+impl State {
+    fn new_socket(&mut self) -> SocketHandle {
+        let socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0u8; TCP_BUFFER_SIZE]),
+            tcp::SocketBuffer::new(vec![0u8; TCP_BUFFER_SIZE]),
+        );
+        self.sockets.add(socket)
+    }
+
+    fn listen_socket(&mut self, endpoint: IpListenEndpoint) -> io::Result<SocketHandle> {
+        let handle = self.new_socket();
+        if let Err(e) = self.sockets.get_mut::<tcp::Socket>(handle).listen(endpoint) {
+            self.sockets.remove(handle);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, e.to_string()));
+        }
+        Ok(handle)
+    }
+
+    /// Look at a packet before smoltcp does; returns whether to pass it on
+    ///
+    /// Packets for advertised routes open a listening socket on their
+    /// destination (TCP) or go straight to the forwarder (UDP).
+    fn screen(&mut self, packet: &[u8], addrs: &[IpAddr]) -> bool {
+        if self.forwarding.is_none() && self.udp.is_empty() {
+            // smoltcp drops packets for other addresses by itself
+            return true;
+        }
+        let Some(ip) = IpHeader::parse(packet) else {
+            return false;
+        };
+        if addrs.contains(&ip.dst) {
+            return !self.deliver_udp(&ip);
+        }
+        let Some(forwarding) = &self.forwarding else {
+            return false;
+        };
+        if !forwarding.routes(&ip.dst) {
+            return false;
+        }
+
+        match ip.protocol {
+            IpProtocol::Tcp => {
+                let Ok(tcp) = TcpPacket::new_checked(ip.payload) else {
+                    return false;
+                };
+                if tcp.syn() && !tcp.ack() {
+                    let src = SocketAddr::new(ip.src, tcp.src_port());
+                    let dst = SocketAddr::new(ip.dst, tcp.dst_port());
+                    self.forward_listen(src, dst);
+                }
+                true
+            }
+            IpProtocol::Udp => {
+                if let Ok(udp) = UdpPacket::new_checked(ip.payload) {
+                    let _ = forwarding.sender.send(Forwarded::Udp {
+                        src: SocketAddr::new(ip.src, udp.src_port()),
+                        dst: SocketAddr::new(ip.dst, udp.dst_port()),
+                        payload: udp.payload().to_vec(),
+                    });
+                }
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// Queue a datagram for the socket bound to its port; returns whether
+    /// there was one
+    fn deliver_udp(&mut self, ip: &IpHeader) -> bool {
+        if ip.protocol != IpProtocol::Udp {
+            return false;
+        }
+        let Ok(udp) = UdpPacket::new_checked(ip.payload) else {
+            return false;
+        };
+        let Some(socket) = self.udp.get(&udp.dst_port()) else {
+            return false;
+        };
+        let src = SocketAddr::new(ip.src, udp.src_port());
+        let _ = socket.send((udp.payload().to_vec(), src));
+        true
+    }
+
+    /// Listen on a forwarded destination for a new connection from `src`
+    fn forward_listen(&mut self, src: SocketAddr, dst: SocketAddr) {
+        let Some(forwarding) = &self.forwarding else {
+            return;
+        };
+        // A retransmitted SYN belongs to the socket that took the first one
+        let known = forwarding.pending.iter().any(|(handle, _)| {
+            let socket = self.sockets.get::<tcp::Socket>(*handle);
+            socket.remote_endpoint().map(endpoint_addr) == Some(src)
+                && socket.local_endpoint().map(endpoint_addr) == Some(dst)
+        });
+        if known {
+            return;
+        }
+
+        let endpoint = IpListenEndpoint {
+            addr: Some(dst.ip().into()),
+            port: dst.port(),
+        };
+        if let Ok(handle) = self.listen_socket(endpoint) {
+            if let Some(forwarding) = &mut self.forwarding {
+                forwarding.pending.push((handle, Instant::now()));
+            }
+        }
+    }
+
+    fn ephemeral_port(&mut self) -> u16 {
+        let port = self.next_port;
+        self.next_port = match port.checked_add(1) {
+            Some(next) => next,
+            None => EPHEMERAL_PORT_START,
+        };
+        port
+    }
+
+    /// Hand sockets that left the listen state to their acceptor
+    fn accept_connections(&mut self, shared: &Arc<Shared>) {
+        let mut accepted = Vec::new();
+        for (port, listening) in &mut self.listeners {
+            for (endpoint, slot) in &mut listening.backlog {
+                let socket = self.sockets.get::<tcp::Socket>(*slot);
+                if socket.state() == tcp::State::Listen {
+                    continue;
+                }
+                accepted.push((
+                    *port,
+                    *endpoint,
+                    *slot,
+                    socket.local_endpoint(),
+                    socket.remote_endpoint(),
+                ));
+            }
+        }
+
+        for (port, endpoint, handle, local, remote) in accepted {
+            let (Some(local), Some(remote)) = (local, remote) else {
+                continue;
+            };
+            let Ok(replacement) = self.listen_socket(endpoint) else {
+                continue;
+            };
+            let listening = self.listeners.get_mut(&port).expect("listener exists");
+            if let Some(slot) = listening.backlog.iter_mut().find(|(_, h)| *h == handle) {
+                slot.1 = replacement;
+            }
+
+            let stream = TailnetStream {
+                shared: shared.clone(),
+                handle,
+                local: endpoint_addr(local),
+                peer: endpoint_addr(remote),
+            };
+            debug!("Tailnet connection {} -> {}", stream.peer, stream.local);
+            // A dropped listener drops the stream, which closes the socket
+            let _ = listening.accept.send(stream);
+        }
+
+        self.accept_forwarded(shared);
+    }
+
+    /// Hand connections to advertised routes to the forwarder, and close
+    /// listening sockets whose SYN never came
+    fn accept_forwarded(&mut self, shared: &Arc<Shared>) {
+        let Some(forwarding) = &mut self.forwarding else {
+            return;
+        };
+        let sockets = &mut self.sockets;
+        forwarding.pending.retain(|(handle, opened)| {
+            let socket = sockets.get::<tcp::Socket>(*handle);
+            if socket.state() == tcp::State::Listen {
+                if opened.elapsed() < CONNECT_TIMEOUT {
+                    return true;
+                }
+                sockets.remove(*handle);
+                return false;
+            }
+
+            match (socket.local_endpoint(), socket.remote_endpoint()) {
+                (Some(local), Some(remote)) => {
+                    let stream = TailnetStream {
+                        shared: shared.clone(),
+                        handle: *handle,
+                        local: endpoint_addr(local),
+                        peer: endpoint_addr(remote),
+                    };
+                    debug!("Forwarding {} -> {}", stream.peer, stream.local);
+                    let _ = forwarding.sender.send(Forwarded::Tcp(stream));
+                }
+                _ => {
+                    sockets.remove(*handle);
+                }
+            }
+            false
+        });
+    }
+
+    fn remove_closed(&mut self) {
+        let sockets = &mut self.sockets;
+        self.closing.retain(|handle| {
+            let closed = sockets.get::<tcp::Socket>(*handle).state() == tcp::State::Closed;
+            if closed {
+                sockets.remove(*handle);
+            }
+            !closed
+        });
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    addrs: Vec<IpAddr>,
+    /// Wakes the poll task after stream I/O or injected packets
+    notify: Notify,
+    outbound: mpsc::UnboundedSender<Vec<u8>>,
+    shutdown: CancellationToken,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+/// Handle to a running userspace stack
+#[derive(Clone)]
+pub struct Netstack {
+    shared: Arc<Shared>,
+}
+
+impl Netstack {
+    /// Start a stack that owns `addrs`; packets it emits go to `outbound`
+    ///
+    /// Must be called from within a Tokio runtime.
+    pub fn new(addrs: &[IpAddr], outbound: mpsc::UnboundedSender<Vec<u8>>) -> Self {
+        let mut device = PacketQueue::default();
+        let mut config = Config::new(HardwareAddress::Ip);
+        config.random_seed = rand::random();
+        let mut iface = Interface::new(config, &mut device, SmolInstant::now());
+
+        iface.update_ip_addrs(|cidrs| {
+            for addr in addrs {
+                let prefix = if addr.is_ipv4() { 32 } else { 128 };
+                let _ = cidrs.push(IpCidr::new(IpAddress::from(*addr), prefix));
+            }
+        });
+        // Every other address is reached through the tunnel
+        for addr in addrs {
+            let _ = match addr {
+                IpAddr::V4(v4) => iface.routes_mut().add_default_ipv4_route(*v4),
+                IpAddr::V6(v6) => iface.routes_mut().add_default_ipv6_route(*v6),
+            };
+        }
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                iface,
+                device,
+                sockets: SocketSet::new(Vec::new()),
+                listeners: HashMap::new(),
+                udp: HashMap::new(),
+                forwarding: None,
+                closing: Vec::new(),
+                next_port: EPHEMERAL_PORT_START,
+                stopped: false,
+            }),
+            addrs: addrs.to_vec(),
+            notify: Notify::new(),
+            outbound,
+            shutdown: CancellationToken::new(),
+        });
+
+        tokio::spawn(run(shared.clone()));
+        Self { shared }
+    }
+
+    /// Addresses owned by the stack
+    pub fn addrs(&self) -> &[IpAddr] {
+        &self.shared.addrs
+    }
+
+    /// Feed an IP packet received from the tailnet
+    pub fn inject(&self, packet: Vec<u8>) {
+        let mut state = self.shared.lock();
+        if state.screen(&packet, &self.shared.addrs) {
+            state.device.rx.push_back(packet);
+        }
+        drop(state);
+        self.shared.notify.notify_one();
+    }
+
+    /// Terminate TCP and UDP traffic addressed to `routes` instead of
+    /// dropping it; the returned channel yields what arrives
+    pub fn forward(&self, routes: &[Cidr]) -> mpsc::UnboundedReceiver<Forwarded> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let mut state = self.shared.lock();
+        state.iface.set_any_ip(true);
+        state.forwarding = Some(Forwarding {
+            routes: routes.to_vec(),
+            sender,
+            pending: Vec::new(),
+        });
+        receiver
+    }
+
+    /// Send a UDP datagram from a forwarded destination back to a peer
+    pub fn send_udp(&self, src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> io::Result<()> {
+        let packet = udp_packet(src, dst, payload)?;
+        self.shared.outbound.send(packet).map_err(|_| stopped())
+    }
+
+    /// Bind a UDP port on the stack addresses; `0` picks a free one
+    pub fn bind_udp(&self, port: u16) -> io::Result<TailnetUdpSocket> {
+        let mut state = self.shared.lock();
+        if state.stopped {
+            return Err(stopped());
+        }
+        let port = match port {
+            0 => {
+                let mut free = None;
+                for _ in EPHEMERAL_PORT_START..=u16::MAX {
+                    let port = state.ephemeral_port();
+                    if !state.udp.contains_key(&port) {
+                        free = Some(port);
+                        break;
+                    }
+                }
+                free.ok_or_else(|| io::Error::from(io::ErrorKind::AddrInUse))?
+            }
+            port if state.udp.contains_key(&port) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("tailnet UDP port {} is already in use", port),
+                ));
+            }
+            port => port,
+        };
+        let (sender, incoming) = mpsc::unbounded_channel();
+        state.udp.insert(port, sender);
+
+        Ok(TailnetUdpSocket {
+            shared: self.shared.clone(),
+            port,
+            incoming: tokio::sync::Mutex::new(incoming),
+        })
+    }
+
+    /// Accept TCP connections to `port` on every stack address
+    pub fn listen(&self, port: u16) -> io::Result<TailnetListener> {
+        let mut state = self.shared.lock();
+        if state.stopped {
+            return Err(stopped());
+        }
+        if state.listeners.contains_key(&port) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("tailnet port {} is already in use", port),
+            ));
+        }
+
+        // Bound to the stack addresses so that forwarded destinations on the
+        // same port are not mistaken for connections to this node
+        let mut backlog = Vec::new();
+        for addr in &self.shared.addrs {
+            let endpoint = IpListenEndpoint {
+                addr: Some((*addr).into()),
+                port,
+            };
+            for _ in 0..LISTEN_BACKLOG {
+                backlog.push((endpoint, state.listen_socket(endpoint)?));
+            }
+        }
+        let (accept, incoming) = mpsc::unbounded_channel();
+        state.listeners.insert(port, Listening { backlog, accept });
+
+        Ok(TailnetListener {
+            shared: self.shared.clone(),
+            port,
+            incoming: tokio::sync::Mutex::new(incoming),
+        })
+    }
+
+    /// Open a TCP connection to a tailnet address
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<TailnetStream> {
+        let stream = {
+            let mut state = self.shared.lock();
+            if state.stopped {
+                return Err(stopped());
+            }
+
+            let handle = state.new_socket();
+            let port = state.ephemeral_port();
+            let state = &mut *state;
+            let socket = state.sockets.get_mut::<tcp::Socket>(handle);
+            if let Err(e) = socket.connect(state.iface.context(), addr, port) {
+                state.sockets.remove(handle);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, e.to_string()));
+            }
+
+            let local = socket
+                .local_endpoint()
+                .map(endpoint_addr)
+                .unwrap_or_else(|| SocketAddr::new(self.shared.addrs[0], port));
+            TailnetStream {
+                shared: self.shared.clone(),
+                handle,
+                local,
+                peer: addr,
+            }
+        };
+        self.shared.notify.notify_one();
+
+        let established = poll_fn(|cx| {
+            let mut state = self.shared.lock();
+            let socket = state.sockets.get_mut::<tcp::Socket>(stream.handle);
+            match socket.state() {
+                tcp::State::SynSent | tcp::State::SynReceived => {
+                    socket.register_send_waker(cx.waker());
+                    Poll::Pending
+                }
+                tcp::State::Closed => Poll::Ready(Err(io::ErrorKind::ConnectionRefused.into())),
+                _ => Poll::Ready(Ok(())),
+            }
+        });
+
+        match tokio::time::timeout(CONNECT_TIMEOUT, established).await {
+            Ok(Ok(())) => Ok(stream),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(io::ErrorKind::TimedOut.into()),
+        }
+    }
+
+    /// Bind an ephemeral UDP port for talking to `addr` only
+    pub fn connect_udp(&self, addr: SocketAddr) -> io::Result<TailnetUdpConn> {
+        Ok(TailnetUdpConn {
+            socket: self.bind_udp(0)?,
+            peer: addr,
+        })
+    }
+
+    /// Reset all connections and stop the stack
+    pub fn shutdown(&self) {
+        self.shared.shutdown.cancel();
+    }
+}
+
+impl std::fmt::Debug for Netstack {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Netstack")
+            .field("addrs", &self.shared.addrs)
+            .finish()
+    }
+}
+
+fn stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "tailnet stack is stopped")
+}
+
+/// Encode a UDP datagram in an IP packet
+fn udp_packet(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> io::Result<Vec<u8>> {
+    let (src_ip, dst_ip): (IpAddress, IpAddress) = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => (s.into(), d.into()),
+        (IpAddr::V6(s), IpAddr::V6(d)) => (s.into(), d.into()),
+        _ => return Err(io::ErrorKind::InvalidInput.into()),
+    };
+    let udp = UdpRepr {
+        src_port: src.port(),
+        dst_port: dst.port(),
+    };
+    let udp_len = udp.header_len() + payload.len();
+    if udp_len > u16::MAX as usize {
+        return Err(io::ErrorKind::InvalidInput.into());
+    }
+    let ip = IpRepr::new(src_ip, dst_ip, IpProtocol::Udp, udp_len, 64);
+    let checksums = ChecksumCapabilities::default();
+
+    let mut packet = vec![0u8; ip.buffer_len()];
+    ip.emit(&mut packet[..], &checksums);
+    let header_len = ip.header_len();
+    udp.emit(
+        &mut UdpPacket::new_unchecked(&mut packet[header_len..]),
+        &src_ip,
+        &dst_ip,
+        payload.len(),
+        |buf| buf.copy_from_slice(payload),
+        &checksums,
+    );
+    Ok(packet)
+}
+
+fn endpoint_addr(endpoint: IpEndpoint) -> SocketAddr {
+    SocketAddr::new(endpoint.addr.into(), endpoint.port)
+}
+
+/// Drive smoltcp: process packets and timers, accept connections
+async fn run(shared: Arc<Shared>) {
+    loop {
+        let delay = {
+            let mut state = shared.lock();
+            let state = &mut *state;
+            let now = SmolInstant::now();
+            state.iface.poll(now, &mut state.device, &mut state.sockets);
+            state.accept_connections(&shared);
+            state.remove_closed();
+
+            for packet in state.device.tx.drain(..) {
+                let _ = shared.outbound.send(packet);
+            }
+            state
+                .iface
+                .poll_delay(SmolInstant::now(), &state.sockets)
+                .map(|d| Duration::from_micros(d.total_micros()))
+                .unwrap_or(Duration::from_secs(1))
+        };
+
+        tokio::select! {
+            _ = shared.shutdown.cancelled() => break,
+            _ = shared.notify.notified() => {}
+            _ = tokio::time::sleep(delay) => {}
+        }
+    }
+
+    // Reset everything; aborting wakes any task blocked on a socket
+    let mut state = shared.lock();
+    let state = &mut *state;
+    state.stopped = true;
+    state.listeners.clear();
+    state.udp.clear();
+    state.forwarding = None;
+    for (_, socket) in state.sockets.iter_mut() {
+        let smoltcp::socket::Socket::Tcp(socket) = socket;
+        socket.abort();
+    }
+    state
+        .iface
+        .poll(SmolInstant::now(), &mut state.device, &mut state.sockets);
+    for packet in state.device.tx.drain(..) {
+        let _ = shared.outbound.send(packet);
+    }
+}
+
+/// Accepts TCP connections on a tailnet port
+pub struct TailnetListener {
+    shared: Arc<Shared>,
+    port: u16,
+    incoming: tokio::sync::Mutex<mpsc::UnboundedReceiver<TailnetStream>>,
+}
+
+impl TailnetListener {
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Wait for the next connection and return it with the peer address
+    pub async fn accept(&self) -> io::Result<(TailnetStream, SocketAddr)> {
+        match self.incoming.lock().await.recv().await {
+            Some(stream) => {
+                let peer = stream.peer;
+                Ok((stream, peer))
+            }
+            None => Err(stopped()),
+        }
+    }
+}
+
+impl std::fmt::Debug for TailnetListener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TailnetListener")
+            .field("port", &self.port)
+            .finish()
+    }
+}
+
+impl Drop for TailnetListener {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        if let Some(listening) = state.listeners.remove(&self.port) {
+            for (_, handle) in listening.backlog {
+                state.sockets.remove(handle);
+            }
+        }
+    }
+}
+
+/// A UDP port on the stack addresses
+pub struct TailnetUdpSocket {
+    shared: Arc<Shared>,
+    port: u16,
+    incoming: tokio::sync::Mutex<mpsc::UnboundedReceiver<Datagram>>,
+}
+
+impl TailnetUdpSocket {
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Address datagrams to `target` are sent from
+    pub fn local_addr_for(&self, target: SocketAddr) -> io::Result<SocketAddr> {
+        self.shared
+            .addrs
+            .iter()
+            .find(|addr| addr.is_ipv4() == target.is_ipv4())
+            .map(|addr| SocketAddr::new(*addr, self.port))
+            .ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))
+    }
+
+    /// Send `buf` to `target` from the stack address of the same family
+    pub fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        let packet = udp_packet(self.local_addr_for(target)?, target, buf)?;
+        self.shared.outbound.send(packet).map_err(|_| stopped())?;
+        Ok(buf.len())
+    }
+
+    /// Wait for a datagram; the excess of one larger than `buf` is dropped
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match self.incoming.lock().await.recv().await {
+            Some((payload, src)) => {
+                let len = payload.len().min(buf.len());
+                buf[..len].copy_from_slice(&payload[..len]);
+                Ok((len, src))
+            }
+            None => Err(stopped()),
+        }
+    }
+}
+
+impl std::fmt::Debug for TailnetUdpSocket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TailnetUdpSocket")
+            .field("port", &self.port)
+            .finish()
+    }
+}
+
+impl Drop for TailnetUdpSocket {
+    fn drop(&mut self) {
+        self.shared.lock().udp.remove(&self.port);
+    }
+}
+
+/// A UDP socket inside the tailnet stack that talks to one peer address
+#[derive(Debug)]
+pub struct TailnetUdpConn {
+    socket: TailnetUdpSocket,
+    peer: SocketAddr,
+}
+
+impl TailnetUdpConn {
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr_for(self.peer)
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.send_to(buf, self.peer)
+    }
+
+    /// Wait for the next datagram from the peer address; others are dropped
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let (len, from) = self.socket.recv_from(buf).await?;
+            if from == self.peer {
+                return Ok(len);
+            }
+        }
+    }
+}
+
+/// A TCP connection inside the tailnet stack
+pub struct TailnetStream {
+    shared: Arc<Shared>,
+    handle: SocketHandle,
+    local: SocketAddr,
+    peer: SocketAddr,
+}
+
+impl TailnetStream {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+}
+
+impl std::fmt::Debug for TailnetStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TailnetStream")
+            .field("local", &self.local)
+            .field("peer", &self.peer)
+            .finish()
+    }
+}
+
+impl AsyncRead for TailnetStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut state = self.shared.lock();
+        let socket = state.sockets.get_mut::<tcp::Socket>(self.handle);
+
+        if socket.can_recv() {
+            let n = socket
+                .recv_slice(buf.initialize_unfilled())
+                .map_err(|e| io::Error::new(io::ErrorKind::ConnectionReset, e.to_string()))?;
+            buf.advance(n);
+            drop(state);
+            // Let the stack announce the freed window
+            self.shared.notify.notify_one();
+            return Poll::Ready(Ok(()));
+        }
+
+        match socket.state() {
+            tcp::State::Listen | tcp::State::SynSent | tcp::State::SynReceived => {}
+            _ if !socket.may_recv() => return Poll::Ready(Ok(())),
+            _ => {}
+        }
+        socket.register_recv_waker(cx.waker());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for TailnetStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut state = self.shared.lock();
+        let socket = state.sockets.get_mut::<tcp::Socket>(self.handle);
+
+        if socket.can_send() {
+            let n = socket
+                .send_slice(buf)
+                .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e.to_string()))?;
+            drop(state);
+            self.shared.notify.notify_one();
+            return Poll::Ready(Ok(n));
+        }
+
+        match socket.state() {
+            tcp::State::Listen | tcp::State::SynSent | tcp::State::SynReceived => {}
+            _ if !socket.may_send() => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+            _ => {}
+        }
+        socket.register_send_waker(cx.waker());
+        Poll::Pending
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.shared
+            .lock()
+            .sockets
+            .get_mut::<tcp::Socket>(self.handle)
+            .close();
+        self.shared.notify.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for TailnetStream {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.sockets.get_mut::<tcp::Socket>(self.handle).close();
+        state.closing.push(self.handle);
+        drop(state);
+        self.shared.notify.notify_one();
+    }
+}
+
+/// Two stacks at 100.64.0.1 and 100.64.0.2 whose packets are delivered to
+/// each other
+#[cfg(test)]
+pub(crate) fn pair() -> (Netstack, Netstack) {
+    pair_at("100.64.0.2".parse().unwrap())
+}
+
+/// Like [`pair`], with the second stack at `addr`
+#[cfg(test)]
+pub(crate) fn pair_at(addr: IpAddr) -> (Netstack, Netstack) {
+    let (a_out, mut a_rx) = mpsc::unbounded_channel();
+    let (b_out, mut b_rx) = mpsc::unbounded_channel();
+    let a = Netstack::new(&["100.64.0.1".parse().unwrap()], a_out);
+    let b = Netstack::new(&[addr], b_out);
+
+    let to_b = b.clone();
+    tokio::spawn(async move {
+        while let Some(packet) = a_rx.recv().await {
+            to_b.inject(packet);
+        }
+    });
+    let to_a = a.clone();
+    tokio::spawn(async move {
+        while let Some(packet) = b_rx.recv().await {
+            to_a.inject(packet);
+        }
+    });
+    (a, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_connect_and_transfer() {
+        let (a, b) = pair();
+        let listener = b.listen(1080).unwrap();
+        assert!(b.listen(1080).is_err());
+
+        let server = tokio::spawn(async move {
+            let (mut stream, peer) = listener.accept().await.unwrap();
+            assert_eq!(peer.ip(), "100.64.0.1".parse::<IpAddr>().unwrap());
+            let mut data = Vec::new();
+            stream.read_to_end(&mut data).await.unwrap();
+            stream.write_all(&data).await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+
+        // Larger than the socket buffers to exercise flow control
+        let payload: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let mut client = a.connect("100.64.0.2:1080".parse().unwrap()).await.unwrap();
+        let (mut reader, mut writer) = tokio::io::split(&mut client);
+        let send = async {
+            writer.write_all(&payload).await.unwrap();
+            writer.shutdown().await.unwrap();
+        };
+        let mut echoed = Vec::new();
+        let recv = reader.read_to_end(&mut echoed);
+        let (_, read) = tokio::join!(send, recv);
+        read.unwrap();
+
+        assert_eq!(echoed, payload);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_udp() {
+        let (a, b) = pair();
+        let server = b.bind_udp(53).unwrap();
+        assert!(b.bind_udp(53).is_err());
+        let client = a.bind_udp(0).unwrap();
+
+        client
+            .send_to(b"query", "100.64.0.2:53".parse().unwrap())
+            .unwrap();
+        let mut buf = [0u8; 64];
+        let (len, from) = server.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"query");
+        assert_eq!(from, SocketAddr::new("100.64.0.1".parse().unwrap(), client.port()));
+
+        server.send_to(b"answer", from).unwrap();
+        let (len, from) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"answer");
+        assert_eq!(from, "100.64.0.2:53".parse().unwrap());
+
+        // A connected socket only sees its peer
+        let conn = a.connect_udp("100.64.0.2:53".parse().unwrap()).unwrap();
+        conn.send(b"again").unwrap();
+        let (_, from) = server.recv_from(&mut buf).await.unwrap();
+        assert_eq!(from, conn.local_addr().unwrap());
+        let other = b.bind_udp(54).unwrap();
+        other.send_to(b"spoofed", from).unwrap();
+        server.send_to(b"reply", from).unwrap();
+        let len = conn.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"reply");
+    }
+
+    #[tokio::test]
+    async fn test_connect_refused() {
+        let (a, _b) = pair();
+        let err = a
+            .connect("100.64.0.2:9".parse().unwrap())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[tokio::test]
+    async fn test_exit_routes_skip_mapped_loopback() {
+        let (out, _replies) = mpsc::unbounded_channel();
+        let exit = Netstack::new(&["100.64.0.1".parse().unwrap()], out);
+        let mut incoming = exit.forward(&["0.0.0.0/0".parse().unwrap(), "::/0".parse().unwrap()]);
+
+        // Datagrams as a peer would send them, built by the stack's own encoder
+        let peer: SocketAddr = "[fd7a:115c:a1e0::2]:5353".parse().unwrap();
+        let (out, mut packets) = mpsc::unbounded_channel();
+        let sender = Netstack::new(&[peer.ip()], out);
+        for dst in ["[::ffff:127.0.0.1]:80", "[::ffff:192.0.2.1]:80"] {
+            sender
+                .send_udp(peer, dst.parse().unwrap(), b"hello")
+                .unwrap();
+            exit.inject(packets.recv().await.unwrap());
+        }
+
+        // Only the public destination is forwarded
+        match incoming.recv().await.unwrap() {
+            Forwarded::Udp { dst, .. } => assert_eq!(dst, "[::ffff:192.0.2.1]:80".parse().unwrap()),
+            Forwarded::Tcp(_) => panic!("unexpected TCP connection"),
+        }
+        assert!(incoming.try_recv().is_err());
+    }
+}
@@ -0,0 +1,691 @@
+//! Publishing local services on the tailnet
+//!
+//! `--serve tcp:PORT=HOST:PORT` accepts connections on a port of the node's
+//! tailnet addresses in the userspace stack and relays them to a service on
+//! the host, like `tailscale serve` for raw TCP. The caller's WhoIs identity
+//! can be passed on in a PROXY protocol v2 header or in HTTP request headers,
+//! so the service knows who is connecting without a login of its own.
+
+use super::netmap::{PeerMap, WhoIs};
+use super::netstack::{TailnetListener, TailnetStream};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{debug, info, trace};
+
+/// How long connecting to the local service may take
+const DIAL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest HTTP request head read when injecting headers
+const MAX_HTTP_HEAD: usize = 64 * 1024;
+
+/// PROXY protocol v2 signature
+const PROXY_V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// TLV types in the PROXY protocol's custom range carrying the WhoIs identity
+pub const PP2_TYPE_TAILSCALE_USER: u8 = 0xE0;
+pub const PP2_TYPE_TAILSCALE_NODE: u8 = 0xE1;
+pub const PP2_TYPE_TAILSCALE_TAGS: u8 = 0xE2;
+
+/// HTTP request headers carrying the WhoIs identity; clients cannot set
+/// them, any header starting with `Tailscale-` is removed
+pub const HEADER_USER_LOGIN: &str = "Tailscale-User-Login";
+pub const HEADER_NODE_NAME: &str = "Tailscale-Node-Name";
+pub const HEADER_NODE_TAGS: &str = "Tailscale-Node-Tags";
+
+/// How the caller's identity reaches the local service
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdentityHeader {
+    /// Plain relay
+    None,
+    /// PROXY protocol v2 header with the caller's address and identity
+    ProxyProtocol,
+    /// `Tailscale-*` headers added to the HTTP request
+    Http,
+}
+
+/// One `tcp:PORT=HOST:PORT` service
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Serve {
+    /// Port on the node's tailnet addresses
+    pub port: u16,
+    /// Local service, `HOST:PORT`
+    pub target: String,
+    pub identity: IdentityHeader,
+}
+
+impl FromStr for Serve {
+    type Err = String;
+
+    /// Parse `tcp:PORT=HOST:PORT[,proxy-protocol|,http-headers]`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        let spec = parts.next().unwrap_or_default();
+        let (port, target) = spec
+            .strip_prefix("tcp:")
+            .and_then(|spec| spec.split_once('='))
+            .ok_or_else(|| format!("invalid serve '{}', expected tcp:PORT=HOST:PORT", s))?;
+        let port = port
+            .parse()
+            .map_err(|_| format!("invalid tailnet port in '{}'", s))?;
+        match target.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {}
+            _ => return Err(format!("invalid local service '{}'", target)),
+        }
+
+        let mut identity = IdentityHeader::None;
+        for option in parts {
+            identity = match option {
+                "proxy-protocol" => IdentityHeader::ProxyProtocol,
+                "http-headers" => IdentityHeader::Http,
+                other => return Err(format!("unknown serve option '{}'", other)),
+            };
+        }
+
+        Ok(Serve {
+            port,
+            target: target.to_string(),
+            identity,
+        })
+    }
+}
+
+impl fmt::Display for Serve {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "tcp:{}={}", self.port, self.target)?;
+        match self.identity {
+            IdentityHeader::None => Ok(()),
+            IdentityHeader::ProxyProtocol => f.write_str(",proxy-protocol"),
+            IdentityHeader::Http => f.write_str(",http-headers"),
+        }
+    }
+}
+
+/// Relay connections accepted on `listener` to the service until the stack
+/// stops
+pub async fn run(listener: TailnetListener, serve: Serve, peers: PeerMap) {
+    info!("Serving {} on tailnet port {}", serve.target, serve.port);
+    while let Ok((stream, caller)) = listener.accept().await {
+        let whois = peers.whois(&caller.ip());
+        let serve = serve.clone();
+        tokio::spawn(async move {
+            if let Err(e) = relay(stream, &serve, whois).await {
+                debug!("Serving {} to {} failed: {}", serve.target, caller, e);
+            }
+        });
+    }
+}
+
+async fn relay(mut stream: TailnetStream, serve: &Serve, whois: Option<WhoIs>) -> io::Result<()> {
+    let (caller, local) = (stream.peer_addr(), stream.local_addr());
+    let mut target = tokio::time::timeout(DIAL_TIMEOUT, TcpStream::connect(&serve.target))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+    match serve.identity {
+        IdentityHeader::None => {}
+        IdentityHeader::ProxyProtocol => {
+            target
+                .write_all(&proxy_header(caller, local, whois.as_ref()))
+                .await?;
+        }
+        IdentityHeader::Http => return relay_http(stream, target, whois.as_ref()).await,
+    }
+
+    let (up, down) = tokio::io::copy_bidirectional(&mut stream, &mut target).await?;
+    trace!(
+        "Served {} -> {}: {} up, {} down",
+        caller,
+        serve.target,
+        up,
+        down
+    );
+    Ok(())
+}
+
+/// PROXY protocol v2 header for a TCP connection from `src` to `dst`
+fn proxy_header(src: SocketAddr, dst: SocketAddr, whois: Option<&WhoIs>) -> Vec<u8> {
+    let mut addrs = Vec::new();
+    let family = match (src, dst) {
+        (SocketAddr::V4(src), SocketAddr::V4(dst)) => {
+            addrs.extend_from_slice(&src.ip().octets());
+            addrs.extend_from_slice(&dst.ip().octets());
+            0x11
+        }
+        _ => {
+            let v6 = |addr: SocketAddr| match addr {
+                SocketAddr::V4(addr) => addr.ip().to_ipv6_mapped(),
+                SocketAddr::V6(addr) => *addr.ip(),
+            };
+            addrs.extend_from_slice(&v6(src).octets());
+            addrs.extend_from_slice(&v6(dst).octets());
+            0x21
+        }
+    };
+    addrs.extend_from_slice(&src.port().to_be_bytes());
+    addrs.extend_from_slice(&dst.port().to_be_bytes());
+
+    if let Some(who) = whois {
+        let mut tlv = |kind: u8, value: &str| {
+            addrs.push(kind);
+            addrs.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            addrs.extend_from_slice(value.as_bytes());
+        };
+        if let Some(user) = &who.user {
+            tlv(PP2_TYPE_TAILSCALE_USER, user);
+        }
+        tlv(PP2_TYPE_TAILSCALE_NODE, &who.node);
+        if !who.tags.is_empty() {
+            tlv(PP2_TYPE_TAILSCALE_TAGS, &who.tags.join(","));
+        }
+    }
+
+    let mut header = PROXY_V2_SIGNATURE.to_vec();
+    header.push(0x21); // version 2, PROXY command
+    header.push(family);
+    header.extend_from_slice(&(addrs.len() as u16).to_be_bytes());
+    header.extend_from_slice(&addrs);
+    header
+}
+
+/// Relay one HTTP request with identity headers, then the response
+///
+/// Anything the client sends after the request, such as a pipelined request
+/// without the headers, is dropped; only an upgrade the service accepts with
+/// `101 Switching Protocols` becomes a plain relay.
+async fn relay_http(
+    stream: TailnetStream,
+    mut target: TcpStream,
+    whois: Option<&WhoIs>,
+) -> io::Result<()> {
+    let (mut client_rd, mut client_wr) = tokio::io::split(stream);
+    let (head, mut buf) = read_head(&mut client_rd).await?;
+    let body = request_body(&head)?;
+    target
+        .write_all(&rewrite_request_head(&head, whois))
+        .await?;
+
+    if !wants_upgrade(&head) {
+        let (mut target_rd, mut target_wr) = target.split();
+        let upload = async {
+            forward_body(&mut client_rd, &mut target_wr, &mut buf, body).await?;
+            tokio::io::copy(&mut client_rd, &mut tokio::io::sink()).await
+        };
+        let download = async {
+            let n = tokio::io::copy(&mut target_rd, &mut client_wr).await?;
+            client_wr.shutdown().await?;
+            Ok(n)
+        };
+        tokio::try_join!(upload, download)?;
+        return Ok(());
+    }
+
+    forward_body(&mut client_rd, &mut target, &mut buf, body).await?;
+    let (response, rest) = read_head(&mut target).await?;
+    client_wr.write_all(&response).await?;
+    client_wr.write_all(b"\r\n\r\n").await?;
+    client_wr.write_all(&rest).await?;
+    if !is_switching_protocols(&response) {
+        // The service may keep the connection open; stop when either side
+        // closes it
+        let mut sink = tokio::io::sink();
+        tokio::select! {
+            result = tokio::io::copy(&mut target, &mut client_wr) => result?,
+            result = tokio::io::copy(&mut client_rd, &mut sink) => result?,
+        };
+        return Ok(());
+    }
+
+    target.write_all(&buf).await?;
+    let mut client = client_rd.unsplit(client_wr);
+    tokio::io::copy_bidirectional(&mut client, &mut target).await?;
+    Ok(())
+}
+
+/// How a request body is delimited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Body {
+    Length(u64),
+    Chunked,
+}
+
+/// Find the body framing of a request; ambiguous framing is refused so the
+/// service cannot see a different request boundary than we do
+fn request_body(head: &[u8]) -> io::Result<Body> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let head = String::from_utf8_lossy(head);
+    let (mut length, mut chunked) = (None, false);
+    for line in head.split("\r\n").skip(1) {
+        let value = line.split_once(':').map_or("", |(_, value)| value.trim());
+        match header_name(line).as_str() {
+            "transfer-encoding" if value.eq_ignore_ascii_case("chunked") => chunked = true,
+            "transfer-encoding" => return Err(invalid("unsupported transfer encoding")),
+            "content-length" => {
+                let n = value
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|_| value.bytes().all(|b| b.is_ascii_digit()))
+                    .ok_or_else(|| invalid("invalid content length"))?;
+                if length.is_some_and(|length| length != n) {
+                    return Err(invalid("conflicting content lengths"));
+                }
+                length = Some(n);
+            }
+            _ => {}
+        }
+    }
+    match (chunked, length) {
+        (true, Some(_)) => Err(invalid("both chunked encoding and a content length")),
+        (true, None) => Ok(Body::Chunked),
+        (false, length) => Ok(Body::Length(length.unwrap_or(0))),
+    }
+}
+
+/// Whether the client asks to switch protocols, e.g. to WebSocket
+fn wants_upgrade(head: &[u8]) -> bool {
+    String::from_utf8_lossy(head)
+        .split("\r\n")
+        .skip(1)
+        .any(|line| header_name(line) == "upgrade")
+}
+
+/// Whether a response head is `101 Switching Protocols`
+fn is_switching_protocols(head: &[u8]) -> bool {
+    head.split(|&b| b == b' ').nth(1) == Some(b"101")
+}
+
+/// Copy one request body from `buf`, then `reader`, to `writer`; whatever
+/// follows the body stays in `buf`
+async fn forward_body<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    buf: &mut Vec<u8>,
+    body: Body,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    if let Body::Length(length) = body {
+        return forward_exact(reader, writer, buf, length).await;
+    }
+    loop {
+        let line = read_line(reader, buf).await?;
+        writer.write_all(&line).await?;
+        let size = line.split(|&b| b == b';').next().unwrap_or_default();
+        let size = std::str::from_utf8(size)
+            .ok()
+            .and_then(|size| u64::from_str_radix(size.trim(), 16).ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid chunk size"))?;
+        if size == 0 {
+            break;
+        }
+        // Chunk data and its CRLF
+        forward_exact(reader, writer, buf, size + 2).await?;
+    }
+    // Trailer fields up to the blank line
+    loop {
+        let line = read_line(reader, buf).await?;
+        writer.write_all(&line).await?;
+        if line == b"\r\n" {
+            return Ok(());
+        }
+    }
+}
+
+async fn forward_exact<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    buf: &mut Vec<u8>,
+    mut length: u64,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    while length > 0 {
+        if buf.is_empty() {
+            fill(reader, buf).await?;
+        }
+        let n = buf.len().min(usize::try_from(length).unwrap_or(usize::MAX));
+        writer.write_all(&buf[..n]).await?;
+        buf.drain(..n);
+        length -= n as u64;
+    }
+    Ok(())
+}
+
+/// Take one CRLF-terminated line, including the CRLF, off `buf`
+async fn read_line<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut Vec<u8>) -> io::Result<Vec<u8>> {
+    loop {
+        if let Some(end) = buf.windows(2).position(|w| w == b"\r\n") {
+            return Ok(buf.drain(..end + 2).collect());
+        }
+        if buf.len() > MAX_HTTP_HEAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "HTTP line too long",
+            ));
+        }
+        fill(reader, buf).await?;
+    }
+}
+
+/// Read up to the end of an HTTP head; returns the head (without the blank
+/// line) and whatever followed it
+async fn read_head<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let mut buf = Vec::new();
+    loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let rest = buf.split_off(end + 4);
+            buf.truncate(end);
+            return Ok((buf, rest));
+        }
+        if buf.len() > MAX_HTTP_HEAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "HTTP head too large",
+            ));
+        }
+        fill(reader, &mut buf).await?;
+    }
+}
+
+/// Append the next read to `buf`, failing at the end of the stream
+async fn fill<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut Vec<u8>) -> io::Result<()> {
+    let mut chunk = [0u8; 4096];
+    let n = reader.read(&mut chunk).await?;
+    if n == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    buf.extend_from_slice(&chunk[..n]);
+    Ok(())
+}
+
+/// Replace `Tailscale-*` headers with the caller's identity
+///
+/// Only this request carries the headers, so the service is asked to close
+/// the connection after it (upgrades such as WebSocket keep their
+/// `Connection` header, see [`relay_http`]).
+fn rewrite_request_head(head: &[u8], whois: Option<&WhoIs>) -> Vec<u8> {
+    let upgrade = wants_upgrade(head);
+    let head = String::from_utf8_lossy(head);
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    let headers: Vec<&str> = lines
+        .filter(|line| !header_name(line).starts_with("tailscale-"))
+        .collect();
+
+    let mut out = format!("{}\r\n", request_line);
+    for line in headers {
+        if !upgrade && header_name(line) == "connection" {
+            continue;
+        }
+        out.push_str(line);
+        out.push_str("\r\n");
+    }
+    if !upgrade {
+        out.push_str("Connection: close\r\n");
+    }
+    if let Some(who) = whois {
+        if let Some(user) = &who.user {
+            push_header(&mut out, HEADER_USER_LOGIN, user);
+        }
+        push_header(&mut out, HEADER_NODE_NAME, &who.node);
+        if !who.tags.is_empty() {
+            push_header(&mut out, HEADER_NODE_TAGS, &who.tags.join(","));
+        }
+    }
+    out.push_str("\r\n");
+    out.into_bytes()
+}
+
+fn header_name(line: &str) -> String {
+    line.split(':')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+fn push_header(out: &mut String, name: &str, value: &str) {
+    // Control-plane values, but never let one end the header
+    let value: String = value.chars().filter(|c| !c.is_control()).collect();
+    out.push_str(&format!("{}: {}\r\n", name, value));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vpn::netstack;
+    use crate::vpn::{Netstack, PeerInfo};
+    use tokio::net::TcpListener;
+
+    fn whois() -> WhoIs {
+        WhoIs {
+            node: "laptop.tail1234.ts.net".to_string(),
+            addr: "100.64.0.2".parse().unwrap(),
+            user: Some("alice@example.com".to_string()),
+            tags: Vec::new(),
+            caps: None,
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let serve: Serve = "tcp:8080=127.0.0.1:3000".parse().unwrap();
+        assert_eq!(serve.port, 8080);
+        assert_eq!(serve.target, "127.0.0.1:3000");
+        assert_eq!(serve.identity, IdentityHeader::None);
+
+        let serve: Serve = "tcp:443=localhost:8443,proxy-protocol".parse().unwrap();
+        assert_eq!(serve.identity, IdentityHeader::ProxyProtocol);
+        assert_eq!(serve.to_string(), "tcp:443=localhost:8443,proxy-protocol");
+
+        for invalid in [
+            "8080=127.0.0.1:3000",
+            "udp:53=127.0.0.1:53",
+            "tcp:x=127.0.0.1:3000",
+            "tcp:8080=127.0.0.1",
+            "tcp:8080=127.0.0.1:3000,gzip",
+        ] {
+            assert!(invalid.parse::<Serve>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_proxy_header() {
+        let header = proxy_header(
+            "100.64.0.2:40000".parse().unwrap(),
+            "100.64.0.1:8080".parse().unwrap(),
+            Some(&whois()),
+        );
+        assert_eq!(&header[..12], &PROXY_V2_SIGNATURE);
+        assert_eq!(&header[12..14], &[0x21, 0x11]);
+        let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+        assert_eq!(header.len(), 16 + len);
+        assert_eq!(&header[16..20], &[100, 64, 0, 2]);
+        assert_eq!(&header[24..26], &40000u16.to_be_bytes());
+
+        // User TLV follows the addresses
+        let user = b"alice@example.com";
+        assert_eq!(header[28], PP2_TYPE_TAILSCALE_USER);
+        assert_eq!(&header[29..31], &(user.len() as u16).to_be_bytes());
+        assert_eq!(&header[31..31 + user.len()], user);
+    }
+
+    #[test]
+    fn test_rewrite_request_head() {
+        let head = b"GET / HTTP/1.1\r\nHost: dev\r\nConnection: keep-alive\r\ntailscale-user-login: mallory@example.com";
+        let rewritten = String::from_utf8(rewrite_request_head(head, Some(&whois()))).unwrap();
+        assert_eq!(
+            rewritten,
+            "GET / HTTP/1.1\r\nHost: dev\r\nConnection: close\r\n\
+             Tailscale-User-Login: alice@example.com\r\n\
+             Tailscale-Node-Name: laptop.tail1234.ts.net\r\n\r\n"
+        );
+
+        let upgrade = b"GET /ws HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: websocket";
+        let rewritten = String::from_utf8(rewrite_request_head(upgrade, None)).unwrap();
+        assert_eq!(
+            rewritten,
+            "GET /ws HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forward_body() {
+        let chunked = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked";
+        assert_eq!(request_body(chunked).unwrap(), Body::Chunked);
+        assert_eq!(request_body(b"GET / HTTP/1.1").unwrap(), Body::Length(0));
+        for ambiguous in [
+            &b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 3"[..],
+            b"POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 4",
+            b"POST / HTTP/1.1\r\nContent-Length: +3",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip",
+        ] {
+            assert!(request_body(ambiguous).is_err());
+        }
+
+        // The body ends at the last chunk's trailers; the rest stays behind
+        let mut buf = b"4;ext=1\r\nWiki\r\n".to_vec();
+        let mut reader = &b"0\r\nExpires: never\r\n\r\nGET /admin"[..];
+        let mut out = Vec::new();
+        forward_body(&mut reader, &mut out, &mut buf, Body::Chunked)
+            .await
+            .unwrap();
+        assert_eq!(out, b"4;ext=1\r\nWiki\r\n0\r\nExpires: never\r\n\r\n");
+        assert_eq!(buf, b"GET /admin");
+    }
+
+    /// Serve the service at `target` with identity headers on a node's
+    /// stack and connect to it from a peer that WhoIs knows as alice
+    async fn connect_http(target: &str) -> (Netstack, TailnetStream) {
+        let (node, peer) = netstack::pair();
+        let peers = PeerMap::new();
+        peers.update(
+            vec![PeerInfo {
+                name: Some("laptop.tail1234.ts.net".to_string()),
+                tailscale_ip: "100.64.0.2".parse().unwrap(),
+                addresses: vec!["100.64.0.2".parse().unwrap()],
+                user: Some("alice@example.com".to_string()),
+                ..Default::default()
+            }],
+            None,
+        );
+        let serve: Serve = format!("tcp:8080={},http-headers", target).parse().unwrap();
+        tokio::spawn(run(node.listen(8080).unwrap(), serve, peers));
+
+        let client = peer
+            .connect("100.64.0.1:8080".parse().unwrap())
+            .await
+            .unwrap();
+        (peer, client)
+    }
+
+    /// Read from `socket` until what was read ends with `end`
+    async fn read_until<S: AsyncRead + Unpin>(socket: &mut S, end: &[u8]) -> String {
+        let mut buf = Vec::new();
+        while !buf.ends_with(end) {
+            let mut byte = [0u8; 1];
+            socket.read_exact(&mut byte).await.unwrap();
+            buf.push(byte[0]);
+        }
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_serve_http_headers() {
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = service.local_addr().unwrap().to_string();
+        let (_peer, mut client) = connect_http(&target).await;
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: dev\r\n\r\n")
+            .await
+            .unwrap();
+
+        let (mut socket, _) = service.accept().await.unwrap();
+        let request = read_until(&mut socket, b"\r\n\r\n").await;
+        assert!(request.contains("Tailscale-User-Login: alice@example.com\r\n"));
+        socket
+            .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+            .await
+            .unwrap();
+        socket.shutdown().await.unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert_eq!(response, "HTTP/1.1 204 No Content\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_serve_http_drops_pipelined_requests() {
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = service.local_addr().unwrap().to_string();
+        let forged = "GET /admin HTTP/1.1\r\nTailscale-User-Login: admin@example.com\r\n\r\n";
+
+        // A second request after the body would skip the header rewrite
+        let (_peer, mut client) = connect_http(&target).await;
+        let request = format!(
+            "POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello{}",
+            forged
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let (mut socket, _) = service.accept().await.unwrap();
+        let request = read_until(&mut socket, b"hello").await;
+        assert!(request.contains("Tailscale-User-Login: alice@example.com\r\n"));
+        socket.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await.unwrap();
+        socket.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        socket.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty(), "{:?}", String::from_utf8_lossy(&rest));
+
+        // Nor does an upgrade the service refuses open the connection up
+        let (_peer, mut client) = connect_http(&target).await;
+        let request = format!(
+            "GET /ws HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n{}",
+            forged
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        let (mut socket, _) = service.accept().await.unwrap();
+        read_until(&mut socket, b"\r\n\r\n").await;
+        let refused = b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n";
+        socket.write_all(refused).await.unwrap();
+        let mut response = vec![0u8; refused.len()];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, refused);
+        drop(client);
+        let mut rest = Vec::new();
+        socket.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty(), "{:?}", String::from_utf8_lossy(&rest));
+    }
+
+    #[tokio::test]
+    async fn test_serve_http_upgrade() {
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = service.local_addr().unwrap().to_string();
+        let (_peer, mut client) = connect_http(&target).await;
+        client
+            .write_all(b"GET /ws HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\nping")
+            .await
+            .unwrap();
+
+        let (mut socket, _) = service.accept().await.unwrap();
+        read_until(&mut socket, b"\r\n\r\n").await;
+        socket
+            .write_all(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n")
+            .await
+            .unwrap();
+        assert_eq!(read_until(&mut socket, b"ping").await, "ping");
+        socket.write_all(b"pong").await.unwrap();
+        let response = read_until(&mut client, b"pong").await;
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+    }
+}
@@ -0,0 +1,291 @@
+//! Node status reported by VPN backends
+//!
+//! [`Status`] is what `socktail status` prints: the node itself, its
+//! tailnet addresses, the backend state and DERP home, and for each peer
+//! whether it is online, how it is reached and the traffic exchanged with
+//! it. It serializes to the JSON of `socktail status --json`.
+
+use super::netmap::PeerInfo;
+use super::wireguard::WgPeerStats;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Whether the backend is on the tailnet
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BackendState {
+    #[default]
+    Stopped,
+    Running,
+}
+
+/// How traffic to a peer currently flows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PeerPath {
+    /// No session with the peer yet
+    #[default]
+    Idle,
+    /// WireGuard to the peer's endpoint
+    Direct,
+    /// Relayed through a DERP server
+    Derp,
+}
+
+impl fmt::Display for PeerPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PeerPath::Idle => "idle",
+            PeerPath::Direct => "direct",
+            PeerPath::Derp => "derp",
+        })
+    }
+}
+
+/// This node
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeStatus {
+    /// Host or MagicDNS name
+    pub name: Option<String>,
+    pub public_key: Option<String>,
+    /// Tailnet addresses
+    pub addresses: Vec<IpAddr>,
+}
+
+/// A peer as seen by this node
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerStatus {
+    pub name: Option<String>,
+    pub public_key: String,
+    pub addresses: Vec<IpAddr>,
+    /// As reported by the control server, if it says
+    pub online: Option<bool>,
+    pub path: PeerPath,
+    /// WireGuard endpoint in use
+    pub endpoint: Option<SocketAddr>,
+    /// DERP region of the peer
+    pub derp: Option<String>,
+    /// Unix time of the last completed handshake
+    pub last_handshake: Option<u64>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+impl PeerStatus {
+    /// Status of a peer from the network map and its WireGuard session
+    pub fn new(peer: &PeerInfo, public_key: String, stats: Option<&WgPeerStats>) -> Self {
+        let handshake = stats.and_then(|s| s.since_handshake);
+        let path = if handshake.is_some() && stats.is_some_and(|s| s.endpoint.is_some()) {
+            PeerPath::Direct
+        } else {
+            PeerPath::Idle
+        };
+        Self {
+            name: peer.name.clone(),
+            public_key,
+            addresses: peer.addresses.clone(),
+            online: peer.online,
+            path,
+            endpoint: stats.and_then(|s| s.endpoint).or(peer.endpoint),
+            derp: peer.derp.clone(),
+            last_handshake: handshake.and_then(|ago| {
+                let at = SystemTime::now().checked_sub(ago)?;
+                Some(at.duration_since(UNIX_EPOCH).ok()?.as_secs())
+            }),
+            rx_bytes: stats.map_or(0, |s| s.rx_bytes),
+            tx_bytes: stats.map_or(0, |s| s.tx_bytes),
+        }
+    }
+}
+
+/// Snapshot returned by [`VpnBackend::status`](super::VpnBackend::status)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Status {
+    /// Name of the backend (`rust`, `tailscaled`, ...)
+    pub backend: String,
+    pub state: BackendState,
+    #[serde(rename = "self")]
+    pub self_node: NodeStatus,
+    /// DERP region this node is homed on
+    pub derp_home: Option<String>,
+    /// `None` if the backend does not track peers
+    pub peers: Option<Vec<PeerStatus>>,
+}
+
+impl Status {
+    /// Status of a backend that is not on the tailnet
+    pub fn stopped(backend: &str) -> Self {
+        Self {
+            backend: backend.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state == BackendState::Running
+    }
+
+    /// Tailnet addresses of the node
+    pub fn addresses(&self) -> &[IpAddr] {
+        &self.self_node.addresses
+    }
+
+    /// Multi-line report for `socktail status`, with handshake times
+    /// relative to `now` (Unix seconds)
+    pub fn report(&self, now: u64) -> String {
+        let mut out = String::new();
+        let addresses = join(self.addresses());
+        let _ = writeln!(out, "Backend:   {} ({:?})", self.backend, self.state);
+        if let Some(name) = &self.self_node.name {
+            let _ = writeln!(out, "Node:      {}", name);
+        }
+        if let Some(key) = &self.self_node.public_key {
+            let _ = writeln!(out, "Key:       {}", key);
+        }
+        if !addresses.is_empty() {
+            let _ = writeln!(out, "Addresses: {}", addresses);
+        }
+        if let Some(derp) = &self.derp_home {
+            let _ = writeln!(out, "DERP home: {}", derp);
+        }
+
+        let Some(peers) = &self.peers else {
+            return out;
+        };
+        let _ = writeln!(out, "\nPeers: {}", peers.len());
+        for peer in peers {
+            let name = peer.name.clone().unwrap_or_else(|| join(&peer.addresses));
+            let online = match peer.online {
+                Some(true) => "online",
+                Some(false) => "offline",
+                None => "-",
+            };
+            let path = match (peer.path, peer.endpoint, &peer.derp) {
+                (PeerPath::Direct, Some(endpoint), _) => format!("direct {}", endpoint),
+                (PeerPath::Derp, _, Some(region)) => format!("derp {}", region),
+                (path, _, _) => path.to_string(),
+            };
+            let handshake = match peer.last_handshake {
+                Some(at) => format!("{} ago", ago(now.saturating_sub(at))),
+                None => "never".to_string(),
+            };
+            let _ = writeln!(
+                out,
+                "  {:<32} {:<16} {:<7} {:<28} handshake {:<10} rx {} tx {}",
+                name,
+                peer.addresses.first().map(IpAddr::to_string).unwrap_or_default(),
+                online,
+                path,
+                handshake,
+                bytes(peer.rx_bytes),
+                bytes(peer.tx_bytes),
+            );
+        }
+        out
+    }
+}
+
+impl fmt::Display for Status {
+    /// One-line summary for logs
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.is_running() {
+            return write!(f, "{}: not connected", self.backend);
+        }
+        write!(f, "{}: ", self.backend)?;
+        if self.addresses().is_empty() {
+            f.write_str("no tailnet address")?;
+        }
+        f.write_str(&join(self.addresses()))?;
+        if let Some(peers) = &self.peers {
+            write!(f, " ({} peers)", peers.len())?;
+        }
+        Ok(())
+    }
+}
+
+fn join(addresses: &[IpAddr]) -> String {
+    addresses
+        .iter()
+        .map(IpAddr::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn ago(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        _ => format!("{}h", secs / 3600),
+    }
+}
+
+fn bytes(n: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = n as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", n)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status() -> Status {
+        Status {
+            backend: "rust".to_string(),
+            state: BackendState::Running,
+            self_node: NodeStatus {
+                name: Some("proxy".to_string()),
+                public_key: None,
+                addresses: vec!["100.64.0.1".parse().unwrap()],
+            },
+            derp_home: Some("1".to_string()),
+            peers: Some(vec![PeerStatus {
+                name: Some("db-1.tail1234.ts.net".to_string()),
+                public_key: "key".to_string(),
+                addresses: vec!["100.64.0.2".parse().unwrap()],
+                online: Some(true),
+                path: PeerPath::Direct,
+                endpoint: Some("192.0.2.10:41641".parse().unwrap()),
+                derp: None,
+                last_handshake: Some(1_000),
+                rx_bytes: 1536,
+                tx_bytes: 10,
+            }]),
+        }
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let status = status();
+        let json = serde_json::to_value(&status).unwrap();
+        assert_eq!(json["state"], "Running");
+        assert_eq!(json["self"]["addresses"][0], "100.64.0.1");
+        assert_eq!(json["peers"][0]["path"], "direct");
+        let parsed: Status = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed, status);
+    }
+
+    #[test]
+    fn test_report() {
+        let status = status();
+        assert_eq!(status.to_string(), "rust: 100.64.0.1 (1 peers)");
+        assert_eq!(Status::stopped("none").to_string(), "none: not connected");
+
+        let report = status.report(1_090);
+        assert!(report.contains("Addresses: 100.64.0.1\n"));
+        assert!(report.contains("DERP home: 1\n"));
+        assert!(report.contains("direct 192.0.2.10:41641"));
+        assert!(report.contains("handshake 1m"));
+        assert!(report.contains("rx 1.5 KiB tx 10 B"));
+    }
+}
@@ -6,21 +6,40 @@
 //! - Works on all platforms (Linux, macOS, Windows)
 //! - No Go dependencies
 
+use super::dial::{self, Network, TailnetConn};
+use super::exit_node::{ExitNode, ExitNodeSelector};
+use super::filter::{FilterRule, PacketFilter};
+use super::forwarder;
+use super::netmap::{PeerInfo, PeerMap, WhoIs};
+use super::netstack::{Netstack, TailnetListener, TailnetUdpSocket};
+use super::serve::{self, Serve};
+use super::status::{BackendState, NodeStatus, PeerStatus, Status};
+use super::tun_device::TunDevice;
+use super::wireguard::WgDevice;
+use crate::metrics::METRICS;
+use crate::utils::Cidr;
 use anyhow::{Context, Result};
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
-use boringtun::noise::Tunn;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
-use std::net::{IpAddr, SocketAddr};
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
 use std::sync::Arc;
-use tokio::net::UdpSocket;
-use tokio::sync::Mutex;
-use tracing::{error, info, warn};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
 use x25519_dalek::{PublicKey, StaticSecret};
 
 /// Tailscale control server URL
 const CONTROL_SERVER: &str = "https://controlplane.tailscale.com";
 
+/// How long `auto` waits for handshakes with candidate exit nodes
+const EXIT_NODE_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the network map is fetched again from the control server
+const NETMAP_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
 /// Pure Rust Tailscale client
 pub struct TailscaleRust {
     /// Node private key (WireGuard)
@@ -37,17 +56,31 @@ pub struct TailscaleRust {
     client: Client,
     /// Assigned Tailscale IP
     tailscale_ip: Option<IpAddr>,
+    /// All assigned tailnet addresses
+    addresses: Vec<IpAddr>,
     /// Connected state
     connected: bool,
-    /// WireGuard tunnel
-    tunnel: Option<Arc<Mutex<Tunn>>>,
-    /// UDP socket for WireGuard
-    socket: Option<Arc<UdpSocket>>,
-    /// Peer endpoints (IP -> SocketAddr mapping)
-    peers: Vec<PeerInfo>,
+    /// WireGuard device and userspace stack
+    device: Option<WgDevice>,
+    /// Peers from the network map (shared with the SOCKS server)
+    peers: PeerMap,
+    /// Requested exit node and whether LAN destinations stay local
+    exit_node_request: Option<(ExitNodeSelector, bool)>,
+    /// Exit node in use
+    exit_node: Option<ExitNode>,
+    /// Routes offered to peers, served from the host's sockets
+    advertised_routes: Vec<Cidr>,
+    /// Name of the kernel TUN interface to use instead of the userspace stack
+    tun_name: Option<String>,
+    /// Local services published on tailnet ports
+    serves: Vec<Serve>,
+    /// DERP region we are homed on
+    derp_home: Option<String>,
+    /// Task keeping the peers and packet filter current
+    refresh: Option<JoinHandle<()>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct RegisterRequest {
     /// Node key (public key)
     #[serde(rename = "NodeKey")]
@@ -57,7 +90,7 @@ struct RegisterRequest {
     hostinfo: HostInfo,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct HostInfo {
     /// Hostname
     #[serde(rename = "Hostname")]
@@ -65,6 +98,9 @@ struct HostInfo {
     /// OS
     #[serde(rename = "OS")]
     os: String,
+    /// Subnets (and default routes, as an exit node) we route for peers
+    #[serde(rename = "RoutableIPs", default, skip_serializing_if = "Vec::is_empty")]
+    routable_ips: Vec<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -82,6 +118,52 @@ struct NetworkMap {
     /// Peers
     #[serde(rename = "Peers")]
     peers: Vec<Peer>,
+    /// Tailnet MagicDNS domain
+    #[serde(rename = "Domain", default)]
+    domain: Option<String>,
+    /// Users that own the peers
+    #[serde(rename = "UserProfiles", default)]
+    user_profiles: Vec<UserProfile>,
+    /// What peers may reach on this node, and their capability grants
+    #[serde(rename = "PacketFilter", default)]
+    packet_filter: Option<Vec<FilterRule>>,
+    /// This node as the control server sees it
+    #[serde(rename = "SelfNode", default)]
+    self_node: Option<SelfNode>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SelfNode {
+    /// Home DERP server, `127.3.3.40:<region>`
+    #[serde(rename = "DERP", default)]
+    derp: Option<String>,
+}
+
+/// Everything needed to register, so the refresh task can do it again
+#[derive(Debug, Clone)]
+struct Control {
+    client: Client,
+    url: String,
+    authkey: String,
+    request: RegisterRequest,
+}
+
+/// What the control server told us at registration
+struct Registration {
+    peers: Vec<PeerInfo>,
+    domain: Option<String>,
+    addresses: Vec<IpAddr>,
+    filter: Option<Vec<FilterRule>>,
+    derp_home: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UserProfile {
+    #[serde(rename = "ID")]
+    id: u64,
+    /// Login name (e.g. `alice@example.com`)
+    #[serde(rename = "LoginName")]
+    login_name: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -89,23 +171,172 @@ struct Peer {
     /// Peer public key
     #[serde(rename = "Key")]
     key: String,
+    /// MagicDNS name (FQDN with trailing dot)
+    #[serde(rename = "Name", default)]
+    name: Option<String>,
     /// Peer IPs
     #[serde(rename = "Addresses")]
     addresses: Vec<String>,
     /// Peer endpoints
     #[serde(rename = "Endpoints", default)]
     endpoints: Vec<String>,
+    /// ACL tags
+    #[serde(rename = "Tags", default)]
+    tags: Vec<String>,
+    /// Owning user ID, see `UserProfiles`
+    #[serde(rename = "User", default)]
+    user: Option<u64>,
+    /// Prefixes routed to the peer, including advertised subnets and
+    /// default routes of exit nodes
+    #[serde(rename = "AllowedIPs", default)]
+    allowed_ips: Vec<String>,
+    /// Whether the peer is connected to the control server
+    #[serde(rename = "Online", default)]
+    online: Option<bool>,
+    /// Home DERP server of the peer, `127.3.3.40:<region>`
+    #[serde(rename = "DERP", default)]
+    derp: Option<String>,
 }
 
-/// Peer information for active connections
-#[derive(Debug, Clone)]
-struct PeerInfo {
-    /// Peer public key
-    public_key: [u8; 32],
-    /// Peer Tailscale IP
-    tailscale_ip: IpAddr,
-    /// Peer WireGuard endpoint
-    endpoint: Option<SocketAddr>,
+/// Packet filter from the control server's rules; without any, nothing is
+/// admitted, as in tailscaled
+fn packet_filter(rules: Option<Vec<FilterRule>>) -> Arc<PacketFilter> {
+    Arc::new(match rules {
+        Some(rules) => PacketFilter::new(&rules),
+        None => {
+            warn!("Control server sent no packet filter; inbound tailnet traffic is denied");
+            PacketFilter::default()
+        }
+    })
+}
+
+/// Region of a netmap DERP address
+fn derp_region(derp: &str) -> Option<String> {
+    let region = derp.strip_prefix("127.3.3.40:").unwrap_or(derp);
+    (!region.is_empty()).then(|| region.to_string())
+}
+
+impl Control {
+    /// Register with the control server and fetch the network map
+    async fn register(&self) -> Result<Registration> {
+        let registration = self.try_register().await;
+        let result = if registration.is_ok() { "ok" } else { "error" };
+        METRICS.control_connects.with(result).inc();
+        registration
+    }
+
+    async fn try_register(&self) -> Result<Registration> {
+        let register_url = format!("{}/machine/register", self.url);
+
+        let response = self
+            .client
+            .post(&register_url)
+            .header("Authorization", format!("Bearer {}", self.authkey))
+            .json(&self.request)
+            .send()
+            .await
+            .context("Failed to register with control server")?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            anyhow::bail!("Registration failed: {} - {}", status, body);
+        }
+
+        let register_response: RegisterResponse = response
+            .json()
+            .await
+            .context("Failed to parse registration response")?;
+
+        // Parse assigned IPs (may carry a /32 or /128 suffix)
+        let addresses = register_response
+            .ip_addresses
+            .iter()
+            .map(|addr| addr.split('/').next().unwrap_or(addr).parse())
+            .collect::<Result<Vec<IpAddr>, _>>()
+            .context("Failed to parse IP address")?;
+        addresses.first().context("No IP address assigned")?;
+
+        // Parse peer information
+        let mut peers = Vec::new();
+        let mut domain = None;
+        let mut filter = None;
+        let mut derp_home = None;
+        if let Some(netmap) = register_response.netmap {
+            domain = netmap.domain;
+            filter = netmap.packet_filter;
+            derp_home = netmap.self_node.and_then(|n| derp_region(n.derp.as_deref()?));
+            let logins: HashMap<u64, String> = netmap
+                .user_profiles
+                .into_iter()
+                .map(|u| (u.id, u.login_name))
+                .collect();
+            for peer in netmap.peers {
+                // Decode peer public key
+                let key_bytes = BASE64
+                    .decode(&peer.key)
+                    .context("Failed to decode peer public key")?;
+
+                if key_bytes.len() != 32 {
+                    warn!("Invalid peer public key length: {}", key_bytes.len());
+                    continue;
+                }
+
+                let mut public_key = [0u8; 32];
+                public_key.copy_from_slice(&key_bytes);
+
+                // Parse peer IPs (addresses may carry a /32 or /128 suffix)
+                let addresses: Vec<IpAddr> = peer
+                    .addresses
+                    .iter()
+                    .filter_map(|addr| addr.split('/').next()?.parse().ok())
+                    .collect();
+                let peer_ip = *addresses.first().context("Failed to parse peer IP")?;
+                let allowed_ips = peer
+                    .allowed_ips
+                    .iter()
+                    .filter_map(|prefix| prefix.parse().ok())
+                    .collect();
+
+                // Parse peer endpoint
+                let endpoint = peer
+                    .endpoints
+                    .first()
+                    .and_then(|ep| ep.parse().ok());
+
+                peers.push(PeerInfo {
+                    public_key,
+                    name: peer.name.map(|n| n.trim_end_matches('.').to_string()),
+                    tailscale_ip: peer_ip,
+                    addresses,
+                    // Tagged nodes belong to their tags, not to whoever added them
+                    user: if peer.tags.is_empty() {
+                        peer.user.and_then(|id| logins.get(&id).cloned())
+                    } else {
+                        None
+                    },
+                    tags: peer.tags,
+                    allowed_ips,
+                    endpoint,
+                    online: peer.online,
+                    derp: peer.derp.as_deref().and_then(derp_region),
+                });
+
+                debug!(
+                    "Discovered peer: {} (endpoint: {:?})",
+                    peer_ip, endpoint
+                );
+            }
+        }
+
+        Ok(Registration {
+            peers,
+            domain,
+            addresses,
+            filter,
+            derp_home,
+        })
+    }
 }
 
 impl TailscaleRust {
@@ -128,10 +359,17 @@ impl TailscaleRust {
             hostname: String::new(),
             client,
             tailscale_ip: None,
+            addresses: Vec::new(),
             connected: false,
-            tunnel: None,
-            socket: None,
-            peers: Vec::new(),
+            device: None,
+            peers: PeerMap::new(),
+            exit_node_request: None,
+            exit_node: None,
+            advertised_routes: Vec::new(),
+            tun_name: None,
+            serves: Vec::new(),
+            derp_home: None,
+            refresh: None,
         })
     }
 
@@ -153,46 +391,170 @@ impl TailscaleRust {
         Ok(())
     }
 
+    /// Route non-tailnet traffic through an exit node once connected
+    pub fn set_exit_node(&mut self, selector: ExitNodeSelector, allow_lan_access: bool) {
+        self.exit_node_request = Some((selector, allow_lan_access));
+    }
+
+    /// Offer `routes` to peers, and with `exit_node` also `0.0.0.0/0` and
+    /// `::/0`; their traffic is re-originated from this host
+    pub fn set_advertised_routes(&mut self, routes: &[Cidr], exit_node: bool) {
+        self.advertised_routes = routes.to_vec();
+        if exit_node {
+            for default in ["0.0.0.0/0", "::/0"] {
+                self.advertised_routes.push(default.parse().unwrap());
+            }
+        }
+    }
+
+    /// Join the host to the tailnet through a kernel TUN interface instead
+    /// of the userspace stack (needs root)
+    pub fn set_tun(&mut self, name: &str) {
+        self.tun_name = Some(name.to_string());
+    }
+
+    /// Publish a local service on a port of the tailnet addresses once
+    /// connected
+    pub fn add_serve(&mut self, serve: Serve) {
+        self.serves.push(serve);
+    }
+
     /// Connect to Tailscale network
     pub async fn connect(&mut self) -> Result<()> {
         info!("Connecting to Tailscale via pure Rust implementation...");
 
+        // Both terminate traffic in the userspace stack
+        if self.tun_name.is_some() && !self.advertised_routes.is_empty() {
+            anyhow::bail!("Advertising routes is not supported in TUN mode");
+        }
+        if self.tun_name.is_some() && self.exit_node_request.is_some() {
+            anyhow::bail!("Using an exit node is not supported in TUN mode");
+        }
+        if self.tun_name.is_some() && !self.serves.is_empty() {
+            anyhow::bail!("Serving on tailnet ports is not supported in TUN mode");
+        }
+
         // Step 1: Register with control server
-        let (peers, assigned_ip) = self.register().await?;
+        info!("Registering with Tailscale control server...");
+        let control = self.control();
+        let registration = control.register().await?;
+        let assigned_ip = registration.addresses[0];
+        info!("Assigned Tailscale IP: {}", assigned_ip);
         self.tailscale_ip = Some(assigned_ip);
-        self.peers = peers;
+        self.addresses = registration.addresses;
+        self.derp_home = registration.derp_home;
+        self.peers.update(registration.peers, registration.domain);
 
-        // Step 2: Set up WireGuard tunnel using boringtun
-        info!("Setting up WireGuard tunnel...");
-
-        // Create UDP socket for WireGuard (bind to random port)
-        let socket = UdpSocket::bind("0.0.0.0:0").await?;
-        let local_addr = socket.local_addr()?;
-        info!("WireGuard listening on: {}", local_addr);
-
-        // Create WireGuard tunnel
-        let tunnel = Tunn::new(
-            self.private_key.clone(),
-            self.public_key.clone(),
-            None, // No pre-shared key
-            None, // No persistent keepalive
-            0,    // Index (not used)
-            None, // Rate limiter (optional)
-        )
-        .map_err(|e| anyhow::anyhow!("Failed to create WireGuard tunnel: {}", e))?;
+        // Tailscale ACLs decide what peers may reach
+        let filter = packet_filter(registration.filter);
+        self.peers.set_filter(filter.clone());
 
-        self.tunnel = Some(Arc::new(Mutex::new(tunnel)));
-        self.socket = Some(Arc::new(socket));
+        // Step 2: Set up WireGuard device with a userspace stack (or a TUN
+        // interface) on our addresses
+        info!("Setting up WireGuard tunnel...");
+        let listen = "0.0.0.0:0".parse().unwrap();
+        let device = match &self.tun_name {
+            Some(name) => {
+                let tun = TunDevice::create(name, &self.addresses)
+                    .with_context(|| format!("Failed to create TUN interface {}", name))?;
+                // Peers' own addresses are covered by the tailnet range
+                let subnets: Vec<Cidr> = self
+                    .peers
+                    .peers()
+                    .iter()
+                    .flat_map(|p| {
+                        p.allowed_ips.iter().filter(|c| {
+                            !c.is_default_route() && !p.addresses.contains(&c.addr())
+                        })
+                    })
+                    .copied()
+                    .collect();
+                tun.add_routes(&subnets)
+                    .context("Failed to route peer subnets")?;
+                WgDevice::bind_tun(self.private_key.clone(), listen, tun).await
+            }
+            None => WgDevice::bind(self.private_key.clone(), listen, &self.addresses).await,
+        }
+        .context("Failed to bind WireGuard socket")?;
+        info!("WireGuard listening on: {}", device.local_addr()?);
 
-        // Step 3: Add peers to tunnel
-        for peer in &self.peers {
+        // Step 3: Add peers to the device
+        let peers = self.peers.peers();
+        for peer in &peers {
             info!(
                 "Adding peer {} with endpoint {:?}",
                 peer.tailscale_ip, peer.endpoint
             );
-            // Note: boringtun will handle peer handshake when we send packets
+        }
+        device.set_peers(&peers);
+        device.set_filter(filter);
+
+        // Serve advertised routes; the control server still has to approve
+        // them before peers send us their traffic
+        if !self.advertised_routes.is_empty() {
+            if let Some(netstack) = device.netstack() {
+                let incoming = netstack.forward(&self.advertised_routes);
+                let rules = forwarder::rules(&self.advertised_routes);
+                tokio::spawn(forwarder::run(netstack.clone(), incoming, rules));
+            }
+            let routes: Vec<_> = self.advertised_routes.iter().map(Cidr::to_string).collect();
+            info!("Advertising routes: {}", routes.join(", "));
+        }
+
+        // Step 4: Pick the exit node
+        if let Some((selector, allow_lan_access)) = self.exit_node_request.clone() {
+            match self.select_exit_node(&device, &selector).await {
+                Some(peer) => {
+                    device.set_exit_node(Some(peer.public_key));
+                    let exit_node = ExitNode::new(&peer, allow_lan_access);
+                    info!("Using exit node {}", exit_node);
+                    self.exit_node = Some(exit_node);
+                }
+                None => {
+                    device.shutdown();
+                    anyhow::bail!("Exit node '{}' not found among peers", selector);
+                }
+            }
+        }
+
+        // Step 5: Publish local services
+        if let Some(netstack) = device.netstack() {
+            for serve in &self.serves {
+                match netstack.listen(serve.port) {
+                    Ok(listener) => {
+                        tokio::spawn(serve::run(listener, serve.clone(), self.peers.clone()));
+                    }
+                    Err(e) => {
+                        device.shutdown();
+                        anyhow::bail!("Failed to serve {}: {}", serve, e);
+                    }
+                }
+            }
         }
 
+        // Step 6: Keep the network map current
+        self.stop_refresh();
+        let peers = self.peers.clone();
+        let refreshed = device.clone();
+        self.refresh = Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(NETMAP_REFRESH_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                match control.register().await {
+                    Ok(registration) => {
+                        peers.update(registration.peers, registration.domain);
+                        let filter = packet_filter(registration.filter);
+                        peers.set_filter(filter.clone());
+                        refreshed.set_filter(filter);
+                        refreshed.set_peers(&peers.peers());
+                    }
+                    Err(e) => warn!("Failed to refresh the network map: {}", e),
+                }
+            }
+        }));
+        self.device = Some(device);
+
         self.connected = true;
         info!("Successfully connected to Tailscale (pure Rust)");
         info!(
@@ -204,108 +566,198 @@ impl TailscaleRust {
         Ok(())
     }
 
-    /// Register with Tailscale control server
-    async fn register(&mut self) -> Result<(Vec<PeerInfo>, IpAddr)> {
-        info!("Registering with Tailscale control server...");
-
-        let public_key_b64 = BASE64.encode(self.public_key.as_bytes());
+    /// Resolve the selector, probing every exit node for `auto`
+    async fn select_exit_node(
+        &self,
+        device: &WgDevice,
+        selector: &ExitNodeSelector,
+    ) -> Option<PeerInfo> {
+        if *selector != ExitNodeSelector::Auto {
+            return selector.find(&self.peers);
+        }
 
-        let request = RegisterRequest {
-            node_key: public_key_b64,
-            hostinfo: HostInfo {
-                hostname: self.hostname.clone(),
-                os: std::env::consts::OS.to_string(),
-            },
-        };
+        let mut probes = tokio::task::JoinSet::new();
+        for peer in self.peers.exit_nodes() {
+            let device = device.clone();
+            probes.spawn(async move {
+                let rtt = device
+                    .handshake_rtt(&peer.public_key, EXIT_NODE_PROBE_TIMEOUT)
+                    .await;
+                (rtt, peer)
+            });
+        }
 
-        let register_url = format!("{}/machine/register", self.control_url);
+        let mut best: Option<(Duration, PeerInfo)> = None;
+        while let Some(Ok((rtt, peer))) = probes.join_next().await {
+            info!("Exit node {} round trip: {:?}", peer.tailscale_ip, rtt);
+            if let Some(rtt) = rtt {
+                if best.as_ref().is_none_or(|(fastest, _)| rtt < *fastest) {
+                    best = Some((rtt, peer));
+                }
+            }
+        }
+        best.map(|(_, peer)| peer)
+    }
 
-        let response = self
-            .client
-            .post(&register_url)
-            .header("Authorization", format!("Bearer {}", self.authkey))
-            .json(&request)
-            .send()
-            .await
-            .context("Failed to register with control server")?;
+    /// Registration request for this node
+    fn control(&self) -> Control {
+        Control {
+            client: self.client.clone(),
+            url: self.control_url.clone(),
+            authkey: self.authkey.clone(),
+            request: RegisterRequest {
+                node_key: BASE64.encode(self.public_key.as_bytes()),
+                hostinfo: HostInfo {
+                    hostname: self.hostname.clone(),
+                    os: std::env::consts::OS.to_string(),
+                    routable_ips: self
+                        .advertised_routes
+                        .iter()
+                        .map(Cidr::to_string)
+                        .collect(),
+                },
+            },
+        }
+    }
 
-        if !response.status().is_success() {
-            let status = response.status();
-            let body = response.text().await.unwrap_or_default();
-            anyhow::bail!("Registration failed: {} - {}", status, body);
+    fn stop_refresh(&mut self) {
+        if let Some(task) = self.refresh.take() {
+            task.abort();
         }
+    }
 
-        let register_response: RegisterResponse = response
-            .json()
-            .await
-            .context("Failed to parse registration response")?;
+    /// Get assigned Tailscale IP
+    pub fn get_ip(&self) -> Option<IpAddr> {
+        self.tailscale_ip
+    }
 
-        // Parse assigned IP
-        let assigned_ip = register_response
-            .ip_addresses
-            .first()
-            .context("No IP address assigned")?
-            .parse()
-            .context("Failed to parse IP address")?;
+    /// Identify the node and user behind a tailnet address
+    pub fn whois(&self, ip: &IpAddr) -> Option<WhoIs> {
+        self.peers.whois(ip)
+    }
 
-        info!("Assigned Tailscale IP: {}", assigned_ip);
+    /// Shared handle to the peer list
+    pub fn peer_map(&self) -> PeerMap {
+        self.peers.clone()
+    }
 
-        // Parse peer information
-        let mut peers = Vec::new();
-        if let Some(netmap) = register_response.netmap {
-            for peer in netmap.peers {
-                // Decode peer public key
-                let key_bytes = BASE64
-                    .decode(&peer.key)
-                    .context("Failed to decode peer public key")?;
+    /// Userspace stack on the node's tailnet addresses
+    pub fn netstack(&self) -> Option<Netstack> {
+        self.device.as_ref()?.netstack().cloned()
+    }
 
-                if key_bytes.len() != 32 {
-                    warn!("Invalid peer public key length: {}", key_bytes.len());
-                    continue;
-                }
+    /// Exit node carrying non-tailnet traffic
+    pub fn exit_node(&self) -> Option<&ExitNode> {
+        self.exit_node.as_ref()
+    }
 
-                let mut public_key = [0u8; 32];
-                public_key.copy_from_slice(&key_bytes);
+    /// Tailnet addresses of the node (empty until connected)
+    pub fn local_addrs(&self) -> &[IpAddr] {
+        &self.addresses
+    }
 
-                // Parse peer IP
-                let peer_ip: IpAddr = peer
-                    .addresses
-                    .first()
-                    .and_then(|addr| addr.parse().ok())
-                    .context("Failed to parse peer IP")?;
+    /// Open a connection through the tailnet, like tsnet's `Dial`
+    ///
+    /// `network` is `tcp` or `udp`, optionally with `4` or `6`; the host in
+    /// `addr` may be a MagicDNS name. Destinations outside the tailnet are
+    /// only reachable through an exit node.
+    pub async fn dial(&self, network: &str, addr: &str) -> io::Result<TailnetConn> {
+        let network: Network = network.parse()?;
+        let netstack = self.require_netstack()?;
+        let addr = dial::resolve(network, addr, &self.peers).await?;
+        if network.is_tcp() {
+            Ok(TailnetConn::Tcp(netstack.connect(addr).await?))
+        } else {
+            Ok(TailnetConn::Udp(netstack.connect_udp(addr)?))
+        }
+    }
 
-                // Parse peer endpoint
-                let endpoint = peer
-                    .endpoints
-                    .first()
-                    .and_then(|ep| ep.parse().ok());
+    /// Accept TCP connections on the node's tailnet addresses, like tsnet's
+    /// `Listen`; `addr` is `:PORT` or one of the node's addresses and a port
+    pub fn listen(&self, network: &str, addr: &str) -> io::Result<TailnetListener> {
+        let network: Network = network.parse()?;
+        if !network.is_tcp() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "listen accepts TCP networks; use listen_packet for UDP",
+            ));
+        }
+        let port = self.listen_port(network, addr)?;
+        self.require_netstack()?.listen(port)
+    }
 
-                peers.push(PeerInfo {
-                    public_key,
-                    tailscale_ip: peer_ip,
-                    endpoint,
-                });
+    /// Bind a UDP port on the node's tailnet addresses, like tsnet's
+    /// `ListenPacket`
+    pub fn listen_packet(&self, network: &str, addr: &str) -> io::Result<TailnetUdpSocket> {
+        let network: Network = network.parse()?;
+        if network.is_tcp() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "listen_packet accepts UDP networks; use listen for TCP",
+            ));
+        }
+        let port = self.listen_port(network, addr)?;
+        self.require_netstack()?.bind_udp(port)
+    }
 
-                info!(
-                    "Discovered peer: {} (endpoint: {:?})",
-                    peer_ip, endpoint
-                );
+    /// Port of a listen address, which must name no host or one of ours
+    fn listen_port(&self, network: Network, addr: &str) -> io::Result<u16> {
+        let (host, port) = dial::split_host_port(addr)?;
+        if !host.is_empty() {
+            let ip: IpAddr = host.parse().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("invalid listen address '{}'", addr))
+            })?;
+            if !network.allows(&ip) || !(ip.is_unspecified() || self.addresses.contains(&ip)) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    format!("{} is not a tailnet address of this node", ip),
+                ));
             }
         }
-
-        Ok((peers, assigned_ip))
+        Ok(port)
     }
 
-    /// Get assigned Tailscale IP
-    pub fn get_ip(&self) -> Option<IpAddr> {
-        self.tailscale_ip
+    fn require_netstack(&self) -> io::Result<&Netstack> {
+        let device = self.device.as_ref().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotConnected, "not connected to Tailscale")
+        })?;
+        device.netstack().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                "no userspace stack in TUN mode; use the host's sockets",
+            )
+        })
     }
 
-    /// Get loopback information (for compatibility with main.rs)
-    pub fn get_loopback(&self) -> Result<String> {
-        match self.tailscale_ip {
-            Some(ip) => Ok(format!("Address: {}, Peers: {}", ip, self.peers.len())),
-            None => anyhow::bail!("Not connected to Tailscale network"),
+    /// Node, peer and session state
+    pub fn status(&self) -> Status {
+        if !self.connected {
+            return Status::stopped("rust");
+        }
+        let stats = self
+            .device
+            .as_ref()
+            .map(WgDevice::peer_stats)
+            .unwrap_or_default();
+        let peers = self
+            .peers
+            .peers()
+            .iter()
+            .map(|peer| {
+                let key = BASE64.encode(peer.public_key);
+                PeerStatus::new(peer, key, stats.get(&peer.public_key))
+            })
+            .collect();
+        Status {
+            backend: "rust".to_string(),
+            state: BackendState::Running,
+            self_node: NodeStatus {
+                name: (!self.hostname.is_empty()).then(|| self.hostname.clone()),
+                public_key: Some(BASE64.encode(self.public_key.as_bytes())),
+                addresses: self.addresses.clone(),
+            },
+            derp_home: self.derp_home.clone(),
+            peers: Some(peers),
         }
     }
 
@@ -316,12 +768,17 @@ impl TailscaleRust {
         }
 
         info!("Disconnecting from Tailscale...");
+        self.stop_refresh();
 
         // Tear down WireGuard tunnel
-        self.tunnel = None;
-        self.socket = None;
+        if let Some(device) = self.device.take() {
+            device.shutdown();
+        }
         self.peers.clear();
+        self.exit_node = None;
         self.tailscale_ip = None;
+        self.addresses.clear();
+        self.derp_home = None;
 
         self.connected = false;
         info!("Disconnected from Tailscale");
@@ -336,6 +793,7 @@ impl TailscaleRust {
 
 impl Drop for TailscaleRust {
     fn drop(&mut self) {
+        self.stop_refresh();
         // Note: Can't use async in Drop, so we just log
         if self.connected {
             error!("TailscaleRust dropped while still connected");
@@ -359,4 +817,33 @@ mod tests {
         assert!(client.set_hostname("test-node").is_ok());
         assert!(client.set_authkey("tskey-test").is_ok());
     }
+
+    #[test]
+    fn test_status() {
+        let client = TailscaleRust::new().unwrap();
+        assert!(!client.status().is_running());
+        assert_eq!(derp_region("127.3.3.40:2").as_deref(), Some("2"));
+        assert_eq!(derp_region(""), None);
+
+        let netmap: NetworkMap = serde_json::from_str(
+            r#"{"Peers": [{"Key": "k", "Addresses": [], "Online": true, "DERP": "127.3.3.40:9"}],
+                "SelfNode": {"DERP": "127.3.3.40:1"}}"#,
+        )
+        .unwrap();
+        assert_eq!(netmap.peers[0].online, Some(true));
+        assert_eq!(netmap.peers[0].derp.as_deref(), Some("127.3.3.40:9"));
+        assert_eq!(netmap.self_node.unwrap().derp.as_deref(), Some("127.3.3.40:1"));
+    }
+
+    #[tokio::test]
+    async fn test_dial_before_connect() {
+        let client = TailscaleRust::new().unwrap();
+        assert!(client.local_addrs().is_empty());
+        let err = client.dial("tcp", "db-1:5432").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+        let err = client.listen("udp", ":53").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = client.listen("tcp", "192.0.2.1:80").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+    }
 }
@@ -0,0 +1,300 @@
+//! Backend for a tailscaled already running on the host
+//!
+//! Instead of registering a second node, socktail sits on top of the host's
+//! tailscaled and talks to it through its LocalAPI socket. The node's
+//! addresses and peers come from `/localapi/v0/status`, so MagicDNS names,
+//! tailnet routes and WhoIs-based authentication work as with the built-in
+//! client. In tailscaled's default TUN mode tailnet addresses are reached
+//! through the host's sockets; in userspace-networking mode connections are
+//! opened through `/localapi/v0/dial`. Exit nodes, advertised routes and
+//! other node settings are managed with the `tailscale` CLI.
+
+use super::backend::{self, BackendConfig, BoxFuture, Listener, Stream, VpnBackend};
+use super::dial;
+use super::localapi::{self, LocalApi};
+use super::netmap::PeerMap;
+use super::status::Status;
+use anyhow::{Context, Result};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+/// How often peers and their state are re-read from tailscaled
+const PEER_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Node provided by the host's tailscaled
+#[derive(Debug)]
+pub struct TailscaledBackend {
+    api: LocalApi,
+    addresses: Vec<IpAddr>,
+    peers: PeerMap,
+    /// Last status read, for [`VpnBackend::status`]
+    last_status: Arc<RwLock<localapi::Status>>,
+    /// tailscaled has a TUN device, so host sockets reach the tailnet
+    tun: bool,
+    refresh: Option<JoinHandle<()>>,
+    connected: bool,
+}
+
+impl Default for TailscaledBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TailscaledBackend {
+    pub fn new() -> Self {
+        Self {
+            api: LocalApi::default(),
+            addresses: Vec::new(),
+            peers: PeerMap::new(),
+            last_status: Arc::default(),
+            tun: false,
+            refresh: None,
+            connected: false,
+        }
+    }
+
+    /// Use another LocalAPI socket
+    pub fn set_socket(&mut self, socket: impl Into<std::path::PathBuf>) {
+        self.api = LocalApi::new(socket);
+    }
+
+    fn stop_refresh(&mut self) {
+        if let Some(task) = self.refresh.take() {
+            task.abort();
+        }
+    }
+}
+
+impl Drop for TailscaledBackend {
+    fn drop(&mut self) {
+        self.stop_refresh();
+    }
+}
+
+impl VpnBackend for TailscaledBackend {
+    fn name(&self) -> &'static str {
+        "tailscaled"
+    }
+
+    fn configure(&mut self, config: &BackendConfig) -> Result<()> {
+        config.require_own_node("tailscaled")?;
+        if config.control_url.is_some() {
+            warn!("Control server is ignored: tailscaled is already logged in");
+        }
+        if let Some(socket) = &config.tailscaled_socket {
+            self.set_socket(socket);
+        }
+        Ok(())
+    }
+
+    fn up(&mut self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            let socket = self.api.socket().display().to_string();
+            let status = self.api.status().await.with_context(|| {
+                format!("failed to query tailscaled at {} (is it running?)", socket)
+            })?;
+            if status.backend_state != "Running" {
+                anyhow::bail!(
+                    "tailscaled is {}; run 'tailscale up' first",
+                    if status.backend_state.is_empty() {
+                        "not running"
+                    } else {
+                        &status.backend_state
+                    }
+                );
+            }
+            self.addresses = status.addresses();
+            if self.addresses.is_empty() {
+                anyhow::bail!("tailscaled has no tailnet address; run 'tailscale up' first");
+            }
+            self.tun = status.tun;
+            self.peers.update(status.peers(), status.domain());
+            *self.last_status.write().unwrap() = status;
+            if !self.tun {
+                debug!("tailscaled uses userspace networking; dialing through the LocalAPI");
+            }
+
+            self.stop_refresh();
+            let api = self.api.clone();
+            let peers = self.peers.clone();
+            let last_status = self.last_status.clone();
+            self.refresh = Some(tokio::spawn(async move {
+                let mut interval = tokio::time::interval(PEER_REFRESH_INTERVAL);
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    match api.status().await {
+                        Ok(status) => {
+                            peers.update(status.peers(), status.domain());
+                            *last_status.write().unwrap() = status;
+                        }
+                        Err(e) => warn!("Failed to refresh peers from tailscaled: {}", e),
+                    }
+                }
+            }));
+            self.connected = true;
+            Ok(())
+        })
+    }
+
+    fn down(&mut self) -> BoxFuture<'_, Result<()>> {
+        // tailscaled keeps running; only our view of it is dropped
+        self.stop_refresh();
+        self.addresses.clear();
+        self.peers.clear();
+        self.connected = false;
+        Box::pin(async { Ok(()) })
+    }
+
+    /// As of the last peer refresh
+    fn status(&self) -> Status {
+        if !self.connected {
+            return Status::stopped("tailscaled");
+        }
+        self.last_status.read().unwrap().node_status()
+    }
+
+    fn dial<'a>(
+        &'a self,
+        network: &'a str,
+        addr: &'a str,
+    ) -> BoxFuture<'a, io::Result<Box<dyn Stream>>> {
+        Box::pin(async move {
+            let network = backend::stream_network(network)?;
+            let addr = dial::resolve(network, addr, &self.peers).await?;
+            if self.tun {
+                Ok(Box::new(TcpStream::connect(addr).await?) as Box<dyn Stream>)
+            } else {
+                let stream = self.api.dial(&addr.ip().to_string(), addr.port()).await?;
+                Ok(Box::new(stream) as Box<dyn Stream>)
+            }
+        })
+    }
+
+    /// Listens on the node's addresses only; `:PORT` takes the first one of
+    /// the network's family
+    fn listen(&self, network: &str, addr: &str) -> io::Result<Box<dyn Listener>> {
+        let network = backend::stream_network(network)?;
+        if self.connected && !self.tun {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "tailscaled uses userspace networking; its tailnet addresses cannot be bound",
+            ));
+        }
+        let (host, port) = dial::split_host_port(addr)?;
+        let ip = if host.is_empty() {
+            self.addresses.iter().copied().find(|ip| network.allows(ip))
+        } else {
+            let ip = backend::parse_listen_ip(network, host)?;
+            self.addresses.contains(&ip).then_some(ip)
+        };
+        let ip = ip.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("'{}' is not a tailnet address of this node", addr),
+            )
+        })?;
+        backend::host_listen(SocketAddr::new(ip, port))
+    }
+
+    fn peer_map(&self) -> Option<PeerMap> {
+        Some(self.peers.clone())
+    }
+
+    fn host_tailnet(&self) -> Vec<IpAddr> {
+        // tailscaled's netfilter rules drop tailnet sources arriving on
+        // other interfaces
+        if self.tun {
+            self.addresses.clone()
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn local_api(&self) -> Option<LocalApi> {
+        (!self.tun).then(|| self.api.clone())
+    }
+
+    fn whois_api(&self) -> Option<LocalApi> {
+        Some(self.api.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vpn::localapi::tests::spawn_fake;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_up_with_localapi() {
+        let dir = tempfile::tempdir().unwrap();
+        let api = spawn_fake(dir.path());
+
+        let mut backend = TailscaledBackend::new();
+        let config = BackendConfig {
+            tailscaled_socket: Some(api.socket().to_path_buf()),
+            tailnet_auth: true,
+            ..Default::default()
+        };
+        backend.configure(&config).unwrap();
+        backend.up().await.unwrap();
+
+        let status = backend.status();
+        assert!(status.is_running());
+        assert_eq!(
+            status.addresses(),
+            [
+                "100.64.0.5".parse::<IpAddr>().unwrap(),
+                "fd7a:115c:a1e0::5".parse().unwrap()
+            ]
+        );
+        assert_eq!(status.derp_home.as_deref(), Some("fra"));
+        assert_eq!(status.peers.map(|p| p.len()), Some(2));
+
+        let peers = VpnBackend::peer_map(&backend).unwrap();
+        let who = peers.whois(&"100.64.0.3".parse().unwrap()).unwrap();
+        assert_eq!(who.node, "ci-1.tail1234.ts.net");
+        assert_eq!(who.tags, vec!["tag:ci".to_string()]);
+
+        // The fake runs in userspace-networking mode
+        assert!(VpnBackend::local_api(&backend).is_some());
+        let err = backend.listen("tcp", ":0").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+
+        let mut stream = backend.dial("tcp", "db-1:5432").await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        backend.down().await.unwrap();
+        assert!(!backend.status().is_running());
+        assert!(peers.is_empty());
+
+        backend.set_socket(dir.path().join("missing.sock"));
+        assert!(backend.up().await.is_err());
+    }
+
+    #[test]
+    fn test_listen_on_node_addresses() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _guard = runtime.enter();
+
+        let mut backend = TailscaledBackend::new();
+        backend.addresses = vec!["127.0.0.1".parse().unwrap()];
+        backend.tun = true;
+        backend.connected = true;
+
+        assert!(backend.listen("tcp", ":0").is_ok());
+        let err = backend.listen("tcp", "192.0.2.1:80").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+        assert!(backend.listen("tcp6", ":0").is_err());
+    }
+}
@@ -0,0 +1,210 @@
+//! Kernel TUN interface
+//!
+//! Instead of the userspace [`Netstack`](super::Netstack), packets can be
+//! exchanged with a TUN device carrying the node's tailnet addresses, so
+//! that the whole host joins the tailnet. Creating the device and
+//! configuring addresses and routes needs root (or `CAP_NET_ADMIN`); the
+//! interface is configured with `ip` and is Linux only.
+
+use super::netmap::{TAILNET_IPV4_RANGE, TAILNET_IPV6_RANGE};
+use super::netstack::TAILNET_MTU;
+use crate::utils::Cidr;
+use std::io;
+use std::net::IpAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info};
+
+/// Interface name used when none is given
+pub const DEFAULT_TUN_NAME: &str = "socktail0";
+
+/// A TUN interface with the node's addresses and routes to the tailnet
+pub struct TunDevice {
+    name: String,
+    device: tun::AsyncDevice,
+}
+
+impl TunDevice {
+    /// Create `name` with `addrs` and route the tailnet ranges into it
+    pub fn create(name: &str, addrs: &[IpAddr]) -> io::Result<Self> {
+        if !cfg!(target_os = "linux") {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "TUN mode is only supported on Linux",
+            ));
+        }
+
+        let mut config = tun::Configuration::default();
+        config.name(name).mtu(TAILNET_MTU as i32).up();
+        let device = tun::create_as_async(&config).map_err(tun_error)?;
+        let name = tun::Device::name(device.get_ref()).map_err(tun_error)?;
+
+        for command in setup_commands(&name, addrs) {
+            run_ip(&command)?;
+        }
+        info!("TUN interface {} up with {:?}", name, addrs);
+        Ok(Self { name, device })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Route `routes` (subnets advertised by peers) into the interface
+    pub fn add_routes(&self, routes: &[Cidr]) -> io::Result<()> {
+        for route in routes {
+            run_ip(&route_command(&self.name, route))?;
+        }
+        Ok(())
+    }
+
+    /// Move packets until `shutdown`: read ones go to `outbound`, and what
+    /// arrives on `inbound` is written to the interface
+    pub fn spawn(
+        self,
+        outbound: mpsc::UnboundedSender<Vec<u8>>,
+        mut inbound: mpsc::UnboundedReceiver<Vec<u8>>,
+        shutdown: CancellationToken,
+    ) {
+        let name = self.name;
+        let (mut reader, mut writer) = tokio::io::split(self.device);
+
+        let read_shutdown = shutdown.clone();
+        let read_name = name.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; TAILNET_MTU + 64];
+            loop {
+                let len = tokio::select! {
+                    _ = read_shutdown.cancelled() => break,
+                    read = reader.read(&mut buf) => match read {
+                        Ok(0) => break,
+                        Ok(len) => len,
+                        Err(e) => {
+                            debug!("Read from {} failed: {}", read_name, e);
+                            break;
+                        }
+                    },
+                };
+                if outbound.send(buf[..len].to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        tokio::spawn(async move {
+            loop {
+                let packet = tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    packet = inbound.recv() => match packet {
+                        Some(packet) => packet,
+                        None => break,
+                    },
+                };
+                if let Err(e) = writer.write_all(&packet).await {
+                    debug!("Write to {} failed: {}", name, e);
+                }
+            }
+        });
+    }
+}
+
+impl std::fmt::Debug for TunDevice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TunDevice")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+fn tun_error(e: tun::Error) -> io::Error {
+    match e {
+        tun::Error::Io(e) => e,
+        e => io::Error::other(e.to_string()),
+    }
+}
+
+/// `ip` invocations that assign `addrs` and route the tailnet ranges
+fn setup_commands(name: &str, addrs: &[IpAddr]) -> Vec<Vec<String>> {
+    let mut commands = Vec::new();
+    for addr in addrs {
+        let family = if addr.is_ipv4() { "-4" } else { "-6" };
+        commands.push(args(&[
+            family,
+            "addr",
+            "replace",
+            &Cidr::host(*addr).to_string(),
+            "dev",
+            name,
+        ]));
+    }
+    let ranges = [
+        (TAILNET_IPV4_RANGE, addrs.iter().any(IpAddr::is_ipv4)),
+        (TAILNET_IPV6_RANGE, addrs.iter().any(IpAddr::is_ipv6)),
+    ];
+    for (range, wanted) in ranges {
+        if wanted {
+            commands.push(route_command(
+                name,
+                &range.parse().expect("valid tailnet range"),
+            ));
+        }
+    }
+    commands
+}
+
+fn route_command(name: &str, route: &Cidr) -> Vec<String> {
+    let family = if route.addr().is_ipv4() { "-4" } else { "-6" };
+    args(&[family, "route", "replace", &route.to_string(), "dev", name])
+}
+
+fn args(parts: &[&str]) -> Vec<String> {
+    parts.iter().map(|s| s.to_string()).collect()
+}
+
+fn run_ip(args: &[String]) -> io::Result<()> {
+    debug!("ip {}", args.join(" "));
+    let output = std::process::Command::new("ip").args(args).output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "ip {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_setup_commands() {
+        let addrs = [
+            "100.64.0.1".parse().unwrap(),
+            "fd7a:115c:a1e0::1".parse().unwrap(),
+        ];
+        let commands: Vec<String> = setup_commands("ts0", &addrs)
+            .iter()
+            .map(|c| c.join(" "))
+            .collect();
+        assert_eq!(
+            commands,
+            [
+                "-4 addr replace 100.64.0.1/32 dev ts0",
+                "-6 addr replace fd7a:115c:a1e0::1/128 dev ts0",
+                "-4 route replace 100.64.0.0/10 dev ts0",
+                "-6 route replace fd7a:115c:a1e0::/48 dev ts0",
+            ]
+        );
+
+        // No IPv6 address, no IPv6 route
+        let v4_only = setup_commands("ts0", &addrs[..1]);
+        assert_eq!(v4_only.len(), 2);
+        assert_eq!(
+            route_command("ts0", &"10.1.0.0/16".parse().unwrap()).join(" "),
+            "-4 route replace 10.1.0.0/16 dev ts0"
+        );
+    }
+}
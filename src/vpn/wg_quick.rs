@@ -0,0 +1,604 @@
+//! Standalone WireGuard from a wg-quick configuration file
+//!
+//! `--wireguard wg0.conf` brings up the userspace WireGuard device and
+//! netstack with the keys, addresses and peers of a plain WireGuard
+//! configuration, without a control server. Like wg-quick, destinations in
+//! a peer's `AllowedIPs` go through the tunnel and a peer with a default
+//! route carries everything else; names are resolved with the `DNS`
+//! servers of the `[Interface]` section.
+//!
+//! Keys wg-quick applies to the host (`MTU`, `Table`, `PreUp`, `PostDown`,
+//! ...) have no meaning here and are ignored.
+
+use super::backend::{self, BackendConfig, BoxFuture, Listener, Stream, VpnBackend};
+use super::dial;
+use super::exit_node::ExitNode;
+use super::netmap::{PeerInfo, PeerMap};
+use super::netstack::Netstack;
+use super::status::{BackendState, NodeStatus, PeerStatus, Status};
+use super::wireguard::{PeerOptions, WgDevice};
+use crate::utils::Cidr;
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tokio::net::TcpStream;
+use tracing::{debug, info};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Keys wg-quick uses to configure the host
+const HOST_KEYS: &[&str] = &[
+    "MTU", "Table", "PreUp", "PostUp", "PreDown", "PostDown", "SaveConfig", "FwMark",
+];
+
+/// Parsed wg-quick configuration
+#[derive(Debug, Clone)]
+pub struct WgQuickConfig {
+    pub private_key: [u8; 32],
+    /// Interface addresses; the prefix length of `Address` is dropped
+    pub addresses: Vec<IpAddr>,
+    pub dns: Vec<IpAddr>,
+    pub listen_port: Option<u16>,
+    pub peers: Vec<WgQuickPeer>,
+}
+
+/// A `[Peer]` section
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WgQuickPeer {
+    pub public_key: [u8; 32],
+    pub preshared_key: Option<[u8; 32]>,
+    pub allowed_ips: Vec<Cidr>,
+    /// `HOST:PORT`, resolved when the tunnel comes up
+    pub endpoint: Option<String>,
+    pub persistent_keepalive: Option<u16>,
+}
+
+impl WgQuickConfig {
+    /// Read and parse a configuration file
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        text.parse()
+            .with_context(|| format!("invalid WireGuard configuration {}", path.display()))
+    }
+}
+
+impl FromStr for WgQuickConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        enum Section {
+            None,
+            Interface,
+            Peer,
+        }
+
+        let mut section = Section::None;
+        let mut private_key = None;
+        let mut addresses = Vec::new();
+        let mut dns = Vec::new();
+        let mut listen_port = None;
+        let mut peers: Vec<WgQuickPeer> = Vec::new();
+
+        for (number, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let context = || format!("line {}", number + 1);
+
+            if line.eq_ignore_ascii_case("[Interface]") {
+                section = Section::Interface;
+                continue;
+            }
+            if line.eq_ignore_ascii_case("[Peer]") {
+                section = Section::Peer;
+                peers.push(WgQuickPeer {
+                    public_key: [0u8; 32],
+                    preshared_key: None,
+                    allowed_ips: Vec::new(),
+                    endpoint: None,
+                    persistent_keepalive: None,
+                });
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .map(|(k, v)| (k.trim(), v.trim()))
+                .with_context(|| format!("{}: expected KEY = VALUE or a section", context()))?;
+            let list = || value.split(',').map(str::trim).filter(|v| !v.is_empty());
+
+            match section {
+                Section::None => anyhow::bail!("{}: '{}' outside a section", context(), key),
+                Section::Interface => match key.to_ascii_lowercase().as_str() {
+                    "privatekey" => private_key = Some(decode_key(value).with_context(context)?),
+                    "address" => {
+                        for address in list() {
+                            let ip = address.split('/').next().unwrap_or_default();
+                            addresses.push(ip.parse().with_context(|| {
+                                format!("{}: invalid address '{}'", context(), address)
+                            })?);
+                        }
+                    }
+                    // Search domains may be listed too; only servers are used
+                    "dns" => dns.extend(list().filter_map(|v| v.parse::<IpAddr>().ok())),
+                    "listenport" => {
+                        listen_port = Some(value.parse().with_context(|| {
+                            format!("{}: invalid ListenPort '{}'", context(), value)
+                        })?)
+                    }
+                    _ if HOST_KEYS.iter().any(|k| k.eq_ignore_ascii_case(key)) => {
+                        debug!("Ignoring {} in WireGuard configuration", key)
+                    }
+                    _ => anyhow::bail!("{}: unknown [Interface] key '{}'", context(), key),
+                },
+                Section::Peer => {
+                    let peer = peers.last_mut().expect("in a [Peer] section");
+                    match key.to_ascii_lowercase().as_str() {
+                        "publickey" => peer.public_key = decode_key(value).with_context(context)?,
+                        "presharedkey" => {
+                            peer.preshared_key = Some(decode_key(value).with_context(context)?)
+                        }
+                        "allowedips" => {
+                            for prefix in list() {
+                                peer.allowed_ips.push(prefix.parse().map_err(|e| {
+                                    anyhow::anyhow!("{}: {}", context(), e)
+                                })?);
+                            }
+                        }
+                        "endpoint" => {
+                            dial::split_host_port(value).with_context(context)?;
+                            peer.endpoint = Some(value.to_string());
+                        }
+                        "persistentkeepalive" => {
+                            peer.persistent_keepalive = match value {
+                                "off" | "0" => None,
+                                _ => Some(value.parse().with_context(|| {
+                                    format!("{}: invalid PersistentKeepalive '{}'", context(), value)
+                                })?),
+                            }
+                        }
+                        _ => anyhow::bail!("{}: unknown [Peer] key '{}'", context(), key),
+                    }
+                }
+            }
+        }
+
+        let private_key = private_key.context("[Interface] has no PrivateKey")?;
+        if addresses.is_empty() {
+            anyhow::bail!("[Interface] has no Address");
+        }
+        if peers.is_empty() {
+            anyhow::bail!("no [Peer] sections");
+        }
+        if let Some(i) = peers.iter().position(|p| p.public_key == [0u8; 32]) {
+            anyhow::bail!("[Peer] {} has no PublicKey", i + 1);
+        }
+
+        Ok(Self {
+            private_key,
+            addresses,
+            dns,
+            listen_port,
+            peers,
+        })
+    }
+}
+
+fn decode_key(value: &str) -> Result<[u8; 32]> {
+    BASE64
+        .decode(value)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .context("invalid key, expected 32 bytes in base64")
+}
+
+impl WgQuickPeer {
+    /// Peer in the form the data plane uses: host prefixes are its
+    /// addresses, everything else is routed to it
+    fn info(&self, endpoint: Option<SocketAddr>) -> PeerInfo {
+        let is_host = |c: &&Cidr| c.prefix_len() == if c.addr().is_ipv4() { 32 } else { 128 };
+        let addresses: Vec<IpAddr> = self.allowed_ips.iter().filter(is_host).map(Cidr::addr).collect();
+        let tailscale_ip = match addresses.first() {
+            Some(ip) => *ip,
+            None => self
+                .allowed_ips
+                .first()
+                .map_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED), Cidr::addr),
+        };
+        PeerInfo {
+            public_key: self.public_key,
+            tailscale_ip,
+            addresses,
+            allowed_ips: self
+                .allowed_ips
+                .iter()
+                .filter(|c| !is_host(c))
+                .copied()
+                .collect(),
+            endpoint,
+            ..Default::default()
+        }
+    }
+
+    fn is_default_route(&self) -> bool {
+        self.allowed_ips.iter().any(Cidr::is_default_route)
+    }
+}
+
+/// The `wireguard` backend
+#[derive(Default)]
+pub struct WireGuardBackend {
+    path: Option<PathBuf>,
+    config: Option<WgQuickConfig>,
+    device: Option<WgDevice>,
+    peers: PeerMap,
+    exit_node: Option<ExitNode>,
+}
+
+impl WireGuardBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use an already parsed configuration
+    pub fn set_config(&mut self, config: WgQuickConfig) {
+        self.config = Some(config);
+    }
+
+    fn netstack_ref(&self) -> io::Result<&Netstack> {
+        self.device
+            .as_ref()
+            .and_then(WgDevice::netstack)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "WireGuard is not up"))
+    }
+
+    /// Whether traffic to `ip` goes through the tunnel
+    fn carries(&self, ip: &IpAddr) -> bool {
+        self.exit_node.is_some() || self.routes().iter().any(|c| c.contains(ip))
+    }
+}
+
+impl Drop for WireGuardBackend {
+    fn drop(&mut self) {
+        if let Some(device) = self.device.take() {
+            device.shutdown();
+        }
+    }
+}
+
+impl VpnBackend for WireGuardBackend {
+    fn name(&self) -> &'static str {
+        "wireguard"
+    }
+
+    fn configure(&mut self, config: &BackendConfig) -> Result<()> {
+        // tailnet:PORT listeners work on the netstack like with `rust`
+        BackendConfig {
+            tailnet_listeners: false,
+            ..config.clone()
+        }
+        .require_own_node("wireguard")?;
+        config.require_no_tailnet_auth("wireguard")?;
+        if let Some(path) = &config.wireguard {
+            self.config = Some(WgQuickConfig::load(path)?);
+            self.path = Some(path.clone());
+        }
+        if self.config.is_none() {
+            anyhow::bail!("the wireguard backend needs a configuration file (--wireguard FILE)");
+        }
+        Ok(())
+    }
+
+    fn up(&mut self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            let config = self.config.clone().context("WireGuard is not configured")?;
+            if let Some(path) = &self.path {
+                info!("Bringing up WireGuard from {}", path.display());
+            }
+
+            // Dual-stack so that peers can have IPv6 endpoints, unless the
+            // host has no IPv6
+            let port = config.listen_port.unwrap_or(0);
+            let key = StaticSecret::from(config.private_key);
+            let listen = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port);
+            let device = match WgDevice::bind(key.clone(), listen, &config.addresses).await {
+                Ok(device) => device,
+                Err(e) => {
+                    debug!("No IPv6 WireGuard socket ({}), using IPv4 only", e);
+                    let listen = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port);
+                    WgDevice::bind(key, listen, &config.addresses)
+                        .await
+                        .context("Failed to bind WireGuard socket")?
+                }
+            };
+            let ipv6 = device.local_addr()?.is_ipv6();
+            info!("WireGuard listening on: {}", device.local_addr()?);
+
+            let mut peers = Vec::new();
+            for peer in &config.peers {
+                let endpoint = match &peer.endpoint {
+                    Some(endpoint) => {
+                        let addrs: Vec<SocketAddr> = tokio::net::lookup_host(endpoint.as_str())
+                            .await
+                            .with_context(|| format!("failed to resolve endpoint {}", endpoint))?
+                            .collect();
+                        // IPv4 first, as before dual-stack sockets
+                        let usable = addrs.iter().find(|addr| addr.is_ipv4());
+                        let usable = usable.or_else(|| addrs.iter().find(|_| ipv6));
+                        Some(*usable.with_context(|| {
+                            format!("endpoint {} has no address this host can reach", endpoint)
+                        })?)
+                    }
+                    None => None,
+                };
+                device.set_peer_options(
+                    peer.public_key,
+                    PeerOptions {
+                        preshared_key: peer.preshared_key,
+                        persistent_keepalive: peer.persistent_keepalive,
+                    },
+                );
+                let info = peer.info(endpoint);
+                info!("Adding peer {} with endpoint {:?}", info.tailscale_ip, endpoint);
+                if self.exit_node.is_none() && peer.is_default_route() {
+                    device.set_exit_node(Some(peer.public_key));
+                    let mut exit = ExitNode::new(&info, false);
+                    if let Some(endpoint) = endpoint {
+                        exit.name = endpoint.to_string();
+                    }
+                    info!("All traffic goes through WireGuard peer {}", exit);
+                    self.exit_node = Some(exit);
+                }
+                peers.push(info);
+            }
+            device.set_peers(&peers);
+            self.peers.update(peers, None);
+            self.device = Some(device);
+            Ok(())
+        })
+    }
+
+    fn down(&mut self) -> BoxFuture<'_, Result<()>> {
+        if let Some(device) = self.device.take() {
+            device.shutdown();
+        }
+        self.peers.clear();
+        self.exit_node = None;
+        Box::pin(async { Ok(()) })
+    }
+
+    fn status(&self) -> Status {
+        let (Some(device), Some(config)) = (&self.device, &self.config) else {
+            return Status::stopped("wireguard");
+        };
+        let stats = device.peer_stats();
+        let peers = self
+            .peers
+            .peers()
+            .iter()
+            .map(|peer| {
+                let key = BASE64.encode(peer.public_key);
+                PeerStatus::new(peer, key, stats.get(&peer.public_key))
+            })
+            .collect();
+        Status {
+            backend: "wireguard".to_string(),
+            state: BackendState::Running,
+            self_node: NodeStatus {
+                name: None,
+                public_key: Some(BASE64.encode(
+                    PublicKey::from(&StaticSecret::from(config.private_key)).as_bytes(),
+                )),
+                addresses: config.addresses.clone(),
+            },
+            derp_home: None,
+            peers: Some(peers),
+        }
+    }
+
+    /// Destinations the tunnel carries go through it, the rest through the
+    /// host's sockets
+    fn dial<'a>(
+        &'a self,
+        network: &'a str,
+        addr: &'a str,
+    ) -> BoxFuture<'a, io::Result<Box<dyn Stream>>> {
+        Box::pin(async move {
+            let network = backend::stream_network(network)?;
+            let netstack = self.netstack_ref()?;
+            let addr = dial::resolve(network, addr, &self.peers).await?;
+            if self.carries(&addr.ip()) {
+                Ok(Box::new(netstack.connect(addr).await?) as Box<dyn Stream>)
+            } else {
+                Ok(Box::new(TcpStream::connect(addr).await?) as Box<dyn Stream>)
+            }
+        })
+    }
+
+    fn listen(&self, network: &str, addr: &str) -> io::Result<Box<dyn Listener>> {
+        let network = backend::stream_network(network)?;
+        let netstack = self.netstack_ref()?;
+        let (host, port) = dial::split_host_port(addr)?;
+        if !host.is_empty() {
+            let ip = backend::parse_listen_ip(network, host)?;
+            if !netstack.addrs().contains(&ip) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    format!("{} is not an address of the WireGuard interface", ip),
+                ));
+            }
+        }
+        Ok(Box::new(netstack.listen(port)?))
+    }
+
+    fn peer_map(&self) -> Option<PeerMap> {
+        Some(self.peers.clone())
+    }
+
+    fn netstack(&self) -> Option<Netstack> {
+        self.device.as_ref()?.netstack().cloned()
+    }
+
+    fn exit_node(&self) -> Option<ExitNode> {
+        self.exit_node.clone()
+    }
+
+    fn routes(&self) -> Vec<Cidr> {
+        self.config
+            .iter()
+            .flat_map(|config| &config.peers)
+            .flat_map(|peer| &peer.allowed_ips)
+            .filter(|c| !c.is_default_route())
+            .copied()
+            .collect()
+    }
+
+    fn dns_servers(&self) -> Vec<IpAddr> {
+        self.config
+            .as_ref()
+            .map(|config| config.dns.clone())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vpn::status::PeerPath;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const CONFIG: &str = "
+        [Interface]
+        # laptop
+        PrivateKey = yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=
+        Address = 10.8.0.2/24, fd00:8::2/64
+        DNS = 10.8.0.1, corp.example
+        MTU = 1420
+
+        [Peer]
+        PublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=
+        PresharedKey = /UwcSPg38hW/D9Y3tcS1FOV0K1wuURMbS0sesJEP5ak=
+        AllowedIPs = 10.8.0.1/32, 10.20.0.0/16
+        Endpoint = 192.0.2.1:51820
+        PersistentKeepalive = 25
+    ";
+
+    #[test]
+    fn test_parse() {
+        let config: WgQuickConfig = CONFIG.parse().unwrap();
+        assert_eq!(
+            config.addresses,
+            vec![
+                "10.8.0.2".parse::<IpAddr>().unwrap(),
+                "fd00:8::2".parse().unwrap()
+            ]
+        );
+        assert_eq!(config.dns, vec!["10.8.0.1".parse::<IpAddr>().unwrap()]);
+        assert_eq!(config.listen_port, None);
+
+        let peer = &config.peers[0];
+        assert!(peer.preshared_key.is_some());
+        assert_eq!(peer.endpoint.as_deref(), Some("192.0.2.1:51820"));
+        assert_eq!(peer.persistent_keepalive, Some(25));
+
+        let info = peer.info(None);
+        assert_eq!(info.tailscale_ip, "10.8.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(info.allowed_ips, vec!["10.20.0.0/16".parse().unwrap()]);
+        assert!(!peer.is_default_route());
+
+        for (config, error) in [
+            ("PrivateKey = x", "outside a section"),
+            ("[Interface]\nPrivateKey = abc", "invalid key"),
+            ("[Interface]\nAddress = 10.0.0.1/24\n[Peer]\nPublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=", "no PrivateKey"),
+            ("[Interface]\nPostUp = iptables\nListenPort = 51820\nFoo = 1", "unknown [Interface] key 'Foo'"),
+            ("[Peer]\nEndpoint = vpn.example", "expected HOST:PORT"),
+        ] {
+            let err = format!("{:#}", config.parse::<WgQuickConfig>().unwrap_err());
+            assert!(err.contains(error), "{}: {}", config, err);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_to_peer() {
+        tunnel_to_peer("127.0.0.1:0").await;
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_to_ipv6_peer() {
+        tunnel_to_peer("[::1]:0").await;
+    }
+
+    async fn tunnel_to_peer(peer_listen: &str) {
+        let peer_key = StaticSecret::random_from_rng(rand::thread_rng());
+        let peer_device = WgDevice::bind(
+            peer_key.clone(),
+            peer_listen.parse().unwrap(),
+            &["10.8.0.1".parse().unwrap(), "10.20.0.5".parse().unwrap()],
+        )
+        .await
+        .unwrap();
+        let our_key = StaticSecret::random_from_rng(rand::thread_rng());
+
+        let config: WgQuickConfig = format!(
+            "[Interface]\nPrivateKey = {}\nAddress = 10.8.0.2/32\n\n\
+             [Peer]\nPublicKey = {}\nAllowedIPs = 10.8.0.1/32, 10.20.0.0/16\nEndpoint = {}\n",
+            BASE64.encode(our_key.to_bytes()),
+            BASE64.encode(PublicKey::from(&peer_key).to_bytes()),
+            peer_device.local_addr().unwrap()
+        )
+        .parse()
+        .unwrap();
+
+        let mut backend = WireGuardBackend::new();
+        backend.set_config(config);
+        backend.configure(&BackendConfig::default()).unwrap();
+        backend.up().await.unwrap();
+
+        let status = backend.status();
+        assert!(status.is_running());
+        assert_eq!(status.peers.as_ref().map(Vec::len), Some(1));
+        assert!(backend.exit_node().is_none());
+        assert_eq!(backend.routes().len(), 2);
+
+        // The peer only learns our endpoint from the handshake
+        let mut us = peer_key_info(&our_key, "10.8.0.2");
+        us.endpoint = None;
+        peer_device.set_peers(&[us]);
+
+        let listener = peer_device.netstack().unwrap().listen(5432).unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, caller) = listener.accept().await.unwrap();
+            assert_eq!(caller.ip(), "10.8.0.2".parse::<IpAddr>().unwrap());
+            stream.write_all(b"hi").await.unwrap();
+        });
+        let mut stream = backend.dial("tcp", "10.20.0.5:5432").await.unwrap();
+        let mut buf = [0u8; 2];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hi");
+        server.await.unwrap();
+
+        let peer = &backend.status().peers.unwrap()[0];
+        assert_eq!(peer.path, PeerPath::Direct);
+        assert_eq!(peer.endpoint, Some(peer_device.local_addr().unwrap()));
+        assert!(peer.rx_bytes > 0);
+
+        backend.down().await.unwrap();
+        assert!(!backend.status().is_running());
+        peer_device.shutdown();
+    }
+
+    fn peer_key_info(key: &StaticSecret, ip: &str) -> PeerInfo {
+        WgQuickPeer {
+            public_key: PublicKey::from(key).to_bytes(),
+            preshared_key: None,
+            allowed_ips: vec![format!("{}/32", ip).parse().unwrap()],
+            endpoint: None,
+            persistent_keepalive: None,
+        }
+        .info(None)
+    }
+}
@@ -0,0 +1,758 @@
+//! WireGuard data plane
+//!
+//! [`WgDevice`] owns the UDP socket and one boringtun [`Tunn`] per peer.
+//! Decrypted packets are fed to the node's [`Netstack`]; packets the stack
+//! emits are routed to a peer by destination address and encrypted.
+//!
+//! Inbound packets must pass the control server's [`PacketFilter`] unless
+//! they answer a flow this node opened.
+//!
+//! Datagrams and completed handshakes are counted in [`METRICS`].
+
+use super::filter::{PacketFilter, PacketInfo};
+use super::netmap::PeerInfo;
+use super::netstack::Netstack;
+use super::tun_device::TunDevice;
+use crate::metrics::{Metrics, METRICS};
+use boringtun::noise::handshake::parse_handshake_anon;
+use boringtun::noise::{Packet, Tunn, TunnResult};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, trace};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Largest datagram we expect from the network
+const MAX_DATAGRAM: usize = 65536;
+
+/// WireGuard overhead added to every encapsulated packet
+const WG_OVERHEAD: usize = 148;
+
+/// Message type of a handshake response, the last message of a handshake
+const HANDSHAKE_RESPONSE: u8 = 2;
+
+/// How often boringtun's handshake and keepalive timers run
+const TIMER_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Idle time after which replies to an outbound flow are filtered again
+const FLOW_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// An outbound flow, keyed the way its replies arrive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Flow {
+    proto: u8,
+    remote: IpAddr,
+    remote_port: u16,
+    local_port: u16,
+}
+
+impl Flow {
+    fn outbound(packet: &PacketInfo) -> Self {
+        Self {
+            proto: packet.proto,
+            remote: packet.dst,
+            remote_port: packet.dst_port,
+            local_port: packet.src_port,
+        }
+    }
+
+    fn inbound(packet: &PacketInfo) -> Self {
+        Self {
+            proto: packet.proto,
+            remote: packet.src,
+            remote_port: packet.src_port,
+            local_port: packet.dst_port,
+        }
+    }
+}
+
+/// WireGuard settings of a peer beyond its [`PeerInfo`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerOptions {
+    pub preshared_key: Option<[u8; 32]>,
+    /// Seconds between keepalives while the tunnel is idle
+    pub persistent_keepalive: Option<u16>,
+}
+
+/// State of a peer's WireGuard session, from [`WgDevice::peer_stats`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WgPeerStats {
+    /// Time since the last completed handshake
+    pub since_handshake: Option<Duration>,
+    /// Address the peer is currently reached at
+    pub endpoint: Option<SocketAddr>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+/// A peer with its WireGuard session
+struct WgPeer {
+    info: PeerInfo,
+    /// Local session index, shifted into `receiver_idx` by boringtun
+    index: u32,
+    tunn: Mutex<Tunn>,
+    /// Last address the peer was heard from, or its netmap endpoint
+    endpoint: Mutex<Option<SocketAddr>>,
+}
+
+impl WgPeer {
+    fn endpoint(&self) -> Option<SocketAddr> {
+        *self.endpoint.lock().unwrap()
+    }
+}
+
+#[derive(Default)]
+struct Peers {
+    by_key: HashMap<[u8; 32], Arc<WgPeer>>,
+    /// Keyed by the local session index boringtun puts in `receiver_idx >> 8`
+    by_index: HashMap<u32, Arc<WgPeer>>,
+    next_index: u32,
+    /// Public key of the peer carrying non-tailnet traffic
+    exit_node: Option<[u8; 32]>,
+    /// Settings for sessions created by `set_peers`
+    options: HashMap<[u8; 32], PeerOptions>,
+}
+
+impl Peers {
+    /// Peer that owns `ip`, else the one with the longest subnet route to
+    /// it, else the exit node
+    fn route(&self, ip: &IpAddr) -> Option<Arc<WgPeer>> {
+        if let Some(peer) = self.by_key.values().find(|p| p.info.has_address(ip)) {
+            return Some(peer.clone());
+        }
+        let subnet = self
+            .by_key
+            .values()
+            .filter_map(|p| Some((p.info.subnet_route(ip)?.prefix_len(), p)))
+            .max_by_key(|(len, _)| *len);
+        if let Some((_, peer)) = subnet {
+            return Some(peer.clone());
+        }
+        self.exit_node
+            .and_then(|key| self.by_key.get(&key).cloned())
+    }
+}
+
+/// Where decrypted packets go
+enum Interface {
+    Netstack(Netstack),
+    /// Writer side of a kernel TUN device
+    Tun(mpsc::UnboundedSender<Vec<u8>>),
+}
+
+struct Shared {
+    private_key: StaticSecret,
+    public_key: PublicKey,
+    socket: UdpSocket,
+    /// The socket is IPv6 and also carries IPv4 as v4-mapped addresses
+    dual_stack: bool,
+    peers: RwLock<Peers>,
+    interface: Interface,
+    /// Inbound filter; `None` accepts every packet
+    filter: RwLock<Option<Arc<PacketFilter>>>,
+    /// Flows we opened, so that their replies pass the filter
+    flows: Mutex<HashMap<Flow, Instant>>,
+    /// [`METRICS`] outside of tests
+    metrics: &'static Metrics,
+    shutdown: CancellationToken,
+}
+
+/// Userspace WireGuard interface feeding a [`Netstack`] or a TUN device
+#[derive(Clone)]
+pub struct WgDevice {
+    shared: Arc<Shared>,
+}
+
+impl WgDevice {
+    /// Bind the UDP socket and start a netstack that owns `addrs`; `[::]`
+    /// also receives IPv4
+    pub async fn bind(
+        private_key: StaticSecret,
+        listen: SocketAddr,
+        addrs: &[IpAddr],
+    ) -> io::Result<Self> {
+        Self::bind_counted(private_key, listen, addrs, &METRICS).await
+    }
+
+    async fn bind_counted(
+        private_key: StaticSecret,
+        listen: SocketAddr,
+        addrs: &[IpAddr],
+        metrics: &'static Metrics,
+    ) -> io::Result<Self> {
+        let socket = bind_udp(listen)?;
+        let (outbound_tx, outbound_rx) = mpsc::unbounded_channel();
+        let netstack = Netstack::new(addrs, outbound_tx);
+        Ok(Self::start(
+            private_key,
+            socket,
+            Interface::Netstack(netstack),
+            outbound_rx,
+            metrics,
+        ))
+    }
+
+    /// Bind the UDP socket and exchange packets with a kernel TUN device
+    pub async fn bind_tun(
+        private_key: StaticSecret,
+        listen: SocketAddr,
+        tun: TunDevice,
+    ) -> io::Result<Self> {
+        let socket = bind_udp(listen)?;
+        let (outbound_tx, outbound_rx) = mpsc::unbounded_channel();
+        let (inbound_tx, inbound_rx) = mpsc::unbounded_channel();
+        let device = Self::start(
+            private_key,
+            socket,
+            Interface::Tun(inbound_tx),
+            outbound_rx,
+            &METRICS,
+        );
+        tun.spawn(outbound_tx, inbound_rx, device.shared.shutdown.clone());
+        Ok(device)
+    }
+
+    fn start(
+        private_key: StaticSecret,
+        socket: UdpSocket,
+        interface: Interface,
+        outbound: mpsc::UnboundedReceiver<Vec<u8>>,
+        metrics: &'static Metrics,
+    ) -> Self {
+        let dual_stack = socket.local_addr().is_ok_and(|addr| addr.is_ipv6());
+        let device = Self {
+            shared: Arc::new(Shared {
+                public_key: PublicKey::from(&private_key),
+                private_key,
+                socket,
+                dual_stack,
+                peers: RwLock::new(Peers::default()),
+                interface,
+                filter: RwLock::new(None),
+                flows: Mutex::new(HashMap::new()),
+                metrics,
+                shutdown: CancellationToken::new(),
+            }),
+        };
+
+        tokio::spawn(device.clone().outbound_loop(outbound));
+        tokio::spawn(device.clone().inbound_loop());
+        tokio::spawn(device.clone().timer_loop());
+        device
+    }
+
+    /// Local address of the UDP socket
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.shared.socket.local_addr()
+    }
+
+    /// Userspace stack bound to the node addresses; `None` in TUN mode
+    pub fn netstack(&self) -> Option<&Netstack> {
+        match &self.shared.interface {
+            Interface::Netstack(netstack) => Some(netstack),
+            Interface::Tun(_) => None,
+        }
+    }
+
+    /// Replace the peer set, keeping sessions of peers that are still present
+    pub fn set_peers(&self, peers: &[PeerInfo]) {
+        let mut table = self.shared.peers.write().unwrap();
+        let mut old = std::mem::take(&mut table.by_key);
+        table.by_index.clear();
+
+        for info in peers {
+            let peer = match old.remove(&info.public_key) {
+                Some(existing) if existing.info.addresses == info.addresses => existing,
+                _ => {
+                    table.next_index = (table.next_index + 1) & 0x00ff_ffff;
+                    let index = table.next_index;
+                    let options = table.options.get(&info.public_key).copied().unwrap_or_default();
+                    let tunn = match Tunn::new(
+                        self.shared.private_key.clone(),
+                        PublicKey::from(info.public_key),
+                        options.preshared_key,
+                        options.persistent_keepalive,
+                        index,
+                        None,
+                    ) {
+                        Ok(tunn) => tunn,
+                        Err(e) => {
+                            debug!("Skipping peer {}: {}", info.tailscale_ip, e);
+                            continue;
+                        }
+                    };
+                    Arc::new(WgPeer {
+                        info: info.clone(),
+                        index,
+                        tunn: Mutex::new(tunn),
+                        endpoint: Mutex::new(info.endpoint),
+                    })
+                }
+            };
+            // Roaming may have replaced the netmap endpoint; keep what we learned
+            if peer.endpoint().is_none() {
+                *peer.endpoint.lock().unwrap() = info.endpoint;
+            }
+            table.by_index.insert(peer.index, peer.clone());
+            table.by_key.insert(info.public_key, peer);
+        }
+    }
+
+    /// Preshared key and keepalive for the peer with `public_key`; applies to
+    /// sessions created by later calls to [`set_peers`](Self::set_peers)
+    pub fn set_peer_options(&self, public_key: [u8; 32], options: PeerOptions) {
+        self.shared.peers.write().unwrap().options.insert(public_key, options);
+    }
+
+    /// Send non-tailnet traffic through the peer with `public_key`, or
+    /// drop it with `None`
+    pub fn set_exit_node(&self, public_key: Option<[u8; 32]>) {
+        self.shared.peers.write().unwrap().exit_node = public_key;
+    }
+
+    /// Round-trip time of the last handshake with a peer, starting one if
+    /// there is no session yet and waiting up to `timeout` for it
+    pub async fn handshake_rtt(
+        &self,
+        public_key: &[u8; 32],
+        timeout: Duration,
+    ) -> Option<Duration> {
+        let peer = self
+            .shared
+            .peers
+            .read()
+            .unwrap()
+            .by_key
+            .get(public_key)
+            .cloned()?;
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        let init = match peer
+            .tunn
+            .lock()
+            .unwrap()
+            .format_handshake_initiation(&mut buf, false)
+        {
+            TunnResult::WriteToNetwork(data) => Some(data.to_vec()),
+            _ => None,
+        };
+        if let Some(init) = init {
+            self.send_to(&peer, &init).await;
+        }
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let (_, _, _, _, Some(rtt)) = peer.tunn.lock().unwrap().stats() {
+                return Some(Duration::from_millis(rtt.into()));
+            }
+            if Instant::now() >= deadline {
+                return None;
+            }
+            tokio::time::sleep(TIMER_INTERVAL).await;
+        }
+    }
+
+    /// Session state of every peer, by public key
+    pub fn peer_stats(&self) -> HashMap<[u8; 32], WgPeerStats> {
+        let peers = self.shared.peers.read().unwrap();
+        peers
+            .by_key
+            .iter()
+            .map(|(key, peer)| {
+                let (since_handshake, tx, rx, _, _) = peer.tunn.lock().unwrap().stats();
+                let stats = WgPeerStats {
+                    since_handshake,
+                    endpoint: peer.endpoint(),
+                    rx_bytes: rx as u64,
+                    tx_bytes: tx as u64,
+                };
+                (*key, stats)
+            })
+            .collect()
+    }
+
+    /// Filter inbound packets from now on
+    pub fn set_filter(&self, filter: Arc<PacketFilter>) {
+        *self.shared.filter.write().unwrap() = Some(filter);
+    }
+
+    /// Stop the packet loops and the netstack or TUN device
+    pub fn shutdown(&self) {
+        if let Some(netstack) = self.netstack() {
+            netstack.shutdown();
+        }
+        self.shared.shutdown.cancel();
+    }
+
+    async fn outbound_loop(self, mut packets: mpsc::UnboundedReceiver<Vec<u8>>) {
+        let mut buf = vec![0u8; MAX_DATAGRAM + WG_OVERHEAD];
+        loop {
+            let packet = tokio::select! {
+                _ = self.shared.shutdown.cancelled() => break,
+                packet = packets.recv() => match packet {
+                    Some(packet) => packet,
+                    None => break,
+                },
+            };
+            self.send_packet(&packet, &mut buf).await;
+        }
+    }
+
+    /// Encrypt an IP packet to the peer that owns its destination
+    async fn send_packet(&self, packet: &[u8], buf: &mut [u8]) {
+        let Some(dst) = Tunn::dst_address(packet) else {
+            return;
+        };
+        let Some(peer) = self.shared.peers.read().unwrap().route(&dst) else {
+            trace!("No tailnet peer for {}, dropping packet", dst);
+            return;
+        };
+        if let Some(info) = PacketInfo::parse(packet) {
+            self.shared
+                .flows
+                .lock()
+                .unwrap()
+                .insert(Flow::outbound(&info), Instant::now());
+        }
+
+        let datagram = match peer.tunn.lock().unwrap().encapsulate(packet, buf) {
+            TunnResult::WriteToNetwork(data) => data.to_vec(),
+            TunnResult::Err(e) => {
+                debug!("Encapsulate to {} failed: {:?}", dst, e);
+                return;
+            }
+            _ => return,
+        };
+        self.send_to(&peer, &datagram).await;
+    }
+
+    async fn send_to(&self, peer: &WgPeer, datagram: &[u8]) {
+        match peer.endpoint() {
+            Some(endpoint) => {
+                let target = match endpoint {
+                    SocketAddr::V4(v4) if self.shared.dual_stack => {
+                        SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port())
+                    }
+                    _ => endpoint,
+                };
+                if let Err(e) = self.shared.socket.send_to(datagram, target).await {
+                    debug!("Send to {} failed: {}", endpoint, e);
+                    return;
+                }
+                self.shared.metrics.packets_direct_tx.inc();
+                if datagram.first() == Some(&HANDSHAKE_RESPONSE) {
+                    self.shared.metrics.wg_handshakes.inc();
+                }
+            }
+            None => trace!("Peer {} has no endpoint", peer.info.tailscale_ip),
+        }
+    }
+
+    async fn inbound_loop(self) {
+        let mut datagram = vec![0u8; MAX_DATAGRAM];
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        loop {
+            let (len, src) = tokio::select! {
+                _ = self.shared.shutdown.cancelled() => break,
+                received = self.shared.socket.recv_from(&mut datagram) => match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        debug!("WireGuard receive failed: {}", e);
+                        continue;
+                    }
+                },
+            };
+            self.shared.metrics.packets_direct_rx.inc();
+            let src = SocketAddr::new(src.ip().to_canonical(), src.port());
+            self.receive(&datagram[..len], src, &mut buf).await;
+        }
+    }
+
+    /// Decrypt a datagram and deliver its packets to the interface
+    async fn receive(&self, datagram: &[u8], src: SocketAddr, buf: &mut [u8]) {
+        let Some(peer) = self.identify(datagram) else {
+            trace!("Datagram from unknown peer {}", src);
+            return;
+        };
+
+        let mut replies = Vec::new();
+        let mut delivered = false;
+        {
+            let mut tunn = peer.tunn.lock().unwrap();
+            let mut result = tunn.decapsulate(Some(src.ip()), datagram, buf);
+            loop {
+                match result {
+                    TunnResult::WriteToNetwork(data) => {
+                        replies.push(data.to_vec());
+                        // Flush packets queued while the handshake was pending
+                        result = tunn.decapsulate(None, &[], buf);
+                        delivered = true;
+                        continue;
+                    }
+                    TunnResult::WriteToTunnelV4(packet, addr) => {
+                        delivered = true;
+                        self.deliver(&peer, packet, addr.into());
+                    }
+                    TunnResult::WriteToTunnelV6(packet, addr) => {
+                        delivered = true;
+                        self.deliver(&peer, packet, addr.into());
+                    }
+                    TunnResult::Done => delivered = true,
+                    TunnResult::Err(e) => debug!("Decapsulate from {} failed: {:?}", src, e),
+                }
+                break;
+            }
+        }
+
+        // Authenticated traffic: follow the peer if it roamed
+        if delivered {
+            *peer.endpoint.lock().unwrap() = Some(src);
+            if datagram.first() == Some(&HANDSHAKE_RESPONSE) {
+                self.shared.metrics.wg_handshakes.inc();
+            }
+        }
+        for reply in replies {
+            self.send_to(&peer, &reply).await;
+        }
+    }
+
+    /// Pass a decrypted packet to the interface if the peer may send from its
+    /// source and the packet filter admits it
+    fn deliver(&self, peer: &WgPeer, packet: &[u8], src: IpAddr) {
+        if !self.routes_from(peer, &src) {
+            debug!(
+                "Dropping packet from {} not owned by peer {}",
+                src, peer.info.tailscale_ip
+            );
+        } else if !self.admits(packet) {
+            trace!("Packet filter dropped packet from {}", src);
+        } else {
+            match &self.shared.interface {
+                Interface::Netstack(netstack) => netstack.inject(packet.to_vec()),
+                Interface::Tun(tun) => {
+                    let _ = tun.send(packet.to_vec());
+                }
+            }
+        }
+    }
+
+    /// Addresses the peer owns, its subnet routes, and anything if it is our
+    /// exit node
+    fn routes_from(&self, peer: &WgPeer, src: &IpAddr) -> bool {
+        peer.info.has_address(src)
+            || peer.info.subnet_route(src).is_some()
+            || self.shared.peers.read().unwrap().exit_node == Some(peer.info.public_key)
+    }
+
+    fn admits(&self, packet: &[u8]) -> bool {
+        let Some(filter) = self.shared.filter.read().unwrap().clone() else {
+            return true;
+        };
+        let Some(info) = PacketInfo::parse(packet) else {
+            return false;
+        };
+        self.shared
+            .flows
+            .lock()
+            .unwrap()
+            .contains_key(&Flow::inbound(&info))
+            || filter.allows(&info)
+    }
+
+    fn identify(&self, datagram: &[u8]) -> Option<Arc<WgPeer>> {
+        let peers = self.shared.peers.read().unwrap();
+        match Tunn::parse_incoming_packet(datagram).ok()? {
+            Packet::HandshakeInit(init) => {
+                let half =
+                    parse_handshake_anon(&self.shared.private_key, &self.shared.public_key, &init)
+                        .ok()?;
+                peers.by_key.get(&half.peer_static_public).cloned()
+            }
+            Packet::HandshakeResponse(p) => peers.by_index.get(&(p.receiver_idx >> 8)).cloned(),
+            Packet::PacketCookieReply(p) => peers.by_index.get(&(p.receiver_idx >> 8)).cloned(),
+            Packet::PacketData(p) => peers.by_index.get(&(p.receiver_idx >> 8)).cloned(),
+        }
+    }
+
+    async fn timer_loop(self) {
+        let mut interval = tokio::time::interval(TIMER_INTERVAL);
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        loop {
+            tokio::select! {
+                _ = self.shared.shutdown.cancelled() => break,
+                _ = interval.tick() => {}
+            }
+
+            let peers: Vec<_> = self
+                .shared
+                .peers
+                .read()
+                .unwrap()
+                .by_key
+                .values()
+                .cloned()
+                .collect();
+            for peer in peers {
+                let datagram = match peer.tunn.lock().unwrap().update_timers(&mut buf) {
+                    TunnResult::WriteToNetwork(data) => data.to_vec(),
+                    _ => continue,
+                };
+                self.send_to(&peer, &datagram).await;
+            }
+
+            self.shared
+                .flows
+                .lock()
+                .unwrap()
+                .retain(|_, seen| seen.elapsed() < FLOW_TIMEOUT);
+        }
+    }
+}
+
+/// Bind a UDP socket; an IPv6 address is dual-stack (v6only off)
+fn bind_udp(listen: SocketAddr) -> io::Result<UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(listen), Type::DGRAM, Some(Protocol::UDP))?;
+    if listen.is_ipv6() {
+        socket.set_only_v6(false)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&listen.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vpn::filter::FilterRule;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn peer(key: &StaticSecret, ip: &str, endpoint: SocketAddr) -> PeerInfo {
+        let ip: IpAddr = ip.parse().unwrap();
+        PeerInfo {
+            public_key: PublicKey::from(key).to_bytes(),
+            tailscale_ip: ip,
+            addresses: vec![ip],
+            endpoint: Some(endpoint),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tcp_over_wireguard() {
+        let key_a = StaticSecret::random_from_rng(rand::thread_rng());
+        let key_b = StaticSecret::random_from_rng(rand::thread_rng());
+        let local = "127.0.0.1:0".parse().unwrap();
+        // Counters of their own, apart from other tests' devices
+        let metrics_a: &'static Metrics = Box::leak(Box::default());
+        let metrics_b: &'static Metrics = Box::leak(Box::default());
+        let a = WgDevice::bind_counted(
+            key_a.clone(),
+            local,
+            &["100.64.0.1".parse().unwrap()],
+            metrics_a,
+        )
+        .await
+        .unwrap();
+        let b = WgDevice::bind_counted(
+            key_b.clone(),
+            local,
+            &["100.64.0.2".parse().unwrap()],
+            metrics_b,
+        )
+        .await
+        .unwrap();
+        a.set_peers(&[peer(&key_b, "100.64.0.2", b.local_addr().unwrap())]);
+        b.set_peers(&[peer(&key_a, "100.64.0.1", a.local_addr().unwrap())]);
+
+        // b only admits a on port 1080; a admits nothing but replies
+        let rules: Vec<FilterRule> = serde_json::from_str(
+            r#"[{"SrcIPs": ["100.64.0.1"], "DstPorts": [{"IP": "*", "Ports": {"First": 1080, "Last": 1080}}]}]"#,
+        )
+        .unwrap();
+        a.set_filter(Arc::new(PacketFilter::default()));
+        b.set_filter(Arc::new(PacketFilter::new(&rules)));
+
+        let listener = b.netstack().unwrap().listen(1080).unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, peer) = listener.accept().await.unwrap();
+            assert_eq!(peer.ip(), "100.64.0.1".parse::<IpAddr>().unwrap());
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+
+        let mut client = a
+            .netstack()
+            .unwrap()
+            .connect("100.64.0.2:1080".parse().unwrap())
+            .await
+            .unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        server.await.unwrap();
+
+        let stats = a.peer_stats()[&PublicKey::from(&key_b).to_bytes()];
+        assert!(stats.since_handshake.is_some());
+        assert_eq!(stats.endpoint, Some(b.local_addr().unwrap()));
+        assert!(stats.rx_bytes > 0 && stats.tx_bytes > 0);
+        // a counts the response it received, b the one it sent
+        assert_eq!(metrics_a.wg_handshakes.get(), 1);
+        assert_eq!(metrics_b.wg_handshakes.get(), 1);
+        for metrics in [metrics_a, metrics_b] {
+            assert!(metrics.packets_direct_rx.get() > 0);
+            assert!(metrics.packets_direct_tx.get() > 0);
+        }
+
+        a.shutdown();
+        b.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_exit_node_routing() {
+        let key_a = StaticSecret::random_from_rng(rand::thread_rng());
+        let key_b = StaticSecret::random_from_rng(rand::thread_rng());
+        let local = "127.0.0.1:0".parse().unwrap();
+        let a = WgDevice::bind(key_a.clone(), local, &["100.64.0.1".parse().unwrap()])
+            .await
+            .unwrap();
+        // b stands in for the internet host behind the exit node
+        let b_addrs = ["100.64.0.2".parse().unwrap(), "192.0.2.80".parse().unwrap()];
+        let b = WgDevice::bind(key_b.clone(), local, &b_addrs)
+            .await
+            .unwrap();
+        let mut exit = peer(&key_b, "100.64.0.2", b.local_addr().unwrap());
+        exit.allowed_ips = vec!["0.0.0.0/0".parse().unwrap(), "::/0".parse().unwrap()];
+        a.set_peers(&[exit]);
+        b.set_peers(&[peer(&key_a, "100.64.0.1", a.local_addr().unwrap())]);
+        let target = "192.0.2.80:80".parse().unwrap();
+
+        // Without an exit node there is no route
+        let timeout = Duration::from_millis(500);
+        assert!(tokio::time::timeout(timeout, a.netstack().unwrap().connect(target))
+            .await
+            .map_or(true, |r| r.is_err()));
+
+        a.set_exit_node(Some(PublicKey::from(&key_b).to_bytes()));
+        let listener = b.netstack().unwrap().listen(80).unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"hi").await.unwrap();
+        });
+        let mut client = a.netstack().unwrap().connect(target).await.unwrap();
+        let mut buf = [0u8; 2];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hi");
+        server.await.unwrap();
+
+        a.shutdown();
+        b.shutdown();
+    }
+}